@@ -0,0 +1,160 @@
+use std::mem;
+use std::ops::Range;
+
+/// A piece of a chunk of text after runs of blank lines are collapsed, see
+/// [BlankLineFilter::filter].
+#[derive(PartialEq, Eq, Debug)]
+pub(crate) enum Piece {
+    /// A byte range of the chunk that is retained.
+    Text(Range<usize>),
+    /// Whitespace at the end of a previous chunk, that was held back until it was known to not
+    /// belong to a blank line that is removed.
+    Whitespace(String),
+}
+
+/// Limits the number of consecutive blank lines in the chunks of text that are passed to it, see
+/// [Options::max_blank_lines].
+///
+/// A line that consists of only whitespace counts as blank. Runs of blank lines may span
+/// multiple chunks.
+///
+/// [Options::max_blank_lines]: crate::Options::max_blank_lines
+#[derive(Debug)]
+pub(crate) struct BlankLineFilter {
+    max: usize,
+    blank_lines: usize,
+    // Whether the current line has only contained whitespace so far.
+    undecided: bool,
+    // The whitespace of the current (undecided) line from previous chunks.
+    whitespace: String,
+}
+
+impl BlankLineFilter {
+    pub(crate) fn new(max: usize) -> Self {
+        BlankLineFilter {
+            max,
+            blank_lines: 0,
+            undecided: true,
+            whitespace: String::new(),
+        }
+    }
+
+    /// Splits `text` into the pieces that remain after removing the blank lines that exceed the
+    /// maximum.
+    ///
+    /// Whitespace at the end of `text` that may start a blank line is held back, see also
+    /// [take_whitespace](BlankLineFilter::take_whitespace).
+    pub(crate) fn filter(&mut self, text: &str) -> Vec<Piece> {
+        let mut pieces = Vec::new();
+        // The start of the range that is currently being retained.
+        let mut start = 0;
+        // The start of the current line, if it is undecided.
+        let mut line_start = 0;
+
+        for (index, c) in text.char_indices() {
+            if !self.undecided {
+                if c == '\n' {
+                    self.undecided = true;
+                    line_start = index + 1;
+                }
+
+                continue;
+            }
+
+            if c == '\n' {
+                self.blank_lines += 1;
+
+                if self.blank_lines > self.max {
+                    push_text(&mut pieces, start..line_start);
+                    self.whitespace.clear();
+                    start = index + 1;
+                } else {
+                    pieces.extend(self.take_whitespace().map(Piece::Whitespace));
+                }
+
+                line_start = index + 1;
+            } else if !c.is_whitespace() {
+                self.blank_lines = 0;
+                self.undecided = false;
+                pieces.extend(self.take_whitespace().map(Piece::Whitespace));
+            }
+        }
+
+        if self.undecided {
+            push_text(&mut pieces, start..line_start);
+            self.whitespace.push_str(&text[line_start..]);
+        } else {
+            push_text(&mut pieces, start..text.len());
+        }
+
+        pieces
+    }
+
+    /// Whether whitespace is held back at the end of the previous chunks.
+    pub(crate) fn holds_whitespace(&self) -> bool {
+        !self.whitespace.is_empty()
+    }
+
+    /// Returns the whitespace that was held back at the end of the previous chunks, if any.
+    pub(crate) fn take_whitespace(&mut self) -> Option<String> {
+        if self.whitespace.is_empty() {
+            None
+        } else {
+            Some(mem::take(&mut self.whitespace))
+        }
+    }
+}
+
+fn push_text(pieces: &mut Vec<Piece>, range: Range<usize>) {
+    if !range.is_empty() {
+        pieces.push(Piece::Text(range));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(filter: &mut BlankLineFilter, chunks: &[&str]) -> String {
+        let mut output = String::new();
+
+        for chunk in chunks {
+            for piece in filter.filter(chunk) {
+                match piece {
+                    Piece::Text(range) => output.push_str(&chunk[range]),
+                    Piece::Whitespace(whitespace) => output.push_str(&whitespace),
+                }
+            }
+        }
+
+        output.extend(filter.take_whitespace());
+
+        output
+    }
+
+    #[test]
+    fn test_filter_blank_lines() {
+        assert_eq!(
+            apply(
+                &mut BlankLineFilter::new(1),
+                &["a\n\n \n\t\nb\n\n  c  \n\n\n"]
+            ),
+            "a\n\nb\n\n  c  \n\n"
+        );
+        assert_eq!(
+            apply(&mut BlankLineFilter::new(0), &["\n\na\r\n\r\nb\n  "]),
+            "a\r\nb\n  "
+        );
+    }
+
+    #[test]
+    fn test_filter_blank_lines_across_chunks() {
+        assert_eq!(
+            apply(
+                &mut BlankLineFilter::new(1),
+                &["a\n", "\n", " ", "\n", "  ", " b\n ", "\n", "\n"]
+            ),
+            "a\n\n   b\n \n"
+        );
+    }
+}
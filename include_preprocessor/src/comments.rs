@@ -0,0 +1,293 @@
+use std::ops::Range;
+
+use crate::{CommentReplacement, CommentStyle, Options};
+
+/// A piece of a chunk of text after its comments are stripped, see [CommentStripper::strip].
+#[derive(PartialEq, Eq, Debug)]
+pub(crate) enum Piece {
+    /// A byte range of the chunk that is retained.
+    Text(Range<usize>),
+    /// Text that is not part of the chunk: the replacement of a comment, or a `/` that was held
+    /// back at the end of the previous chunk.
+    Inserted(&'static str),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Code,
+    /// A `/` that may start a comment.
+    Slash,
+    String {
+        escaped: bool,
+    },
+    LineComment,
+    BlockComment {
+        star: bool,
+    },
+}
+
+/// Strips comments from the chunks of text that are passed to it, see [Options::strip_comments].
+///
+/// Comments may span multiple chunks. The line endings inside of a block comment are retained,
+/// so that the lines that follow it keep their line numbers.
+#[derive(Debug)]
+pub(crate) struct CommentStripper {
+    line_comments: bool,
+    block_comments: bool,
+    strings: bool,
+    replacement: &'static str,
+    state: State,
+}
+
+impl CommentStripper {
+    /// Returns `None` if [Options::strip_comments] is empty.
+    pub(crate) fn new(options: &Options) -> Option<Self> {
+        if options.strip_comments.is_empty() {
+            return None;
+        }
+
+        Some(CommentStripper {
+            line_comments: options.strip_comments.contains(&CommentStyle::Line),
+            block_comments: options.strip_comments.contains(&CommentStyle::Block),
+            strings: options.preserve_strings,
+            replacement: match options.comment_replacement {
+                CommentReplacement::Remove => "",
+                CommentReplacement::Space => " ",
+            },
+            state: State::Code,
+        })
+    }
+
+    /// Splits `text` into the pieces that remain after stripping its comments.
+    ///
+    /// A `/` at the end of `text` is held back until the next chunk shows whether it starts a
+    /// comment, see also [take_slash](CommentStripper::take_slash).
+    pub(crate) fn strip(&mut self, text: &str) -> Vec<Piece> {
+        let mut pieces = Vec::new();
+        // The start of the range that is currently being retained, if any.
+        let mut start = match self.state {
+            State::Code | State::String { .. } => Some(0),
+            _ => None,
+        };
+        // The offset of a `/` in `text` that may start a comment; `None` while in the slash
+        // state means that it was held back at the end of the previous chunk.
+        let mut slash = None;
+
+        for (index, c) in text.char_indices() {
+            if self.state == State::Slash {
+                let style = match c {
+                    '/' if self.line_comments => Some(State::LineComment),
+                    '*' if self.block_comments => Some(State::BlockComment { star: false }),
+                    _ => None,
+                };
+
+                if let Some(state) = style {
+                    self.state = state;
+
+                    if !self.replacement.is_empty() {
+                        pieces.push(Piece::Inserted(self.replacement));
+                    }
+
+                    continue;
+                }
+
+                // The `/` did not start a comment after all.
+                self.state = State::Code;
+                start = match slash.take() {
+                    Some(offset) => Some(offset),
+                    None => {
+                        pieces.push(Piece::Inserted("/"));
+
+                        Some(index)
+                    }
+                };
+            }
+
+            match self.state {
+                State::Code => match c {
+                    '/' if self.line_comments || self.block_comments => {
+                        push_text(&mut pieces, start.take(), index);
+                        self.state = State::Slash;
+                        slash = Some(index);
+                    }
+                    '"' if self.strings => self.state = State::String { escaped: false },
+                    _ => (),
+                },
+                State::String { escaped } => {
+                    self.state = match c {
+                        _ if escaped => State::String { escaped: false },
+                        '\\' => State::String { escaped: true },
+                        // An unterminated string ends at the end of its line.
+                        '"' | '\n' => State::Code,
+                        _ => State::String { escaped: false },
+                    };
+                }
+                State::LineComment => {
+                    if c == '\n' {
+                        self.state = State::Code;
+
+                        // Retain the line ending.
+                        start = if text[..index].ends_with('\r') {
+                            Some(index - 1)
+                        } else {
+                            Some(index)
+                        };
+                    }
+                }
+                State::BlockComment { star } => {
+                    self.state = match c {
+                        '/' if star => {
+                            start = Some(index + 1);
+
+                            State::Code
+                        }
+                        '\r' | '\n' => {
+                            push_text(&mut pieces, Some(index), index + 1);
+
+                            State::BlockComment { star: false }
+                        }
+                        _ => State::BlockComment { star: c == '*' },
+                    };
+                }
+                State::Slash => unreachable!(),
+            }
+        }
+
+        push_text(&mut pieces, start, text.len());
+
+        pieces
+    }
+
+    /// Whether a `/` is held back at the end of the previous chunk.
+    pub(crate) fn holds_slash(&self) -> bool {
+        self.state == State::Slash
+    }
+
+    /// Returns the `/` that was held back at the end of the previous chunk, if any, as it is
+    /// followed by text that is not stripped (e.g. a line inserted by the preprocessor).
+    pub(crate) fn take_slash(&mut self) -> Option<&'static str> {
+        if self.state == State::Slash {
+            self.state = State::Code;
+
+            Some("/")
+        } else {
+            None
+        }
+    }
+
+    /// Ends any comment or string, as the text that follows belongs to a different file; returns
+    /// the `/` that was held back, if any.
+    pub(crate) fn reset(&mut self) -> Option<&'static str> {
+        let slash = self.take_slash();
+
+        self.state = State::Code;
+
+        slash
+    }
+}
+
+/// Adds the range from `start` to `end` to `pieces`, merging it into the last piece if they are
+/// adjacent.
+fn push_text(pieces: &mut Vec<Piece>, start: Option<usize>, end: usize) {
+    let start = match start {
+        Some(start) if start < end => start,
+        _ => return,
+    };
+
+    if let Some(Piece::Text(last)) = pieces.last_mut() {
+        if last.end == start {
+            last.end = end;
+
+            return;
+        }
+    }
+
+    pieces.push(Piece::Text(start..end));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stripper(styles: &[CommentStyle], replacement: CommentReplacement) -> CommentStripper {
+        let mut options = Options {
+            comment_replacement: replacement,
+            ..Options::default()
+        };
+
+        for style in styles {
+            options.strip_comment(*style);
+        }
+
+        CommentStripper::new(&options).unwrap()
+    }
+
+    fn apply(stripper: &mut CommentStripper, chunks: &[&str]) -> String {
+        let mut output = String::new();
+
+        for chunk in chunks {
+            for piece in stripper.strip(chunk) {
+                match piece {
+                    Piece::Text(range) => output.push_str(&chunk[range]),
+                    Piece::Inserted(text) => output.push_str(text),
+                }
+            }
+        }
+
+        output.extend(stripper.take_slash());
+
+        output
+    }
+
+    #[test]
+    fn test_strip_comments() {
+        let both = [CommentStyle::Line, CommentStyle::Block];
+        let mut space = stripper(&both, CommentReplacement::Space);
+        let mut remove = stripper(&both, CommentReplacement::Remove);
+
+        assert_eq!(
+            apply(&mut space, &["float a; // a\r\nfloat/**/b;\n"]),
+            "float a;  \r\nfloat b;\n"
+        );
+        assert_eq!(
+            apply(&mut remove, &["float a; // a\r\nfloat/**/b;\n"]),
+            "float a; \r\nfloatb;\n"
+        );
+        assert_eq!(
+            apply(&mut remove, &["a /* b\n * c **/ d / e //"]),
+            "a \n d / e "
+        );
+    }
+
+    #[test]
+    fn test_strip_comments_across_chunks() {
+        let mut stripper = stripper(
+            &[CommentStyle::Line, CommentStyle::Block],
+            CommentReplacement::Remove,
+        );
+
+        assert_eq!(
+            apply(
+                &mut stripper,
+                &["a /", "* b\n", "c */ d /", " e /", "/ f\n", "g /"]
+            ),
+            "a \n d / e \ng /"
+        );
+    }
+
+    #[test]
+    fn test_strip_comments_strings() {
+        let mut line = stripper(&[CommentStyle::Line], CommentReplacement::Remove);
+
+        assert_eq!(
+            apply(&mut line, &["\"http://a\\\"//\" /* b */ // c\n\"//"]),
+            "\"http://a\\\"//\" /* b */ \n\"//"
+        );
+
+        let mut plain = stripper(&[CommentStyle::Line], CommentReplacement::Remove);
+
+        plain.strings = false;
+
+        assert_eq!(apply(&mut plain, &["\"http://a\"\n"]), "\"http:\n");
+    }
+}
@@ -0,0 +1,205 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{IncludeKind, SourceTracker};
+
+/// Writes a Makefile-style depfile (as understood by Make and Ninja) to `out`, with a single rule
+/// that makes `target` depend on the `dependencies`, e.g. `out/shader.spv: src/a.glsl src/b.glsl`.
+///
+/// Spaces, tabs and `#` characters in the target and the dependencies are escaped with a
+/// backslash, and `$` characters are doubled. Fails with [io::ErrorKind::InvalidInput] if the
+/// target or a dependency contains a line break, or if a dependency is not valid UTF-8.
+pub fn write_depfile<W>(target: &str, dependencies: &[PathBuf], mut out: W) -> io::Result<()>
+where
+    W: Write,
+{
+    let mut rule = escape(target)?;
+
+    rule.push(':');
+
+    for dependency in dependencies {
+        let dependency = dependency.to_str().ok_or_else(|| {
+            invalid_input(format!(
+                "dependency `{}` is not valid UTF-8",
+                dependency.display()
+            ))
+        })?;
+
+        rule.push(' ');
+        rule.push_str(&escape(dependency)?);
+    }
+
+    rule.push('\n');
+
+    out.write_all(rule.as_bytes())
+}
+
+/// Escapes `name` for use in a rule of a depfile.
+///
+/// A backslash only needs escaping if it precedes a character that is escaped, or the separator
+/// that follows the name; such backslashes are doubled.
+fn escape(name: &str) -> io::Result<String> {
+    let mut escaped = String::with_capacity(name.len());
+    // The number of backslashes that directly precede the current character.
+    let mut backslashes = 0;
+
+    for c in name.chars() {
+        match c {
+            '\n' | '\r' => {
+                return Err(invalid_input(format!(
+                    "`{}` contains a line break",
+                    name.escape_debug()
+                )))
+            }
+            ' ' | '\t' | '#' => escaped.push_str(&"\\".repeat(backslashes + 1)),
+            '$' => escaped.push('$'),
+            _ => (),
+        }
+
+        escaped.push(c);
+        backslashes = if c == '\\' { backslashes + 1 } else { 0 };
+    }
+
+    escaped.push_str(&"\\".repeat(backslashes));
+
+    Ok(escaped)
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// A [SourceTracker] that records the files that are used while preprocessing, so that a depfile
+/// can be written for the output (see [write_depfile]), and passes everything on to the
+/// `source_tracker` it wraps.
+#[derive(Debug)]
+pub struct DepfileTracker<T = ()> {
+    source_tracker: T,
+    dependencies: Vec<PathBuf>,
+}
+
+impl<T> DepfileTracker<T> {
+    pub fn new(source_tracker: T) -> Self {
+        DepfileTracker {
+            source_tracker,
+            dependencies: Vec::new(),
+        }
+    }
+
+    /// The files that were tracked, in sorted order (so that the depfile does not depend on the
+    /// order in which files finish loading).
+    pub fn dependencies(&self) -> &[PathBuf] {
+        &self.dependencies
+    }
+
+    /// Writes a depfile that makes `target` depend on the files that were tracked, see
+    /// [write_depfile].
+    pub fn write_depfile<W>(&self, target: &str, out: W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        write_depfile(target, &self.dependencies, out)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.source_tracker
+    }
+}
+
+impl Default for DepfileTracker<()> {
+    fn default() -> Self {
+        DepfileTracker::new(())
+    }
+}
+
+impl<T> SourceTracker for DepfileTracker<T>
+where
+    T: SourceTracker,
+{
+    fn track(&mut self, path: &Path, source: &str) {
+        let dependencies = &mut self.dependencies;
+
+        if let Err(index) =
+            dependencies.binary_search_by(|dependency| dependency.as_path().cmp(path))
+        {
+            dependencies.insert(index, path.to_path_buf());
+        }
+
+        self.source_tracker.track(path, source);
+    }
+
+    fn warning(&mut self, message: &str, source_file: &Path, line_number: usize) {
+        self.source_tracker
+            .warning(message, source_file, line_number);
+    }
+
+    fn note(&mut self, message: &str, source_file: &Path, line_number: usize) {
+        self.source_tracker.note(message, source_file, line_number);
+    }
+
+    fn track_include(
+        &mut self,
+        parent: &Path,
+        child: &Path,
+        line_number: usize,
+        kind: IncludeKind,
+    ) {
+        self.source_tracker
+            .track_include(parent, child, line_number, kind);
+    }
+
+    fn track_missing(&mut self, path: &Path) {
+        self.source_tracker.track_missing(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn depfile(target: &str, dependencies: &[&str]) -> io::Result<String> {
+        let dependencies: Vec<PathBuf> = dependencies.iter().map(PathBuf::from).collect();
+        let mut out = Vec::new();
+
+        write_depfile(target, &dependencies, &mut out)?;
+
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_write_depfile() {
+        assert_eq!(
+            depfile("out/shader.spv", &["src/a.glsl", "src/inc/b.glsl"]).unwrap(),
+            "out/shader.spv: src/a.glsl src/inc/b.glsl\n"
+        );
+        assert_eq!(depfile("out.spv", &[]).unwrap(), "out.spv:\n");
+    }
+
+    #[test]
+    fn test_write_depfile_escaping() {
+        assert_eq!(
+            depfile(
+                "out dir/a.spv",
+                &["my shaders/a b.glsl", "a\tb#c", "$HOME/$$.glsl"]
+            )
+            .unwrap(),
+            "out\\ dir/a.spv: my\\ shaders/a\\ b.glsl a\\\tb\\#c $$HOME/$$$$.glsl\n"
+        );
+        assert_eq!(
+            depfile(
+                "out.spv",
+                &["C:\\shaders\\a.glsl", "a\\ b", "a\\\\#b", "dir\\"]
+            )
+            .unwrap(),
+            "out.spv: C:\\shaders\\a.glsl a\\\\\\ b a\\\\\\\\\\#b dir\\\\\n"
+        );
+    }
+
+    #[test]
+    fn test_write_depfile_line_break() {
+        let err = depfile("out.spv", &["a\nb.glsl"]).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(depfile("out\r\n.spv", &[]).is_err());
+    }
+}
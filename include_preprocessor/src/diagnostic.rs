@@ -0,0 +1,127 @@
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Returns `path` relative to the `display_root`, if it is inside of it.
+pub(crate) fn display_path(path: &Path, display_root: Option<&Path>) -> String {
+    let path = display_root
+        .and_then(|display_root| path.strip_prefix(display_root).ok())
+        .filter(|relative| !relative.as_os_str().is_empty())
+        .unwrap_or(path);
+
+    path.display().to_string()
+}
+
+/// Rewrites the paths inside of the `display_root` that are quoted in `message` (as `` `path` ``)
+/// relative to the display root.
+pub(crate) fn relativize_message<'a>(
+    message: &'a str,
+    display_root: Option<&Path>,
+) -> Cow<'a, str> {
+    let display_root = match display_root {
+        Some(display_root) => display_root,
+        None => return Cow::Borrowed(message),
+    };
+
+    // Collecting the components drops any trailing separator.
+    let display_root: PathBuf = display_root.components().collect();
+    let prefix = format!("`{}{}", display_root.display(), MAIN_SEPARATOR);
+
+    if message.contains(&prefix) {
+        Cow::Owned(message.replace(&prefix, "`"))
+    } else {
+        Cow::Borrowed(message)
+    }
+}
+
+/// Renders a compiler-style error diagnostic: a header with the `message`, the location of
+/// `span`, the line of `source` that contains it with the span underlined, and a `note` line for
+/// each of the `notes`.
+///
+/// The `span` is a byte range that must not extend past the end of its line; an empty span is
+/// marked with a single caret. Line and column numbers are rendered one-based. If `color` is
+/// `true`, the output is styled with ANSI escape codes.
+pub(crate) fn render(
+    message: &str,
+    source_file: &str,
+    source: &str,
+    span: Range<usize>,
+    notes: &[String],
+    color: bool,
+) -> String {
+    let paint = |text: &str, style: &str| {
+        if color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_string()
+        }
+    };
+
+    let line_start = source[..span.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = source[line_start..]
+        .find('\n')
+        .map(|i| line_start + i)
+        .unwrap_or(source.len());
+    let line = source[line_start..line_end].trim_end_matches('\r');
+    let line_number = source[..line_start].matches('\n').count() + 1;
+    let column = source[line_start..span.start].chars().count() + 1;
+
+    // Tabs are retained in the indentation of the underline, so that it lines up with the
+    // source line however tabs are displayed.
+    let indent: String = source[line_start..span.start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let span_len = source[span.start..span.end.min(line_end)].chars().count();
+    let underline = "^".repeat(span_len.max(1));
+
+    let gutter_width = line_number.to_string().len();
+    let gutter = paint(&format!("{} |", " ".repeat(gutter_width)), BLUE);
+
+    let mut output = String::new();
+
+    writeln!(
+        output,
+        "{}{}",
+        paint("error", RED),
+        paint(&format!(": {}", message), BOLD)
+    )
+    .unwrap();
+    writeln!(
+        output,
+        "{}{} {}:{}:{}",
+        " ".repeat(gutter_width),
+        paint("-->", BLUE),
+        source_file,
+        line_number,
+        column
+    )
+    .unwrap();
+    writeln!(output, "{}", gutter).unwrap();
+    writeln!(
+        output,
+        "{} {}",
+        paint(&format!("{} |", line_number), BLUE),
+        line
+    )
+    .unwrap();
+    write!(output, "{} {}{}", gutter, indent, paint(&underline, RED)).unwrap();
+
+    for note in notes {
+        write!(
+            output,
+            "\n{} {}",
+            paint(&format!("{} =", " ".repeat(gutter_width)), BLUE),
+            paint(&format!("note: {}", note), BOLD)
+        )
+        .unwrap();
+    }
+
+    output
+}
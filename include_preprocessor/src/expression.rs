@@ -0,0 +1,462 @@
+use std::fmt;
+
+/// An error that occurred while evaluating a conditional expression.
+#[derive(Debug)]
+pub struct ExpressionError {
+    message: String,
+}
+
+impl ExpressionError {
+    fn new<M>(message: M) -> Self
+    where
+        M: Into<String>,
+    {
+        ExpressionError {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Evaluates a (macro expanded) `#if` expression.
+///
+/// Supports integer literals, identifiers, the comparison operators, `&&`, `||`, `!` and
+/// parentheses. Identifiers evaluate to `0`, as any identifier that still remains after macro
+/// expansion is not defined. `defined` operators must already have been resolved with
+/// [resolve_defined].
+pub fn evaluate(expression: &str) -> Result<i64, ExpressionError> {
+    let tokens = tokenize(expression)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        depth: 0,
+    };
+
+    let value = parser.parse_or()?;
+
+    if let Some(token) = parser.peek() {
+        return Err(ExpressionError::new(format!(
+            "unexpected `{}` in expression",
+            token
+        )));
+    }
+
+    Ok(value)
+}
+
+/// Replaces every `defined(NAME)` and `defined NAME` operator in an `#if` expression with `1` if
+/// `is_defined` returns `true` for `NAME`, or `0` otherwise.
+pub fn resolve_defined<F>(expression: &str, is_defined: F) -> Result<String, ExpressionError>
+where
+    F: Fn(&str) -> bool,
+{
+    let mut output = String::new();
+    let mut rem = expression;
+
+    while let Some(start) = rem.find(|c: char| c.is_ascii_alphanumeric() || c == '_') {
+        output.push_str(&rem[..start]);
+        rem = &rem[start..];
+
+        // Numeric literals are skipped as a whole, so that a suffix is never mistaken for an
+        // identifier.
+        let end = rem
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(rem.len());
+        let token = &rem[..end];
+
+        rem = &rem[end..];
+
+        if token != "defined" {
+            output.push_str(token);
+
+            continue;
+        }
+
+        let operand = rem.trim_start();
+        let (name, r) = if let Some(operand) = operand.strip_prefix('(') {
+            let (name, r) = split_identifier(operand.trim_start())?;
+            let r = r
+                .trim_start()
+                .strip_prefix(')')
+                .ok_or_else(|| ExpressionError::new("expected `)` after `defined` operand"))?;
+
+            (name, r)
+        } else {
+            split_identifier(operand)?
+        };
+
+        output.push_str(if is_defined(name) { "1" } else { "0" });
+        rem = r;
+    }
+
+    output.push_str(rem);
+
+    Ok(output)
+}
+
+fn split_identifier(input: &str) -> Result<(&str, &str), ExpressionError> {
+    let end = input
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(input.len());
+
+    if end == 0 || input.as_bytes()[0].is_ascii_digit() {
+        return Err(ExpressionError::new(
+            "expected an identifier after `defined`",
+        ));
+    }
+
+    Ok(input.split_at(end))
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Operator {
+    Or,
+    And,
+    Not,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    OpenParen,
+    CloseParen,
+}
+
+impl Operator {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Operator::Or => "||",
+            Operator::And => "&&",
+            Operator::Not => "!",
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+            Operator::Less => "<",
+            Operator::LessEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterEqual => ">=",
+            Operator::OpenParen => "(",
+            Operator::CloseParen => ")",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Integer(i64),
+    Identifier(String),
+    Operator(Operator),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Integer(value) => write!(f, "{}", value),
+            Token::Identifier(name) => f.write_str(name),
+            Token::Operator(operator) => f.write_str(operator.as_str()),
+        }
+    }
+}
+
+const OPERATORS: [Operator; 11] = [
+    // Two-character operators must be matched before their one-character prefixes.
+    Operator::Or,
+    Operator::And,
+    Operator::Equal,
+    Operator::NotEqual,
+    Operator::LessEqual,
+    Operator::GreaterEqual,
+    Operator::Not,
+    Operator::Less,
+    Operator::Greater,
+    Operator::OpenParen,
+    Operator::CloseParen,
+];
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, ExpressionError> {
+    let mut tokens = Vec::new();
+    let mut rem = expression;
+
+    'outer: loop {
+        rem = rem.trim_start();
+
+        if rem.is_empty() || rem.starts_with("//") {
+            break;
+        }
+
+        for operator in OPERATORS {
+            if let Some(r) = rem.strip_prefix(operator.as_str()) {
+                tokens.push(Token::Operator(operator));
+                rem = r;
+
+                continue 'outer;
+            }
+        }
+
+        let first = rem.as_bytes()[0];
+
+        if first.is_ascii_digit() {
+            let end = rem
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rem.len());
+
+            tokens.push(Token::Integer(parse_integer(&rem[..end])?));
+            rem = &rem[end..];
+        } else if first.is_ascii_alphabetic() || first == b'_' {
+            let end = rem
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rem.len());
+
+            tokens.push(Token::Identifier(rem[..end].to_string()));
+            rem = &rem[end..];
+        } else {
+            let c = rem.chars().next().unwrap();
+
+            return Err(ExpressionError::new(format!(
+                "unexpected character `{}` in expression",
+                c
+            )));
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_integer(literal: &str) -> Result<i64, ExpressionError> {
+    let digits = literal.trim_end_matches(['u', 'U']);
+
+    let result = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        i64::from_str_radix(hex, 16)
+    } else {
+        digits.parse()
+    };
+
+    result.map_err(|_| ExpressionError::new(format!("invalid integer literal `{}`", literal)))
+}
+
+/// The maximum number of nested `!` operators and parentheses in an expression.
+const MAX_DEPTH: usize = 256;
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, operator: Operator) -> bool {
+        if self.peek() == Some(&Token::Operator(operator)) {
+            self.pos += 1;
+
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<i64, ExpressionError> {
+        let mut value = self.parse_and()?;
+
+        while self.eat(Operator::Or) {
+            let rhs = self.parse_and()?;
+
+            value = (value != 0 || rhs != 0) as i64;
+        }
+
+        Ok(value)
+    }
+
+    fn parse_and(&mut self) -> Result<i64, ExpressionError> {
+        let mut value = self.parse_equality()?;
+
+        while self.eat(Operator::And) {
+            let rhs = self.parse_equality()?;
+
+            value = (value != 0 && rhs != 0) as i64;
+        }
+
+        Ok(value)
+    }
+
+    fn parse_equality(&mut self) -> Result<i64, ExpressionError> {
+        let mut value = self.parse_relational()?;
+
+        loop {
+            if self.eat(Operator::Equal) {
+                value = (value == self.parse_relational()?) as i64;
+            } else if self.eat(Operator::NotEqual) {
+                value = (value != self.parse_relational()?) as i64;
+            } else {
+                break;
+            }
+        }
+
+        Ok(value)
+    }
+
+    fn parse_relational(&mut self) -> Result<i64, ExpressionError> {
+        let mut value = self.parse_unary()?;
+
+        loop {
+            if self.eat(Operator::Less) {
+                value = (value < self.parse_unary()?) as i64;
+            } else if self.eat(Operator::LessEqual) {
+                value = (value <= self.parse_unary()?) as i64;
+            } else if self.eat(Operator::Greater) {
+                value = (value > self.parse_unary()?) as i64;
+            } else if self.eat(Operator::GreaterEqual) {
+                value = (value >= self.parse_unary()?) as i64;
+            } else {
+                break;
+            }
+        }
+
+        Ok(value)
+    }
+
+    fn enter(&mut self) -> Result<(), ExpressionError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(ExpressionError::new("expression nested too deeply"));
+        }
+
+        self.depth += 1;
+
+        Ok(())
+    }
+
+    fn parse_unary(&mut self) -> Result<i64, ExpressionError> {
+        if self.eat(Operator::Not) {
+            self.enter()?;
+
+            let value = self.parse_unary()?;
+
+            self.depth -= 1;
+
+            Ok((value == 0) as i64)
+        } else {
+            self.parse_primary()
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<i64, ExpressionError> {
+        let token = self
+            .peek()
+            .cloned()
+            .ok_or_else(|| ExpressionError::new("unexpected end of expression"))?;
+
+        self.pos += 1;
+
+        match token {
+            Token::Integer(value) => Ok(value),
+            Token::Identifier(_) => Ok(0),
+            Token::Operator(Operator::OpenParen) => {
+                self.enter()?;
+
+                let value = self.parse_or()?;
+
+                if !self.eat(Operator::CloseParen) {
+                    return Err(ExpressionError::new("expected `)` in expression"));
+                }
+
+                self.depth -= 1;
+
+                Ok(value)
+            }
+            token => Err(ExpressionError::new(format!(
+                "unexpected `{}` in expression",
+                token
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(evaluate("1").unwrap(), 1);
+        assert_eq!(evaluate("0x1F").unwrap(), 31);
+        assert_eq!(evaluate("300u >= 300").unwrap(), 1);
+        assert_eq!(evaluate("2 < 1").unwrap(), 0);
+        assert_eq!(evaluate("1 == 1 && 2 != 2").unwrap(), 0);
+        assert_eq!(evaluate("1 == 1 || 2 != 2").unwrap(), 1);
+        assert_eq!(evaluate("!(1 < 2) || !0").unwrap(), 1);
+        assert_eq!(evaluate("UNDEFINED").unwrap(), 0);
+        assert_eq!(evaluate("!UNDEFINED && (1 <= 1)").unwrap(), 1);
+        assert_eq!(evaluate("1 // trailing comment").unwrap(), 1);
+
+        assert!(evaluate("").is_err());
+        assert!(evaluate("1 &&").is_err());
+        assert!(evaluate("(1").is_err());
+        assert!(evaluate("1 1").is_err());
+        assert!(evaluate("3.0 > 1").is_err());
+        assert!(evaluate("1 + 1").is_err());
+    }
+
+    #[test]
+    fn test_evaluate_nesting() {
+        let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+
+        assert_eq!(evaluate(&nested(MAX_DEPTH)).unwrap(), 1);
+        assert_eq!(evaluate(&format!("{}1", "!".repeat(MAX_DEPTH))).unwrap(), 1);
+
+        let error = evaluate(&nested(2000)).unwrap_err();
+
+        assert_eq!(error.to_string(), "expression nested too deeply");
+
+        let error = evaluate(&format!("{}1", "!".repeat(2000))).unwrap_err();
+
+        assert_eq!(error.to_string(), "expression nested too deeply");
+
+        let error = evaluate(&format!("{}1", "!(".repeat(1000))).unwrap_err();
+
+        assert_eq!(error.to_string(), "expression nested too deeply");
+    }
+
+    #[test]
+    fn test_resolve_defined() {
+        let is_defined = |name: &str| name == "FOO";
+
+        assert_eq!(resolve_defined("defined(FOO)", is_defined).unwrap(), "1");
+        assert_eq!(resolve_defined("defined FOO", is_defined).unwrap(), "1");
+        assert_eq!(resolve_defined("defined ( FOO )", is_defined).unwrap(), "1");
+        assert_eq!(resolve_defined("defined\tBAR", is_defined).unwrap(), "0");
+        assert_eq!(
+            resolve_defined("defined(FOO) && !defined(BAR)", is_defined).unwrap(),
+            "1 && !0"
+        );
+        assert_eq!(
+            resolve_defined("VERSION >= 300u || defined FOO", is_defined).unwrap(),
+            "VERSION >= 300u || 1"
+        );
+
+        // `defined` as part of a longer identifier is not the operator.
+        assert_eq!(
+            resolve_defined(
+                "is_defined(FOO) + undefined_value + defined_FOO",
+                is_defined
+            )
+            .unwrap(),
+            "is_defined(FOO) + undefined_value + defined_FOO"
+        );
+
+        assert!(resolve_defined("defined", is_defined).is_err());
+        assert!(resolve_defined("defined()", is_defined).is_err());
+        assert!(resolve_defined("defined(FOO", is_defined).is_err());
+        assert!(resolve_defined("defined 1", is_defined).is_err());
+    }
+}
@@ -0,0 +1,556 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{Error as IOError, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Provides the preprocessor with access to source files, see [Options::file_provider].
+///
+/// [Options::file_provider]: crate::Options::file_provider
+pub trait FileProvider: fmt::Debug + Send + Sync {
+    /// Reads the contents of the file at `path`.
+    fn read(&self, path: &Path) -> Result<String, IOError>;
+
+    /// Returns `true` if `path` refers to a file.
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Returns the canonical form of `path`, which identifies the file (e.g. for `#pragma once`).
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, IOError>;
+
+    /// Returns the paths of the entries of the directory at `path`.
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, IOError>;
+
+    /// Returns the modification time and the size of the file at `path`, which lets a
+    /// [PreprocessCache] detect that the file did not change without reading it.
+    ///
+    /// Returns `None` by default, in which case the cache compares the file's content instead.
+    ///
+    /// [PreprocessCache]: crate::PreprocessCache
+    fn modified(&self, _path: &Path) -> Option<(SystemTime, u64)> {
+        None
+    }
+}
+
+/// A [FileProvider] that accesses the file system through [std::fs].
+///
+/// Files are decoded as UTF-8, with or without a byte order mark, which is removed. With the
+/// `encoding` feature, files that start with a UTF-16 byte order mark are decoded as UTF-16 (little
+/// or big endian) instead.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct StdFs;
+
+impl FileProvider for StdFs {
+    fn read(&self, path: &Path) -> Result<String, IOError> {
+        fs::read(path).and_then(decode)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, IOError> {
+        path.canonicalize()
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, IOError> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn modified(&self, path: &Path) -> Option<(SystemTime, u64)> {
+        let metadata = fs::metadata(path).ok()?;
+
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+}
+
+/// Decodes the contents of a source file, see [StdFs].
+///
+/// Fails with [ErrorKind::InvalidData] if the contents are not valid in the encoding that is
+/// detected, with the byte offset (from the start of the contents, including the byte order mark)
+/// of the sequence that is invalid.
+pub(crate) fn decode(mut bytes: Vec<u8>) -> Result<String, IOError> {
+    match bytes.as_slice() {
+        [0xFF, 0xFE, ..] => return decode_utf16(&bytes, "UTF-16LE", u16::from_le_bytes),
+        [0xFE, 0xFF, ..] => return decode_utf16(&bytes, "UTF-16BE", u16::from_be_bytes),
+        _ => (),
+    }
+
+    let bom_len = if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        3
+    } else {
+        0
+    };
+
+    bytes.drain(..bom_len);
+
+    String::from_utf8(bytes).map_err(|err| {
+        invalid_data(format!(
+            "invalid UTF-8 sequence at byte offset {}",
+            bom_len + err.utf8_error().valid_up_to()
+        ))
+    })
+}
+
+/// Decodes `bytes`, which start with a byte order mark, as UTF-16 with the code units converted by
+/// `from_bytes`.
+#[cfg(feature = "encoding")]
+fn decode_utf16(
+    bytes: &[u8],
+    encoding: &str,
+    from_bytes: fn([u8; 2]) -> u16,
+) -> Result<String, IOError> {
+    let units = bytes[2..]
+        .chunks_exact(2)
+        .map(|unit| from_bytes([unit[0], unit[1]]));
+
+    let mut source = String::with_capacity(bytes.len() / 2);
+    let mut offset = 2;
+
+    for c in char::decode_utf16(units) {
+        let c = c.map_err(|_| {
+            invalid_data(format!(
+                "invalid {} sequence at byte offset {}",
+                encoding, offset
+            ))
+        })?;
+
+        offset += c.len_utf16() * 2;
+        source.push(c);
+    }
+
+    if offset < bytes.len() {
+        return Err(invalid_data(format!(
+            "incomplete {} code unit at byte offset {}",
+            encoding, offset
+        )));
+    }
+
+    Ok(source)
+}
+
+#[cfg(not(feature = "encoding"))]
+fn decode_utf16(
+    _bytes: &[u8],
+    encoding: &str,
+    _from_bytes: fn([u8; 2]) -> u16,
+) -> Result<String, IOError> {
+    Err(invalid_data(format!(
+        "{} (detected from the byte order mark) is only supported with the `encoding` feature",
+        encoding
+    )))
+}
+
+fn invalid_data(message: String) -> IOError {
+    IOError::new(ErrorKind::InvalidData, message)
+}
+
+/// A [FileProvider] that serves files from memory.
+///
+/// Paths are normalized lexically (`.` and `..` components are resolved without consulting the
+/// file system), which also serves as their canonical form.
+#[derive(Clone, Default, Debug)]
+pub struct MemoryFs {
+    files: HashMap<PathBuf, String>,
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        MemoryFs {
+            files: HashMap::new(),
+        }
+    }
+
+    /// Adds a file at `path` with the given `source`, replacing any existing file at that path.
+    pub fn insert<P, S>(&mut self, path: P, source: S) -> &mut Self
+    where
+        P: AsRef<Path>,
+        S: Into<String>,
+    {
+        self.files.insert(normalize(path.as_ref()), source.into());
+
+        self
+    }
+}
+
+impl From<HashMap<PathBuf, String>> for MemoryFs {
+    fn from(files: HashMap<PathBuf, String>) -> Self {
+        let files = files
+            .into_iter()
+            .map(|(path, source)| (normalize(&path), source))
+            .collect();
+
+        MemoryFs { files }
+    }
+}
+
+impl FileProvider for MemoryFs {
+    fn read(&self, path: &Path) -> Result<String, IOError> {
+        self.files
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.contains_key(&normalize(path))
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, IOError> {
+        let path = normalize(path);
+
+        if self.files.contains_key(&path) {
+            Ok(path)
+        } else {
+            Err(not_found(&path))
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, IOError> {
+        let directory = normalize(path);
+
+        // Directories only exist implicitly, as the ancestors of the files.
+        let mut entries: Vec<PathBuf> = self
+            .files
+            .keys()
+            .filter_map(|file| {
+                let name = file.strip_prefix(&directory).ok()?.components().next()?;
+
+                Some(directory.join(name))
+            })
+            .collect();
+
+        if entries.is_empty() {
+            return Err(not_found(&directory));
+        }
+
+        entries.sort();
+        entries.dedup();
+
+        Ok(entries)
+    }
+}
+
+/// A [FileProvider] that serves files from an in-memory overlay where present, and from the file
+/// system otherwise.
+///
+/// An overlaid file that also exists on the file system takes its place, i.e. it has the same
+/// canonical path, so that e.g. `#pragma once` treats both as the same file. This allows
+/// preprocessing unsaved edits to a file without writing it to disk.
+#[derive(Clone, Default, Debug)]
+pub struct OverlayProvider {
+    overlay: MemoryFs,
+}
+
+impl OverlayProvider {
+    pub fn new() -> Self {
+        OverlayProvider {
+            overlay: MemoryFs::new(),
+        }
+    }
+
+    /// Overlays the file at `path` with the given `source`.
+    pub fn insert<P, S>(&mut self, path: P, source: S) -> &mut Self
+    where
+        P: AsRef<Path>,
+        S: Into<String>,
+    {
+        let path = path.as_ref();
+        let key = path.canonicalize().unwrap_or_else(|_| normalize(path));
+
+        self.overlay.insert(key, source);
+
+        self
+    }
+
+    /// Returns the path under which `path` is overlaid, if it is.
+    fn overlay_key(&self, path: &Path) -> Option<PathBuf> {
+        let normalized = normalize(path);
+
+        if self.overlay.files.contains_key(&normalized) {
+            return Some(normalized);
+        }
+
+        let canonical = path.canonicalize().ok()?;
+
+        if self.overlay.files.contains_key(&canonical) {
+            Some(canonical)
+        } else {
+            None
+        }
+    }
+}
+
+impl FileProvider for OverlayProvider {
+    fn read(&self, path: &Path) -> Result<String, IOError> {
+        if let Some(key) = self.overlay_key(path) {
+            self.overlay.read(&key)
+        } else {
+            StdFs.read(path)
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.overlay_key(path).is_some() || StdFs.exists(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, IOError> {
+        if let Some(key) = self.overlay_key(path) {
+            Ok(key)
+        } else {
+            StdFs.canonicalize(path)
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, IOError> {
+        let mut entries = match (StdFs.read_dir(path), self.overlay.read_dir(path)) {
+            (Err(err), Err(_)) => return Err(err),
+            (disk, overlay) => {
+                let mut entries = disk.unwrap_or_default();
+
+                entries.extend(overlay.unwrap_or_default());
+
+                entries
+            }
+        };
+
+        entries.sort();
+        entries.dedup();
+
+        Ok(entries)
+    }
+
+    fn modified(&self, path: &Path) -> Option<(SystemTime, u64)> {
+        if self.overlay_key(path).is_some() {
+            None
+        } else {
+            StdFs.modified(path)
+        }
+    }
+}
+
+/// A [FileProvider] that serves a single file from memory, and all other files from another
+/// provider, see [preprocess_str](crate::preprocess_str).
+#[derive(Debug)]
+pub(crate) struct VirtualFile {
+    // The normalized path of the file, which is also its canonical form.
+    path: PathBuf,
+    source: String,
+    file_provider: Arc<dyn FileProvider>,
+}
+
+impl VirtualFile {
+    pub(crate) fn new(path: &Path, source: String, file_provider: Arc<dyn FileProvider>) -> Self {
+        VirtualFile {
+            path: normalize(path),
+            source,
+            file_provider,
+        }
+    }
+
+    fn is_virtual(&self, path: &Path) -> bool {
+        normalize(path) == self.path
+    }
+}
+
+impl FileProvider for VirtualFile {
+    fn read(&self, path: &Path) -> Result<String, IOError> {
+        if self.is_virtual(path) {
+            Ok(self.source.clone())
+        } else {
+            self.file_provider.read(path)
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.is_virtual(path) || self.file_provider.exists(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, IOError> {
+        if self.is_virtual(path) {
+            Ok(self.path.clone())
+        } else {
+            self.file_provider.canonicalize(path)
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, IOError> {
+        let contains_virtual = self.path.parent() == Some(&normalize(path));
+        let mut entries = match self.file_provider.read_dir(path) {
+            Ok(entries) => entries,
+            Err(_) if contains_virtual => Vec::new(),
+            Err(err) => return Err(err),
+        };
+
+        if contains_virtual {
+            entries.push(self.path.clone());
+            entries.sort();
+            entries.dedup();
+        }
+
+        Ok(entries)
+    }
+
+    fn modified(&self, path: &Path) -> Option<(SystemTime, u64)> {
+        if self.is_virtual(path) {
+            None
+        } else {
+            self.file_provider.modified(path)
+        }
+    }
+}
+
+/// Resolves the `.` and `..` components of `path`, see [try_normalize].
+///
+/// Returns `path` unchanged if it leads above the root.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    try_normalize(path).unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Resolves the `.` and `..` components of `path` lexically, without consulting the file system.
+///
+/// Leading `..` components of a relative path are retained. Returns `None` if a `..` component
+/// of an absolute path leads above the root.
+pub(crate) fn try_normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir | Component::Prefix(_)) => return None,
+                _ => normalized.push(component),
+            },
+            component => normalized.push(component),
+        }
+    }
+
+    Some(normalized)
+}
+
+fn not_found(path: &Path) -> IOError {
+    IOError::new(
+        ErrorKind::NotFound,
+        format!("no such file: {}", path.display()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_fs() {
+        let mut files = MemoryFs::new();
+
+        files
+            .insert("/shaders/a.glsl", "a")
+            .insert("/shaders/lib/b.glsl", "b");
+
+        assert_eq!(
+            files.read(Path::new("/shaders/./lib/../a.glsl")).unwrap(),
+            "a"
+        );
+        assert!(files.exists(Path::new("/shaders/lib/b.glsl")));
+        assert!(!files.exists(Path::new("/shaders/lib")));
+        assert!(files.read(Path::new("/shaders/c.glsl")).is_err());
+        assert_eq!(
+            files
+                .canonicalize(Path::new("/shaders/lib/../a.glsl"))
+                .unwrap(),
+            Path::new("/shaders/a.glsl")
+        );
+        assert_eq!(
+            files.read_dir(Path::new("/shaders")).unwrap(),
+            vec![
+                PathBuf::from("/shaders/a.glsl"),
+                PathBuf::from("/shaders/lib")
+            ]
+        );
+        assert!(files.read_dir(Path::new("/other")).is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        let message = |bytes: &[u8]| decode(bytes.to_vec()).unwrap_err().to_string();
+
+        assert_eq!(decode(b"\xEF\xBB\xBFa\n".to_vec()).unwrap(), "a\n");
+        assert_eq!(decode("ü".into()).unwrap(), "ü");
+        assert_eq!(
+            message(b"\xEF\xBB\xBFab\xC3"),
+            "invalid UTF-8 sequence at byte offset 5"
+        );
+
+        if cfg!(feature = "encoding") {
+            assert_eq!(decode(b"\xFF\xFEa\0\xFC\0".to_vec()).unwrap(), "aü");
+            assert_eq!(decode(b"\xFE\xFF\xD8\x3D\xDE\x00".to_vec()).unwrap(), "😀");
+            assert_eq!(
+                message(b"\xFE\xFF\0a\xDC\x00"),
+                "invalid UTF-16BE sequence at byte offset 4"
+            );
+            assert_eq!(
+                message(b"\xFF\xFEa\0b"),
+                "incomplete UTF-16LE code unit at byte offset 4"
+            );
+        } else {
+            assert_eq!(
+                message(b"\xFF\xFEa\0"),
+                "UTF-16LE (detected from the byte order mark) is only supported with the \
+                 `encoding` feature"
+            );
+        }
+    }
+
+    #[test]
+    fn test_virtual_file() {
+        let mut files = MemoryFs::new();
+
+        files.insert("/shaders/lib/b.glsl", "b");
+
+        let files = VirtualFile::new(
+            Path::new("/shaders/./a.glsl"),
+            "a".to_string(),
+            Arc::new(files),
+        );
+
+        assert_eq!(files.read(Path::new("/shaders/a.glsl")).unwrap(), "a");
+        assert_eq!(files.read(Path::new("/shaders/lib/b.glsl")).unwrap(), "b");
+        assert!(files.exists(Path::new("/shaders/lib/../a.glsl")));
+        assert_eq!(
+            files
+                .canonicalize(Path::new("/shaders/lib/../a.glsl"))
+                .unwrap(),
+            Path::new("/shaders/a.glsl")
+        );
+        assert_eq!(
+            files.read_dir(Path::new("/shaders")).unwrap(),
+            vec![
+                PathBuf::from("/shaders/a.glsl"),
+                PathBuf::from("/shaders/lib")
+            ]
+        );
+        assert_eq!(
+            files.read_dir(Path::new("/shaders/lib")).unwrap(),
+            vec![PathBuf::from("/shaders/lib/b.glsl")]
+        );
+    }
+
+    #[test]
+    fn test_try_normalize() {
+        assert_eq!(
+            try_normalize(Path::new("/shaders//lib/./../common/a.glsl")),
+            Some(PathBuf::from("/shaders/common/a.glsl"))
+        );
+        assert_eq!(
+            try_normalize(Path::new("../shaders/../../a.glsl")),
+            Some(PathBuf::from("../../a.glsl"))
+        );
+        assert_eq!(try_normalize(Path::new("/shaders/../../a.glsl")), None);
+    }
+}
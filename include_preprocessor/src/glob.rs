@@ -0,0 +1,57 @@
+use std::path::Path;
+
+/// Returns `true` if `pattern` matches `path`, or any trailing sequence of its components.
+///
+/// In a pattern, `*` matches any sequence of characters within a component, `**` matches any
+/// sequence of characters including `/`, and `?` matches a single character other than `/`.
+pub fn matches_path(pattern: &str, path: &Path) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.to_string_lossy().replace('\\', "/").chars().collect();
+
+    (0..path.len())
+        .filter(|start| *start == 0 || path[start - 1] == '/')
+        .any(|start| matches(&pattern, &path[start..]))
+}
+
+fn matches(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', rest @ ..] => {
+            // `**/` also matches no directories at all.
+            let skip_separator = rest.first() == Some(&'/') && matches(&rest[1..], text);
+
+            skip_separator || (0..=text.len()).any(|start| matches(rest, &text[start..]))
+        }
+        ['*', rest @ ..] => {
+            let component_len = text.iter().position(|c| *c == '/').unwrap_or(text.len());
+
+            (0..=component_len).any(|start| matches(rest, &text[start..]))
+        }
+        ['?', rest @ ..] => {
+            text.first().map(|c| *c != '/').unwrap_or(false) && matches(rest, &text[1..])
+        }
+        [c, rest @ ..] => text.first() == Some(c) && matches(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_path() {
+        let path = Path::new("/project/third_party/gpl/noise/simplex.glsl");
+
+        assert!(matches_path("third_party/gpl/**", path));
+        assert!(matches_path("/project/**/simplex.glsl", path));
+        assert!(matches_path("**/gpl/**", path));
+        assert!(matches_path("noise/*.glsl", path));
+        assert!(matches_path("simplex.gls?", path));
+        assert!(matches_path("**/simplex.glsl", Path::new("simplex.glsl")));
+
+        assert!(!matches_path("third_party/gpl/*.glsl", path));
+        assert!(!matches_path("party/gpl/**", path));
+        assert!(!matches_path("third_party/mit/**", path));
+        assert!(!matches_path("*.wgsl", path));
+    }
+}
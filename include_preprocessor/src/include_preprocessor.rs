@@ -1,18 +1,63 @@
-use std::collections::{hash_map::DefaultHasher, HashMap, HashSet};
+use std::borrow::Cow;
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
+use std::error::Error as StdError;
+use std::ffi::OsStr;
 use std::hash::{Hash, Hasher};
-use std::io::Error as IOError;
+use std::io::{Error as IOError, ErrorKind as IOErrorKind, Read, Write};
 use std::ops::Range;
-use std::path::{Path, PathBuf};
-use std::sync::{mpsc, Arc};
-use std::{fs, mem, slice};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+#[cfg(any(feature = "threadpool", feature = "rayon"))]
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use std::{env, fmt, iter, mem, slice, thread};
 
+#[cfg(feature = "threadpool")]
 use threadpool::ThreadPool;
+#[cfg(feature = "tokio")]
+use tokio::sync::Semaphore;
+#[cfg(feature = "tokio")]
+use tokio::task::JoinSet;
 
-use crate::line_parser::{parse_line, IncludePath, Line};
+use crate::blank_lines::{self, BlankLineFilter};
+use crate::comments::{self, CommentStripper};
+use crate::diagnostic;
+use crate::expression;
+use crate::file_provider::{self, FileProvider, StdFs, VirtualFile};
+use crate::glob;
+use crate::line_parser::{
+    parse_custom_line, parse_include_path, parse_line, skip_line, IncludeKind, IncludePath, Line,
+};
+use crate::macros::{Builtins, Macro, MacroTable};
+use crate::minify::{self, Minifier};
 
+#[derive(Clone, Debug)]
 pub struct SearchPaths {
     base_paths: Vec<PathBuf>,
     quoted_paths: Vec<PathBuf>,
+    extensions: Vec<String>,
+    case_insensitive: bool,
+    angle_quoted_search: bool,
+    angle_as_quoted: bool,
+    quoted_fallback: bool,
+    root_path: Option<PathBuf>,
+    aliases: Vec<(String, String)>,
+    deny_patterns: Vec<String>,
+    sandbox_root: Option<PathBuf>,
+    groups: Vec<SearchPathGroup>,
+    path_normalization: PathNormalization,
+    resolver: Option<Arc<dyn Resolver>>,
+    tracer: Option<Arc<dyn ResolutionTracer>>,
+}
+
+impl Default for SearchPaths {
+    fn default() -> Self {
+        SearchPaths::new()
+    }
 }
 
 impl SearchPaths {
@@ -20,10 +65,23 @@ impl SearchPaths {
         SearchPaths {
             base_paths: Vec::new(),
             quoted_paths: Vec::new(),
+            extensions: Vec::new(),
+            case_insensitive: false,
+            angle_quoted_search: false,
+            angle_as_quoted: false,
+            quoted_fallback: true,
+            root_path: None,
+            aliases: Vec::new(),
+            deny_patterns: Vec::new(),
+            sandbox_root: None,
+            groups: Vec::new(),
+            path_normalization: PathNormalization::Canonical,
+            resolver: None,
+            tracer: None,
         }
     }
 
-    pub fn push_base_path<P>(&mut self, path: P)
+    pub fn push_base_path<P>(&mut self, path: P) -> &mut Self
     where
         P: AsRef<Path>,
     {
@@ -32,9 +90,31 @@ impl SearchPaths {
         buf.push(path);
 
         self.base_paths.push(buf);
+
+        self
+    }
+
+    /// Adds a base path, see [push_base_path](SearchPaths::push_base_path).
+    pub fn with_base_path<P>(mut self, path: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        self.push_base_path(path);
+
+        self
+    }
+
+    /// Adds a quoted path, see [push_quoted_path](SearchPaths::push_quoted_path).
+    pub fn with_quoted_path<P>(mut self, path: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        self.push_quoted_path(path);
+
+        self
     }
 
-    pub fn push_quoted_path<P>(&mut self, path: P)
+    pub fn push_quoted_path<P>(&mut self, path: P) -> &mut Self
     where
         P: AsRef<Path>,
     {
@@ -43,551 +123,9613 @@ impl SearchPaths {
         buf.push(path);
 
         self.quoted_paths.push(buf);
+
+        self
     }
 
-    pub fn base_paths(&self) -> impl Iterator<Item = &PathBuf> {
-        self.base_paths.iter()
+    /// Like [push_base_path](SearchPaths::push_base_path), but first replaces a leading `~`
+    /// component of `path` with the home directory of the current user, see [expand_home].
+    pub fn push_base_path_expand_home<P>(&mut self, path: P) -> Result<&mut Self, HomeDirError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = expand_home(path)?;
+
+        Ok(self.push_base_path(path))
     }
 
-    pub fn quoted_paths(&self) -> impl Iterator<Item = &PathBuf> {
-        self.quoted_paths.iter().chain(self.base_paths.iter())
+    /// Like [push_quoted_path](SearchPaths::push_quoted_path), but first replaces a leading `~`
+    /// component of `path` with the home directory of the current user, see [expand_home].
+    pub fn push_quoted_path_expand_home<P>(&mut self, path: P) -> Result<&mut Self, HomeDirError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = expand_home(path)?;
+
+        Ok(self.push_quoted_path(path))
     }
-}
 
-#[derive(Debug)]
-pub enum Error {
-    FileNotFound(FileNotFoundError),
-    IO(IOError),
-    Parse(ParseError),
-}
+    /// Creates search paths with a base path for every entry in `paths`, a list separated by the
+    /// platform's path list separator (`:` on Unix, `;` on Windows, as in the `PATH` environment
+    /// variable).
+    ///
+    /// Empty entries are skipped.
+    pub fn from_env_str<S>(paths: S) -> Self
+    where
+        S: AsRef<OsStr>,
+    {
+        let mut search_paths = SearchPaths::new();
 
-impl From<FileNotFoundError> for Error {
-    fn from(err: FileNotFoundError) -> Self {
-        Error::FileNotFound(err)
+        search_paths.base_paths = env::split_paths(paths.as_ref())
+            .filter(|path| !path.as_os_str().is_empty())
+            .collect();
+
+        search_paths
     }
-}
 
-impl From<IOError> for Error {
-    fn from(err: IOError) -> Self {
-        Error::IO(err)
+    /// Like [from_env_str](SearchPaths::from_env_str), for the value of the environment variable
+    /// `name`.
+    ///
+    /// The search paths are empty if the variable is not set.
+    pub fn from_env_var<K>(name: K) -> Self
+    where
+        K: AsRef<OsStr>,
+    {
+        env::var_os(name)
+            .map(SearchPaths::from_env_str)
+            .unwrap_or_default()
     }
-}
 
-impl From<ParseError> for Error {
-    fn from(err: ParseError) -> Self {
-        Error::Parse(err)
+    /// Like [from_env_str](SearchPaths::from_env_str), but appends the base paths to these search
+    /// paths.
+    ///
+    /// Returns the number of base paths that were added.
+    pub fn extend_from_env_str<S>(&mut self, paths: S) -> usize
+    where
+        S: AsRef<OsStr>,
+    {
+        self.append_base_paths(SearchPaths::from_env_str(paths))
     }
-}
 
-#[derive(Debug)]
-pub struct FileNotFoundError {
-    included_path: PathBuf,
-    source_file: PathBuf,
-    source: String,
-    line_number: usize,
-}
+    /// Like [from_env_var](SearchPaths::from_env_var), but appends the base paths to these search
+    /// paths.
+    ///
+    /// Returns the number of base paths that were added, which is `0` if the variable is not set.
+    pub fn extend_from_env_var<K>(&mut self, name: K) -> usize
+    where
+        K: AsRef<OsStr>,
+    {
+        self.append_base_paths(SearchPaths::from_env_var(name))
+    }
 
-impl FileNotFoundError {
-    pub fn included_path(&self) -> &Path {
-        &self.included_path
+    fn append_base_paths(&mut self, other: SearchPaths) -> usize {
+        let count = other.base_paths.len();
+
+        self.base_paths.extend(other.base_paths);
+
+        count
     }
 
-    pub fn source_file(&self) -> &Path {
-        &self.source_file
+    /// Adds an extension (e.g. `".glsl"`) that is appended to an include path that does not
+    /// resolve to a file as written.
+    ///
+    /// A file that matches the include path exactly always takes precedence. If more than one
+    /// extension matches under the same search path, the include is ambiguous and resolution
+    /// fails.
+    pub fn push_extension<E>(&mut self, extension: E) -> &mut Self
+    where
+        E: Into<String>,
+    {
+        self.extensions.push(extension.into());
+
+        self
     }
 
-    pub fn source(&self) -> &str {
-        &self.source
+    /// Sets whether an include path that does not resolve to a file may fall back to a file that
+    /// matches it case-insensitively.
+    ///
+    /// This is disabled by default. A case-insensitive match is reported as a warning with
+    /// [SourceTracker::warning]; if more than one file matches, resolution fails.
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) -> &mut Self {
+        self.case_insensitive = case_insensitive;
+
+        self
     }
 
-    pub fn line_number(&self) -> usize {
-        self.line_number
+    /// Sets whether angle includes (e.g. `#include <foo.glsl>`) also search the
+    /// [quoted_paths](SearchPaths::quoted_paths), before the base paths.
+    ///
+    /// This is disabled by default. Angle includes never search the directory of the including
+    /// file.
+    pub fn set_angle_quoted_search(&mut self, angle_quoted_search: bool) -> &mut Self {
+        self.angle_quoted_search = angle_quoted_search;
+
+        self
     }
-}
 
-#[derive(Debug)]
-pub struct ParseError {
-    message: String,
-    source_file: PathBuf,
-    source: String,
-    line_number: usize,
-}
+    /// Sets whether angle includes are resolved exactly like quoted includes, i.e. relative to the
+    /// including file first.
+    ///
+    /// This is disabled by default, and takes precedence over
+    /// [set_angle_quoted_search](SearchPaths::set_angle_quoted_search). Only affects resolution:
+    /// a [Resolver] still receives an [IncludePath::Angle].
+    pub fn set_angle_as_quoted(&mut self, angle_as_quoted: bool) -> &mut Self {
+        self.angle_as_quoted = angle_as_quoted;
 
-impl ParseError {
-    pub fn message(&self) -> &str {
-        &self.message
+        self
     }
 
-    pub fn source_file(&self) -> &Path {
-        &self.source_file
+    /// Sets whether a quoted include (e.g. `#include "foo.glsl"`) that is not found relative to
+    /// the including file falls back to searching the quoted paths and the base paths.
+    ///
+    /// This is enabled by default. When disabled, quoted includes only ever resolve relative to
+    /// the including file.
+    pub fn set_quoted_fallback(&mut self, quoted_fallback: bool) -> &mut Self {
+        self.quoted_fallback = quoted_fallback;
+
+        self
     }
 
-    pub fn source(&self) -> &str {
-        &self.source
+    /// Sets the path that include paths with a leading `/` (e.g. `#include </common/colors.glsl>`)
+    /// are resolved against.
+    ///
+    /// Defaults to the first base path.
+    pub fn set_root_path<P>(&mut self, path: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.root_path = Some(path.as_ref().to_path_buf());
+
+        self
     }
 
-    pub fn line_number(&self) -> usize {
-        self.line_number
+    /// Adds an alias that replaces the `prefix` of an include path with `replacement` before the
+    /// include path is resolved, e.g. `push_alias("lygia/", "third_party/lygia-1.2/")`.
+    ///
+    /// Applies to both angle and quoted include paths. If more than one alias matches, the alias
+    /// with the longest prefix is applied.
+    pub fn push_alias<P, R>(&mut self, prefix: P, replacement: R) -> &mut Self
+    where
+        P: Into<String>,
+        R: Into<String>,
+    {
+        self.aliases.push((prefix.into(), replacement.into()));
+
+        self
     }
-}
 
-pub fn preprocess<P, S, T>(
-    entry_point: P,
-    search_paths: SearchPaths,
-    mut writer: S,
-    source_tracker: &mut T,
-) -> Result<S, Error>
-where
-    P: AsRef<Path>,
-    S: OutputSink,
-    T: SourceTracker,
-{
-    let parsed = Parsed::try_init(entry_point, search_paths)?;
+    /// Adds a glob-style pattern (e.g. `third_party/gpl/**`) for files that must never be
+    /// included.
+    ///
+    /// A pattern is matched against both the include path as written and the resolved (canonical)
+    /// path of the included file, where it may match the path as a whole or any trailing sequence
+    /// of its components. In a pattern, `*` matches within a single component, `**` matches
+    /// across components and `?` matches a single character.
+    pub fn push_deny_pattern<P>(&mut self, pattern: P) -> &mut Self
+    where
+        P: Into<String>,
+    {
+        self.deny_patterns.push(pattern.into());
 
-    parsed.write(&mut writer, source_tracker);
+        self
+    }
 
-    Ok(writer)
-}
+    /// Sets a directory that every included file must be located in.
+    ///
+    /// Included files are checked after their paths are canonicalized, so neither `..`
+    /// components nor symbolic links can be used to include a file outside of the sandbox root.
+    /// With [PathNormalization::Lexical], symbolic links are not resolved and may point outside
+    /// of the sandbox root.
+    pub fn set_sandbox_root<P>(&mut self, path: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.sandbox_root = Some(path.as_ref().to_path_buf());
 
-enum LoadState {
-    Loaded(ParsedNode),
-    Pending,
-}
+        self
+    }
 
-impl LoadState {
-    fn loaded(&self) -> Option<&ParsedNode> {
-        if let LoadState::Loaded(node) = self {
-            Some(node)
-        } else {
-            None
-        }
+    /// Sets how the paths of included files are normalized, see [PathNormalization].
+    ///
+    /// Defaults to [PathNormalization::Canonical].
+    pub fn set_path_normalization(&mut self, path_normalization: PathNormalization) -> &mut Self {
+        self.path_normalization = path_normalization;
+
+        self
     }
-}
 
-struct Parsed {
-    lookup: HashMap<u64, LoadState>,
-    root_key: u64,
-}
+    /// Sets a [Resolver] that is consulted before the search paths when resolving an include
+    /// path.
+    pub fn set_resolver<R>(&mut self, resolver: R) -> &mut Self
+    where
+        R: Resolver + 'static,
+    {
+        self.resolver = Some(Arc::new(resolver));
 
-impl Parsed {
-    fn try_init<P>(entry_point: P, search_paths: SearchPaths) -> Result<Self, Error>
+        self
+    }
+
+    /// Sets a [ResolutionTracer] that is passed a [ResolutionTrace] for every include directive
+    /// that is resolved.
+    pub fn set_tracer<T>(&mut self, tracer: T) -> &mut Self
     where
-        P: AsRef<Path>,
+        T: ResolutionTracer + 'static,
     {
-        let mut lookup = HashMap::new();
-        let (tx, rx) = mpsc::channel();
-        let pool = ThreadPool::new(num_cpus::get());
-        let entry_path = entry_point.as_ref().canonicalize()?;
+        self.tracer = Some(Arc::new(tracer));
 
-        let mut hasher = DefaultHasher::new();
+        self
+    }
 
-        entry_path.hash(&mut hasher);
+    pub fn base_paths(&self) -> &[PathBuf] {
+        &self.base_paths
+    }
 
-        let root_key = hasher.finish();
-        let root_node = ParsedNode::try_parse(entry_path, &search_paths);
+    /// The paths added with [push_quoted_path](SearchPaths::push_quoted_path). Quoted includes
+    /// search these first, followed by the [base_paths](SearchPaths::base_paths).
+    pub fn quoted_paths(&self) -> &[PathBuf] {
+        &self.quoted_paths
+    }
 
-        lookup.insert(root_key, LoadState::Pending);
+    /// Adds a group of search paths that is used instead of the base paths and quoted paths for
+    /// include paths that match the group's pattern.
+    ///
+    /// If more than one group matches an include path, the group that was added first is used.
+    pub fn push_group(&mut self, group: SearchPathGroup) -> &mut Self {
+        self.groups.push(group);
 
-        tx.send(root_node).unwrap();
+        self
+    }
 
-        let search_paths = Arc::new(search_paths);
-        let mut balance = 1;
+    pub fn groups(&self) -> &[SearchPathGroup] {
+        &self.groups
+    }
 
-        loop {
-            if balance == 0 {
-                break;
-            }
+    /// The group that `include_path` resolves through, or `None` for the default group.
+    fn group_for(&self, include_path: &Path) -> Option<&SearchPathGroup> {
+        self.groups
+            .iter()
+            .find(|group| glob::matches_path(&group.pattern, include_path))
+    }
 
-            let node = rx.recv().unwrap()?;
+    /// The base paths and quoted paths of the group that `include_path` resolves through.
+    fn paths_for(&self, include_path: &Path) -> (&[PathBuf], &[PathBuf]) {
+        match self.group_for(include_path) {
+            Some(group) => (&group.base_paths, &group.quoted_paths),
+            None => (&self.base_paths, &self.quoted_paths),
+        }
+    }
 
-            balance -= 1;
+    pub fn extensions(&self) -> impl Iterator<Item = &str> {
+        self.extensions.iter().map(|extension| extension.as_str())
+    }
 
-            // Load and parse any files included by this node.
-            'inner: for chunk in node.chunks() {
-                if let NodeChunk::Include(path) = chunk {
-                    let mut hasher = DefaultHasher::new();
+    pub fn case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
 
-                    path.hash(&mut hasher);
+    pub fn angle_quoted_search(&self) -> bool {
+        self.angle_quoted_search
+    }
 
-                    let key = hasher.finish();
+    pub fn angle_as_quoted(&self) -> bool {
+        self.angle_as_quoted
+    }
 
-                    if lookup.contains_key(&key) {
-                        // File has been/is being loaded, skip
-                        continue 'inner;
-                    }
+    pub fn quoted_fallback(&self) -> bool {
+        self.quoted_fallback
+    }
 
-                    // Not yet loaded, try and load
-                    lookup.insert(key, LoadState::Pending);
-                    balance += 1;
+    pub fn root_path(&self) -> Option<&Path> {
+        self.root_path
+            .as_deref()
+            .or_else(|| self.base_paths.first().map(PathBuf::as_path))
+    }
 
-                    let tx_clone = tx.clone();
-                    let search_paths_clone = search_paths.clone();
-                    let path_buf = path.to_path_buf();
+    pub fn path_normalization(&self) -> PathNormalization {
+        self.path_normalization
+    }
 
-                    pool.execute(move || {
-                        tx_clone
-                            .send(ParsedNode::try_parse(path_buf, &search_paths_clone))
-                            .unwrap();
-                    });
-                }
-            }
+    /// Returns the normalized form of `path`, see [PathNormalization].
+    fn normalize(
+        &self,
+        path: &Path,
+        file_provider: &dyn FileProvider,
+    ) -> Result<PathBuf, FileAccessError> {
+        let normalized = match self.path_normalization {
+            PathNormalization::Canonical => file_provider.canonicalize(path),
+            PathNormalization::Lexical if path.is_absolute() => Ok(file_provider::normalize(path)),
+            PathNormalization::Lexical => env::current_dir()
+                .map(|current_dir| file_provider::normalize(&current_dir.join(path))),
+        };
 
-            lookup.insert(node.key(), LoadState::Loaded(node));
-        }
+        normalized.map_err(|err| FileAccessError::new(path, FileOperation::Canonicalize, err))
+    }
 
-        Ok(Parsed { lookup, root_key })
+    pub fn resolver(&self) -> Option<&dyn Resolver> {
+        self.resolver.as_deref()
     }
 
-    fn get_by_key(&self, key: u64) -> Option<&ParsedNode> {
-        self.lookup.get(&key).and_then(|node| node.loaded())
+    pub fn tracer(&self) -> Option<&dyn ResolutionTracer> {
+        self.tracer.as_deref()
     }
 
-    fn get_by_path<P>(&self, path: P) -> Option<&ParsedNode>
-    where
-        P: AsRef<Path>,
-    {
-        let mut hasher = DefaultHasher::new();
+    pub fn sandbox_root(&self) -> Option<&Path> {
+        self.sandbox_root.as_deref()
+    }
 
-        path.as_ref().hash(&mut hasher);
+    pub fn deny_patterns(&self) -> impl Iterator<Item = &str> {
+        self.deny_patterns.iter().map(|pattern| pattern.as_str())
+    }
 
-        let key = hasher.finish();
+    pub fn aliases(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.aliases
+            .iter()
+            .map(|(prefix, replacement)| (prefix.as_str(), replacement.as_str()))
+    }
 
-        self.get_by_key(key)
+    /// Checks that every base path, quoted path, root path and sandbox root exists and is a
+    /// directory on the file system.
+    ///
+    /// Returns the entries that are not, see also [Options::search_path_validation].
+    pub fn validate(&self) -> Vec<InvalidSearchPath> {
+        self.validate_with(&StdFs)
     }
 
-    fn write<S, T>(&self, output_sink: &mut S, source_tracker: &mut T)
-    where
-        S: OutputSink,
-        T: SourceTracker,
-    {
-        let mut stack = Vec::new();
-        let mut seen = HashSet::new();
+    /// Like [validate](SearchPaths::validate), but accesses the directories through the given
+    /// [FileProvider].
+    pub fn validate_with(&self, file_provider: &dyn FileProvider) -> Vec<InvalidSearchPath> {
+        self.base_paths
+            .iter()
+            .chain(self.quoted_paths.iter())
+            .chain(
+                self.groups
+                    .iter()
+                    .flat_map(|group| group.base_paths.iter().chain(group.quoted_paths.iter())),
+            )
+            .chain(self.root_path.iter())
+            .chain(self.sandbox_root.iter())
+            .filter_map(|path| {
+                let reason = if file_provider.exists(path) {
+                    InvalidSearchPathReason::NotADirectory
+                } else {
+                    match file_provider.read_dir(path) {
+                        Ok(_) => return None,
+                        Err(err) if err.kind() == IOErrorKind::NotFound => {
+                            InvalidSearchPathReason::Missing
+                        }
+                        Err(err) => InvalidSearchPathReason::Unreadable(err.to_string()),
+                    }
+                };
 
-        let root_node = self.get_by_key(self.root_key).unwrap();
+                Some(InvalidSearchPath {
+                    path: path.clone(),
+                    reason,
+                    display_root: None,
+                })
+            })
+            .collect()
+    }
 
-        if root_node.once() {
-            seen.insert(root_node.key());
+    /// Applies the alias with the longest matching prefix (if any) to `path`.
+    fn remap(&self, path: &Path) -> PathBuf {
+        let remapped = path.to_str().and_then(|path| {
+            self.aliases()
+                .filter(|(prefix, _)| path.starts_with(prefix))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(prefix, replacement)| format!("{}{}", replacement, &path[prefix.len()..]))
+        });
+
+        remapped
+            .map(PathBuf::from)
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+}
+
+/// A group of search paths for the include paths that match a glob-style pattern, see
+/// [SearchPaths::push_group].
+///
+/// The pattern is matched against the include path (after the [SearchPaths::push_alias] aliases
+/// are applied) with the same syntax as [SearchPaths::push_deny_pattern], e.g. `*.wgsl` selects
+/// the group for every include of a `.wgsl` file.
+#[derive(Clone, Debug)]
+pub struct SearchPathGroup {
+    pattern: String,
+    base_paths: Vec<PathBuf>,
+    quoted_paths: Vec<PathBuf>,
+}
+
+impl SearchPathGroup {
+    pub fn new<P>(pattern: P) -> Self
+    where
+        P: Into<String>,
+    {
+        SearchPathGroup {
+            pattern: pattern.into(),
+            base_paths: Vec::new(),
+            quoted_paths: Vec::new(),
         }
+    }
 
-        let mut current_node = root_node;
-        let mut current_chunk = 0;
+    /// Adds a base path to the group, see [SearchPaths::push_base_path].
+    pub fn push_base_path<P>(&mut self, path: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.base_paths.push(path.as_ref().to_path_buf());
 
-        loop {
-            if let Some(chunk) = current_node.get_chunk(current_chunk) {
-                match chunk {
-                    NodeChunk::Text(chunk) => {
-                        output_sink.sink_source_mapped(SourceMappedChunk {
-                            text: chunk.text(),
-                            source_path: current_node.path(),
-                            source_range: chunk.byte_range(),
-                        });
+        self
+    }
 
-                        current_chunk += 1;
-                    }
-                    NodeChunk::Include(path) => {
-                        let node = self.get_by_path(path).unwrap();
+    /// Adds a quoted path to the group, see [SearchPaths::push_quoted_path].
+    pub fn push_quoted_path<P>(&mut self, path: P) -> &mut Self
+    where
+        P: AsRef<Path>,
+    {
+        self.quoted_paths.push(path.as_ref().to_path_buf());
 
-                        if node.once() && seen.contains(&node.key()) {
-                            current_chunk += 1;
-                        } else {
-                            seen.insert(node.key());
+        self
+    }
 
-                            stack.push((current_node.key(), current_chunk));
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
 
-                            current_node = node;
-                            current_chunk = 0;
-                        }
-                    }
-                }
-            } else {
-                if let Some((parent_key, child_chunk)) = stack.pop() {
-                    // Ensure newline after included chunk
-                    output_sink.sink("\n");
+    pub fn base_paths(&self) -> &[PathBuf] {
+        &self.base_paths
+    }
 
-                    current_node = self.get_by_key(parent_key).unwrap();
-                    current_chunk = child_chunk + 1;
-                } else {
-                    break;
-                }
-            }
-        }
+    pub fn quoted_paths(&self) -> &[PathBuf] {
+        &self.quoted_paths
+    }
+}
+
+/// Replaces a leading `~` component of `path` (i.e. `~` or `~/...`) with the home directory of the
+/// current user.
+///
+/// Other paths, including paths that start with `~user`, are returned unchanged.
+pub fn expand_home<P>(path: P) -> Result<PathBuf, HomeDirError>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
 
-        for node in self.lookup.values() {
-            let node = node.loaded().unwrap();
+    match path.strip_prefix("~") {
+        Ok(rest) => {
+            let home = env::home_dir().ok_or_else(|| HomeDirError {
+                path: path.to_path_buf(),
+            })?;
 
-            source_tracker.track(node.path(), node.source());
+            Ok(home.join(rest))
         }
+        Err(_) => Ok(path.to_path_buf()),
     }
 }
 
+/// Raised by [expand_home] when the home directory of the current user cannot be determined.
 #[derive(Debug)]
-enum NodeChunkInternal {
-    Text(Range<usize>),
-    Include(PathBuf),
+pub struct HomeDirError {
+    path: PathBuf,
 }
 
-struct TextChunk<'a> {
-    byte_range: Range<usize>,
-    text: &'a str,
+impl HomeDirError {
+    /// The path that required the home directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 }
 
-impl<'a> TextChunk<'a> {
-    fn text(&self) -> &str {
-        &self.text
-    }
+/// Resolves include paths before the default resolution, see [SearchPaths::set_resolver].
+///
+/// A resolved path is subject to the same checks as a file found through the search paths (e.g.
+/// [SearchPaths::push_deny_pattern]) and is read through the [Options::file_provider]. The
+/// resolver is not consulted for `#include_next` directives.
+pub trait Resolver: fmt::Debug + Send + Sync {
+    fn resolve(
+        &self,
+        include_path: &IncludePath,
+        including_file: &Path,
+        search_paths: &SearchPaths,
+    ) -> Resolution;
+}
 
-    fn byte_range(&self) -> Range<usize> {
-        self.byte_range.clone()
-    }
+/// How the paths of included files are normalized, see [SearchPaths::set_path_normalization].
+///
+/// The normalized path identifies a file: it is the path passed to [SourceTracker::track], and
+/// two includes that normalize to the same path include the same file (e.g. for `#pragma once`).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum PathNormalization {
+    /// Paths are canonicalized with [FileProvider::canonicalize], which resolves symbolic links.
+    /// A file that is included through a symbolic link and through its target is the same file.
+    Canonical,
+    /// Paths are made absolute and their `.` and `..` components are resolved without accessing
+    /// the file system. Symbolic links are not resolved, so a file that is included through a
+    /// symbolic link and through its target counts as two different files.
+    Lexical,
 }
 
-enum NodeChunk<'a> {
-    Text(TextChunk<'a>),
-    Include(&'a Path),
+/// The result of [Resolver::resolve].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Resolution {
+    /// The include path resolves to the file at the given path.
+    Resolved(PathBuf),
+    /// The include path is resolved through the search paths instead.
+    Unhandled,
+    /// The include path cannot be resolved; fails with the given message.
+    Failed(String),
 }
 
-struct ParsedNode {
+/// A search path that does not refer to a readable directory, see [SearchPaths::validate].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct InvalidSearchPath {
     path: PathBuf,
-    key: u64,
-    once: bool,
-    source: String,
-    chunk_buffer: Vec<NodeChunkInternal>,
+    reason: InvalidSearchPathReason,
+    display_root: Option<Arc<PathBuf>>,
 }
 
-impl ParsedNode {
-    fn try_parse(path: PathBuf, search_paths: &SearchPaths) -> Result<Self, Error> {
-        let source = fs::read_to_string(&path)?;
-        let source_len = source.len();
-
-        let mut remainder = source.as_str();
-        let mut line_number = 0;
-        let mut chunk_buffer = Vec::new();
-        let mut once = false;
-        let mut current_text_range = 0..0;
+impl InvalidSearchPath {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 
-        while remainder.len() > 0 {
-            let (new_remainder, line) = parse_line(remainder).map_err(|err| {
-                let mut buf = PathBuf::new();
+    pub fn reason(&self) -> &InvalidSearchPathReason {
+        &self.reason
+    }
+}
+
+impl fmt::Display for InvalidSearchPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let display_root = self.display_root.as_deref().map(PathBuf::as_path);
+        let path = diagnostic::display_path(&self.path, display_root);
+
+        match &self.reason {
+            InvalidSearchPathReason::Missing => write!(f, "search path `{}` does not exist", path),
+            InvalidSearchPathReason::NotADirectory => {
+                write!(f, "search path `{}` is not a directory", path)
+            }
+            InvalidSearchPathReason::Unreadable(err) => {
+                write!(f, "search path `{}` cannot be read: {}", path, err)
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum InvalidSearchPathReason {
+    Missing,
+    NotADirectory,
+    /// The directory cannot be read, with the message of the underlying IO error.
+    Unreadable(String),
+}
+
+/// Observes how include paths are resolved, see [SearchPaths::set_tracer].
+///
+/// Files are parsed concurrently, so traces for different files may arrive in any order.
+pub trait ResolutionTracer: fmt::Debug + Send + Sync {
+    fn trace(&self, trace: ResolutionTrace);
+}
+
+impl<T> ResolutionTracer for Arc<T>
+where
+    T: ResolutionTracer,
+{
+    fn trace(&self, trace: ResolutionTrace) {
+        (**self).trace(trace)
+    }
+}
+
+/// How an include directive was resolved, see [ResolutionTracer].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ResolutionTrace {
+    source_file: PathBuf,
+    line_number: usize,
+    include_path: String,
+    group: Option<String>,
+    candidates: Vec<ResolutionCandidate>,
+    resolved_path: Option<PathBuf>,
+}
+
+impl ResolutionTrace {
+    /// The file that contains the include directive.
+    pub fn source_file(&self) -> &Path {
+        &self.source_file
+    }
+
+    /// The (one-based) line number of the include directive.
+    pub fn line_number(&self) -> usize {
+        self.line_number + 1
+    }
+
+    /// The include path as written, including its delimiters, e.g. `<common.glsl>`.
+    pub fn include_path(&self) -> &str {
+        &self.include_path
+    }
+
+    /// The pattern of the [SearchPathGroup] that was searched, or `None` for the default group.
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    /// The files that were probed, in order.
+    pub fn candidates(&self) -> &[ResolutionCandidate] {
+        &self.candidates
+    }
+
+    /// The (canonical) path of the included file, or `None` if the include path did not resolve.
+    pub fn resolved_path(&self) -> Option<&Path> {
+        self.resolved_path.as_deref()
+    }
+}
+
+/// A file that was probed while resolving an include path, see [ResolutionTrace::candidates].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ResolutionCandidate {
+    path: PathBuf,
+    exists: bool,
+}
+
+impl ResolutionCandidate {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn exists(&self) -> bool {
+        self.exists
+    }
+}
+
+/// A [ResolutionTracer] that collects every [ResolutionTrace].
+///
+/// Pass a clone of an `Arc<ResolutionCollector>` to [SearchPaths::set_tracer] to retain access to
+/// the traces.
+#[derive(Default, Debug)]
+pub struct ResolutionCollector {
+    traces: Mutex<Vec<ResolutionTrace>>,
+}
+
+impl ResolutionCollector {
+    pub fn new() -> Self {
+        ResolutionCollector::default()
+    }
+
+    /// The collected traces, ordered by file and line number.
+    pub fn traces(&self) -> Vec<ResolutionTrace> {
+        let mut traces = self.traces.lock().unwrap().clone();
+
+        traces
+            .sort_by(|a, b| (&a.source_file, a.line_number).cmp(&(&b.source_file, b.line_number)));
+
+        traces
+    }
+}
+
+impl ResolutionTracer for ResolutionCollector {
+    fn trace(&self, trace: ResolutionTrace) {
+        self.traces.lock().unwrap().push(trace);
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    FileNotFound(FileNotFoundError),
+    IO(FileAccessError),
+    Parse(ParseError),
+    Directive(DirectiveError),
+    UnterminatedConditional(UnterminatedConditionalError),
+    IncludeCycle(IncludeCycleError),
+    DeniedInclude(DeniedIncludeError),
+    SandboxEscape(SandboxEscapeError),
+    InvalidSearchPath(InvalidSearchPath),
+    SizeLimit(SizeLimitError),
+    /// The [OutputSink] failed, with the error it returned.
+    Sink(Box<dyn StdError + Send + Sync>),
+    /// The errors that were collected when [Options::collect_errors] is enabled, in the order in
+    /// which they were encountered.
+    Multiple(Vec<Error>),
+    /// Preprocessing was cancelled through the [Options::cancellation] token.
+    Cancelled,
+}
+
+impl Error {
+    fn sink<E>(err: E) -> Self
+    where
+        E: StdError + Send + Sync + 'static,
+    {
+        Error::Sink(Box::new(err))
+    }
+
+    /// Sets the directory that the paths in the rendered error are shown relative to, see
+    /// [Options::display_root].
+    fn set_display_root(&mut self, display_root: &Arc<PathBuf>) {
+        match self {
+            Error::FileNotFound(err) => err.display_root = Some(display_root.clone()),
+            Error::IO(err) => err.display_root = Some(display_root.clone()),
+            Error::Parse(err) => err.display_root = Some(display_root.clone()),
+            Error::Directive(err) => err.display_root = Some(display_root.clone()),
+            Error::UnterminatedConditional(err) => err.display_root = Some(display_root.clone()),
+            Error::IncludeCycle(err) => err.display_root = Some(display_root.clone()),
+            Error::DeniedInclude(err) => err.display_root = Some(display_root.clone()),
+            Error::SandboxEscape(err) => err.display_root = Some(display_root.clone()),
+            Error::InvalidSearchPath(err) => err.display_root = Some(display_root.clone()),
+            Error::SizeLimit(err) => err.display_root = Some(display_root.clone()),
+            Error::Multiple(errors) => {
+                for err in errors {
+                    err.set_display_root(display_root);
+                }
+            }
+            Error::Sink(_) | Error::Cancelled => (),
+        }
+    }
+
+    /// The kind of the error, which can be matched on without depending on the variants of
+    /// [Error].
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::FileNotFound(_) => ErrorKind::FileNotFound,
+            Error::IO(_) => ErrorKind::Io,
+            Error::Parse(_) => ErrorKind::Parse,
+            Error::Directive(_) => ErrorKind::Directive,
+            Error::UnterminatedConditional(_) => ErrorKind::UnterminatedConditional,
+            Error::IncludeCycle(_) => ErrorKind::Cycle,
+            Error::DeniedInclude(_) => ErrorKind::DeniedInclude,
+            Error::SandboxEscape(_) => ErrorKind::SandboxEscape,
+            Error::InvalidSearchPath(_) => ErrorKind::InvalidSearchPath,
+            Error::SizeLimit(_) => ErrorKind::SizeLimit,
+            Error::Sink(_) => ErrorKind::Sink,
+            Error::Multiple(_) => ErrorKind::Multiple,
+            Error::Cancelled => ErrorKind::Cancelled,
+        }
+    }
+
+    /// The file the error occurred in, or that could not be accessed for [ErrorKind::Io],
+    /// [ErrorKind::InvalidSearchPath] and [ErrorKind::SizeLimit] errors.
+    ///
+    /// For an [ErrorKind::Cycle] error, this is the file with the first include directive of the
+    /// cycle. Returns `None` for [ErrorKind::Sink], [ErrorKind::Multiple] and
+    /// [ErrorKind::Cancelled] errors.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::FileNotFound(err) => Some(err.source_file()),
+            Error::IO(err) => Some(err.path()),
+            Error::Parse(err) => Some(err.source_file()),
+            Error::Directive(err) => Some(err.source_file()),
+            Error::UnterminatedConditional(err) => Some(err.source_file()),
+            Error::IncludeCycle(err) => err.includes().first().map(|(path, _)| path.as_path()),
+            Error::DeniedInclude(err) => Some(err.source_file()),
+            Error::SandboxEscape(err) => Some(err.source_file()),
+            Error::InvalidSearchPath(err) => Some(err.path()),
+            Error::SizeLimit(err) => Some(err.path()),
+            Error::Sink(_) | Error::Multiple(_) | Error::Cancelled => None,
+        }
+    }
+
+    /// The (one-based) line number in the [path](Error::path) at which the error occurred, if
+    /// the error occurred at a specific line.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Error::FileNotFound(err) => Some(err.line_number()),
+            Error::Parse(err) => Some(err.line_number()),
+            Error::Directive(err) => Some(err.line_number()),
+            Error::UnterminatedConditional(err) => Some(err.line_number()),
+            Error::IncludeCycle(err) => err.includes().first().map(|(_, line)| *line),
+            Error::DeniedInclude(err) => Some(err.line_number()),
+            Error::SandboxEscape(err) => Some(err.line_number()),
+            Error::IO(_)
+            | Error::InvalidSearchPath(_)
+            | Error::SizeLimit(_)
+            | Error::Sink(_)
+            | Error::Multiple(_)
+            | Error::Cancelled => None,
+        }
+    }
+}
+
+/// Renders the error as a message; [ErrorKind::FileNotFound] and [ErrorKind::Parse] errors are
+/// rendered with a snippet of the source, as with [ParseError::render].
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::FileNotFound(err) => f.write_str(&err.render(false)),
+            Error::IO(err) => err.fmt(f),
+            Error::Parse(err) => f.write_str(&err.render(false)),
+            Error::Directive(err) => err.fmt(f),
+            Error::UnterminatedConditional(err) => err.fmt(f),
+            Error::IncludeCycle(err) => err.fmt(f),
+            Error::DeniedInclude(err) => err.fmt(f),
+            Error::SandboxEscape(err) => err.fmt(f),
+            Error::InvalidSearchPath(err) => err.fmt(f),
+            Error::SizeLimit(err) => err.fmt(f),
+            Error::Sink(err) => write!(f, "failed to write the output: {}", err),
+            Error::Multiple(errors) => {
+                for (index, err) in errors.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str("\n")?;
+                    }
+
+                    err.fmt(f)?;
+                }
+
+                Ok(())
+            }
+            Error::Cancelled => f.write_str("preprocessing was cancelled"),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::IO(err) => Some(err.io_error()),
+            Error::Sink(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// The kind of an [Error], see [Error::kind].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    FileNotFound,
+    Io,
+    Parse,
+    Directive,
+    UnterminatedConditional,
+    Cycle,
+    DeniedInclude,
+    SandboxEscape,
+    InvalidSearchPath,
+    SizeLimit,
+    Sink,
+    Multiple,
+    Cancelled,
+}
+
+impl From<FileNotFoundError> for Error {
+    fn from(err: FileNotFoundError) -> Self {
+        Error::FileNotFound(err)
+    }
+}
+
+impl From<FileAccessError> for Error {
+    fn from(err: FileAccessError) -> Self {
+        Error::IO(err)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        Error::Parse(err)
+    }
+}
+
+impl From<DirectiveError> for Error {
+    fn from(err: DirectiveError) -> Self {
+        Error::Directive(err)
+    }
+}
+
+impl From<UnterminatedConditionalError> for Error {
+    fn from(err: UnterminatedConditionalError) -> Self {
+        Error::UnterminatedConditional(err)
+    }
+}
+
+impl From<IncludeCycleError> for Error {
+    fn from(err: IncludeCycleError) -> Self {
+        Error::IncludeCycle(err)
+    }
+}
+
+impl From<DeniedIncludeError> for Error {
+    fn from(err: DeniedIncludeError) -> Self {
+        Error::DeniedInclude(err)
+    }
+}
+
+impl From<SandboxEscapeError> for Error {
+    fn from(err: SandboxEscapeError) -> Self {
+        Error::SandboxEscape(err)
+    }
+}
+
+impl From<InvalidSearchPath> for Error {
+    fn from(err: InvalidSearchPath) -> Self {
+        Error::InvalidSearchPath(err)
+    }
+}
+
+impl From<SizeLimitError> for Error {
+    fn from(err: SizeLimitError) -> Self {
+        Error::SizeLimit(err)
+    }
+}
+
+/// The operation that failed with a [FileAccessError].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileOperation {
+    /// Reading the contents of a file.
+    Read,
+    /// Obtaining the canonical (or otherwise normalized) path of a file, see [PathNormalization].
+    Canonicalize,
+    /// Transforming the contents of a file with the [Options::content_filter].
+    Filter,
+}
+
+/// An IO error that occurred while accessing a file, together with the include directive that
+/// caused the access (if any).
+#[derive(Debug)]
+pub struct FileAccessError {
+    path: PathBuf,
+    operation: FileOperation,
+    included_from: Option<(PathBuf, usize)>,
+    error: IOError,
+    display_root: Option<Arc<PathBuf>>,
+}
+
+impl FileAccessError {
+    fn new(path: &Path, operation: FileOperation, error: IOError) -> Self {
+        FileAccessError {
+            path: path.to_path_buf(),
+            operation,
+            included_from: None,
+            error,
+            display_root: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn operation(&self) -> FileOperation {
+        self.operation
+    }
+
+    /// The including file and the (one-based) line number of the include directive, unless the
+    /// file is the entry point.
+    pub fn included_from(&self) -> Option<(&Path, usize)> {
+        self.included_from
+            .as_ref()
+            .map(|(path, line_number)| (path.as_path(), line_number + 1))
+    }
+
+    pub fn io_error(&self) -> &IOError {
+        &self.error
+    }
+}
+
+impl fmt::Display for FileAccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operation = match self.operation {
+            FileOperation::Read => "read",
+            FileOperation::Canonicalize => "canonicalize",
+            FileOperation::Filter => "filter",
+        };
+
+        let display_root = self.display_root.as_deref().map(PathBuf::as_path);
+
+        write!(
+            f,
+            "failed to {} `{}`",
+            operation,
+            diagnostic::display_path(&self.path, display_root)
+        )?;
+
+        if let Some((path, line_number)) = &self.included_from {
+            write!(
+                f,
+                " (included from `{}:{}`)",
+                diagnostic::display_path(path, display_root),
+                line_number + 1
+            )?;
+        }
+
+        write!(f, ": {}", self.error)
+    }
+}
+
+#[derive(Debug)]
+pub struct FileNotFoundError {
+    included_path: PathBuf,
+    source_file: PathBuf,
+    source: String,
+    line_number: usize,
+    byte_offset: usize,
+    include_chain: Vec<(PathBuf, usize)>,
+    display_root: Option<Arc<PathBuf>>,
+}
+
+impl FileNotFoundError {
+    /// Creates the error for the include directive on line `line_number` of `source`, which
+    /// refers to the include path (or macro) that follows the directive name.
+    fn new(
+        included_path: &Path,
+        source_file: &Path,
+        source: &str,
+        line_number: usize,
+        include_chain: Vec<(PathBuf, usize)>,
+    ) -> Self {
+        let line_start = line_offset(source, line_number);
+        let line = source[line_start..].lines().next().unwrap_or("");
+        let directive_len = line.find(char::is_whitespace).unwrap_or(line.len());
+        let path_start = line[directive_len..]
+            .find(|c: char| !c.is_whitespace())
+            .map(|offset| directive_len + offset)
+            .unwrap_or(0);
+        let byte_offset = line_start + path_start;
+
+        FileNotFoundError {
+            included_path: included_path.to_path_buf(),
+            source_file: source_file.to_path_buf(),
+            source: source.to_string(),
+            line_number,
+            byte_offset,
+            include_chain,
+            display_root: None,
+        }
+    }
+
+    pub fn included_path(&self) -> &Path {
+        &self.included_path
+    }
+
+    pub fn source_file(&self) -> &Path {
+        &self.source_file
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The (one-based) line number of the include directive.
+    pub fn line_number(&self) -> usize {
+        self.line_number + 1
+    }
+
+    /// The (zero-based) line number of the include directive.
+    pub fn line_number_zero_based(&self) -> usize {
+        self.line_number
+    }
+
+    /// The (zero-based) column of the include path in the directive, counted in characters.
+    pub fn column(&self) -> usize {
+        column_at(&self.source, self.byte_offset)
+    }
+
+    /// The offset in bytes of the include path in the [source](FileNotFoundError::source).
+    pub fn byte_offset(&self) -> usize {
+        self.byte_offset
+    }
+
+    /// The includes through which the [source_file](FileNotFoundError::source_file) was reached,
+    /// starting at the entry point, as the file and (one-based) line number of each include
+    /// directive.
+    ///
+    /// Empty if the source file is the entry point.
+    pub fn include_chain(&self) -> &[(PathBuf, usize)] {
+        &self.include_chain
+    }
+
+    /// Renders the error as a compiler-style diagnostic, with the include directive's line and the
+    /// include path underlined, followed by the include chain.
+    ///
+    /// If `color` is `true`, the output is styled with ANSI escape codes.
+    pub fn render(&self, color: bool) -> String {
+        let message = format!("could not find `{}`", self.included_path.display());
+        let line_end = self.source[self.byte_offset..]
+            .find('\n')
+            .map(|i| self.byte_offset + i)
+            .unwrap_or(self.source.len());
+        let span_end = self.byte_offset + self.source[self.byte_offset..line_end].trim_end().len();
+        let display_root = self.display_root.as_deref().map(PathBuf::as_path);
+        let notes: Vec<String> = self
+            .include_chain
+            .iter()
+            .rev()
+            .map(|(path, line_number)| {
+                format!(
+                    "included from {}:{}",
+                    diagnostic::display_path(path, display_root),
+                    line_number
+                )
+            })
+            .collect();
+
+        diagnostic::render(
+            &message,
+            &diagnostic::display_path(&self.source_file, display_root),
+            &self.source,
+            self.byte_offset..span_end,
+            &notes,
+            color,
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    message: String,
+    source_file: PathBuf,
+    source: String,
+    line_number: usize,
+    column: usize,
+    byte_offset: usize,
+    display_root: Option<Arc<PathBuf>>,
+}
+
+impl ParseError {
+    /// Creates an error for the line `line_number` of `source` as a whole, i.e. at its first
+    /// column.
+    fn new(message: String, source_file: &Path, source: &str, line_number: usize) -> Self {
+        ParseError {
+            message,
+            source_file: source_file.to_path_buf(),
+            source: source.to_string(),
+            line_number,
+            column: 0,
+            byte_offset: line_offset(source, line_number),
+            display_root: None,
+        }
+    }
+
+    /// Creates an error at the byte at `byte_offset` in `source`.
+    fn at_offset(message: String, source_file: &Path, source: &str, byte_offset: usize) -> Self {
+        ParseError {
+            message,
+            source_file: source_file.to_path_buf(),
+            source: source.to_string(),
+            line_number: line_number_at(source, byte_offset),
+            column: column_at(source, byte_offset),
+            byte_offset,
+            display_root: None,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn source_file(&self) -> &Path {
+        &self.source_file
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The (one-based) line number at which the error occurred.
+    pub fn line_number(&self) -> usize {
+        self.line_number + 1
+    }
+
+    /// The (zero-based) line number at which the error occurred.
+    pub fn line_number_zero_based(&self) -> usize {
+        self.line_number
+    }
+
+    /// The (zero-based) column at which the error occurred, counted in characters.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// The offset in bytes at which the error occurred in the [source](ParseError::source).
+    pub fn byte_offset(&self) -> usize {
+        self.byte_offset
+    }
+
+    /// Renders the error as a compiler-style diagnostic, with the line on which it occurred and a
+    /// caret under its column.
+    ///
+    /// If `color` is `true`, the output is styled with ANSI escape codes.
+    pub fn render(&self, color: bool) -> String {
+        let display_root = self.display_root.as_deref().map(PathBuf::as_path);
+
+        diagnostic::render(
+            &diagnostic::relativize_message(&self.message, display_root),
+            &diagnostic::display_path(&self.source_file, display_root),
+            &self.source,
+            self.byte_offset..self.byte_offset,
+            &[],
+            color,
+        )
+    }
+}
+
+/// Raised when an `#error` directive is reached.
+#[derive(Debug)]
+pub struct DirectiveError {
+    message: String,
+    source_file: PathBuf,
+    source: String,
+    line_number: usize,
+    display_root: Option<Arc<PathBuf>>,
+}
+
+impl DirectiveError {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn source_file(&self) -> &Path {
+        &self.source_file
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The (one-based) line number at which the error occurred.
+    pub fn line_number(&self) -> usize {
+        self.line_number + 1
+    }
+
+    /// The (zero-based) line number at which the error occurred.
+    pub fn line_number_zero_based(&self) -> usize {
+        self.line_number
+    }
+}
+
+impl fmt::Display for DirectiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let display_root = self.display_root.as_deref().map(PathBuf::as_path);
+
+        write!(
+            f,
+            "{}:{}: #error {}",
+            diagnostic::display_path(&self.source_file, display_root),
+            self.line_number(),
+            self.message
+        )
+    }
+}
+
+/// Raised when a file ends while a conditional group is still open.
+///
+/// Refers to the opening directive (e.g. `#ifdef FOO`) of the innermost unterminated group.
+#[derive(Debug)]
+pub struct UnterminatedConditionalError {
+    directive: String,
+    source_file: PathBuf,
+    source: String,
+    line_number: usize,
+    display_root: Option<Arc<PathBuf>>,
+}
+
+impl UnterminatedConditionalError {
+    pub fn directive(&self) -> &str {
+        &self.directive
+    }
+
+    pub fn source_file(&self) -> &Path {
+        &self.source_file
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The (one-based) line number at which the error occurred.
+    pub fn line_number(&self) -> usize {
+        self.line_number + 1
+    }
+
+    /// The (zero-based) line number at which the error occurred.
+    pub fn line_number_zero_based(&self) -> usize {
+        self.line_number
+    }
+}
+
+impl fmt::Display for UnterminatedConditionalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let display_root = self.display_root.as_deref().map(PathBuf::as_path);
+
+        write!(
+            f,
+            "unterminated `{}` started at {}:{}",
+            self.directive,
+            diagnostic::display_path(&self.source_file, display_root),
+            self.line_number()
+        )
+    }
+}
+
+/// Raised when a file that is not guarded against repeated inclusion (e.g. with `#pragma once`)
+/// includes itself, directly or through other files.
+#[derive(Debug)]
+pub struct IncludeCycleError {
+    includes: Vec<(PathBuf, usize)>,
+    display_root: Option<Arc<PathBuf>>,
+}
+
+impl IncludeCycleError {
+    /// The include directives that form the cycle, as the file and (one-based) line number of
+    /// each directive, starting with the file that is included again.
+    pub fn includes(&self) -> &[(PathBuf, usize)] {
+        &self.includes
+    }
+}
+
+impl fmt::Display for IncludeCycleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let display_root = self.display_root.as_deref().map(PathBuf::as_path);
+
+        f.write_str("include cycle: ")?;
+
+        for (path, line_number) in &self.includes {
+            write!(
+                f,
+                "{}:{} → ",
+                diagnostic::display_path(path, display_root),
+                line_number
+            )?;
+        }
+
+        match self.includes.first() {
+            Some((first, _)) => f.write_str(&diagnostic::display_path(first, display_root)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Raised when an include resolves to a file that matches one of the
+/// [SearchPaths::push_deny_pattern] patterns.
+#[derive(Debug)]
+pub struct DeniedIncludeError {
+    pattern: String,
+    resolved_path: PathBuf,
+    source_file: PathBuf,
+    source: String,
+    line_number: usize,
+    display_root: Option<Arc<PathBuf>>,
+}
+
+impl DeniedIncludeError {
+    /// The deny pattern that matched.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn resolved_path(&self) -> &Path {
+        &self.resolved_path
+    }
+
+    pub fn source_file(&self) -> &Path {
+        &self.source_file
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The (one-based) line number of the include directive.
+    pub fn line_number(&self) -> usize {
+        self.line_number + 1
+    }
+
+    /// The (zero-based) line number of the include directive.
+    pub fn line_number_zero_based(&self) -> usize {
+        self.line_number
+    }
+}
+
+impl fmt::Display for DeniedIncludeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let display_root = self.display_root.as_deref().map(PathBuf::as_path);
+
+        write!(
+            f,
+            "{}:{}: including `{}` is denied by pattern `{}`",
+            diagnostic::display_path(&self.source_file, display_root),
+            self.line_number(),
+            diagnostic::display_path(&self.resolved_path, display_root),
+            self.pattern
+        )
+    }
+}
+
+/// Raised when an include resolves to a file outside of the [SearchPaths::set_sandbox_root]
+/// directory.
+#[derive(Debug)]
+pub struct SandboxEscapeError {
+    included_path: PathBuf,
+    resolved_path: PathBuf,
+    source_file: PathBuf,
+    source: String,
+    line_number: usize,
+    display_root: Option<Arc<PathBuf>>,
+}
+
+impl SandboxEscapeError {
+    /// The include path as written in the directive.
+    pub fn included_path(&self) -> &Path {
+        &self.included_path
+    }
+
+    /// The canonical path of the file outside of the sandbox root.
+    pub fn resolved_path(&self) -> &Path {
+        &self.resolved_path
+    }
+
+    pub fn source_file(&self) -> &Path {
+        &self.source_file
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The (one-based) line number of the include directive.
+    pub fn line_number(&self) -> usize {
+        self.line_number + 1
+    }
+
+    /// The (zero-based) line number of the include directive.
+    pub fn line_number_zero_based(&self) -> usize {
+        self.line_number
+    }
+}
+
+impl fmt::Display for SandboxEscapeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let display_root = self.display_root.as_deref().map(PathBuf::as_path);
+
+        write!(
+            f,
+            "{}:{}: `{}` resolves to `{}`, which is outside of the sandbox root",
+            diagnostic::display_path(&self.source_file, display_root),
+            self.line_number(),
+            self.included_path.display(),
+            diagnostic::display_path(&self.resolved_path, display_root)
+        )
+    }
+}
+
+/// The limit that is exceeded with a [SizeLimitError].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SizeLimit {
+    /// The size of a single file, see [Options::max_file_size].
+    File,
+    /// The combined size of the files that are read, see [Options::max_total_size].
+    Total,
+}
+
+/// Raised when a file exceeds [Options::max_file_size], or when reading it brings the combined
+/// size of the files over [Options::max_total_size].
+#[derive(Debug)]
+pub struct SizeLimitError {
+    path: PathBuf,
+    limit: SizeLimit,
+    size: u64,
+    max: u64,
+    included_from: Option<(PathBuf, usize)>,
+    display_root: Option<Arc<PathBuf>>,
+}
+
+impl SizeLimitError {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn limit(&self) -> SizeLimit {
+        self.limit
+    }
+
+    /// The size of the file in bytes for [SizeLimit::File], or the combined size of the files
+    /// including this file for [SizeLimit::Total].
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The maximum number of bytes that the limit allows.
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// The including file and the (one-based) line number of the include directive, unless the
+    /// file is the entry point.
+    pub fn included_from(&self) -> Option<(&Path, usize)> {
+        self.included_from
+            .as_ref()
+            .map(|(path, line_number)| (path.as_path(), line_number + 1))
+    }
+}
+
+impl fmt::Display for SizeLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let display_root = self.display_root.as_deref().map(PathBuf::as_path);
+
+        write!(
+            f,
+            "`{}`",
+            diagnostic::display_path(&self.path, display_root)
+        )?;
+
+        if let Some((path, line_number)) = &self.included_from {
+            write!(
+                f,
+                " (included from `{}:{}`)",
+                diagnostic::display_path(path, display_root),
+                line_number + 1
+            )?;
+        }
+
+        match self.limit {
+            SizeLimit::File => write!(
+                f,
+                " is {} bytes, which exceeds the maximum file size of {} bytes",
+                self.size, self.max
+            ),
+            SizeLimit::Total => write!(
+                f,
+                " brings the total size of the files to {} bytes, which exceeds the maximum of {} \
+                 bytes",
+                self.size, self.max
+            ),
+        }
+    }
+}
+
+/// Options that control the output of [preprocess_with_options].
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Whether `#define` directive lines are retained in the output.
+    ///
+    /// Defaults to `false`, in which case `#define` lines are removed from the output.
+    pub keep_define_directives: bool,
+
+    /// The maximum nesting depth of macro expansions.
+    ///
+    /// Expanding a macro whose replacement (transitively) nests more macro invocations than this
+    /// limit results in an error, which guards against infinitely recursive macros. Defaults to
+    /// `64`.
+    pub max_macro_expansion_depth: usize,
+
+    /// Object-like macros that are defined before preprocessing starts, as if the entry point
+    /// started with an equivalent `#define` line for each name/value pair.
+    pub defines: HashMap<String, String>,
+
+    /// Whether the builtin `__FILE__` (and `__BASE_FILE__`) macro expands to just the name of the
+    /// file, rather than its full (canonical) path.
+    ///
+    /// Defaults to `false`. Setting this to `true` makes the output independent of where the
+    /// sources are located on the machine that runs the preprocessor.
+    pub file_macro_name_only: bool,
+
+    /// Whether the `#define` line of a detected include guard is removed from the output when
+    /// [keep_define_directives](Options::keep_define_directives) is enabled.
+    ///
+    /// Defaults to `false`. A file whose entire body is wrapped in an `#ifndef NAME` /
+    /// `#define NAME` / `#endif` include guard is always treated as if it contained
+    /// `#pragma once`; this option only affects the output.
+    pub strip_include_guards: bool,
+
+    /// When a newline is emitted after the contents of an included file.
+    ///
+    /// Defaults to [IncludeNewline::IfMissing].
+    pub include_newline: IncludeNewline,
+
+    /// How the line endings of the output are converted.
+    ///
+    /// Defaults to [LineEndings::Preserve]. The source ranges of [SourceMappedChunk]s still refer
+    /// to the original text of the source files.
+    pub line_endings: LineEndings,
+
+    /// Whether the output is made to end with exactly one line ending.
+    ///
+    /// Defaults to `false`. If enabled, any trailing line endings are trimmed and a single one is
+    /// appended, in the style of [line_endings](Options::line_endings) (for
+    /// [LineEndings::Preserve], the style of the first trailing line ending, or `\n`). An output
+    /// that is empty, or consists of only line endings, is left empty.
+    pub ensure_trailing_newline: bool,
+
+    /// The styles of comments that are removed from the output.
+    ///
+    /// Defaults to empty. Comments are only stripped from the text of the source files (and the
+    /// expansions of macros), not from the lines the preprocessor inserts, and do not continue
+    /// into another file. The line endings inside of a block comment are retained, so that line
+    /// numbers are not affected.
+    pub strip_comments: HashSet<CommentStyle>,
+
+    /// What a comment is replaced with when it is stripped, see
+    /// [strip_comments](Options::strip_comments).
+    ///
+    /// Defaults to [CommentReplacement::Space].
+    pub comment_replacement: CommentReplacement,
+
+    /// Whether comment markers inside of double-quoted string literals are left alone when
+    /// stripping comments, see [strip_comments](Options::strip_comments).
+    ///
+    /// Defaults to `true`. Disable it for languages in which a `"` does not start a string
+    /// literal.
+    pub preserve_strings: bool,
+
+    /// The maximum number of consecutive blank lines in the output, see also
+    /// [collapse_blank_lines](Options::collapse_blank_lines).
+    ///
+    /// Defaults to `None`, which does not limit blank lines. A line that consists of only
+    /// whitespace counts as blank, and a run of blank lines is collapsed wherever it originates
+    /// from (e.g. from removed directives, or the newline after an included file). As this
+    /// removes lines from the output, [line_markers](Options::line_markers) should be used
+    /// alongside it if output line numbers matter.
+    pub max_blank_lines: Option<usize>,
+
+    /// How `#line` directives in the input files are handled.
+    ///
+    /// Defaults to [LineDirectiveMode::PassThrough].
+    pub line_directives: LineDirectiveMode,
+
+    /// Whether `#line <line> <file-id>` directives are emitted wherever the output stops
+    /// continuing from the previous line of the same file, e.g. at the start of an included file
+    /// and after returning from one.
+    ///
+    /// Defaults to `false`. The numeric file ids (as used by GLSL) are listed in
+    /// [PreprocessReport::file_ids]; the entry point has id `0` and the output starts at its first
+    /// line, so no directive precedes e.g. a leading `#version` directive. As in C, the line that
+    /// follows a directive has the number `<line>`. When enabled, `#line` directives in the input
+    /// files are removed, regardless of [line_directives](Options::line_directives).
+    pub line_markers: bool,
+
+    /// The marker lines that are emitted around the contents of included files.
+    ///
+    /// Defaults to `None`, in which case no markers are emitted. The markers are not attributed
+    /// to a source file, e.g. by a [SourceMapBuilder](crate::SourceMapBuilder).
+    pub include_markers: Option<IncludeMarkers>,
+
+    /// Whether the contents of an included file are indented with the whitespace that precedes
+    /// the include directive.
+    ///
+    /// Defaults to `false`. When enabled, include directives may be indented. Every line of the
+    /// included file's output that is not blank is prefixed with the spaces and tabs exactly as
+    /// they precede the directive, and the indentation of nested includes accumulates, e.g. for
+    /// including indented YAML fragments.
+    pub indent_includes: bool,
+
+    /// Text that is emitted before the output of the entry point, e.g. a `#version` directive or
+    /// engine-wide defines, see also [prologue](Options::prologue()).
+    ///
+    /// Defaults to empty. It is terminated with a newline if it does not end in one. Like other
+    /// text the preprocessor inserts, it is not attributed to a source file, e.g. by a
+    /// [SourceMapBuilder](crate::SourceMapBuilder), and [line_markers](Options::line_markers)
+    /// resume the numbering of the entry point after it.
+    pub prologue: String,
+
+    /// Text that is emitted after all other output, starting on a new line, see also
+    /// [epilogue](Options::epilogue()).
+    ///
+    /// Defaults to empty.
+    pub epilogue: String,
+
+    /// Whether lines that start with `#` but are not a known directive are rejected, rather than
+    /// treated as text.
+    ///
+    /// Defaults to `false`. Catches typos such as `#inlcude "foo.glsl"` or `#pragma onse`, that
+    /// would otherwise silently end up in the output. Lines in disabled conditional groups are
+    /// not checked.
+    pub strict_directives: bool,
+
+    /// The names of directives that are passed through to the output as text when
+    /// [strict_directives](Options::strict_directives) is enabled, e.g. `version` and `extension`
+    /// for GLSL.
+    pub allowed_directives: HashSet<String>,
+
+    /// The kinds of directives whose lines are replaced by blank lines in the output, rather than
+    /// removed.
+    ///
+    /// Defaults to empty. Keeps the output lines of a file at the same line numbers as in the
+    /// source (e.g. to diff the output against it, or to match tooling that reports line
+    /// numbers), as long as its includes do not add lines. Lines in disabled conditional groups
+    /// are still removed.
+    pub blank_directives: HashSet<DirectiveKind>,
+
+    /// Provides access to the source files.
+    ///
+    /// Defaults to [StdFs], which reads files from the file system.
+    pub file_provider: Arc<dyn FileProvider>,
+
+    /// Whether the search paths are checked with [SearchPaths::validate_with] before
+    /// preprocessing starts.
+    ///
+    /// Defaults to [SearchPathValidation::Ignore].
+    pub search_path_validation: SearchPathValidation,
+
+    /// Whether files with `#pragma once` (or an include guard) are identified by their content,
+    /// rather than by their path.
+    ///
+    /// Defaults to `false`. When enabled, a file is skipped if a file with the same content
+    /// (ignoring `#pragma once` lines) was already included, e.g. a copy of the same header in a
+    /// different directory. Every file is still passed to [SourceTracker::track].
+    pub once_by_content: bool,
+
+    /// Whether preprocessing continues past recoverable errors, to report all of them at once.
+    ///
+    /// Defaults to `false`. When enabled, malformed directives are skipped and includes that fail
+    /// to resolve or load are left out, and preprocessing fails with [Error::Multiple] if any
+    /// errors occurred, including an error that preprocessing could not continue past.
+    pub collect_errors: bool,
+
+    /// Whether an include of a file that is not found is replaced by a marker comment, rather than
+    /// failing preprocessing.
+    ///
+    /// Defaults to `false`. When enabled, a `// [ipp] missing include: "path"` line is emitted in
+    /// place of the include, which is also recorded in [PreprocessReport::diagnostics], and
+    /// preprocessing continues. Includes that are rejected for other reasons (e.g. by
+    /// [SearchPaths::push_deny_pattern]) still fail.
+    pub recover_includes: bool,
+
+    /// The directory that paths in rendered errors and diagnostics are shown relative to.
+    ///
+    /// Defaults to `None`. Applies to the `Display` implementations of [Error] and of the error
+    /// types it wraps, to [ParseError::render], [FileNotFoundError::render] and to [Diagnostic]'s
+    /// `Display` implementation; paths outside of the display root are shown as they are.
+    /// Accessors such as [FileNotFoundError::source_file] still return the full paths.
+    pub display_root: Option<PathBuf>,
+    /// The maximum number of bytes [preprocess_reader] reads as the source of the entry point.
+    ///
+    /// Defaults to `None`, which does not limit the size. A larger source fails with an
+    /// [Error::IO] for the virtual path of the entry point.
+    pub max_reader_size: Option<usize>,
+    /// The maximum size in bytes of a file that is preprocessed, including the entry point.
+    ///
+    /// Defaults to `None`, which does not limit the size. A file is checked against the size
+    /// reported by [FileProvider::modified] before it is read, and against the length of its
+    /// contents once read; a larger file fails with an [Error::SizeLimit].
+    pub max_file_size: Option<u64>,
+    /// The maximum combined size in bytes of the files that are preprocessed.
+    ///
+    /// Defaults to `None`, which does not limit the size. Every file counts once, no matter how
+    /// often it is included, and files that are reused from a [PreprocessCache] count as well. The
+    /// file that brings the combined size over the limit fails with an [Error::SizeLimit].
+    pub max_total_size: Option<u64>,
+    /// The maximum nesting depth of included files.
+    ///
+    /// Defaults to `None`, which does not limit the depth. The entry point has a depth of `0`, the
+    /// files it includes a depth of `1`, and so on; an include directive that would exceed the
+    /// limit fails with an [Error::Parse].
+    pub max_depth: Option<usize>,
+    /// A cache of parsed files to reuse files from, and to add the files that are parsed to.
+    ///
+    /// Defaults to `None`. See [PreprocessCache].
+    pub cache: Option<PreprocessCache>,
+    /// A token through which preprocessing can be cancelled from another thread.
+    ///
+    /// Defaults to `None`. Once the token is cancelled, preprocessing fails with
+    /// [Error::Cancelled]: no further files start loading, and the output stops at the next chunk
+    /// that is emitted. A file that is being read when the token is cancelled is not waited for.
+    pub cancellation: Option<CancellationToken>,
+    /// Observes the progress of loading the files, e.g. to show how many of the files that were
+    /// discovered so far have been loaded.
+    ///
+    /// Defaults to `None`. See [ProgressObserver].
+    pub progress: Option<Arc<dyn ProgressObserver>>,
+    /// Handles the custom directives, the lines that start with
+    /// [directive_prefix](Options::directive_prefix).
+    ///
+    /// Defaults to `None`, in which case such lines are treated like any other line. See
+    /// [DirectiveHandler].
+    pub directive_handler: Option<Arc<dyn DirectiveHandler>>,
+    /// The prefix of the lines that are passed to the
+    /// [directive_handler](Options::directive_handler) as custom directives.
+    ///
+    /// Defaults to `#pragma ipp`. A line is a custom directive if it starts with the prefix,
+    /// followed by whitespace, the directive's name and optionally an argument, e.g.
+    /// `#pragma ipp bind_group 2`. A line with the prefix but without a name is malformed.
+    pub directive_prefix: String,
+    /// Transforms the contents of every file that is read, before it is parsed, e.g. to fill in
+    /// a template.
+    ///
+    /// Defaults to `None`. See [ContentFilter].
+    pub content_filter: Option<Arc<dyn ContentFilter>>,
+    /// How the files are loaded.
+    ///
+    /// Defaults to [Concurrency::NewPool] with the `threadpool` feature (which is enabled by
+    /// default), to [Concurrency::Rayon] with only the `rayon` feature, and to
+    /// [Concurrency::SingleThreaded] otherwise. The output and the errors do not depend on it.
+    pub concurrency: Concurrency,
+}
+
+/// How the files of a preprocessing run are loaded, see [Options::concurrency].
+#[derive(Clone, Debug)]
+pub enum Concurrency {
+    /// The files are loaded on a new thread pool for every run, with a thread per CPU.
+    #[cfg(feature = "threadpool")]
+    NewPool,
+    /// The files are loaded on the given thread pool, which may be shared with other runs (and
+    /// other work).
+    #[cfg(feature = "threadpool")]
+    Pool(ThreadPool),
+    /// The files are loaded on the current rayon thread pool: the pool of the thread that runs
+    /// the preprocessor if it is a thread of a rayon pool, or the global pool otherwise.
+    ///
+    /// A run on a thread of the pool helps run the jobs of the pool while it waits for a file.
+    #[cfg(feature = "rayon")]
+    Rayon,
+    /// The files are loaded on the thread that runs the preprocessor, as they are needed; no
+    /// threads are spawned.
+    SingleThreaded,
+}
+
+impl Default for Concurrency {
+    #[cfg(feature = "threadpool")]
+    fn default() -> Self {
+        Concurrency::NewPool
+    }
+
+    #[cfg(all(feature = "rayon", not(feature = "threadpool")))]
+    fn default() -> Self {
+        Concurrency::Rayon
+    }
+
+    #[cfg(not(any(feature = "threadpool", feature = "rayon")))]
+    fn default() -> Self {
+        Concurrency::SingleThreaded
+    }
+}
+
+/// A token that cancels the preprocessing runs it is passed to, see [Options::cancellation].
+///
+/// Clones of a token share its state, so that a clone can be cancelled from another thread.
+#[derive(Clone, Default, Debug)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Cancels the runs that use this token (or a clone of it). A token cannot be reset.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Observes the progress of loading the files of a preprocessing run, see [Options::progress].
+///
+/// Files are loaded concurrently, so the observer is called from the threads that load the files
+/// as well as from the thread that runs the preprocessor, and should return quickly. A panic in
+/// the observer is caught and ignored.
+pub trait ProgressObserver: fmt::Debug + Send + Sync {
+    fn progress(&self, progress: LoadProgress);
+}
+
+impl<T> ProgressObserver for Arc<T>
+where
+    T: ProgressObserver,
+{
+    fn progress(&self, progress: LoadProgress) {
+        (**self).progress(progress);
+    }
+}
+
+/// Transforms the contents of the files of a preprocessing run before they are parsed, see
+/// [Options::content_filter].
+///
+/// The filtered contents are what the file consists of from then on: errors, byte ranges,
+/// [SourceTracker::track] and source maps all refer to them. The filter is applied to the files
+/// that are read through the [Options::file_provider], after the size limits are checked against
+/// the unfiltered contents, but not to a source that is passed in directly (as to
+/// [preprocess_str]). Like a [ProgressObserver], it is called from the threads that load the
+/// files.
+///
+/// Files of a [PreprocessCache] are only reused by runs that also have a filter, so runs with
+/// different filters should use separate caches.
+pub trait ContentFilter: fmt::Debug + Send + Sync {
+    /// Returns the filtered `contents` of the file at `path`, or an error, in which case
+    /// preprocessing fails with a [FileAccessError] for the file (with [FileOperation::Filter]).
+    fn filter(&self, path: &Path, contents: String) -> Result<String, IOError>;
+}
+
+impl<T> ContentFilter for Arc<T>
+where
+    T: ContentFilter,
+{
+    fn filter(&self, path: &Path, contents: String) -> Result<String, IOError> {
+        (**self).filter(path, contents)
+    }
+}
+
+/// Handles the custom directives of a preprocessing run, see [Options::directive_handler].
+///
+/// The handler is called from the thread that runs the preprocessor, as the directives are
+/// reached while the output is emitted; directives in disabled conditional groups are not passed
+/// to it.
+pub trait DirectiveHandler: fmt::Debug + Send + Sync {
+    /// Returns what the `directive` is replaced with in the output, or an error message, in which
+    /// case preprocessing fails with a [ParseError] for the directive's line.
+    fn handle(&self, directive: &CustomDirective) -> Result<DirectiveAction, String>;
+}
+
+impl<T> DirectiveHandler for Arc<T>
+where
+    T: DirectiveHandler,
+{
+    fn handle(&self, directive: &CustomDirective) -> Result<DirectiveAction, String> {
+        (**self).handle(directive)
+    }
+}
+
+/// A custom directive that is passed to a [DirectiveHandler].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CustomDirective<'a> {
+    name: &'a str,
+    argument: &'a str,
+    source_file: &'a Path,
+    line_number: usize,
+}
+
+impl<'a> CustomDirective<'a> {
+    /// The name of the directive, e.g. `bind_group` for `#pragma ipp bind_group 2`.
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// The rest of the line after the name, without surrounding whitespace, e.g. `2` for
+    /// `#pragma ipp bind_group 2`; empty if there is none.
+    pub fn argument(&self) -> &'a str {
+        self.argument
+    }
+
+    pub fn source_file(&self) -> &'a Path {
+        self.source_file
+    }
+
+    /// The (one-based) line number of the directive in the [source_file](Self::source_file).
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+}
+
+/// What a custom directive is replaced with in the output, see [DirectiveHandler::handle].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum DirectiveAction {
+    /// The directive's line is removed, or replaced by a blank line if [DirectiveKind::Custom] is
+    /// in [Options::blank_directives].
+    Drop,
+    /// The directive's line is replaced by the text, which is terminated with a newline if it is
+    /// not empty and does not end in one. Like other text the preprocessor inserts, it is not
+    /// attributed to a source file, e.g. by a [SourceMapBuilder](crate::SourceMapBuilder).
+    Replace(String),
+}
+
+/// A step in loading the files of a preprocessing run, see [ProgressObserver].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LoadProgress<'a> {
+    event: LoadEvent,
+    path: &'a Path,
+    discovered: usize,
+    loaded: usize,
+}
+
+impl<'a> LoadProgress<'a> {
+    pub fn event(&self) -> LoadEvent {
+        self.event
+    }
+
+    /// The file that was discovered or loaded.
+    pub fn path(&self) -> &'a Path {
+        self.path
+    }
+
+    /// The number of files that were discovered so far, including the entry point.
+    pub fn discovered(&self) -> usize {
+        self.discovered
+    }
+
+    /// The number of files that finished loading so far, including files that failed to load.
+    pub fn loaded(&self) -> usize {
+        self.loaded
+    }
+}
+
+/// What happened to the file of a [LoadProgress].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LoadEvent {
+    /// The file is included for the first time, and starts loading.
+    Discovered,
+    /// The file finished loading and parsing, or failed to.
+    Loaded,
+}
+
+/// How invalid search paths are handled, see [Options::search_path_validation].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SearchPathValidation {
+    /// The search paths are not checked.
+    Ignore,
+    /// Every invalid search path is reported as a warning [Diagnostic] for the search path, without
+    /// a line number.
+    Warn,
+    /// Preprocessing fails with [Error::InvalidSearchPath] for the first invalid search path.
+    Strict,
+}
+
+/// When a newline is emitted after the contents of an included file, see
+/// [Options::include_newline].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IncludeNewline {
+    /// A newline is always emitted, so that an included file that ends with a newline is
+    /// followed by a blank line.
+    Always,
+    /// A newline is only emitted if the output does not already end with one, e.g. because the
+    /// included file does not end with a newline.
+    IfMissing,
+    /// No newline is emitted, so that the last line of an included file that does not end with a
+    /// newline continues on the line that follows the include.
+    Never,
+}
+
+/// How the line endings of the output are converted, see [Options::line_endings].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LineEndings {
+    /// Line endings are emitted as they occur in the source files; lines that the preprocessor
+    /// inserts (e.g. the newline after an included file) end in `\n`.
+    Preserve,
+    /// All line endings are converted to `\n`.
+    Lf,
+    /// All line endings are converted to `\r\n`.
+    CrLf,
+}
+
+/// The marker lines that are emitted around the contents of included files, see
+/// [Options::include_markers].
+///
+/// In the templates, `{path}` is replaced with the path of the included file (relative to the
+/// [display_root](Options::display_root)), and `{depth}` with its nesting depth, which is `1` for
+/// a file included by the entry point. A marker that does not end in a newline is terminated
+/// with one.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct IncludeMarkers {
+    /// The line emitted before the contents of an included file.
+    ///
+    /// Defaults to `// >>> begin "{path}"`.
+    pub begin: String,
+
+    /// The line emitted after the contents of an included file.
+    ///
+    /// Defaults to `// <<< end "{path}"`.
+    pub end: String,
+
+    /// The line emitted in place of an include that is skipped, as the file was already included
+    /// and contains `#pragma once` (or an include guard).
+    ///
+    /// Defaults to `// skipped (already included)`.
+    pub skipped: String,
+}
+
+impl Default for IncludeMarkers {
+    fn default() -> Self {
+        IncludeMarkers {
+            begin: "// >>> begin \"{path}\"".to_string(),
+            end: "// <<< end \"{path}\"".to_string(),
+            skipped: "// skipped (already included)".to_string(),
+        }
+    }
+}
+
+/// A style of comment, see [Options::strip_comments].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum CommentStyle {
+    /// `//` comments, which end at the end of the line.
+    Line,
+    /// `/* */` comments.
+    Block,
+}
+
+/// What a stripped comment is replaced with, see [Options::comment_replacement].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CommentReplacement {
+    /// The comment is removed without replacement, e.g. `a/**/b` becomes `ab`.
+    Remove,
+    /// The comment is replaced with a single space, which keeps the tokens on either side of it
+    /// separated.
+    Space,
+}
+
+/// How `#line` directives in the input files are handled, see [Options::line_directives].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LineDirectiveMode {
+    /// `#line` directives are removed from the output.
+    Strip,
+    /// `#line` directives are copied to the output as they were written.
+    PassThrough,
+    /// `#line` directives are retained, and the line numbering is restored at every file
+    /// boundary that follows, so that any subsequent output is attributed to the file (and line)
+    /// it originates from, rather than to the origin named by the last `#line` directive.
+    Rewrite,
+}
+
+/// A kind of directive that is consumed by the preprocessor, see [Options::blank_directives].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum DirectiveKind {
+    /// `#include`, `#include_next` and `#include_optional` directives. The blank line for a
+    /// directive that includes a file follows the newline that terminates its last line, if it
+    /// does not end with one, regardless of [Options::include_newline].
+    Include,
+    /// `#pragma once` and `#pragma message` directives.
+    Pragma,
+    /// `#define` and `#undef` directives; kept `#define` lines are not affected.
+    Define,
+    /// `#if`, `#ifdef`, `#ifndef`, `#elif`, `#else` and `#endif` directives.
+    Conditional,
+    /// `#line` directives that are removed.
+    Line,
+    /// `#warning` directives.
+    Warning,
+    /// Custom directives that the [Options::directive_handler] drops.
+    Custom,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            keep_define_directives: false,
+            max_macro_expansion_depth: 64,
+            defines: HashMap::new(),
+            file_macro_name_only: false,
+            strip_include_guards: false,
+            include_newline: IncludeNewline::IfMissing,
+            line_endings: LineEndings::Preserve,
+            ensure_trailing_newline: false,
+            strip_comments: HashSet::new(),
+            comment_replacement: CommentReplacement::Space,
+            preserve_strings: true,
+            max_blank_lines: None,
+            line_directives: LineDirectiveMode::PassThrough,
+            line_markers: false,
+            include_markers: None,
+            indent_includes: false,
+            prologue: String::new(),
+            epilogue: String::new(),
+            strict_directives: false,
+            allowed_directives: HashSet::new(),
+            blank_directives: HashSet::new(),
+            file_provider: Arc::new(StdFs),
+            search_path_validation: SearchPathValidation::Ignore,
+            once_by_content: false,
+            collect_errors: false,
+            recover_includes: false,
+            display_root: None,
+            max_reader_size: None,
+            max_file_size: None,
+            max_total_size: None,
+            max_depth: None,
+            cache: None,
+            cancellation: None,
+            progress: None,
+            directive_handler: None,
+            directive_prefix: "#pragma ipp".to_string(),
+            content_filter: None,
+            concurrency: Concurrency::default(),
+        }
+    }
+}
+
+impl Options {
+    /// Adds an object-like macro definition to [Options::defines].
+    pub fn define<N, V>(&mut self, name: N, value: V) -> &mut Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.defines.insert(name.into(), value.into());
+
+        self
+    }
+
+    /// Adds a directive name to [Options::allowed_directives].
+    pub fn allow_directive<N>(&mut self, name: N) -> &mut Self
+    where
+        N: Into<String>,
+    {
+        self.allowed_directives.insert(name.into());
+
+        self
+    }
+
+    /// Adds a directive kind to [Options::blank_directives].
+    pub fn blank_directive(&mut self, kind: DirectiveKind) -> &mut Self {
+        self.blank_directives.insert(kind);
+
+        self
+    }
+
+    /// Adds a comment style to [Options::strip_comments].
+    pub fn strip_comment(&mut self, style: CommentStyle) -> &mut Self {
+        self.strip_comments.insert(style);
+
+        self
+    }
+
+    /// Appends `text` to [Options::prologue].
+    pub fn prologue(&mut self, text: &str) -> &mut Self {
+        self.prologue.push_str(text);
+
+        self
+    }
+
+    /// Appends `text` to [Options::epilogue].
+    pub fn epilogue(&mut self, text: &str) -> &mut Self {
+        self.epilogue.push_str(text);
+
+        self
+    }
+
+    /// Limits the number of consecutive blank lines in the output to `max`, see
+    /// [Options::max_blank_lines].
+    pub fn collapse_blank_lines(&mut self, max: usize) -> &mut Self {
+        self.max_blank_lines = Some(max);
+
+        self
+    }
+
+    /// Whether the [cancellation](Options::cancellation) token was cancelled.
+    fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+}
+
+/// Preprocesses entry points with one configuration of search paths and [Options].
+///
+/// A configuration only needs to be set up once, and can be cloned to vary it, e.g. per entry
+/// point. [preprocess] and [preprocess_with_options] are equivalent to running a preprocessor
+/// that is configured with the given search paths and options.
+#[derive(Clone, Debug, Default)]
+pub struct Preprocessor {
+    search_paths: SearchPaths,
+    options: Options,
+}
+
+impl Preprocessor {
+    /// Creates a preprocessor without search paths and with the default [Options].
+    pub fn new() -> Self {
+        Preprocessor::default()
+    }
+
+    /// Creates a preprocessor with the given configuration.
+    pub fn with_options(search_paths: SearchPaths, options: Options) -> Self {
+        Preprocessor {
+            search_paths,
+            options,
+        }
+    }
+
+    /// Replaces the search paths that includes are resolved against.
+    pub fn search_paths(&mut self, search_paths: SearchPaths) -> &mut Self {
+        self.search_paths = search_paths;
+
+        self
+    }
+
+    /// The options the preprocessor runs with.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Adds an object-like macro definition to [Options::defines].
+    pub fn define<N, V>(&mut self, name: N, value: V) -> &mut Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        self.options.define(name, value);
+
+        self
+    }
+
+    /// Adds a comment style to [Options::strip_comments].
+    pub fn strip_comment(&mut self, style: CommentStyle) -> &mut Self {
+        self.options.strip_comment(style);
+
+        self
+    }
+
+    /// Limits the number of consecutive blank lines in the output to `max`, see
+    /// [Options::max_blank_lines].
+    pub fn collapse_blank_lines(&mut self, max: usize) -> &mut Self {
+        self.options.collapse_blank_lines(max);
+
+        self
+    }
+
+    /// Sets [Options::include_markers].
+    pub fn include_markers(&mut self, include_markers: IncludeMarkers) -> &mut Self {
+        self.options.include_markers = Some(include_markers);
+
+        self
+    }
+
+    /// Appends `text` to [Options::prologue].
+    pub fn prologue(&mut self, text: &str) -> &mut Self {
+        self.options.prologue(text);
+
+        self
+    }
+
+    /// Appends `text` to [Options::epilogue].
+    pub fn epilogue(&mut self, text: &str) -> &mut Self {
+        self.options.epilogue(text);
+
+        self
+    }
+
+    /// Adds a directive name to [Options::allowed_directives].
+    pub fn allow_directive<N>(&mut self, name: N) -> &mut Self
+    where
+        N: Into<String>,
+    {
+        self.options.allow_directive(name);
+
+        self
+    }
+
+    /// Adds a directive kind to [Options::blank_directives].
+    pub fn blank_directive(&mut self, kind: DirectiveKind) -> &mut Self {
+        self.options.blank_directive(kind);
+
+        self
+    }
+
+    /// Sets [Options::file_provider].
+    pub fn file_provider<F>(&mut self, file_provider: F) -> &mut Self
+    where
+        F: FileProvider + 'static,
+    {
+        self.options.file_provider = Arc::new(file_provider);
+
+        self
+    }
+
+    /// Sets [Options::cache].
+    pub fn cache(&mut self, cache: PreprocessCache) -> &mut Self {
+        self.options.cache = Some(cache);
+
+        self
+    }
+
+    /// Sets [Options::cancellation].
+    pub fn cancellation(&mut self, token: CancellationToken) -> &mut Self {
+        self.options.cancellation = Some(token);
+
+        self
+    }
+
+    /// Sets [Options::concurrency].
+    pub fn concurrency(&mut self, concurrency: Concurrency) -> &mut Self {
+        self.options.concurrency = concurrency;
+
+        self
+    }
+
+    /// Sets [Options::progress].
+    pub fn progress<O>(&mut self, observer: O) -> &mut Self
+    where
+        O: ProgressObserver + 'static,
+    {
+        self.options.progress = Some(Arc::new(observer));
+
+        self
+    }
+
+    /// Sets [Options::directive_handler].
+    pub fn directive_handler<H>(&mut self, handler: H) -> &mut Self
+    where
+        H: DirectiveHandler + 'static,
+    {
+        self.options.directive_handler = Some(Arc::new(handler));
+
+        self
+    }
+
+    /// Sets [Options::directive_prefix].
+    pub fn directive_prefix(&mut self, prefix: &str) -> &mut Self {
+        self.options.directive_prefix = prefix.to_string();
+
+        self
+    }
+
+    /// Sets [Options::content_filter].
+    pub fn content_filter<F>(&mut self, filter: F) -> &mut Self
+    where
+        F: ContentFilter + 'static,
+    {
+        self.options.content_filter = Some(Arc::new(filter));
+
+        self
+    }
+
+    /// Sets [Options::max_reader_size].
+    pub fn max_reader_size(&mut self, max: usize) -> &mut Self {
+        self.options.max_reader_size = Some(max);
+
+        self
+    }
+
+    /// Sets [Options::max_file_size].
+    pub fn max_file_size(&mut self, max: u64) -> &mut Self {
+        self.options.max_file_size = Some(max);
+
+        self
+    }
+
+    /// Sets [Options::max_total_size].
+    pub fn max_total_size(&mut self, max: u64) -> &mut Self {
+        self.options.max_total_size = Some(max);
+
+        self
+    }
+
+    /// Sets [Options::max_depth].
+    pub fn max_depth(&mut self, max: usize) -> &mut Self {
+        self.options.max_depth = Some(max);
+
+        self
+    }
+
+    /// Sets [Options::display_root].
+    pub fn display_root<P>(&mut self, display_root: P) -> &mut Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.options.display_root = Some(display_root.into());
+
+        self
+    }
+
+    /// Sets [Options::keep_define_directives].
+    pub fn keep_define_directives(&mut self, keep_define_directives: bool) -> &mut Self {
+        self.options.keep_define_directives = keep_define_directives;
+
+        self
+    }
+
+    /// Sets [Options::max_macro_expansion_depth].
+    pub fn max_macro_expansion_depth(&mut self, max_macro_expansion_depth: usize) -> &mut Self {
+        self.options.max_macro_expansion_depth = max_macro_expansion_depth;
+
+        self
+    }
+
+    /// Sets [Options::file_macro_name_only].
+    pub fn file_macro_name_only(&mut self, file_macro_name_only: bool) -> &mut Self {
+        self.options.file_macro_name_only = file_macro_name_only;
+
+        self
+    }
+
+    /// Sets [Options::strip_include_guards].
+    pub fn strip_include_guards(&mut self, strip_include_guards: bool) -> &mut Self {
+        self.options.strip_include_guards = strip_include_guards;
+
+        self
+    }
+
+    /// Sets [Options::include_newline].
+    pub fn include_newline(&mut self, include_newline: IncludeNewline) -> &mut Self {
+        self.options.include_newline = include_newline;
+
+        self
+    }
+
+    /// Sets [Options::line_endings].
+    pub fn line_endings(&mut self, line_endings: LineEndings) -> &mut Self {
+        self.options.line_endings = line_endings;
+
+        self
+    }
+
+    /// Sets [Options::ensure_trailing_newline].
+    pub fn ensure_trailing_newline(&mut self, ensure_trailing_newline: bool) -> &mut Self {
+        self.options.ensure_trailing_newline = ensure_trailing_newline;
+
+        self
+    }
+
+    /// Sets [Options::comment_replacement].
+    pub fn comment_replacement(&mut self, comment_replacement: CommentReplacement) -> &mut Self {
+        self.options.comment_replacement = comment_replacement;
+
+        self
+    }
+
+    /// Sets [Options::preserve_strings].
+    pub fn preserve_strings(&mut self, preserve_strings: bool) -> &mut Self {
+        self.options.preserve_strings = preserve_strings;
+
+        self
+    }
+
+    /// Sets [Options::line_directives].
+    pub fn line_directives(&mut self, line_directives: LineDirectiveMode) -> &mut Self {
+        self.options.line_directives = line_directives;
+
+        self
+    }
+
+    /// Sets [Options::line_markers].
+    pub fn line_markers(&mut self, line_markers: bool) -> &mut Self {
+        self.options.line_markers = line_markers;
+
+        self
+    }
+
+    /// Sets [Options::indent_includes].
+    pub fn indent_includes(&mut self, indent_includes: bool) -> &mut Self {
+        self.options.indent_includes = indent_includes;
+
+        self
+    }
+
+    /// Sets [Options::strict_directives].
+    pub fn strict_directives(&mut self, strict_directives: bool) -> &mut Self {
+        self.options.strict_directives = strict_directives;
+
+        self
+    }
+
+    /// Sets [Options::search_path_validation].
+    pub fn search_path_validation(
+        &mut self,
+        search_path_validation: SearchPathValidation,
+    ) -> &mut Self {
+        self.options.search_path_validation = search_path_validation;
+
+        self
+    }
+
+    /// Sets [Options::once_by_content].
+    pub fn once_by_content(&mut self, once_by_content: bool) -> &mut Self {
+        self.options.once_by_content = once_by_content;
+
+        self
+    }
+
+    /// Sets [Options::collect_errors].
+    pub fn collect_errors(&mut self, collect_errors: bool) -> &mut Self {
+        self.options.collect_errors = collect_errors;
+
+        self
+    }
+
+    /// Sets [Options::recover_includes].
+    pub fn recover_includes(&mut self, recover_includes: bool) -> &mut Self {
+        self.options.recover_includes = recover_includes;
+
+        self
+    }
+
+    /// Preprocesses `entry_point` into `writer`, see [preprocess_with_options].
+    pub fn run<P, S, T>(
+        &self,
+        entry_point: P,
+        writer: S,
+        source_tracker: &mut T,
+    ) -> Result<S, Error>
+    where
+        P: AsRef<Path>,
+        S: OutputSink,
+        T: SourceTracker,
+    {
+        preprocess_with_options(
+            entry_point,
+            self.search_paths.clone(),
+            self.options.clone(),
+            writer,
+            source_tracker,
+        )
+    }
+
+    /// Preprocesses the entry point `source` into `writer`, as if it were a file at
+    /// `virtual_path`, see [preprocess_str_with_options].
+    pub fn run_str<P, S, T>(
+        &self,
+        source: &str,
+        virtual_path: P,
+        writer: S,
+        source_tracker: &mut T,
+    ) -> Result<S, Error>
+    where
+        P: AsRef<Path>,
+        S: OutputSink,
+        T: SourceTracker,
+    {
+        preprocess_str_with_options(
+            source,
+            virtual_path,
+            self.search_paths.clone(),
+            self.options.clone(),
+            writer,
+            source_tracker,
+        )
+    }
+
+    /// Preprocesses the entry point read from `reader` into `writer`, as if it were a file at
+    /// `virtual_path`, see [preprocess_reader].
+    pub fn run_reader<R, P, S, T>(
+        &self,
+        reader: R,
+        virtual_path: P,
+        writer: S,
+        source_tracker: &mut T,
+    ) -> Result<S, Error>
+    where
+        R: Read,
+        P: AsRef<Path>,
+        S: OutputSink,
+        T: SourceTracker,
+    {
+        preprocess_reader(
+            reader,
+            virtual_path,
+            self.search_paths.clone(),
+            self.options.clone(),
+            writer,
+            source_tracker,
+        )
+    }
+
+    /// Returns the files that preprocessing `entry_point` depends on, see
+    /// [preprocess_dependencies].
+    pub fn dependencies<P>(&self, entry_point: P) -> Result<Vec<PathBuf>, Error>
+    where
+        P: AsRef<Path>,
+    {
+        preprocess_dependencies_with_options(
+            entry_point,
+            self.search_paths.clone(),
+            self.options.clone(),
+        )
+    }
+
+    /// Returns the [IncludeGraph] of preprocessing `entry_point`, see [preprocess_include_graph].
+    pub fn include_graph<P>(&self, entry_point: P) -> Result<IncludeGraph, Error>
+    where
+        P: AsRef<Path>,
+    {
+        preprocess_include_graph_with_options(
+            entry_point,
+            self.search_paths.clone(),
+            self.options.clone(),
+        )
+    }
+
+    /// Like [run](Preprocessor::run), but also returns a [PreprocessReport], see
+    /// [preprocess_with_report].
+    pub fn run_with_report<P, S, T>(
+        &self,
+        entry_point: P,
+        writer: S,
+        source_tracker: &mut T,
+    ) -> Result<(S, PreprocessReport), Error>
+    where
+        P: AsRef<Path>,
+        S: OutputSink,
+        T: SourceTracker,
+    {
+        preprocess_with_report(
+            entry_point,
+            self.search_paths.clone(),
+            self.options.clone(),
+            writer,
+            source_tracker,
+        )
+    }
+
+    /// Returns a [Session] with the search paths and options of this preprocessor.
+    pub fn session(&self) -> Session {
+        Session::new(self.search_paths.clone(), self.options.clone())
+    }
+}
+
+/// Preprocesses multiple entry points that share files, reading and parsing every file only once
+/// per session, see [Session::preprocess].
+///
+/// Files are assumed to not change during the session: a file that was loaded for one entry point
+/// is reused as is for the next. To pick up changes, use a new session, or see [PreprocessCache]
+/// for a cache that checks files for changes. As for a [PreprocessCache], files with errors are
+/// not reused, nor are files if the search paths have a [Resolver] or a [ResolutionTracer].
+#[derive(Debug)]
+pub struct Session {
+    search_paths: SearchPaths,
+    options: Options,
+}
+
+impl Session {
+    /// Returns a session that preprocesses with the given `search_paths` and `options`.
+    ///
+    /// The session keeps the files it loaded in its own cache, which replaces
+    /// [Options::cache]. With [Concurrency::NewPool], the runs of the session share a single
+    /// thread pool.
+    pub fn new(search_paths: SearchPaths, mut options: Options) -> Self {
+        options.cache = Some(PreprocessCache {
+            validate: false,
+            ..PreprocessCache::new()
+        });
+
+        #[cfg(feature = "threadpool")]
+        if let Concurrency::NewPool = options.concurrency {
+            options.concurrency = Concurrency::Pool(ThreadPool::new(num_cpus::get()));
+        }
+
+        Session {
+            search_paths,
+            options,
+        }
+    }
+
+    /// Preprocesses the file at `entry_point` like [preprocess_with_options], loading only the
+    /// files that were not loaded by the session before.
+    pub fn preprocess<P, S, T>(
+        &self,
+        entry_point: P,
+        writer: S,
+        source_tracker: &mut T,
+    ) -> Result<S, Error>
+    where
+        P: AsRef<Path>,
+        S: OutputSink,
+        T: SourceTracker,
+    {
+        validate_search_paths(&self.search_paths, &self.options, &mut Discard)?;
+
+        self.preprocess_validated(entry_point, writer, source_tracker)
+    }
+
+    /// Preprocesses the files at `entry_points` concurrently (see [preprocess_batch]), loading
+    /// only the files that were not loaded by the session before; returns the output or the error
+    /// for every entry point, in order.
+    ///
+    /// The `source_tracker` is passed every file once, in the order in which the entry points
+    /// first track it (including the files that an entry point that fails loaded, as with
+    /// [preprocess]), along with the include directives of the file. Warnings and notes are
+    /// passed for every entry point that reported them, in the order of the entry points.
+    ///
+    /// With [Concurrency::SingleThreaded], the entry points are preprocessed one after the other
+    /// on the calling thread.
+    pub fn preprocess_batch<P, T>(
+        &self,
+        entry_points: &[P],
+        source_tracker: &mut T,
+    ) -> Vec<Result<String, Error>>
+    where
+        P: AsRef<Path> + Sync,
+        T: SourceTracker,
+    {
+        if let Err(invalid) = validate_search_paths(&self.search_paths, &self.options, &mut Discard)
+        {
+            return entry_points
+                .iter()
+                .map(|_| Err(invalid.clone().into()))
+                .collect();
+        }
+
+        let next = AtomicUsize::new(0);
+        let work = || {
+            let mut outcomes = Vec::new();
+
+            loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+
+                if index >= entry_points.len() {
+                    break outcomes;
+                }
+
+                let mut recorded = RecordedTracker::default();
+                let result =
+                    self.preprocess_validated(&entry_points[index], String::new(), &mut recorded);
+
+                outcomes.push((index, result, recorded));
+            }
+        };
+
+        let mut outcomes = if matches!(self.options.concurrency, Concurrency::SingleThreaded) {
+            work()
+        } else {
+            let workers = thread_count().min(entry_points.len());
+
+            thread::scope(|scope| {
+                let handles: Vec<_> = (0..workers).map(|_| scope.spawn(work)).collect();
+
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().unwrap())
+                    .collect()
+            })
+        };
+
+        outcomes.sort_by_key(|(index, _, _)| *index);
+
+        let mut tracked = HashSet::new();
+
+        outcomes
+            .into_iter()
+            .map(|(_, result, recorded)| {
+                recorded.replay(source_tracker, &mut tracked);
+
+                result
+            })
+            .collect()
+    }
+
+    fn preprocess_validated<P, S, T>(
+        &self,
+        entry_point: P,
+        mut writer: S,
+        source_tracker: &mut T,
+    ) -> Result<S, Error>
+    where
+        P: AsRef<Path>,
+        S: OutputSink,
+        T: SourceTracker,
+    {
+        let mut errors = Vec::new();
+        let entry_point = entry_point.as_ref();
+
+        let result = Parsed::try_init(
+            entry_point,
+            self.search_paths.clone(),
+            &self.options,
+            &mut errors,
+        )
+        .inspect_err(|_| track_missing_entry(entry_point, &self.options, source_tracker))
+        .and_then(|parsed| {
+            parsed.write(
+                &mut writer,
+                source_tracker,
+                &mut Discard,
+                &mut Vec::new(),
+                &self.options,
+                &mut errors,
+            )
+        });
+
+        finish_result(result.map(|_| writer), errors, &self.options)
+    }
+
+    /// The search paths the session preprocesses with.
+    pub fn search_paths(&self) -> &SearchPaths {
+        &self.search_paths
+    }
+
+    /// The options the session preprocesses with.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+}
+
+/// Preprocesses the files at `entry_points` concurrently, loading the files they share only once,
+/// and returns the output or the error for every entry point, in order.
+///
+/// The files are loaded on a single thread pool, see [Session::preprocess_batch].
+pub fn preprocess_batch<P, T>(
+    entry_points: &[P],
+    search_paths: SearchPaths,
+    options: Options,
+    source_tracker: &mut T,
+) -> Vec<Result<String, Error>>
+where
+    P: AsRef<Path> + Sync,
+    T: SourceTracker,
+{
+    Session::new(search_paths, options).preprocess_batch(entry_points, source_tracker)
+}
+
+/// The number of threads to spread work over, e.g. the entry points of a
+/// [Session::preprocess_batch].
+fn thread_count() -> usize {
+    #[cfg(feature = "threadpool")]
+    {
+        num_cpus::get()
+    }
+
+    #[cfg(not(feature = "threadpool"))]
+    {
+        thread::available_parallelism().map_or(1, |count| count.get())
+    }
+}
+
+/// Records what is passed to a [SourceTracker], to pass it on later, see
+/// [Session::preprocess_batch].
+#[derive(Default)]
+struct RecordedTracker {
+    events: Vec<TrackerEvent>,
+}
+
+enum TrackerEvent {
+    Track(PathBuf, String),
+    Warning(String, PathBuf, usize),
+    Note(String, PathBuf, usize),
+    TrackInclude(PathBuf, PathBuf, usize, IncludeKind),
+    TrackMissing(PathBuf),
+}
+
+impl RecordedTracker {
+    /// Passes the recorded events on to `source_tracker`, except for tracking the files that were
+    /// already `tracked` (and their include directives).
+    fn replay<T>(self, source_tracker: &mut T, tracked: &mut HashSet<PathBuf>)
+    where
+        T: SourceTracker,
+    {
+        let mut newly_tracked = HashSet::new();
+
+        for event in self.events {
+            match event {
+                TrackerEvent::Track(path, source) => {
+                    if !tracked.contains(&path) {
+                        source_tracker.track(&path, &source);
+                        tracked.insert(path.clone());
+                        newly_tracked.insert(path);
+                    }
+                }
+                TrackerEvent::TrackInclude(parent, child, line_number, kind) => {
+                    if newly_tracked.contains(&parent) {
+                        source_tracker.track_include(&parent, &child, line_number, kind);
+                    }
+                }
+                TrackerEvent::TrackMissing(path) => source_tracker.track_missing(&path),
+                TrackerEvent::Warning(message, source_file, line_number) => {
+                    source_tracker.warning(&message, &source_file, line_number)
+                }
+                TrackerEvent::Note(message, source_file, line_number) => {
+                    source_tracker.note(&message, &source_file, line_number)
+                }
+            }
+        }
+    }
+}
+
+impl SourceTracker for RecordedTracker {
+    fn track(&mut self, path: &Path, source: &str) {
+        self.events
+            .push(TrackerEvent::Track(path.to_path_buf(), source.to_string()));
+    }
+
+    fn warning(&mut self, message: &str, source_file: &Path, line_number: usize) {
+        self.events.push(TrackerEvent::Warning(
+            message.to_string(),
+            source_file.to_path_buf(),
+            line_number,
+        ));
+    }
+
+    fn note(&mut self, message: &str, source_file: &Path, line_number: usize) {
+        self.events.push(TrackerEvent::Note(
+            message.to_string(),
+            source_file.to_path_buf(),
+            line_number,
+        ));
+    }
+
+    fn track_include(
+        &mut self,
+        parent: &Path,
+        child: &Path,
+        line_number: usize,
+        kind: IncludeKind,
+    ) {
+        self.events.push(TrackerEvent::TrackInclude(
+            parent.to_path_buf(),
+            child.to_path_buf(),
+            line_number,
+            kind,
+        ));
+    }
+
+    fn track_missing(&mut self, path: &Path) {
+        self.events
+            .push(TrackerEvent::TrackMissing(path.to_path_buf()));
+    }
+}
+
+pub fn preprocess<P, S, T>(
+    entry_point: P,
+    search_paths: SearchPaths,
+    writer: S,
+    source_tracker: &mut T,
+) -> Result<S, Error>
+where
+    P: AsRef<Path>,
+    S: OutputSink,
+    T: SourceTracker,
+{
+    preprocess_with_options(
+        entry_point,
+        search_paths,
+        Options::default(),
+        writer,
+        source_tracker,
+    )
+}
+
+pub fn preprocess_with_options<P, S, T>(
+    entry_point: P,
+    search_paths: SearchPaths,
+    options: Options,
+    writer: S,
+    source_tracker: &mut T,
+) -> Result<S, Error>
+where
+    P: AsRef<Path>,
+    S: OutputSink,
+    T: SourceTracker,
+{
+    preprocess_with_report(entry_point, search_paths, options, writer, source_tracker)
+        .map(|(writer, _)| writer)
+}
+
+/// Like [preprocess_with_options], but reads the files with `tokio::fs`, with at most as many
+/// files being read at a time as the `semaphore` has permits (so that a semaphore can bound the
+/// reads along with other IO).
+///
+/// The files are read, and parsed on tokio's blocking threads, before the output is emitted on
+/// the calling task. Files that are included through `#include MACRO` directives, files that fail
+/// to load or have errors, and all files if the search paths have a [Resolver] or a
+/// [ResolutionTracer], are read through [Options::file_provider] while the output is emitted
+/// instead, as with [Concurrency::SingleThreaded]. The files that are read are kept in a new
+/// cache for the run, which replaces [Options::cache].
+#[cfg(feature = "tokio")]
+pub async fn preprocess_async<P, S, T>(
+    entry_point: P,
+    search_paths: SearchPaths,
+    mut options: Options,
+    semaphore: Arc<Semaphore>,
+    writer: S,
+    source_tracker: &mut T,
+) -> Result<S, Error>
+where
+    P: AsRef<Path>,
+    S: OutputSink,
+    T: SourceTracker,
+{
+    let cache = PreprocessCache {
+        validate: false,
+        ..PreprocessCache::new()
+    };
+
+    if search_paths.resolver().is_none() && search_paths.tracer().is_none() {
+        prefetch(
+            entry_point.as_ref(),
+            &search_paths,
+            &options,
+            &cache,
+            semaphore,
+        )
+        .await;
+    }
+
+    options.cache = Some(cache);
+    options.concurrency = Concurrency::SingleThreaded;
+
+    preprocess_with_options(entry_point, search_paths, options, writer, source_tracker)
+}
+
+/// Reads and parses the files that `entry_point` includes (other than through `#include MACRO`
+/// directives) with `tokio::fs`, and adds them to the `cache`, see [preprocess_async].
+///
+/// Files that fail to load or that have errors are not added, so that their errors are reported
+/// when the output is emitted.
+#[cfg(feature = "tokio")]
+async fn prefetch(
+    entry_point: &Path,
+    search_paths: &SearchPaths,
+    options: &Options,
+    cache: &PreprocessCache,
+    semaphore: Arc<Semaphore>,
+) {
+    let entry_path = match search_paths.normalize(entry_point, options.file_provider.as_ref()) {
+        Ok(entry_path) => entry_path,
+        Err(_) => return,
+    };
+    let configuration = configuration_key(search_paths, options);
+    let mut started = HashSet::from([entry_path.clone()]);
+    let mut prefetcher = Prefetcher {
+        semaphore,
+        search_paths: Arc::new(search_paths.clone()),
+        file_provider: options.file_provider.clone(),
+        // The combined size is counted as the prefetched files are taken from the cache.
+        settings: ParseSettings {
+            max_total_size: None,
+            ..ParseSettings::new(options)
+        },
+        tasks: JoinSet::new(),
+    };
+
+    prefetcher.spawn(entry_path, None);
+
+    while let Some(result) = prefetcher.tasks.join_next().await {
+        // The remaining tasks are aborted when the prefetcher is dropped.
+        if options.is_cancelled() {
+            return;
+        }
+
+        let (search_path_index, node) = match result {
+            Ok((search_path_index, Some(node))) => (search_path_index, node),
+            Ok((_, None)) => continue,
+            Err(err) if err.is_panic() => panic::resume_unwind(err.into_panic()),
+            Err(_) => continue,
+        };
+
+        for chunk in node.chunks() {
+            if let NodeChunk::Include {
+                path,
+                search_path_index,
+                ..
+            } = chunk
+            {
+                if started.insert(path.to_path_buf()) {
+                    prefetcher.spawn(path.to_path_buf(), search_path_index);
+                }
+            }
+        }
+
+        if node.errors.is_empty() {
+            let key = CacheKey {
+                path: node.path().to_path_buf(),
+                search_path_index,
+                configuration,
+            };
+
+            cache.insert(
+                key,
+                CacheEntry {
+                    modified: None,
+                    queries: Vec::new(),
+                    node,
+                },
+            );
+        }
+    }
+}
+
+/// Spawns the tasks that read and parse files for [prefetch].
+#[cfg(feature = "tokio")]
+struct Prefetcher {
+    semaphore: Arc<Semaphore>,
+    search_paths: Arc<SearchPaths>,
+    file_provider: Arc<dyn FileProvider>,
+    settings: ParseSettings,
+    // The tasks resolve to the search path index and the node of the file, or `None` if the file
+    // failed to load.
+    tasks: JoinSet<(Option<usize>, Option<ParsedNode>)>,
+}
+
+#[cfg(feature = "tokio")]
+impl Prefetcher {
+    fn spawn(&mut self, path: PathBuf, search_path_index: Option<usize>) {
+        let semaphore = self.semaphore.clone();
+        let search_paths = self.search_paths.clone();
+        let file_provider = self.file_provider.clone();
+        let settings = self.settings.clone();
+
+        self.tasks.spawn(async move {
+            let source = {
+                // A closed semaphore does not bound the reads.
+                let _permit = semaphore.acquire_owned().await;
+
+                // A file that is too large is left to fail when it is loaded from the cache.
+                if let Some(max) = settings.max_file_size {
+                    match tokio::fs::metadata(&path).await {
+                        Ok(metadata) if metadata.len() <= max => (),
+                        _ => return (search_path_index, None),
+                    }
+                }
+
+                tokio::fs::read(&path).await.and_then(file_provider::decode)
+            };
+            let source = match source {
+                Ok(source) => source,
+                Err(_) => return (search_path_index, None),
+            };
+
+            // Resolving the includes queries the file provider, which may block.
+            let node = tokio::task::spawn_blocking(move || {
+                let file = VirtualFile::new(&path, source, file_provider);
+
+                ParsedNode::try_parse(
+                    path,
+                    search_path_index,
+                    None,
+                    &search_paths,
+                    &file,
+                    &settings,
+                )
+                .ok()
+            })
+            .await;
+
+            match node {
+                Ok(node) => (search_path_index, node),
+                Err(err) => panic::resume_unwind(err.into_panic()),
+            }
+        });
+    }
+}
+
+/// Like [preprocess], but with the source of the entry point given as `source`, rather than read
+/// from a file, see [preprocess_str_with_options].
+pub fn preprocess_str<P, S, T>(
+    source: &str,
+    virtual_path: P,
+    search_paths: SearchPaths,
+    writer: S,
+    source_tracker: &mut T,
+) -> Result<S, Error>
+where
+    P: AsRef<Path>,
+    S: OutputSink,
+    T: SourceTracker,
+{
+    preprocess_str_with_options(
+        source,
+        virtual_path,
+        search_paths,
+        Options::default(),
+        writer,
+        source_tracker,
+    )
+}
+
+/// Like [preprocess_with_options], but with the source of the entry point given as `source`,
+/// rather than read from a file, e.g. the contents of an editor buffer or generated code.
+///
+/// The entry point is treated as a file at `virtual_path` (relative to the current directory, if
+/// it is relative), which need not exist: quoted includes are resolved relative to its directory,
+/// errors and [SourceTracker::track] refer to it, and `#pragma once` identifies it by this path.
+/// All other files are accessed through [Options::file_provider].
+pub fn preprocess_str_with_options<P, S, T>(
+    source: &str,
+    virtual_path: P,
+    search_paths: SearchPaths,
+    options: Options,
+    writer: S,
+    source_tracker: &mut T,
+) -> Result<S, Error>
+where
+    P: AsRef<Path>,
+    S: OutputSink,
+    T: SourceTracker,
+{
+    let virtual_path = virtual_path.as_ref();
+    let virtual_path = if virtual_path.is_absolute() {
+        virtual_path.to_path_buf()
+    } else {
+        env::current_dir()
+            .map(|current_dir| current_dir.join(virtual_path))
+            .map_err(|err| FileAccessError::new(virtual_path, FileOperation::Canonicalize, err))?
+    };
+    let virtual_path = file_provider::normalize(&virtual_path);
+    let file_provider = VirtualFile::new(
+        &virtual_path,
+        source.to_string(),
+        options.file_provider.clone(),
+    );
+    let options = Options {
+        file_provider: Arc::new(file_provider),
+        ..options
+    };
+
+    preprocess_with_options(virtual_path, search_paths, options, writer, source_tracker)
+}
+
+/// Like [preprocess_str_with_options], but with the source of the entry point read from `reader`,
+/// e.g. from standard input.
+///
+/// The source is decoded like a file that is read by [StdFs]. Fails with [Error::IO] for
+/// `virtual_path` if reading fails, if the source cannot be decoded (with the detected encoding
+/// and the byte offset of the invalid sequence), or if it is larger than
+/// [Options::max_reader_size].
+pub fn preprocess_reader<R, P, S, T>(
+    mut reader: R,
+    virtual_path: P,
+    search_paths: SearchPaths,
+    options: Options,
+    writer: S,
+    source_tracker: &mut T,
+) -> Result<S, Error>
+where
+    R: Read,
+    P: AsRef<Path>,
+    S: OutputSink,
+    T: SourceTracker,
+{
+    let virtual_path = virtual_path.as_ref();
+    let read_error = |err| FileAccessError::new(virtual_path, FileOperation::Read, err);
+
+    let mut bytes = Vec::new();
+
+    match options.max_reader_size {
+        Some(max) => {
+            // Read one byte past the limit, to tell whether the source exceeds it.
+            reader
+                .by_ref()
+                .take(max as u64 + 1)
+                .read_to_end(&mut bytes)
+                .map_err(read_error)?;
+
+            if bytes.len() > max {
+                let message = format!("source exceeds the size limit of {} bytes", max);
+
+                return Err(read_error(IOError::new(IOErrorKind::InvalidData, message)).into());
+            }
+        }
+        None => {
+            reader.read_to_end(&mut bytes).map_err(read_error)?;
+        }
+    }
+
+    let source = file_provider::decode(bytes).map_err(read_error)?;
+
+    preprocess_str_with_options(
+        &source,
+        virtual_path,
+        search_paths,
+        options,
+        writer,
+        source_tracker,
+    )
+}
+
+/// Like [preprocess], but without a [SourceTracker], for when the files that are used and the
+/// diagnostics that are reported for them are of no interest.
+///
+/// ```no_run
+/// use include_preprocessor::{preprocess_untracked, SearchPaths};
+///
+/// let mut search_paths = SearchPaths::new();
+///
+/// search_paths.push_base_path("shaders/include");
+///
+/// let output = preprocess_untracked("shaders/main.glsl", search_paths, String::new()).unwrap();
+/// ```
+pub fn preprocess_untracked<P, S>(
+    entry_point: P,
+    search_paths: SearchPaths,
+    writer: S,
+) -> Result<S, Error>
+where
+    P: AsRef<Path>,
+    S: OutputSink,
+{
+    preprocess(entry_point, search_paths, writer, &mut NoopTracker)
+}
+
+/// Like [preprocess], but writes the output to `writer` as it is produced, rather than collecting
+/// it in memory.
+///
+/// Fails with [Error::Sink] (with an [std::io::Error]) if writing or flushing the output fails.
+pub fn preprocess_to_writer<P, W, T>(
+    entry_point: P,
+    search_paths: SearchPaths,
+    writer: W,
+    source_tracker: &mut T,
+) -> Result<W, Error>
+where
+    P: AsRef<Path>,
+    W: Write,
+    T: SourceTracker,
+{
+    preprocess(
+        entry_point,
+        search_paths,
+        IoSink::new(writer),
+        source_tracker,
+    )
+    .map(IoSink::into_inner)
+}
+
+/// Like [preprocess_with_options], but also returns a [PreprocessReport] with additional
+/// information about the preprocessing run.
+pub fn preprocess_with_report<P, S, T>(
+    entry_point: P,
+    search_paths: SearchPaths,
+    options: Options,
+    writer: S,
+    source_tracker: &mut T,
+) -> Result<(S, PreprocessReport), Error>
+where
+    P: AsRef<Path>,
+    S: OutputSink,
+    T: SourceTracker,
+{
+    preprocess_with_diagnostics(
+        entry_point,
+        search_paths,
+        options,
+        writer,
+        source_tracker,
+        &mut Discard,
+    )
+}
+
+/// Like [preprocess_with_report], but also passes each [Diagnostic] to the `diagnostic_handler`
+/// as it is reported.
+pub fn preprocess_with_diagnostics<P, S, T, H>(
+    entry_point: P,
+    search_paths: SearchPaths,
+    options: Options,
+    mut writer: S,
+    source_tracker: &mut T,
+    diagnostic_handler: &mut H,
+) -> Result<(S, PreprocessReport), Error>
+where
+    P: AsRef<Path>,
+    S: OutputSink,
+    T: SourceTracker,
+    H: DiagnosticHandler,
+{
+    let mut errors = Vec::new();
+
+    let result = write_report(
+        entry_point.as_ref(),
+        search_paths,
+        &options,
+        &mut writer,
+        source_tracker,
+        diagnostic_handler,
+        &mut errors,
+    );
+
+    finish_result(result.map(|report| (writer, report)), errors, &options)
+}
+
+/// Returns the files that preprocessing `entry_point` depends on: the canonical paths of the
+/// entry point and of every file it (transitively) includes, in sorted order.
+///
+/// Resolves includes exactly like [preprocess], but without emitting the output, unless a file
+/// has an `#include MACRO` directive: as the file such a directive includes depends on the macros
+/// that are defined when it is reached, the output is then emitted (and discarded) to find it.
+pub fn preprocess_dependencies<P>(
+    entry_point: P,
+    search_paths: SearchPaths,
+) -> Result<Vec<PathBuf>, Error>
+where
+    P: AsRef<Path>,
+{
+    preprocess_dependencies_with_options(entry_point, search_paths, Options::default())
+}
+
+/// Like [preprocess_dependencies], but with the given [Options].
+pub fn preprocess_dependencies_with_options<P>(
+    entry_point: P,
+    search_paths: SearchPaths,
+    options: Options,
+) -> Result<Vec<PathBuf>, Error>
+where
+    P: AsRef<Path>,
+{
+    let graph = preprocess_include_graph_with_options(entry_point, search_paths, options)?;
+
+    Ok(graph.nodes().map(|path| path.to_path_buf()).collect())
+}
+
+/// Returns the [IncludeGraph] of preprocessing `entry_point`, without emitting the output (see
+/// [preprocess_dependencies]).
+pub fn preprocess_include_graph<P>(
+    entry_point: P,
+    search_paths: SearchPaths,
+) -> Result<IncludeGraph, Error>
+where
+    P: AsRef<Path>,
+{
+    preprocess_include_graph_with_options(entry_point, search_paths, Options::default())
+}
+
+/// Like [preprocess_include_graph], but with the given [Options].
+pub fn preprocess_include_graph_with_options<P>(
+    entry_point: P,
+    search_paths: SearchPaths,
+    options: Options,
+) -> Result<IncludeGraph, Error>
+where
+    P: AsRef<Path>,
+{
+    validate_search_paths(&search_paths, &options, &mut Discard)?;
+
+    let mut errors = Vec::new();
+
+    let result =
+        Parsed::try_init(entry_point, search_paths, &options, &mut errors).and_then(|parsed| {
+            parsed.load_all(&options, &mut errors)?;
+
+            Ok(parsed.include_graph())
+        });
+
+    finish_result(result, errors, &options)
+}
+
+/// Parses `entry_point` and every file it (transitively) includes, without emitting the output,
+/// see [ParsedTree].
+pub fn parse_tree<P>(entry_point: P, search_paths: SearchPaths) -> Result<ParsedTree, Error>
+where
+    P: AsRef<Path>,
+{
+    parse_tree_with_options(entry_point, search_paths, Options::default())
+}
+
+/// Like [parse_tree], but with the given [Options].
+pub fn parse_tree_with_options<P>(
+    entry_point: P,
+    search_paths: SearchPaths,
+    options: Options,
+) -> Result<ParsedTree, Error>
+where
+    P: AsRef<Path>,
+{
+    validate_search_paths(&search_paths, &options, &mut Discard)?;
+
+    let mut errors = Vec::new();
+
+    let result =
+        Parsed::try_init(entry_point, search_paths, &options, &mut errors).and_then(|parsed| {
+            parsed.load_all(&options, &mut errors)?;
+
+            Ok(parsed.parse_tree())
+        });
+
+    finish_result(result, errors, &options)
+}
+
+/// Checks the search paths if [Options::search_path_validation] is enabled, failing at the first
+/// invalid search path if it is strict, or reporting them as warnings otherwise.
+fn validate_search_paths<H>(
+    search_paths: &SearchPaths,
+    options: &Options,
+    diagnostic_handler: &mut H,
+) -> Result<(), InvalidSearchPath>
+where
+    H: DiagnosticHandler,
+{
+    if options.search_path_validation != SearchPathValidation::Ignore {
+        for invalid in search_paths.validate_with(options.file_provider.as_ref()) {
+            if options.search_path_validation == SearchPathValidation::Strict {
+                return Err(InvalidSearchPath {
+                    display_root: options.display_root.clone().map(Arc::new),
+                    ..invalid
+                });
+            }
+
+            diagnostic_handler.handle(Diagnostic::without_line(
+                Severity::Warning,
+                invalid.to_string(),
+                &invalid.path,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Passes `entry_point` to [SourceTracker::track_missing] if it does not exist, after preprocessing
+/// it failed before any file was loaded.
+fn track_missing_entry<T>(entry_point: &Path, options: &Options, source_tracker: &mut T)
+where
+    T: SourceTracker,
+{
+    if !options.file_provider.exists(entry_point) {
+        source_tracker.track_missing(entry_point);
+    }
+}
+
+/// Combines the `result` of a preprocessing run with the recoverable `errors` that were collected
+/// (see [Options::collect_errors]), and applies [Options::display_root] to the error.
+fn finish_result<R>(
+    result: Result<R, Error>,
+    mut errors: Vec<Error>,
+    options: &Options,
+) -> Result<R, Error> {
+    let result = match result {
+        Ok(result) if errors.is_empty() => Ok(result),
+        Ok(_) => Err(Error::Multiple(errors)),
+        // A cancelled run is not reported with the errors that were collected before.
+        Err(Error::Cancelled) => Err(Error::Cancelled),
+        Err(err) if options.collect_errors => {
+            errors.push(err);
+
+            Err(Error::Multiple(errors))
+        }
+        Err(err) => Err(err),
+    };
+
+    result.map_err(|mut err| {
+        if let Some(display_root) = &options.display_root {
+            err.set_display_root(&Arc::new(display_root.clone()));
+        }
+
+        err
+    })
+}
+
+/// Preprocesses `entry_point` into `writer`; recoverable errors are added to `errors` if
+/// [Options::collect_errors] is enabled.
+fn write_report<S, T, H>(
+    entry_point: &Path,
+    search_paths: SearchPaths,
+    options: &Options,
+    writer: &mut S,
+    source_tracker: &mut T,
+    diagnostic_handler: &mut H,
+    errors: &mut Vec<Error>,
+) -> Result<PreprocessReport, Error>
+where
+    S: OutputSink,
+    T: SourceTracker,
+    H: DiagnosticHandler,
+{
+    let mut diagnostics = Reported {
+        handler: diagnostic_handler,
+        display_root: options.display_root.clone().map(Arc::new),
+        diagnostics: Vec::new(),
+    };
+
+    validate_search_paths(&search_paths, options, &mut diagnostics)?;
+
+    let start = Instant::now();
+    let parsed = Parsed::try_init(entry_point, search_paths, options, errors)
+        .inspect_err(|_| track_missing_entry(entry_point, options, source_tracker))?;
+    let init_time = start.elapsed();
+    let mut file_ids = Vec::new();
+    let mut output_sink = StatsSink {
+        output_sink: writer,
+        bytes_emitted: 0,
+        skipped_includes: 0,
+    };
+    let start = Instant::now();
+    let macros = parsed.write(
+        &mut output_sink,
+        source_tracker,
+        &mut diagnostics,
+        &mut file_ids,
+        options,
+        errors,
+    )?;
+    let write_time = start.elapsed();
+
+    let stats = {
+        let loader = parsed.loader.borrow();
+        let settings = &loader.context.settings;
+
+        PreprocessStats {
+            files_read: settings.files_read.load(Ordering::SeqCst),
+            bytes_read: settings.bytes_read.load(Ordering::SeqCst),
+            bytes_emitted: output_sink.bytes_emitted,
+            skipped_includes: output_sink.skipped_includes,
+            load_time: init_time + loader.wait_time,
+            write_time: write_time.saturating_sub(loader.wait_time),
+        }
+    };
+
+    let defined_macros = macros
+        .definitions()
+        .map(|(name, definition, origin)| {
+            let defined_macro = DefinedMacro {
+                parameters: definition.parameters().map(|p| p.to_vec()),
+                body: definition.body().to_string(),
+                source_file: origin.map(|(path, _)| path.to_path_buf()),
+                line_number: origin.map(|(_, line_number)| line_number + 1),
+            };
+
+            (name.to_string(), defined_macro)
+        })
+        .collect();
+
+    Ok(PreprocessReport {
+        defined_macros,
+        diagnostics: diagnostics.diagnostics,
+        file_ids,
+        include_graph: parsed.include_graph(),
+        stats,
+    })
+}
+
+/// Counts the output for the [PreprocessStats] of a run, and passes it on to the `output_sink`
+/// it wraps.
+struct StatsSink<'a, S> {
+    output_sink: &'a mut S,
+    bytes_emitted: usize,
+    skipped_includes: usize,
+}
+
+impl<S> OutputSink for StatsSink<'_, S>
+where
+    S: OutputSink,
+{
+    type Error = S::Error;
+
+    fn sink(&mut self, chunk: &str) -> Result<(), S::Error> {
+        self.bytes_emitted += chunk.len();
+        self.output_sink.sink(chunk)
+    }
+
+    fn sink_source_mapped(
+        &mut self,
+        source_mapped_chunk: SourceMappedChunk,
+    ) -> Result<(), S::Error> {
+        self.bytes_emitted += source_mapped_chunk.text.len();
+        self.output_sink.sink_source_mapped(source_mapped_chunk)
+    }
+
+    fn enter_include(
+        &mut self,
+        path: &Path,
+        from: &Path,
+        line_number: usize,
+    ) -> Result<(), S::Error> {
+        self.output_sink.enter_include(path, from, line_number)
+    }
+
+    fn exit_include(&mut self, path: &Path) -> Result<(), S::Error> {
+        self.output_sink.exit_include(path)
+    }
+
+    fn skip_include(
+        &mut self,
+        path: &Path,
+        from: &Path,
+        line_number: usize,
+    ) -> Result<(), S::Error> {
+        self.skipped_includes += 1;
+        self.output_sink.skip_include(path, from, line_number)
+    }
+
+    fn finish(&mut self) -> Result<(), S::Error> {
+        self.output_sink.finish()
+    }
+}
+
+/// Information about a preprocessing run, see [preprocess_with_report].
+#[derive(Clone, Debug)]
+pub struct PreprocessReport {
+    defined_macros: BTreeMap<String, DefinedMacro>,
+    diagnostics: Vec<Diagnostic>,
+    file_ids: Vec<PathBuf>,
+    include_graph: IncludeGraph,
+    stats: PreprocessStats,
+}
+
+impl PreprocessReport {
+    /// The macros that were still defined at the end of the preprocessing run, by name.
+    ///
+    /// This includes the macros defined through [Options::defines], but not macros that were
+    /// removed with `#undef`.
+    pub fn defined_macros(&self) -> &BTreeMap<String, DefinedMacro> {
+        &self.defined_macros
+    }
+
+    /// The diagnostics that were reported during the preprocessing run, in the order in which
+    /// they were reported.
+    ///
+    /// If this contains a diagnostic with [Severity::Error] (see [Options::recover_includes]),
+    /// the output is incomplete.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// The files that were emitted, indexed by the file id that identifies them in the `#line`
+    /// directives emitted when [Options::line_markers] is enabled.
+    ///
+    /// Empty if line markers are not enabled.
+    pub fn file_ids(&self) -> &[PathBuf] {
+        &self.file_ids
+    }
+
+    /// The include structure of the preprocessing run.
+    pub fn include_graph(&self) -> &IncludeGraph {
+        &self.include_graph
+    }
+
+    /// The counters and timings of the preprocessing run.
+    pub fn stats(&self) -> &PreprocessStats {
+        &self.stats
+    }
+}
+
+/// Counters and timings of a preprocessing run, see [PreprocessReport::stats].
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct PreprocessStats {
+    files_read: usize,
+    bytes_read: usize,
+    bytes_emitted: usize,
+    skipped_includes: usize,
+    load_time: Duration,
+    write_time: Duration,
+}
+
+impl PreprocessStats {
+    /// The number of files that were read; files that are reused from a [PreprocessCache] are
+    /// not counted.
+    pub fn files_read(&self) -> usize {
+        self.files_read
+    }
+
+    /// The combined size in bytes of the files that were read.
+    pub fn bytes_read(&self) -> usize {
+        self.bytes_read
+    }
+
+    /// The number of bytes of output that were passed to the [OutputSink].
+    pub fn bytes_emitted(&self) -> usize {
+        self.bytes_emitted
+    }
+
+    /// The number of includes that were skipped as the file was already included, see
+    /// [OutputSink::skip_include].
+    pub fn skipped_includes(&self) -> usize {
+        self.skipped_includes
+    }
+
+    /// The wall time spent loading the files: loading the entry point, and waiting for the files
+    /// it includes (or loading them, if the run is single-threaded) while writing the output.
+    pub fn load_time(&self) -> Duration {
+        self.load_time
+    }
+
+    /// The wall time spent writing the output, excluding the time spent waiting for files to
+    /// load.
+    pub fn write_time(&self) -> Duration {
+        self.write_time
+    }
+}
+
+/// Receives the [Diagnostic]s that are reported during preprocessing, see
+/// [preprocess_with_diagnostics].
+pub trait DiagnosticHandler {
+    fn handle(&mut self, diagnostic: Diagnostic);
+}
+
+impl DiagnosticHandler for Vec<Diagnostic> {
+    fn handle(&mut self, diagnostic: Diagnostic) {
+        self.push(diagnostic);
+    }
+}
+
+/// Passes diagnostics on to a [DiagnosticHandler], while also recording them for the
+/// [PreprocessReport].
+struct Reported<'a, H> {
+    handler: &'a mut H,
+    display_root: Option<Arc<PathBuf>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<H> DiagnosticHandler for Reported<'_, H>
+where
+    H: DiagnosticHandler,
+{
+    fn handle(&mut self, mut diagnostic: Diagnostic) {
+        diagnostic.display_root = self.display_root.clone();
+
+        self.diagnostics.push(diagnostic.clone());
+        self.handler.handle(diagnostic);
+    }
+}
+
+/// The severity of a [Diagnostic].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Severity {
+    /// A problem that preprocessing recovered from, see [Options::recover_includes].
+    Error,
+    Warning,
+    Note,
+}
+
+/// A problem that was reported without failing preprocessing.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Diagnostic {
+    severity: Severity,
+    message: String,
+    path: PathBuf,
+    line: Option<usize>,
+    column: usize,
+    display_root: Option<Arc<PathBuf>>,
+}
+
+impl Diagnostic {
+    /// Creates a diagnostic for the (zero-based) `line_number` of `path`.
+    fn new<M>(severity: Severity, message: M, path: &Path, line_number: usize) -> Self
+    where
+        M: Into<String>,
+    {
+        Diagnostic {
+            severity,
+            message: message.into(),
+            path: path.to_path_buf(),
+            line: Some(line_number + 1),
+            column: 0,
+            display_root: None,
+        }
+    }
+
+    /// Creates a diagnostic for `path` as a whole, e.g. an invalid search path.
+    fn without_line<M>(severity: Severity, message: M, path: &Path) -> Self
+    where
+        M: Into<String>,
+    {
+        Diagnostic {
+            severity,
+            message: message.into(),
+            path: path.to_path_buf(),
+            line: None,
+            column: 0,
+            display_root: None,
+        }
+    }
+
+    /// Creates the diagnostic for an include that was recovered from, see
+    /// [Options::recover_includes].
+    fn recovered_include(err: &FileNotFoundError) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: format!("missing include: \"{}\"", err.included_path.display()),
+            path: err.source_file.clone(),
+            line: Some(err.line_number()),
+            column: err.column(),
+            display_root: None,
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The file the diagnostic refers to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The (one-based) line number the diagnostic refers to, or `None` if it refers to the
+    /// [path](Diagnostic::path) as a whole.
+    pub fn line(&self) -> Option<usize> {
+        self.line
+    }
+
+    /// The (zero-based) column the diagnostic refers to, counted in characters.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+}
+
+/// Formats the diagnostic as `path:line:column: severity: message`, with a one-based column, or
+/// as `path: severity: message` if it has no line.
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let display_root = self.display_root.as_deref().map(PathBuf::as_path);
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        };
+
+        write!(f, "{}:", diagnostic::display_path(&self.path, display_root))?;
+
+        if let Some(line) = self.line {
+            write!(f, "{}:{}:", line, self.column + 1)?;
+        }
+
+        write!(
+            f,
+            " {}: {}",
+            severity,
+            diagnostic::relativize_message(&self.message, display_root)
+        )
+    }
+}
+
+/// A macro that was defined at the end of a preprocessing run.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DefinedMacro {
+    parameters: Option<Vec<String>>,
+    body: String,
+    source_file: Option<PathBuf>,
+    line_number: Option<usize>,
+}
+
+impl DefinedMacro {
+    /// The parameters of a function-like macro, or `None` for an object-like macro.
+    pub fn parameters(&self) -> Option<&[String]> {
+        self.parameters.as_deref()
+    }
+
+    /// The value the macro expands to.
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    /// The file that contains the latest `#define` of the macro, or `None` if the macro was
+    /// defined through [Options::defines].
+    pub fn source_file(&self) -> Option<&Path> {
+        self.source_file.as_deref()
+    }
+
+    /// The (one-based) line number of the latest `#define` of the macro in
+    /// [source_file](DefinedMacro::source_file).
+    pub fn line_number(&self) -> Option<usize> {
+        self.line_number
+    }
+}
+
+/// The include structure of a preprocessing run: the files that were loaded, and the include
+/// directives that connect them, see [preprocess_include_graph] and
+/// [PreprocessReport::include_graph].
+///
+/// The include directives of a file are its `#include`, `#include_next` and `#include_optional`
+/// directives that include a file, including those in conditional groups that are not emitted,
+/// and its `#include MACRO` directives that were reached during emission.
+#[derive(Clone, Default, Debug)]
+pub struct IncludeGraph {
+    entry_point: PathBuf,
+    includes: BTreeMap<PathBuf, Vec<IncludeEdge>>,
+}
+
+impl IncludeGraph {
+    /// The canonical path of the entry point.
+    pub fn entry_point(&self) -> &Path {
+        &self.entry_point
+    }
+
+    /// The canonical paths of the entry point and of every file it (transitively) includes, in
+    /// sorted order.
+    pub fn nodes(&self) -> impl Iterator<Item = &Path> {
+        self.includes.keys().map(|path| path.as_path())
+    }
+
+    /// The include directives of the file at the canonical `path`, in order; empty if the file is
+    /// not part of the graph.
+    ///
+    /// A glob include directive has an edge for each file it includes.
+    pub fn includes_of(&self, path: &Path) -> &[IncludeEdge] {
+        self.includes
+            .get(path)
+            .map(|edges| edges.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// The files that include the file at the canonical `path`, together with the include
+    /// directive that includes it, in sorted order.
+    pub fn included_by<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> impl Iterator<Item = (&'a Path, &'a IncludeEdge)> + 'a {
+        self.includes.iter().flat_map(move |(includer, edges)| {
+            edges
+                .iter()
+                .filter(move |edge| edge.included_file == path)
+                .map(move |edge| (includer.as_path(), edge))
+        })
+    }
+}
+
+/// An include directive in an [IncludeGraph].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct IncludeEdge {
+    line_number: usize,
+    include_path: IncludePathBuf,
+    included_file: PathBuf,
+}
+
+impl IncludeEdge {
+    /// The (one-based) line number of the include directive.
+    pub fn line_number(&self) -> usize {
+        self.line_number + 1
+    }
+
+    /// The include path as written; for an `#include MACRO` directive, the include path the macro
+    /// expanded to.
+    pub fn include_path(&self) -> IncludePath<'_> {
+        self.include_path.as_include_path()
+    }
+
+    /// The canonical path of the included file.
+    pub fn included_file(&self) -> &Path {
+        &self.included_file
+    }
+}
+
+/// A read-only view of the files that preprocessing an entry point parses, see [parse_tree].
+#[derive(Clone, Default, Debug)]
+pub struct ParsedTree {
+    entry_point: PathBuf,
+    files: BTreeMap<PathBuf, ParsedFile>,
+}
+
+impl ParsedTree {
+    /// The canonical path of the entry point.
+    pub fn entry_point(&self) -> &Path {
+        &self.entry_point
+    }
+
+    /// The file of the entry point, unless it failed to load (see [Options::collect_errors]).
+    pub fn root(&self) -> Option<&ParsedFile> {
+        self.files.get(&self.entry_point)
+    }
+
+    /// The file at the canonical `path`, if it is part of the tree.
+    pub fn file(&self, path: &Path) -> Option<&ParsedFile> {
+        self.files.get(path)
+    }
+
+    /// The entry point and every file it (transitively) includes, sorted by path.
+    pub fn files(&self) -> impl Iterator<Item = &ParsedFile> {
+        self.files.values()
+    }
+}
+
+/// A file in a [ParsedTree].
+#[derive(Clone, Debug)]
+pub struct ParsedFile {
+    path: PathBuf,
+    source: String,
+    once: bool,
+    chunks: Vec<Chunk>,
+}
+
+impl ParsedFile {
+    /// The canonical path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The contents of the file, which the ranges of its [Chunk::Text] chunks refer to.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the file contains a `#pragma once` directive.
+    pub fn is_once(&self) -> bool {
+        self.once
+    }
+
+    /// The text and include directives of the file, in order.
+    ///
+    /// Other directives (such as `#define` and conditional directives) are not represented, and
+    /// the text of every conditional group is included.
+    pub fn chunks(&self) -> impl Iterator<Item = &Chunk> {
+        self.chunks.iter()
+    }
+}
+
+/// A chunk of a [ParsedFile].
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub enum Chunk {
+    /// Lines of text, as a byte range of the [source](ParsedFile::source) of the file.
+    Text { range: Range<usize> },
+    /// An include directive that includes the file at the canonical path `resolved`, at the
+    /// (one-based) line number `directive_line`.
+    ///
+    /// A glob include directive has a chunk for each file it includes.
+    Include {
+        resolved: PathBuf,
+        directive_line: usize,
+    },
+}
+
+/// A cache of parsed files that can be shared between preprocessing runs, so that files that did
+/// not change are not read and parsed again, see [Options::cache].
+///
+/// A cached file is reused if neither the file nor the outcome of resolving its include
+/// directives changed since it was parsed: it is parsed again if e.g. an include that was missing
+/// (or that resolved under a later search path) appears. Whether the file itself changed is
+/// detected through [FileProvider::modified] if the file provider supports it, or by reading the
+/// file and comparing its content otherwise.
+///
+/// Files are cached per configuration of the [SearchPaths], so that a cache can be shared between
+/// runs with different entry points or search paths. Files with errors are not cached, and the
+/// cache is not used if the search paths have a [Resolver] or a [ResolutionTracer].
+///
+/// Clones of a cache share the cached files.
+#[derive(Clone)]
+pub struct PreprocessCache {
+    entries: Arc<Mutex<HashMap<CacheKey, Arc<CacheEntry>>>>,
+    // Whether cached files are checked for changes; not for the cache of a [Session].
+    validate: bool,
+}
+
+impl PreprocessCache {
+    pub fn new() -> Self {
+        PreprocessCache {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            validate: true,
+        }
+    }
+
+    /// The number of cached files.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes all cached files.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Arc<CacheEntry>> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    fn insert(&self, key: CacheKey, entry: CacheEntry) {
+        self.entries.lock().unwrap().insert(key, Arc::new(entry));
+    }
+}
+
+impl Default for PreprocessCache {
+    fn default() -> Self {
+        PreprocessCache::new()
+    }
+}
+
+impl fmt::Debug for PreprocessCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PreprocessCache")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct CacheKey {
+    path: PathBuf,
+    search_path_index: Option<usize>,
+    /// See [configuration_key].
+    configuration: u64,
+}
+
+struct CacheEntry {
+    /// The modification time and size of the file before it was read, see
+    /// [FileProvider::modified].
+    modified: Option<(SystemTime, u64)>,
+    /// The queries that were made to the file provider while resolving the file's includes.
+    queries: Vec<FileQuery>,
+    node: ParsedNode,
+}
+
+impl CacheEntry {
+    /// Whether the cached node is still valid for a file with the given modification time and
+    /// size.
+    ///
+    /// Without a modification time, the file is read and compared to the cached source, after
+    /// applying the `content_filter` that the cached source was produced with.
+    fn is_valid(
+        &self,
+        modified: Option<(SystemTime, u64)>,
+        file_provider: &dyn FileProvider,
+        content_filter: Option<&dyn ContentFilter>,
+    ) -> bool {
+        let unchanged = match (modified, self.modified) {
+            (Some(modified), Some(cached)) => modified == cached,
+            _ => file_provider
+                .read(&self.node.path)
+                .and_then(|source| match content_filter {
+                    Some(filter) => filter.filter(&self.node.path, source),
+                    None => Ok(source),
+                })
+                .is_ok_and(|source| source == self.node.source),
+        };
+
+        unchanged && self.queries.iter().all(|query| query.holds(file_provider))
+    }
+}
+
+/// A query to a [FileProvider], with its outcome.
+#[derive(Debug)]
+enum FileQuery {
+    Exists(PathBuf, bool),
+    Canonicalize(PathBuf, Option<PathBuf>),
+    ReadDir(PathBuf, Option<Vec<PathBuf>>),
+}
+
+impl FileQuery {
+    /// Whether the query still has the same outcome.
+    fn holds(&self, file_provider: &dyn FileProvider) -> bool {
+        match self {
+            FileQuery::Exists(path, exists) => file_provider.exists(path) == *exists,
+            FileQuery::Canonicalize(path, canonical) => {
+                file_provider.canonicalize(path).ok().as_ref() == canonical.as_ref()
+            }
+            FileQuery::ReadDir(path, entries) => {
+                file_provider.read_dir(path).ok().as_ref() == entries.as_ref()
+            }
+        }
+    }
+}
+
+enum LoadState {
+    /// The file was loaded; the index of its node in the [NodeArena].
+    Loaded(usize),
+    /// The file failed to load.
+    Failed,
+}
+
+/// A file that is to be loaded and parsed, see [LoadContext::start].
+struct LoadJob {
+    id: FileId,
+    path: PathBuf,
+    search_path_index: Option<usize>,
+    included_from: Option<(PathBuf, usize)>,
+}
+
+/// Where the jobs that load files run, see [Options::concurrency].
+#[derive(Clone)]
+enum Executor {
+    #[cfg(feature = "threadpool")]
+    Pool {
+        pool: ThreadPool,
+        sender: mpsc::Sender<LoadResult>,
+    },
+    #[cfg(feature = "rayon")]
+    Rayon { sender: mpsc::Sender<LoadResult> },
+    /// The jobs are queued, and run on the thread that waits for them, see [Loader::receive].
+    Inline(Arc<Mutex<VecDeque<LoadJob>>>),
+}
+
+/// The result of loading and parsing a file on the thread pool of a [Loader].
+struct LoadResult {
+    id: FileId,
+    node: Result<ParsedNode, Error>,
+}
+
+/// How [ParsedNode::try_parse] reads and parses files.
+#[derive(Clone)]
+struct ParseSettings {
+    /// Whether malformed lines and includes that fail to resolve are skipped and recorded in the
+    /// node's `errors`, see [Options::collect_errors].
+    collect_errors: bool,
+    /// Whether include directives may be preceded by spaces and tabs, see
+    /// [Options::indent_includes].
+    indented_includes: bool,
+    /// The prefix of custom directives, if there is a handler for them, see
+    /// [Options::directive_prefix].
+    directive_prefix: Option<String>,
+    content_filter: Option<Arc<dyn ContentFilter>>,
+    max_file_size: Option<u64>,
+    max_total_size: Option<u64>,
+    // The combined size of the files that were read or reused so far.
+    total_size: Arc<AtomicU64>,
+    // The number of files that were read and their combined size, see [PreprocessStats].
+    files_read: Arc<AtomicUsize>,
+    bytes_read: Arc<AtomicUsize>,
+}
+
+impl ParseSettings {
+    fn new(options: &Options) -> Self {
+        ParseSettings {
+            collect_errors: options.collect_errors,
+            indented_includes: options.indent_includes,
+            directive_prefix: options
+                .directive_handler
+                .as_ref()
+                .map(|_| options.directive_prefix.clone()),
+            content_filter: options.content_filter.clone(),
+            max_file_size: options.max_file_size,
+            max_total_size: options.max_total_size,
+            total_size: Arc::new(AtomicU64::new(0)),
+            files_read: Arc::new(AtomicUsize::new(0)),
+            bytes_read: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Reads the file at `path` through `file_provider`, failing if it exceeds the size limits,
+    /// and applies the content filter (if any) to it.
+    fn read(
+        &self,
+        path: &Path,
+        included_from: Option<(PathBuf, usize)>,
+        file_provider: &dyn FileProvider,
+    ) -> Result<String, Error> {
+        // If the file provider knows the size, a file that is too large is not read at all.
+        if self.max_file_size.is_some() || self.max_total_size.is_some() {
+            if let Some((_, size)) = file_provider.modified(path) {
+                let total = self.total_size.load(Ordering::SeqCst) + size;
+
+                self.check(path, size, total, &included_from)?;
+            }
+        }
+
+        let source = match file_provider.read(path) {
+            Ok(source) => source,
+            Err(err) => {
+                return Err(FileAccessError {
+                    included_from,
+                    ..FileAccessError::new(path, FileOperation::Read, err)
+                }
+                .into())
+            }
+        };
+
+        self.files_read.fetch_add(1, Ordering::SeqCst);
+        self.bytes_read.fetch_add(source.len(), Ordering::SeqCst);
+        self.count(path, source.len() as u64, &included_from)?;
+
+        match &self.content_filter {
+            Some(filter) => filter.filter(path, source).map_err(|err| {
+                FileAccessError {
+                    included_from,
+                    ..FileAccessError::new(path, FileOperation::Filter, err)
+                }
+                .into()
+            }),
+            None => Ok(source),
+        }
+    }
+
+    /// Adds a file of `size` bytes to the combined size, failing if it exceeds the size limits.
+    fn count(
+        &self,
+        path: &Path,
+        size: u64,
+        included_from: &Option<(PathBuf, usize)>,
+    ) -> Result<(), SizeLimitError> {
+        let total = self.total_size.fetch_add(size, Ordering::SeqCst) + size;
+
+        self.check(path, size, total, included_from)
+    }
+
+    fn check(
+        &self,
+        path: &Path,
+        size: u64,
+        total: u64,
+        included_from: &Option<(PathBuf, usize)>,
+    ) -> Result<(), SizeLimitError> {
+        let (limit, size, max) = match (self.max_file_size, self.max_total_size) {
+            (Some(max), _) if size > max => (SizeLimit::File, size, max),
+            (_, Some(max)) if total > max => (SizeLimit::Total, total, max),
+            _ => return Ok(()),
+        };
+
+        Err(SizeLimitError {
+            path: path.to_path_buf(),
+            limit,
+            size,
+            max,
+            included_from: included_from.clone(),
+            display_root: None,
+        })
+    }
+}
+
+/// What the jobs that load files on the thread pool share.
+#[derive(Clone)]
+struct LoadContext {
+    executor: Executor,
+    search_paths: Arc<SearchPaths>,
+    file_provider: Arc<dyn FileProvider>,
+    settings: ParseSettings,
+    // The cache to reuse files from, with the key of the configuration the files are parsed with.
+    cache: Option<(PreprocessCache, u64)>,
+    // The ids of the files that started loading.
+    file_ids: Arc<Mutex<FileIds>>,
+    // The number of files that started loading, and whose results were not received yet. A file
+    // is counted before the file that includes it is sent, so that the count does not drop to
+    // zero while files are still being loaded.
+    pending: Arc<AtomicUsize>,
+    // Set when a file fails to load and errors are not collected, to not start loading further
+    // files.
+    stopped: Arc<AtomicBool>,
+    cancellation: Option<CancellationToken>,
+    progress: Option<Arc<dyn ProgressObserver>>,
+    // The number of files that were discovered and that finished loading, see [LoadProgress].
+    discovered: Arc<AtomicUsize>,
+    loaded: Arc<AtomicUsize>,
+}
+
+impl LoadContext {
+    /// Starts loading and parsing the file at `path` on the thread pool (or queues it to be
+    /// loaded, if the run is single-threaded), unless it already started loading; once parsed,
+    /// the files it includes start loading as well.
+    ///
+    /// Returns whether the file started loading.
+    fn start(
+        &self,
+        path: PathBuf,
+        search_path_index: Option<usize>,
+        included_from: Option<(PathBuf, usize)>,
+    ) -> bool {
+        if self.stopped.load(Ordering::SeqCst) || self.is_cancelled() {
+            return false;
+        }
+
+        let (id, new) = self.file_ids.lock().unwrap().insert(&path);
+
+        if !new {
+            return false;
+        }
+
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        self.report_progress(LoadEvent::Discovered, &path);
+
+        let job = LoadJob {
+            id,
+            path,
+            search_path_index,
+            included_from,
+        };
+
+        // The receiver is only gone if the preprocessing run panicked, or if it was cancelled.
+        match &self.executor {
+            #[cfg(feature = "threadpool")]
+            Executor::Pool { pool, sender } => {
+                let context = self.clone();
+                let sender = sender.clone();
+
+                pool.execute(move || {
+                    let _ = sender.send(context.run(job));
+                });
+            }
+            #[cfg(feature = "rayon")]
+            Executor::Rayon { sender } => {
+                let context = self.clone();
+                let sender = sender.clone();
+
+                rayon::spawn(move || {
+                    let _ = sender.send(context.run(job));
+                });
+            }
+            Executor::Inline(queue) => queue.lock().unwrap().push_back(job),
+        }
+
+        true
+    }
+
+    /// Loads and parses the file of the `job`, and starts loading the files it includes.
+    fn run(&self, job: LoadJob) -> LoadResult {
+        let LoadJob {
+            id,
+            path,
+            search_path_index,
+            included_from,
+        } = job;
+
+        // Jobs that were queued before the run was cancelled do not read their files.
+        if self.is_cancelled() {
+            return LoadResult {
+                id,
+                node: Err(Error::Cancelled),
+            };
+        }
+
+        let node = self.parse(path.clone(), search_path_index, included_from);
+
+        self.report_progress(LoadEvent::Loaded, &path);
+
+        if let Ok(node) = &node {
+            self.start_includes(node);
+        }
+
+        LoadResult { id, node }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+
+    /// Counts the `event` for the file at `path`, and passes the counts to the [ProgressObserver],
+    /// if any.
+    fn report_progress(&self, event: LoadEvent, path: &Path) {
+        let counter = match event {
+            LoadEvent::Discovered => &self.discovered,
+            LoadEvent::Loaded => &self.loaded,
+        };
+
+        counter.fetch_add(1, Ordering::SeqCst);
+
+        if let Some(observer) = &self.progress {
+            let progress = LoadProgress {
+                event,
+                path,
+                discovered: self.discovered.load(Ordering::SeqCst),
+                loaded: self.loaded.load(Ordering::SeqCst),
+            };
+
+            // A panicking observer does not affect the run.
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| observer.progress(progress)));
+        }
+    }
+
+    /// Returns a copy of a node from the [PreprocessCache], which counts towards
+    /// [Options::max_total_size] as if the file was read.
+    fn reuse(
+        &self,
+        node: &ParsedNode,
+        included_from: Option<(PathBuf, usize)>,
+    ) -> Result<ParsedNode, Error> {
+        self.settings
+            .count(&node.path, node.source.len() as u64, &included_from)?;
+
+        Ok(node.clone_without_errors())
+    }
+
+    /// Parses the file at `path`, see [ParsedNode::try_parse]; the file is reused from the
+    /// [PreprocessCache] if it is cached and still valid, and added to it otherwise.
+    fn parse(
+        &self,
+        path: PathBuf,
+        search_path_index: Option<usize>,
+        included_from: Option<(PathBuf, usize)>,
+    ) -> Result<ParsedNode, Error> {
+        let file_provider = self.file_provider.as_ref();
+        let (cache, configuration) = match &self.cache {
+            Some(cache) => cache,
+            None => {
+                return ParsedNode::try_parse(
+                    path,
+                    search_path_index,
+                    included_from,
+                    &self.search_paths,
+                    file_provider,
+                    &self.settings,
+                )
+            }
+        };
+
+        let key = CacheKey {
+            path: path.clone(),
+            search_path_index,
+            configuration: *configuration,
+        };
+
+        if !cache.validate {
+            if let Some(entry) = cache.get(&key) {
+                return self.reuse(&entry.node, included_from);
+            }
+        }
+
+        // Queried before the file is read, so that a change in between invalidates the entry.
+        let modified = file_provider.modified(&path);
+
+        if let Some(entry) = cache.get(&key) {
+            if entry.is_valid(
+                modified,
+                file_provider,
+                self.settings.content_filter.as_deref(),
+            ) {
+                return self.reuse(&entry.node, included_from);
+            }
+        }
+
+        let recording = RecordingProvider {
+            inner: file_provider,
+            queries: Mutex::new(Vec::new()),
+        };
+        let node = ParsedNode::try_parse(
+            path,
+            search_path_index,
+            included_from,
+            &self.search_paths,
+            &recording,
+            &self.settings,
+        )?;
+
+        // The errors of a file are reported for every run, so a file with errors is not cached.
+        if node.errors.is_empty() {
+            cache.insert(
+                key,
+                CacheEntry {
+                    modified,
+                    queries: recording.queries.into_inner().unwrap(),
+                    node: node.clone_without_errors(),
+                },
+            );
+        }
+
+        Ok(node)
+    }
+
+    /// Starts loading the files included by `node`.
+    fn start_includes(&self, node: &ParsedNode) {
+        for chunk in node.chunks() {
+            if let NodeChunk::Include {
+                path,
+                search_path_index,
+                line_number,
+                ..
+            } = chunk
+            {
+                let included_from = Some((node.path().to_path_buf(), line_number));
+
+                self.start(path.to_path_buf(), search_path_index, included_from);
+            }
+        }
+    }
+}
+
+/// How often a [Loader] that waits for a file checks whether the run was cancelled, or whether
+/// jobs of the rayon pool it runs on are waiting to run.
+#[cfg(any(feature = "threadpool", feature = "rayon"))]
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Receives the files that are loaded on the thread pool, see [Parsed::wait_for].
+struct Loader {
+    context: LoadContext,
+    // Unused if the run is single-threaded, in which case the files are loaded by the loader.
+    #[cfg(any(feature = "threadpool", feature = "rayon"))]
+    receiver: mpsc::Receiver<LoadResult>,
+    states: HashMap<FileId, LoadState>,
+    // Set when the run is cancelled, after which the files that are still being loaded are not
+    // waited for.
+    cancelled: bool,
+    /// The errors of the files that failed to load, and the recoverable errors of the files that
+    /// were loaded, if errors are collected.
+    errors: Vec<Error>,
+    /// The first error if errors are not collected, which is returned once the files that are
+    /// still being loaded have finished.
+    first_error: Option<Error>,
+    /// The time spent waiting for (or loading) files, see [PreprocessStats::load_time].
+    wait_time: Duration,
+}
+
+impl Loader {
+    /// Blocks until the next file has finished loading, and adds its node to `nodes`; if the run
+    /// is single-threaded, the next file that is queued is loaded instead.
+    ///
+    /// If the run is cancelled while waiting, this stops waiting for the files that are still
+    /// being loaded, and sets the first error to [Error::Cancelled].
+    fn receive(&mut self, nodes: &NodeArena) {
+        let start = Instant::now();
+        let result = match &self.context.executor {
+            #[cfg(feature = "threadpool")]
+            Executor::Pool { .. } => self.wait_for_result(false),
+            #[cfg(feature = "rayon")]
+            Executor::Rayon { .. } => self.wait_for_result(true),
+            Executor::Inline(queue) => {
+                // Every file that is pending was queued.
+                let job = queue.lock().unwrap().pop_front().unwrap();
+
+                Some(self.context.run(job))
+            }
+        };
+
+        self.wait_time += start.elapsed();
+
+        let LoadResult { id, node } = match result {
+            Some(result) => result,
+            None => {
+                self.cancel();
+
+                return;
+            }
+        };
+
+        self.context.pending.fetch_sub(1, Ordering::SeqCst);
+
+        let state = match node {
+            Err(Error::Cancelled) => {
+                self.cancel();
+
+                return;
+            }
+            Ok(mut node) => {
+                self.errors.append(&mut node.errors);
+
+                LoadState::Loaded(nodes.push(node))
+            }
+            Err(err) if self.context.settings.collect_errors => {
+                self.errors.push(err);
+
+                LoadState::Failed
+            }
+            Err(err) => {
+                self.context.stopped.store(true, Ordering::SeqCst);
+                self.first_error.get_or_insert(err);
+
+                LoadState::Failed
+            }
+        };
+
+        self.states.insert(id, state);
+    }
+
+    /// Blocks until the next result is received from the threads that load the files; `None` if
+    /// the run is cancelled while waiting.
+    ///
+    /// If `on_rayon`, the jobs of the current rayon pool are run while waiting, so that the files
+    /// still load when every thread of the pool is waiting for one.
+    #[cfg(any(feature = "threadpool", feature = "rayon"))]
+    fn wait_for_result(&self, on_rayon: bool) -> Option<LoadResult> {
+        if self.context.cancellation.is_none() && !on_rayon {
+            return Some(self.receiver.recv().unwrap());
+        }
+
+        loop {
+            if self.context.is_cancelled() {
+                return None;
+            }
+
+            #[cfg(feature = "rayon")]
+            if on_rayon {
+                if let Ok(result) = self.receiver.try_recv() {
+                    return Some(result);
+                }
+
+                if let Some(rayon::Yield::Executed) = rayon::yield_now() {
+                    continue;
+                }
+            }
+
+            match self.receiver.recv_timeout(POLL_INTERVAL) {
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                result => return Some(result.unwrap()),
+            }
+        }
+    }
+
+    fn cancel(&mut self) {
+        self.context.stopped.store(true, Ordering::SeqCst);
+        self.first_error = Some(Error::Cancelled);
+        // The results of the files that are still being loaded are discarded along with the
+        // receiver.
+        self.cancelled = true;
+    }
+
+    /// The number of files that are still being loaded, and that are waited for.
+    fn pending(&self) -> usize {
+        if self.cancelled {
+            0
+        } else {
+            self.context.pending.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Blocks until all files have finished loading.
+    fn finish(&mut self, nodes: &NodeArena) {
+        while self.pending() > 0 {
+            self.receive(nodes);
+        }
+    }
+}
+
+/// An append-only list of nodes, to which nodes can be added while references to the nodes it
+/// holds are in use.
+struct NodeArena {
+    len: Cell<usize>,
+    segment: NodeSegment,
+}
+
+/// A fixed number of node slots, followed by a segment of twice that number once they are taken.
+struct NodeSegment {
+    slots: Box<[OnceCell<ParsedNode>]>,
+    next: OnceCell<Box<NodeSegment>>,
+}
+
+impl NodeSegment {
+    fn new(capacity: usize) -> Self {
+        NodeSegment {
+            slots: iter::repeat_with(OnceCell::new).take(capacity).collect(),
+            next: OnceCell::new(),
+        }
+    }
+
+    fn slot(&self, index: usize) -> &OnceCell<ParsedNode> {
+        match self.slots.get(index) {
+            Some(slot) => slot,
+            None => self
+                .next
+                .get_or_init(|| Box::new(NodeSegment::new(self.slots.len() * 2)))
+                .slot(index - self.slots.len()),
+        }
+    }
+}
+
+impl NodeArena {
+    fn new() -> Self {
+        NodeArena {
+            len: Cell::new(0),
+            segment: NodeSegment::new(16),
+        }
+    }
+
+    /// Adds `node`, returning its index.
+    fn push(&self, node: ParsedNode) -> usize {
+        let index = self.len.get();
+
+        self.len.set(index + 1);
+
+        if self.segment.slot(index).set(node).is_err() {
+            unreachable!("node slot is taken");
+        }
+
+        index
+    }
+
+    fn get(&self, index: usize) -> Option<&ParsedNode> {
+        if index < self.len.get() {
+            self.segment.slot(index).get()
+        } else {
+            None
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &ParsedNode> {
+        (0..self.len.get()).filter_map(|index| self.get(index))
+    }
+}
+
+/// Identifies a file of a preprocessing run, see [FileIds].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+struct FileId(usize);
+
+/// Hands out a [FileId] for every distinct (normalized) path of a preprocessing run.
+///
+/// Files are told apart by their paths themselves, so distinct paths never share an id.
+#[derive(Default)]
+struct FileIds {
+    ids: HashMap<PathBuf, FileId>,
+}
+
+impl FileIds {
+    /// Returns the id of `path`, and whether it was newly handed out.
+    fn insert(&mut self, path: &Path) -> (FileId, bool) {
+        if let Some(id) = self.ids.get(path) {
+            return (*id, false);
+        }
+
+        let id = FileId(self.ids.len());
+
+        self.ids.insert(path.to_path_buf(), id);
+
+        (id, true)
+    }
+
+    fn get(&self, path: &Path) -> Option<FileId> {
+        self.ids.get(path).copied()
+    }
+}
+
+/// The key that identifies a file for `#pragma once`, see [ParsedNode::once_key].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum OnceKey {
+    File(FileId),
+    /// A hash of the file's content, see [Options::once_by_content].
+    Content(u64),
+}
+
+/// Returns a key for the configuration that affects how files are parsed, which identifies the
+/// files of a [PreprocessCache] that can be reused.
+fn configuration_key(search_paths: &SearchPaths, options: &Options) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    search_paths.base_paths.hash(&mut hasher);
+    search_paths.quoted_paths.hash(&mut hasher);
+    search_paths.extensions.hash(&mut hasher);
+    search_paths.case_insensitive.hash(&mut hasher);
+    search_paths.angle_quoted_search.hash(&mut hasher);
+    search_paths.angle_as_quoted.hash(&mut hasher);
+    search_paths.quoted_fallback.hash(&mut hasher);
+    search_paths.root_path.hash(&mut hasher);
+    search_paths.aliases.hash(&mut hasher);
+    search_paths.deny_patterns.hash(&mut hasher);
+    search_paths.sandbox_root.hash(&mut hasher);
+    search_paths.path_normalization.hash(&mut hasher);
+
+    for group in &search_paths.groups {
+        group.pattern.hash(&mut hasher);
+        group.base_paths.hash(&mut hasher);
+        group.quoted_paths.hash(&mut hasher);
+    }
+
+    options.indent_includes.hash(&mut hasher);
+    options
+        .directive_handler
+        .as_ref()
+        .map(|_| &options.directive_prefix)
+        .hash(&mut hasher);
+    options.content_filter.is_some().hash(&mut hasher);
+
+    hasher.finish()
+}
+
+/// The files of a preprocessing run, which are loaded on a thread pool while the output is
+/// emitted.
+///
+/// All files that are included (other than through `#include MACRO` directives) start loading as
+/// soon as the file that includes them is parsed; the emission only blocks when it reaches a file
+/// that has not finished loading yet, see [Parsed::wait_for].
+struct Parsed {
+    nodes: NodeArena,
+    loader: RefCell<Loader>,
+    root_path: PathBuf,
+    root_id: FileId,
+    /// The `#include MACRO` directives that were reached during emission, with the path of the
+    /// file that contains them.
+    macro_includes: RefCell<Vec<(PathBuf, IncludeEdge)>>,
+    search_paths: Arc<SearchPaths>,
+    file_provider: Arc<dyn FileProvider>,
+}
+
+impl Parsed {
+    /// Loads and parses the entry point, and starts loading the files it includes.
+    ///
+    /// If errors are collected, an entry point that fails to load is added to `errors`, in which
+    /// case nothing is emitted.
+    ///
+    /// The files are loaded as configured by [Options::concurrency].
+    fn try_init<P>(
+        entry_point: P,
+        search_paths: SearchPaths,
+        options: &Options,
+        errors: &mut Vec<Error>,
+    ) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let file_provider = options.file_provider.clone();
+        let entry_path = search_paths.normalize(entry_point.as_ref(), file_provider.as_ref())?;
+        let mut file_ids = FileIds::default();
+        let (root_id, _) = file_ids.insert(&entry_path);
+        // Files resolved through a custom resolver, or whose resolution is traced, are not cached.
+        let cache = options
+            .cache
+            .clone()
+            .filter(|_| search_paths.resolver().is_none() && search_paths.tracer().is_none())
+            .map(|cache| {
+                let configuration = configuration_key(&search_paths, options);
+
+                (cache, configuration)
+            });
+        let search_paths = Arc::new(search_paths);
+        #[cfg(any(feature = "threadpool", feature = "rayon"))]
+        let (sender, receiver) = mpsc::channel();
+        let executor = match &options.concurrency {
+            #[cfg(feature = "threadpool")]
+            Concurrency::NewPool => Executor::Pool {
+                pool: ThreadPool::new(num_cpus::get()),
+                sender,
+            },
+            #[cfg(feature = "threadpool")]
+            Concurrency::Pool(pool) => Executor::Pool {
+                pool: pool.clone(),
+                sender,
+            },
+            #[cfg(feature = "rayon")]
+            Concurrency::Rayon => Executor::Rayon { sender },
+            Concurrency::SingleThreaded => Executor::Inline(Arc::default()),
+        };
+        let context = LoadContext {
+            executor,
+            search_paths: search_paths.clone(),
+            file_provider: file_provider.clone(),
+            settings: ParseSettings::new(options),
+            cache,
+            file_ids: Arc::new(Mutex::new(file_ids)),
+            pending: Arc::new(AtomicUsize::new(0)),
+            stopped: Arc::new(AtomicBool::new(false)),
+            cancellation: options.cancellation.clone(),
+            progress: options.progress.clone(),
+            discovered: Arc::new(AtomicUsize::new(0)),
+            loaded: Arc::new(AtomicUsize::new(0)),
+        };
+
+        if options.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+
+        context.report_progress(LoadEvent::Discovered, &entry_path);
+
+        let root = context.parse(entry_path.clone(), None, None);
+
+        context.report_progress(LoadEvent::Loaded, &entry_path);
+
+        let nodes = NodeArena::new();
+        let mut states = HashMap::new();
+
+        match root {
+            Ok(mut node) => {
+                errors.append(&mut node.errors);
+                context.start_includes(&node);
+                states.insert(root_id, LoadState::Loaded(nodes.push(node)));
+            }
+            Err(err) if options.collect_errors => {
+                errors.push(err);
+                states.insert(root_id, LoadState::Failed);
+            }
+            Err(err) => return Err(err),
+        }
+
+        Ok(Parsed {
+            nodes,
+            loader: RefCell::new(Loader {
+                context,
+                #[cfg(any(feature = "threadpool", feature = "rayon"))]
+                receiver,
+                states,
+                cancelled: false,
+                errors: Vec::new(),
+                first_error: None,
+                wait_time: Duration::ZERO,
+            }),
+            root_path: entry_path,
+            root_id,
+            macro_includes: RefCell::new(Vec::new()),
+            search_paths,
+            file_provider,
+        })
+    }
+
+    /// Returns the id and the node of the file at `path`, blocking until it has finished loading;
+    /// `None` if it failed to load and errors are collected.
+    ///
+    /// If errors are not collected and any file failed to load, this waits for the files that
+    /// are still being loaded and returns the first error.
+    fn wait_for(&self, path: &Path) -> Result<Option<(FileId, &ParsedNode)>, Error> {
+        let mut loader = self.loader.borrow_mut();
+        let file_id = |loader: &Loader| loader.context.file_ids.lock().unwrap().get(path);
+        let mut id = file_id(&loader);
+
+        while !id.is_some_and(|id| loader.states.contains_key(&id)) && loader.pending() > 0 {
+            loader.receive(&self.nodes);
+
+            if loader.first_error.is_some() {
+                break;
+            }
+
+            // The file may be discovered by a file that finished loading in the meantime.
+            id = id.or_else(|| file_id(&loader));
+        }
+
+        if let Some(err) = loader.first_error.take() {
+            loader.finish(&self.nodes);
+
+            return Err(err);
+        }
+
+        drop(loader);
+
+        Ok(id.and_then(|id| self.get(id).map(|node| (id, node))))
+    }
+
+    /// Like [wait_for](Parsed::wait_for), but first starts loading the file at `path` if it has
+    /// not started loading yet, as for a file included through an `#include MACRO` directive.
+    fn load(
+        &self,
+        path: PathBuf,
+        search_path_index: Option<usize>,
+        included_from: (PathBuf, usize),
+    ) -> Result<Option<(FileId, &ParsedNode)>, Error> {
+        self.loader
+            .borrow()
+            .context
+            .start(path.clone(), search_path_index, Some(included_from));
+
+        self.wait_for(&path)
+    }
+
+    /// Blocks until all files have finished loading, and adds the errors of the files that failed
+    /// to load to `errors`; if errors are not collected, returns the first error instead.
+    fn finish_loading(&self, errors: &mut Vec<Error>) -> Result<(), Error> {
+        let mut loader = self.loader.borrow_mut();
+
+        loader.finish(&self.nodes);
+        errors.append(&mut loader.errors);
+
+        match loader.first_error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Loads all files, including those included through `#include MACRO` directives, see
+    /// [preprocess_include_graph].
+    fn load_all(&self, options: &Options, errors: &mut Vec<Error>) -> Result<(), Error> {
+        self.finish_loading(errors)?;
+
+        let has_macro_includes = self.nodes.iter().any(|node| {
+            node.chunks()
+                .any(|chunk| matches!(chunk, NodeChunk::IncludeMacro { .. }))
+        });
+
+        if has_macro_includes {
+            self.write(
+                &mut (),
+                &mut Discard,
+                &mut Discard,
+                &mut Vec::new(),
+                options,
+                errors,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the [IncludeGraph] of the files that were loaded.
+    fn include_graph(&self) -> IncludeGraph {
+        let mut includes: BTreeMap<PathBuf, Vec<IncludeEdge>> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let edges = node
+                    .chunks()
+                    .filter_map(|chunk| match chunk {
+                        NodeChunk::Include {
+                            path,
+                            include_path,
+                            line_number,
+                            ..
+                        } => Some(IncludeEdge {
+                            line_number,
+                            include_path: include_path.clone(),
+                            included_file: path.to_path_buf(),
+                        }),
+                        _ => None,
+                    })
+                    .collect();
+
+                (node.path().to_path_buf(), edges)
+            })
+            .collect();
+
+        // A file that is included more than once reaches its `#include MACRO` directives again.
+        for (path, edge) in self.macro_includes.borrow().iter() {
+            if let Some(edges) = includes.get_mut(path) {
+                if !edges.contains(edge) {
+                    edges.push(edge.clone());
+                }
+            }
+        }
+
+        for edges in includes.values_mut() {
+            edges.sort_by_key(|edge| edge.line_number);
+        }
+
+        IncludeGraph {
+            entry_point: self.root_path.clone(),
+            includes,
+        }
+    }
+
+    /// Returns a [ParsedTree] view of the files that were loaded.
+    fn parse_tree(&self) -> ParsedTree {
+        let files = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let chunks = node
+                    .chunks()
+                    .filter_map(|chunk| match chunk {
+                        NodeChunk::Text(text) => Some(Chunk::Text {
+                            range: text.byte_range(),
+                        }),
+                        NodeChunk::Include {
+                            path, line_number, ..
+                        } => Some(Chunk::Include {
+                            resolved: path.to_path_buf(),
+                            directive_line: line_number + 1,
+                        }),
+                        _ => None,
+                    })
+                    .collect();
+                let file = ParsedFile {
+                    path: node.path().to_path_buf(),
+                    source: node.source().to_string(),
+                    once: node.once(),
+                    chunks,
+                };
+
+                (file.path.clone(), file)
+            })
+            .collect();
+
+        ParsedTree {
+            entry_point: self.root_path.clone(),
+            files,
+        }
+    }
+
+    /// Returns the node of the file with the `id` if it has finished loading, without blocking.
+    fn get(&self, id: FileId) -> Option<&ParsedNode> {
+        match self.loader.borrow().states.get(&id) {
+            Some(LoadState::Loaded(index)) => self.nodes.get(*index),
+            _ => None,
+        }
+    }
+
+    /// The file and (one-based) line number of the include directive of every `(id, chunk)`
+    /// entry of the emission stack.
+    fn include_chain(&self, stack: &[(FileId, usize)]) -> Vec<(PathBuf, usize)> {
+        stack
+            .iter()
+            .map(|&(id, chunk)| {
+                let parent = self.get(id).unwrap();
+                let line_number = match parent.get_chunk(chunk) {
+                    Some(
+                        NodeChunk::Include { line_number, .. }
+                        | NodeChunk::IncludeMacro { line_number, .. },
+                    ) => line_number,
+                    _ => unreachable!(),
+                };
+
+                (parent.path().to_path_buf(), line_number + 1)
+            })
+            .collect()
+    }
+
+    /// Resolves the file that the `#include MACRO` directive in `node` includes, by expanding the
+    /// macro `name` at the directive's line (see [Builtins::line_number]).
+    ///
+    /// If no file is found, the paths that were looked at are passed on to `source_tracker`.
+    fn resolve_include_macro<T>(
+        &self,
+        name: &str,
+        node: &ParsedNode,
+        macros: &MacroTable,
+        builtins: Builtins,
+        stack: &[(FileId, usize)],
+        source_tracker: &mut T,
+    ) -> Result<(ResolvedInclude, IncludePathBuf, IncludeWarnings), Error>
+    where
+        T: SourceTracker,
+    {
+        let line_number = builtins.line_number;
+        let expanded = expand_include_macro(name, macros, builtins)
+            .map_err(|message| node.parse_error(message, line_number))?;
+        let include_path = parse_include_path(&expanded).ok_or_else(|| {
+            let message = format!("macro `{}` does not expand to an include path", name);
+
+            node.parse_error(message, line_number)
+        })?;
+
+        let resolved = try_resolve_include_path(
+            &include_path,
+            node.path(),
+            line_number,
+            &self.search_paths,
+            self.file_provider.as_ref(),
+            None,
+        )
+        .map_err(|err| err.into_error(node.path(), node.source(), line_number))?;
+
+        let resolved = resolved.ok_or_else(|| {
+            let candidates = missing_candidates(
+                &include_path,
+                node.path(),
+                &self.search_paths,
+                self.file_provider.as_ref(),
+                None,
+            );
+
+            for candidate in candidates {
+                source_tracker.track_missing(&candidate);
+            }
+
+            FileNotFoundError::new(
+                include_path.path(),
+                node.path(),
+                node.source(),
+                line_number,
+                self.include_chain(stack),
+            )
+        })?;
+
+        let warnings = resolved.warnings(&include_path);
+
+        Ok((resolved, IncludePathBuf::new(&include_path), warnings))
+    }
+
+    fn write<S, T, H>(
+        &self,
+        output_sink: &mut S,
+        source_tracker: &mut T,
+        diagnostic_handler: &mut H,
+        file_ids: &mut Vec<PathBuf>,
+        options: &Options,
+        errors: &mut Vec<Error>,
+    ) -> Result<MacroTable, Error>
+    where
+        S: OutputSink,
+        T: SourceTracker,
+        H: DiagnosticHandler,
+    {
+        let mut macros = initial_macros(options);
+        let mut emit_errors = Vec::new();
+        let emitted = self.emit(
+            EmitTargets {
+                output_sink,
+                source_tracker: &mut *source_tracker,
+                diagnostic_handler,
+                file_ids,
+            },
+            &mut macros,
+            options,
+            &mut emit_errors,
+        );
+
+        // The errors of files that fail to load come first, as if all files were loaded before
+        // the output was emitted.
+        let loaded = self.finish_loading(errors);
+
+        errors.append(&mut emit_errors);
+
+        // The files that were loaded are tracked even if preprocessing fails, so that a change
+        // to them (e.g. fixing an error) is picked up.
+        let graph = self.include_graph();
+
+        for node in self.nodes.iter() {
+            source_tracker.track(node.path(), node.source());
+
+            for edge in graph.includes_of(node.path()) {
+                source_tracker.track_include(
+                    node.path(),
+                    edge.included_file(),
+                    edge.line_number(),
+                    edge.include_path().kind(),
+                );
+            }
+        }
+
+        loaded?;
+        emitted?;
+
+        Ok(macros)
+    }
+
+    /// Emits the output, starting at the root node, while the files it includes are loaded.
+    ///
+    /// Recoverable errors are added to `errors` if [Options::collect_errors] is enabled, and the
+    /// files are added to `file_ids` if [Options::line_markers] is enabled.
+    fn emit<S, T, H>(
+        &self,
+        targets: EmitTargets<S, T, H>,
+        macros: &mut MacroTable,
+        options: &Options,
+        errors: &mut Vec<Error>,
+    ) -> Result<(), Error>
+    where
+        S: OutputSink,
+        T: SourceTracker,
+        H: DiagnosticHandler,
+    {
+        let EmitTargets {
+            output_sink,
+            source_tracker,
+            diagnostic_handler,
+            file_ids,
+        } = targets;
+        let output_sink = &mut EmitSink::new(output_sink, options);
+        let mut stack = Vec::new();
+        let mut seen = HashSet::new();
+
+        let root_node = if let Some(root_node) = self.get(self.root_id) {
+            root_node
+        } else {
+            // The entry point failed to load, which was collected as an error.
+            return Ok(());
+        };
+
+        if root_node.once() {
+            seen.insert(root_node.once_key(self.root_id, options));
+        }
+
+        let mut current_id = self.root_id;
+        let mut current_node = root_node;
+        let mut current_chunk = 0;
+
+        let root_builtins = Builtins {
+            file: root_node.path(),
+            base_file: root_node.path(),
+            file_name_only: options.file_macro_name_only,
+            include_depth: 0,
+            line_number: 0,
+        };
+
+        // The `#line` mapping that is in effect for the current file, and whether any `#line`
+        // directive has been emitted. Only used in `LineDirectiveMode::Rewrite`.
+        let mut line_mapping: Option<LineMapping> = None;
+        let mut line_mapping_stack = Vec::new();
+        let mut remapped = false;
+
+        let mut line_markers = if options.line_markers {
+            Some(LineMarkers::new(file_ids, root_node.path()))
+        } else {
+            None
+        };
+
+        if !options.prologue.is_empty() {
+            output_sink.sink(&options.prologue).map_err(Error::sink)?;
+
+            if !output_sink.at_line_start() {
+                output_sink.sink("\n").map_err(Error::sink)?;
+            }
+
+            if let Some(line_markers) = &mut line_markers {
+                line_markers.reset();
+            }
+        }
+
+        loop {
+            if options.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+
+            if let Some(chunk) = current_node.get_chunk(current_chunk) {
+                // The file that an include directive resolved to, with the directive's line number.
+                let mut included = None;
+
+                match chunk {
+                    NodeChunk::Text(chunk) => {
+                        if options.strict_directives {
+                            let unknown =
+                                find_unknown_directive(chunk.text(), &options.allowed_directives);
+
+                            if let Some((line_offset, line)) = unknown {
+                                return Err(current_node
+                                    .parse_error(
+                                        format!("unknown directive `{}`", line),
+                                        chunk.line_number() + line_offset,
+                                    )
+                                    .into());
+                            }
+                        }
+
+                        let offset = chunk.byte_range().start;
+                        let builtins = Builtins {
+                            file: current_node.path(),
+                            include_depth: stack.len(),
+                            line_number: chunk.line_number(),
+                            ..root_builtins
+                        };
+
+                        if let Some(line_markers) = &mut line_markers {
+                            line_markers.mark(
+                                output_sink,
+                                current_node.path(),
+                                chunk.line_number(),
+                            )?;
+                        }
+
+                        // Expansion continues after the sink fails, but nothing more is sunk.
+                        let mut sunk = Ok(());
+                        let mut output_lines = 0;
+
+                        // A byte offset into the chunk's text and the (zero-based) line number at
+                        // that offset, so that lines are not counted from the start of the chunk
+                        // for every expanded piece.
+                        let mut line_cursor = (0, chunk.line_number());
+
+                        macros
+                            .expand(chunk.text(), builtins, |text, range| {
+                                output_lines += text.matches('\n').count();
+
+                                if range.start < line_cursor.0 {
+                                    line_cursor = (0, chunk.line_number());
+                                }
+
+                                line_cursor.1 += line_number_at(
+                                    &chunk.text()[line_cursor.0..],
+                                    range.start - line_cursor.0,
+                                );
+                                line_cursor.0 = range.start;
+
+                                if sunk.is_ok() {
+                                    sunk = output_sink.sink_source_mapped(SourceMappedChunk {
+                                        text,
+                                        source_path: current_node.path(),
+                                        source: current_node.source(),
+                                        source_range: offset + range.start..offset + range.end,
+                                        line_range: line_span(
+                                            &chunk.text()[range.clone()],
+                                            line_cursor.1,
+                                        ),
+                                    });
+                                }
+                            })
+                            .map_err(|err| {
+                                let line_number =
+                                    chunk.line_number() + line_number_at(chunk.text(), err.offset);
+
+                                current_node.parse_error(err.to_string(), line_number)
+                            })?;
+
+                        sunk.map_err(Error::sink)?;
+
+                        if let Some(line_markers) = &mut line_markers {
+                            line_markers.advance(chunk.text().matches('\n').count(), output_lines);
+                        }
+
+                        current_chunk += 1;
+                    }
+                    NodeChunk::Define(define) => {
+                        let defined = macros.define_at(
+                            define.name,
+                            define.definition.clone(),
+                            current_node.path(),
+                            define.line_number,
+                        );
+
+                        if !defined {
+                            let message =
+                                format!("macro `{}` redefined with a different value", define.name);
+
+                            return Err(current_node
+                                .parse_error(message, define.line_number)
+                                .into());
+                        }
+
+                        let is_guard = current_node.include_guard() == Some(current_chunk);
+
+                        if options.keep_define_directives
+                            && !(is_guard && options.strip_include_guards)
+                        {
+                            if let Some(line_markers) = &mut line_markers {
+                                line_markers.mark(
+                                    output_sink,
+                                    current_node.path(),
+                                    define.line_number,
+                                )?;
+                                line_markers.advance(1, 1);
+                            }
+
+                            output_sink
+                                .sink_source_mapped(SourceMappedChunk {
+                                    text: define.line.text(),
+                                    source_path: current_node.path(),
+                                    source: current_node.source(),
+                                    source_range: define.line.byte_range(),
+                                    line_range: line_span(define.line.text(), define.line_number),
+                                })
+                                .map_err(Error::sink)?;
+                        } else {
+                            sink_blank_directive(
+                                output_sink,
+                                &mut line_markers,
+                                DirectiveKind::Define,
+                                current_node.path(),
+                                define.line_number,
+                                options,
+                            )?;
+                        }
+
+                        current_chunk += 1;
+                    }
+                    NodeChunk::Undef { name, line_number } => {
+                        // Undefining a name that is not defined is not an error
+                        macros.undefine(name);
+
+                        sink_blank_directive(
+                            output_sink,
+                            &mut line_markers,
+                            DirectiveKind::Define,
+                            current_node.path(),
+                            line_number,
+                            options,
+                        )?;
+
+                        current_chunk += 1;
+                    }
+                    NodeChunk::Conditional {
+                        condition,
+                        next,
+                        line_number,
+                    } => {
+                        let builtins = Builtins {
+                            file: current_node.path(),
+                            include_depth: stack.len(),
+                            line_number,
+                            ..root_builtins
+                        };
+
+                        let holds = condition
+                            .holds(macros, builtins)
+                            .map_err(|message| current_node.parse_error(message, line_number))?;
+
+                        sink_blank_directive(
+                            output_sink,
+                            &mut line_markers,
+                            DirectiveKind::Conditional,
+                            current_node.path(),
+                            line_number,
+                            options,
+                        )?;
+
+                        current_chunk = if holds {
+                            current_chunk + 1
+                        } else {
+                            select_branch(current_node, next, macros, builtins)?
+                        };
+                    }
+                    NodeChunk::Elif {
+                        end, line_number, ..
+                    }
+                    | NodeChunk::Else { end, line_number } => {
+                        sink_blank_directive(
+                            output_sink,
+                            &mut line_markers,
+                            DirectiveKind::Conditional,
+                            current_node.path(),
+                            line_number,
+                            options,
+                        )?;
+
+                        // Reached the end of the branch that was selected, skip the remaining
+                        // branches of the group.
+                        current_chunk = end;
+                    }
+                    NodeChunk::EndIf { line_number } => {
+                        sink_blank_directive(
+                            output_sink,
+                            &mut line_markers,
+                            DirectiveKind::Conditional,
+                            current_node.path(),
+                            line_number,
+                            options,
+                        )?;
+
+                        current_chunk += 1;
+                    }
+                    NodeChunk::PragmaOnce { line_number } => {
+                        sink_blank_directive(
+                            output_sink,
+                            &mut line_markers,
+                            DirectiveKind::Pragma,
+                            current_node.path(),
+                            line_number,
+                            options,
+                        )?;
+
+                        current_chunk += 1;
+                    }
+                    NodeChunk::Warning {
+                        message,
+                        line_number,
+                    } => {
+                        report(
+                            Diagnostic::new(
+                                Severity::Warning,
+                                message,
+                                current_node.path(),
+                                line_number,
+                            ),
+                            source_tracker,
+                            diagnostic_handler,
+                        );
+
+                        sink_blank_directive(
+                            output_sink,
+                            &mut line_markers,
+                            DirectiveKind::Warning,
+                            current_node.path(),
+                            line_number,
+                            options,
+                        )?;
+
+                        current_chunk += 1;
+                    }
+                    NodeChunk::Message {
+                        message,
+                        line_number,
+                    } => {
+                        report(
+                            Diagnostic::new(
+                                Severity::Note,
+                                message,
+                                current_node.path(),
+                                line_number,
+                            ),
+                            source_tracker,
+                            diagnostic_handler,
+                        );
+
+                        sink_blank_directive(
+                            output_sink,
+                            &mut line_markers,
+                            DirectiveKind::Pragma,
+                            current_node.path(),
+                            line_number,
+                            options,
+                        )?;
+
+                        current_chunk += 1;
+                    }
+                    NodeChunk::Custom {
+                        name,
+                        argument,
+                        line_number,
+                    } => {
+                        let directive = CustomDirective {
+                            name,
+                            argument,
+                            source_file: current_node.path(),
+                            line_number: line_number + 1,
+                        };
+                        // Custom directives are only parsed if there is a handler.
+                        let action = match &options.directive_handler {
+                            Some(handler) => handler.handle(&directive),
+                            None => Ok(DirectiveAction::Drop),
+                        };
+
+                        match action
+                            .map_err(|message| current_node.parse_error(message, line_number))?
+                        {
+                            DirectiveAction::Drop => {
+                                sink_blank_directive(
+                                    output_sink,
+                                    &mut line_markers,
+                                    DirectiveKind::Custom,
+                                    current_node.path(),
+                                    line_number,
+                                    options,
+                                )?;
+                            }
+                            DirectiveAction::Replace(mut text) => {
+                                if !text.is_empty() && !text.ends_with('\n') {
+                                    text.push('\n');
+                                }
+
+                                if let Some(line_markers) = &mut line_markers {
+                                    line_markers.mark(
+                                        output_sink,
+                                        current_node.path(),
+                                        line_number,
+                                    )?;
+                                    line_markers.advance(1, text.matches('\n').count());
+                                }
+
+                                output_sink.sink(&text).map_err(Error::sink)?;
+                            }
+                        }
+
+                        current_chunk += 1;
+                    }
+                    NodeChunk::Diagnostic {
+                        severity,
+                        message,
+                        line_number,
+                    } => {
+                        diagnostic_handler.handle(Diagnostic::new(
+                            severity,
+                            message,
+                            current_node.path(),
+                            line_number,
+                        ));
+
+                        current_chunk += 1;
+                    }
+                    NodeChunk::Error {
+                        message,
+                        line_number,
+                    } => {
+                        return Err(DirectiveError {
+                            message: message.to_string(),
+                            source_file: current_node.path().to_path_buf(),
+                            source: current_node.source().to_string(),
+                            line_number,
+                            display_root: None,
+                        }
+                        .into());
+                    }
+                    NodeChunk::MissingInclude {
+                        included_path,
+                        optional: true,
+                        line_number,
+                        candidates,
+                    } => {
+                        for candidate in candidates {
+                            source_tracker.track_missing(candidate);
+                        }
+
+                        let message = format!(
+                            "optional include `{}` was not found",
+                            included_path.display()
+                        );
+
+                        report(
+                            Diagnostic::new(
+                                Severity::Note,
+                                message,
+                                current_node.path(),
+                                line_number,
+                            ),
+                            source_tracker,
+                            diagnostic_handler,
+                        );
+
+                        sink_blank_directive(
+                            output_sink,
+                            &mut line_markers,
+                            DirectiveKind::Include,
+                            current_node.path(),
+                            line_number,
+                            options,
+                        )?;
+
+                        current_chunk += 1;
+                    }
+                    NodeChunk::MissingInclude {
+                        included_path,
+                        line_number,
+                        candidates,
+                        ..
+                    } => {
+                        for candidate in candidates {
+                            source_tracker.track_missing(candidate);
+                        }
+
+                        let err = FileNotFoundError::new(
+                            included_path,
+                            current_node.path(),
+                            current_node.source(),
+                            line_number,
+                            self.include_chain(&stack),
+                        );
+
+                        if options.recover_includes {
+                            // The marker takes the place of the include directive's line.
+                            if let Some(line_markers) = &mut line_markers {
+                                line_markers.mark(output_sink, current_node.path(), line_number)?;
+                                line_markers.advance(1, 1);
+                            }
+
+                            recover_include(&err, output_sink, diagnostic_handler)?;
+                        } else if options.collect_errors {
+                            errors.push(err.into());
+                        } else {
+                            return Err(err.into());
+                        }
+
+                        current_chunk += 1;
+                    }
+                    NodeChunk::Line(line) => {
+                        let removed = options.line_markers
+                            || options.line_directives == LineDirectiveMode::Strip;
+
+                        if removed {
+                            sink_blank_directive(
+                                output_sink,
+                                &mut line_markers,
+                                DirectiveKind::Line,
+                                current_node.path(),
+                                line.line_number,
+                                options,
+                            )?;
+                        }
+
+                        match options.line_directives {
+                            _ if options.line_markers => (),
+                            LineDirectiveMode::Strip => (),
+                            LineDirectiveMode::PassThrough => {
+                                output_sink
+                                    .sink_source_mapped(SourceMappedChunk {
+                                        text: line.line.text(),
+                                        source_path: current_node.path(),
+                                        source: current_node.source(),
+                                        source_range: line.line.byte_range(),
+                                        line_range: line_span(line.line.text(), line.line_number),
+                                    })
+                                    .map_err(Error::sink)?;
+                            }
+                            LineDirectiveMode::Rewrite => {
+                                // A `#line` directive without a file name keeps the file name
+                                // set by a previous directive.
+                                let file = line
+                                    .file
+                                    .or_else(|| line_mapping.and_then(|mapping| mapping.file));
+
+                                sink_line_directive(output_sink, line.number, file)?;
+
+                                line_mapping = Some(LineMapping {
+                                    line_number: line.line_number,
+                                    number: line.number,
+                                    file,
+                                });
+                                remapped = true;
+                            }
+                        }
+
+                        current_chunk += 1;
+                    }
+                    NodeChunk::Include {
+                        path, line_number, ..
+                    } => {
+                        // Only a file that failed to load (when errors are collected) is missing.
+                        match self.wait_for(path)? {
+                            Some((id, node)) => included = Some((id, node, line_number)),
+                            None => current_chunk += 1,
+                        }
+                    }
+                    NodeChunk::IncludeMacro { name, line_number } => {
+                        let builtins = Builtins {
+                            file: current_node.path(),
+                            include_depth: stack.len(),
+                            line_number,
+                            ..root_builtins
+                        };
+                        let resolved = self.resolve_include_macro(
+                            name,
+                            current_node,
+                            macros,
+                            builtins,
+                            &stack,
+                            source_tracker,
+                        );
+
+                        let (resolved, include_path, warnings) = match resolved {
+                            Ok(resolved) => resolved,
+                            Err(Error::FileNotFound(err)) if options.recover_includes => {
+                                if let Some(line_markers) = &mut line_markers {
+                                    line_markers.mark(
+                                        output_sink,
+                                        current_node.path(),
+                                        line_number,
+                                    )?;
+                                    line_markers.advance(1, 1);
+                                }
+
+                                recover_include(&err, output_sink, diagnostic_handler)?;
+
+                                current_chunk += 1;
+
+                                continue;
+                            }
+                            Err(err) if options.collect_errors => {
+                                errors.push(err);
+
+                                current_chunk += 1;
+
+                                continue;
+                            }
+                            Err(err) => return Err(err),
+                        };
+
+                        let included_from = (current_node.path().to_path_buf(), line_number);
+                        let edge = IncludeEdge {
+                            line_number,
+                            include_path,
+                            included_file: resolved.path.clone(),
+                        };
+                        let node =
+                            self.load(resolved.path, resolved.search_path_index, included_from)?;
+
+                        if let Some((id, node)) = node {
+                            self.macro_includes
+                                .borrow_mut()
+                                .push((current_node.path().to_path_buf(), edge));
+
+                            if let Some(message) = warnings.case_mismatch {
+                                report(
+                                    Diagnostic::new(
+                                        Severity::Warning,
+                                        message,
+                                        current_node.path(),
+                                        line_number,
+                                    ),
+                                    source_tracker,
+                                    diagnostic_handler,
+                                );
+                            }
+
+                            if let Some(message) = warnings.shadowing {
+                                report(
+                                    Diagnostic::new(
+                                        Severity::Warning,
+                                        message,
+                                        current_node.path(),
+                                        line_number,
+                                    ),
+                                    source_tracker,
+                                    diagnostic_handler,
+                                );
+                            }
+
+                            included = Some((id, node, line_number));
+                        } else {
+                            // The file failed to load, which was collected as an error.
+                            current_chunk += 1;
+                        }
+                    }
+                }
+
+                if let Some((id, node, line_number)) = included {
+                    if node.once() && seen.contains(&node.once_key(id, options)) {
+                        output_sink
+                            .skip_include(node.path(), current_node.path(), line_number + 1)
+                            .map_err(Error::sink)?;
+
+                        if let Some(markers) = &options.include_markers {
+                            // The marker takes the place of the directive's line.
+                            if let Some(line_markers) = &mut line_markers {
+                                line_markers.mark(output_sink, current_node.path(), line_number)?;
+                                line_markers.advance(1, 1);
+                            }
+
+                            sink_include_marker(
+                                output_sink,
+                                &markers.skipped,
+                                node.path(),
+                                stack.len() + 1,
+                                options,
+                            )?;
+                        } else {
+                            sink_blank_directive(
+                                output_sink,
+                                &mut line_markers,
+                                DirectiveKind::Include,
+                                current_node.path(),
+                                line_number,
+                                options,
+                            )?;
+                        }
+
+                        current_chunk += 1;
+                    } else {
+                        // Re-entering a file that is still being emitted would never end.
+                        let in_stack =
+                            id == current_id || stack.iter().any(|(parent, _)| *parent == id);
+
+                        if in_stack {
+                            let mut chain = stack.clone();
+
+                            chain.push((current_id, current_chunk));
+
+                            let start = chain.iter().position(|(parent, _)| *parent == id).unwrap();
+                            let includes = self.include_chain(&chain[start..]);
+
+                            return Err(IncludeCycleError {
+                                includes,
+                                display_root: None,
+                            }
+                            .into());
+                        }
+
+                        if let Some(max) = options.max_depth.filter(|max| stack.len() >= *max) {
+                            return Err(current_node
+                                .parse_error(
+                                    format!("maximum include depth of {} exceeded", max),
+                                    line_number,
+                                )
+                                .into());
+                        }
+
+                        seen.insert(node.once_key(id, options));
+
+                        stack.push((current_id, current_chunk));
+                        line_mapping_stack.push(line_mapping.take());
+
+                        output_sink
+                            .enter_include(node.path(), current_node.path(), line_number + 1)
+                            .map_err(Error::sink)?;
+
+                        if let Some(markers) = &options.include_markers {
+                            sink_include_marker(
+                                output_sink,
+                                &markers.begin,
+                                node.path(),
+                                stack.len(),
+                                options,
+                            )?;
+
+                            if let Some(line_markers) = &mut line_markers {
+                                line_markers.reset();
+                            }
+                        }
+
+                        let indent = if options.indent_includes {
+                            let source = current_node.source();
+                            let line = &source[line_offset(source, line_number)..];
+
+                            &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+                        } else {
+                            ""
+                        };
+
+                        output_sink.push_indent(indent);
+
+                        if remapped {
+                            sink_line_directive(
+                                output_sink,
+                                1,
+                                Some(&format!("{}", node.path().display())),
+                            )?;
+                        }
+
+                        current_id = id;
+                        current_node = node;
+                        current_chunk = 0;
+                    }
+                }
+            } else {
+                if let Some((parent_id, child_chunk)) = stack.pop() {
+                    output_sink.pop_indent();
+
+                    let terminate = match options.include_newline {
+                        _ if options.blank_directives.contains(&DirectiveKind::Include) => {
+                            !output_sink.at_line_start()
+                        }
+                        IncludeNewline::Always => true,
+                        IncludeNewline::IfMissing => !output_sink.at_line_start(),
+                        IncludeNewline::Never => false,
+                    };
+
+                    if terminate {
+                        output_sink.sink("\n").map_err(Error::sink)?;
+                    }
+
+                    if let Some(markers) = &options.include_markers {
+                        sink_include_marker(
+                            output_sink,
+                            &markers.end,
+                            current_node.path(),
+                            stack.len() + 1,
+                            options,
+                        )?;
+                    }
+
+                    output_sink
+                        .exit_include(current_node.path())
+                        .map_err(Error::sink)?;
+
+                    if options.blank_directives.contains(&DirectiveKind::Include) {
+                        output_sink.sink("\n").map_err(Error::sink)?;
+                    }
+
+                    if let Some(line_markers) = &mut line_markers {
+                        line_markers.reset();
+                    }
+
+                    current_id = parent_id;
+                    current_node = self.get(parent_id).unwrap();
+                    current_chunk = child_chunk + 1;
+                    line_mapping = line_mapping_stack.pop().unwrap();
+
+                    if remapped {
+                        if let Some(
+                            NodeChunk::Include { line_number, .. }
+                            | NodeChunk::IncludeMacro { line_number, .. },
+                        ) = current_node.get_chunk(child_chunk)
+                        {
+                            // Restore the numbering for the line that follows the include.
+                            let parent_path = format!("{}", current_node.path().display());
+                            let (number, file) = match line_mapping {
+                                Some(mapping) => (
+                                    mapping.map(line_number + 1),
+                                    mapping.file.unwrap_or(&parent_path),
+                                ),
+                                None => (line_number + 2, parent_path.as_str()),
+                            };
+
+                            sink_line_directive(output_sink, number, Some(file))?;
+                        }
+                    }
+                } else {
+                    break;
+                }
+            }
+        }
+
+        if !options.epilogue.is_empty() {
+            if !output_sink.at_line_start() {
+                output_sink.sink("\n").map_err(Error::sink)?;
+            }
+
+            output_sink.sink(&options.epilogue).map_err(Error::sink)?;
+        }
+
+        output_sink.finish().map_err(Error::sink)?;
+
+        Ok(())
+    }
+}
+
+/// An [OutputSink] that forwards to another sink, while converting the line endings (see
+/// [Options::line_endings]) and tracking whether the output ends with a newline (or is empty).
+///
+/// If [Options::ensure_trailing_newline] is enabled, trailing line endings are held back as
+/// `pending` until more output follows, or replaced by a single line ending when finished. If
+/// [Options::strip_comments] is not empty, comments are stripped from source mapped chunks first,
+/// after which blank lines are collapsed if [Options::max_blank_lines] is set.
+struct EmitSink<'a, S> {
+    output_sink: &'a mut S,
+    comment_stripper: Option<CommentStripper>,
+    blank_line_filter: Option<BlankLineFilter>,
+    // The file of the last source mapped chunk, as comments do not continue into another file.
+    comment_path: PathBuf,
+    // The indentation of the current included file, and the lengths it had at the includes that
+    // lead to it.
+    indent: String,
+    indent_lengths: Vec<usize>,
+    line_endings: LineEndings,
+    at_line_start: bool,
+    after_carriage_return: bool,
+    ensure_trailing_newline: bool,
+    pending: String,
+    emitted: bool,
+}
+
+impl<'a, S> EmitSink<'a, S> {
+    fn new(output_sink: &'a mut S, options: &Options) -> Self {
+        EmitSink {
+            output_sink,
+            comment_stripper: CommentStripper::new(options),
+            blank_line_filter: options.max_blank_lines.map(BlankLineFilter::new),
+            comment_path: PathBuf::new(),
+            indent: String::new(),
+            indent_lengths: Vec::new(),
+            line_endings: options.line_endings,
+            at_line_start: true,
+            after_carriage_return: false,
+            ensure_trailing_newline: options.ensure_trailing_newline,
+            pending: String::new(),
+            emitted: false,
+        }
+    }
+
+    /// Whether the output ends with a newline (or is empty), including any text that is held
+    /// back.
+    fn at_line_start(&self) -> bool {
+        let holds_slash = match &self.comment_stripper {
+            Some(stripper) => stripper.holds_slash(),
+            None => false,
+        };
+        let holds_whitespace = match &self.blank_line_filter {
+            Some(filter) => filter.holds_whitespace(),
+            None => false,
+        };
+
+        self.at_line_start && !holds_slash && !holds_whitespace
+    }
+
+    /// Adds `indent` to the indentation of the output, when entering an included file, see
+    /// [Options::indent_includes].
+    fn push_indent(&mut self, indent: &str) {
+        self.indent_lengths.push(self.indent.len());
+        self.indent.push_str(indent);
+    }
+
+    /// Restores the indentation of the output, when returning from an included file.
+    fn pop_indent(&mut self) {
+        if let Some(len) = self.indent_lengths.pop() {
+            self.indent.truncate(len);
+        }
+    }
+
+    /// Returns the offsets in `text` at which the indentation is inserted: the start of every
+    /// line that is not blank.
+    fn indent_offsets(&self, text: &str) -> Vec<usize> {
+        if self.indent.is_empty() {
+            return Vec::new();
+        }
+
+        let mut at_line_start = self.at_line_start;
+
+        text.char_indices()
+            .filter_map(|(index, c)| {
+                let is_line_ending = c == '\n' || c == '\r';
+                let offset = (at_line_start && !is_line_ending).then_some(index);
+
+                at_line_start = c == '\n' || (at_line_start && is_line_ending);
+
+                offset
+            })
+            .collect()
+    }
+
+    /// Returns `chunk` with its line endings converted, only allocating if any change.
+    fn convert<'b>(&mut self, chunk: &'b str) -> Cow<'b, str> {
+        let converted = match self.line_endings {
+            LineEndings::Preserve => Cow::Borrowed(chunk),
+            LineEndings::Lf if chunk.contains("\r\n") => Cow::Owned(chunk.replace("\r\n", "\n")),
+            LineEndings::Lf => Cow::Borrowed(chunk),
+            LineEndings::CrLf => {
+                let mut converted = String::new();
+                let mut copied = 0;
+                let mut after_carriage_return = self.after_carriage_return;
+
+                for (index, c) in chunk.char_indices() {
+                    if c == '\n' && !after_carriage_return {
+                        converted.push_str(&chunk[copied..index]);
+                        converted.push('\r');
+                        copied = index;
+                    }
+
+                    after_carriage_return = c == '\r';
+                }
+
+                if converted.is_empty() {
+                    Cow::Borrowed(chunk)
+                } else {
+                    converted.push_str(&chunk[copied..]);
+
+                    Cow::Owned(converted)
+                }
+            }
+        };
+
+        if !converted.is_empty() {
+            self.at_line_start = converted.ends_with('\n');
+            self.after_carriage_return = converted.ends_with('\r');
+        }
+
+        converted
+    }
+}
+
+impl<S> EmitSink<'_, S>
+where
+    S: OutputSink,
+{
+    /// Splits the trailing line endings off of `chunk` if [Options::ensure_trailing_newline] is
+    /// enabled, returning the remaining text, or `None` if nothing remains.
+    ///
+    /// Any pending line endings are sunk first if text remains, and the trailing line endings of
+    /// `chunk` become pending.
+    fn hold_back<'b>(&mut self, chunk: &'b str) -> Result<Option<&'b str>, S::Error> {
+        if !self.ensure_trailing_newline {
+            return Ok(Some(chunk));
+        }
+
+        let text = chunk.trim_end_matches(['\r', '\n']);
+
+        if text.is_empty() {
+            self.pending.push_str(chunk);
+
+            return Ok(None);
+        }
+
+        if !self.pending.is_empty() {
+            self.output_sink.sink(&self.pending)?;
+            self.pending.clear();
+        }
+
+        self.pending.push_str(&chunk[text.len()..]);
+        self.emitted = true;
+
+        Ok(Some(text))
+    }
+
+    /// Sinks a `/` that the comment stripper held back at the end of the previous chunk, if any.
+    fn sink_slash(&mut self, reset: bool) -> Result<(), S::Error> {
+        let slash = match &mut self.comment_stripper {
+            Some(stripper) if reset => stripper.reset(),
+            Some(stripper) => stripper.take_slash(),
+            None => None,
+        };
+
+        match slash {
+            Some(slash) => self.collapse(slash),
+            None => Ok(()),
+        }
+    }
+
+    /// Sinks the whitespace that the blank line filter held back, if any.
+    fn sink_whitespace(&mut self) -> Result<(), S::Error> {
+        let whitespace = match &mut self.blank_line_filter {
+            Some(filter) => filter.take_whitespace(),
+            None => None,
+        };
+
+        match whitespace {
+            Some(whitespace) => self.forward(&whitespace),
+            None => Ok(()),
+        }
+    }
+
+    fn collapse(&mut self, chunk: &str) -> Result<(), S::Error> {
+        let pieces = match &mut self.blank_line_filter {
+            Some(filter) => filter.filter(chunk),
+            None => return self.forward(chunk),
+        };
+
+        for piece in pieces {
+            match piece {
+                blank_lines::Piece::Text(range) => self.forward(&chunk[range])?,
+                blank_lines::Piece::Whitespace(whitespace) => self.forward(&whitespace)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn collapse_source_mapped(
+        &mut self,
+        source_mapped_chunk: SourceMappedChunk,
+    ) -> Result<(), S::Error> {
+        let pieces = match &mut self.blank_line_filter {
+            Some(filter) => filter.filter(source_mapped_chunk.text),
+            None => return self.forward_source_mapped(source_mapped_chunk),
+        };
+
+        for piece in pieces {
+            match piece {
+                blank_lines::Piece::Text(range) => {
+                    self.forward_source_mapped(source_mapped_chunk.slice(range))?
+                }
+                blank_lines::Piece::Whitespace(whitespace) => self.forward(&whitespace)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn forward(&mut self, chunk: &str) -> Result<(), S::Error> {
+        let offsets = self.indent_offsets(chunk);
+
+        if offsets.is_empty() {
+            return self.forward_text(chunk);
+        }
+
+        let indent = self.indent.clone();
+        let mut start = 0;
+
+        for offset in offsets {
+            self.forward_text(&chunk[start..offset])?;
+            self.forward_text(&indent)?;
+            start = offset;
+        }
+
+        self.forward_text(&chunk[start..])
+    }
+
+    fn forward_source_mapped(
+        &mut self,
+        source_mapped_chunk: SourceMappedChunk,
+    ) -> Result<(), S::Error> {
+        let offsets = self.indent_offsets(source_mapped_chunk.text);
+
+        if offsets.is_empty() {
+            return self.forward_text_source_mapped(source_mapped_chunk);
+        }
+
+        // The indentation is not attributed to the source file.
+        let indent = self.indent.clone();
+        let mut start = 0;
+
+        for offset in offsets {
+            if start < offset {
+                self.forward_text_source_mapped(source_mapped_chunk.slice(start..offset))?;
+            }
+
+            self.forward_text(&indent)?;
+            start = offset;
+        }
+
+        self.forward_text_source_mapped(
+            source_mapped_chunk.slice(start..source_mapped_chunk.text.len()),
+        )
+    }
+
+    fn forward_text(&mut self, chunk: &str) -> Result<(), S::Error> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = self.convert(chunk);
+
+        match self.hold_back(&chunk)? {
+            Some(text) => self.output_sink.sink(text),
+            None => Ok(()),
+        }
+    }
+
+    fn forward_text_source_mapped(
+        &mut self,
+        source_mapped_chunk: SourceMappedChunk,
+    ) -> Result<(), S::Error> {
+        let converted = self.convert(source_mapped_chunk.text);
+        let text = match self.hold_back(&converted)? {
+            Some(text) => text,
+            None => return Ok(()),
+        };
+
+        // The source range still refers to the original text, without the held back line
+        // endings.
+        let SourceMappedChunk {
+            source_path,
+            source,
+            source_range,
+            line_range,
+            ..
+        } = source_mapped_chunk;
+        let (source_range, line_range) = if text.len() < converted.len() {
+            let end = source_range.start
+                + source[source_range.clone()]
+                    .trim_end_matches(['\r', '\n'])
+                    .len();
+
+            (source_range.start..end, line_span(text, line_range.start))
+        } else {
+            (source_range, line_range)
+        };
+
+        self.output_sink.sink_source_mapped(SourceMappedChunk {
+            text,
+            source_path,
+            source,
+            source_range,
+            line_range,
+        })
+    }
+}
+
+impl<S> OutputSink for EmitSink<'_, S>
+where
+    S: OutputSink,
+{
+    type Error = S::Error;
+
+    fn sink(&mut self, chunk: &str) -> Result<(), S::Error> {
+        self.sink_slash(false)?;
+        self.collapse(chunk)
+    }
+
+    fn sink_source_mapped(
+        &mut self,
+        source_mapped_chunk: SourceMappedChunk,
+    ) -> Result<(), S::Error> {
+        if self.comment_stripper.is_none() {
+            return self.collapse_source_mapped(source_mapped_chunk);
+        }
+
+        if source_mapped_chunk.source_path != self.comment_path {
+            self.sink_slash(true)?;
+            self.comment_path = source_mapped_chunk.source_path.to_path_buf();
+        }
+
+        let pieces = self
+            .comment_stripper
+            .as_mut()
+            .unwrap()
+            .strip(source_mapped_chunk.text);
+
+        for piece in pieces {
+            match piece {
+                comments::Piece::Text(range) => {
+                    self.collapse_source_mapped(source_mapped_chunk.slice(range))?
+                }
+                comments::Piece::Inserted(inserted) => self.collapse(inserted)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn enter_include(
+        &mut self,
+        path: &Path,
+        from: &Path,
+        line_number: usize,
+    ) -> Result<(), S::Error> {
+        self.output_sink.enter_include(path, from, line_number)
+    }
+
+    fn exit_include(&mut self, path: &Path) -> Result<(), S::Error> {
+        self.output_sink.exit_include(path)
+    }
+
+    fn skip_include(
+        &mut self,
+        path: &Path,
+        from: &Path,
+        line_number: usize,
+    ) -> Result<(), S::Error> {
+        self.output_sink.skip_include(path, from, line_number)
+    }
+
+    fn finish(&mut self) -> Result<(), S::Error> {
+        self.sink_slash(true)?;
+        self.sink_whitespace()?;
+
+        if self.emitted {
+            let line_ending = match self.line_endings {
+                LineEndings::CrLf => "\r\n",
+                LineEndings::Preserve if self.pending.starts_with("\r\n") => "\r\n",
+                _ => "\n",
+            };
+
+            self.output_sink.sink(line_ending)?;
+            self.pending.clear();
+        }
+
+        self.output_sink.finish()
+    }
+}
+
+/// Where [Parsed::emit] sends the output and everything it reports.
+struct EmitTargets<'a, S, T, H> {
+    output_sink: &'a mut S,
+    source_tracker: &'a mut T,
+    diagnostic_handler: &'a mut H,
+    file_ids: &'a mut Vec<PathBuf>,
+}
+
+/// Replaces a missing include with a marker comment, see [Options::recover_includes].
+fn recover_include<S, H>(
+    err: &FileNotFoundError,
+    output_sink: &mut S,
+    diagnostic_handler: &mut H,
+) -> Result<(), Error>
+where
+    S: OutputSink,
+    H: DiagnosticHandler,
+{
+    let diagnostic = Diagnostic::recovered_include(err);
+
+    output_sink
+        .sink(&format!("// [ipp] {}\n", diagnostic.message))
+        .map_err(Error::sink)?;
+    diagnostic_handler.handle(diagnostic);
+
+    Ok(())
+}
+
+/// Reports a warning or note to both the [SourceTracker] and the [DiagnosticHandler]; a
+/// diagnostic without a line is only reported to the [DiagnosticHandler].
+fn report<T, H>(diagnostic: Diagnostic, source_tracker: &mut T, diagnostic_handler: &mut H)
+where
+    T: SourceTracker,
+    H: DiagnosticHandler,
+{
+    let Diagnostic {
+        severity,
+        message,
+        path,
+        line,
+        ..
+    } = &diagnostic;
+
+    match (severity, line) {
+        (Severity::Warning, Some(line)) => source_tracker.warning(message, path, *line),
+        (Severity::Note, Some(line)) => source_tracker.note(message, path, *line),
+        _ => (),
+    }
+
+    diagnostic_handler.handle(diagnostic);
+}
+
+/// Creates the macro table a preprocessing run starts out with.
+fn initial_macros(options: &Options) -> MacroTable {
+    let mut macros = MacroTable::new(options.max_macro_expansion_depth);
+
+    for (name, value) in &options.defines {
+        macros.define(name, Macro::object_like(value));
+    }
+
+    macros
+}
+
+/// Discards all diagnostics and tracked sources, see [preprocess_with_report] and
+/// [preprocess_dependencies].
+struct Discard;
+
+impl SourceTracker for Discard {
+    fn track(&mut self, _path: &Path, _source: &str) {}
+}
+
+impl DiagnosticHandler for Discard {
+    fn handle(&mut self, _diagnostic: Diagnostic) {}
+}
+
+#[derive(Clone, Debug)]
+enum NodeChunkInternal {
+    Text {
+        range: Range<usize>,
+        line_number: usize,
+    },
+    Include {
+        path: PathBuf,
+        search_path_index: Option<usize>,
+        include_path: IncludePathBuf,
+        line_number: usize,
+    },
+    Define {
+        name: String,
+        definition: Macro,
+        line: Range<usize>,
+        line_number: usize,
+    },
+    Undef {
+        name: String,
+        line_number: usize,
+    },
+    /// An `#include MACRO` directive, which is resolved when it is reached during emission.
+    IncludeMacro {
+        name: String,
+        line_number: usize,
+    },
+    /// An include directive for which no file could be found.
+    ///
+    /// This only results in an error if the directive is reached during emission, so that
+    /// includes in disabled conditional groups are not required to exist. A missing
+    /// `#include_optional` is reported as a note instead.
+    MissingInclude {
+        included_path: PathBuf,
+        optional: bool,
+        line_number: usize,
+        // The paths at which the file was looked for, see [SourceTracker::track_missing].
+        candidates: Vec<PathBuf>,
+    },
+    /// The opening directive of a conditional group, where `next` is the index of the chunk that
+    /// starts the next branch of the group (or closes the group).
+    Conditional {
+        condition: Condition,
+        next: usize,
+        line_number: usize,
+    },
+    /// An `#elif` branch, where `end` is the index of the chunk that closes the group.
+    Elif {
+        condition: Condition,
+        next: usize,
+        end: usize,
+        line_number: usize,
+    },
+    Else {
+        end: usize,
+        line_number: usize,
+    },
+    EndIf {
+        line_number: usize,
+    },
+    /// A `#pragma once` directive, which has no effect during emission.
+    PragmaOnce {
+        line_number: usize,
+    },
+    Error {
+        message: String,
+        line_number: usize,
+    },
+    Warning {
+        message: String,
+        line_number: usize,
+    },
+    Message {
+        message: String,
+        line_number: usize,
+    },
+    /// A custom directive, see [Options::directive_handler].
+    Custom {
+        name: String,
+        argument: String,
+        line_number: usize,
+    },
+    /// A diagnostic that is only reported to the [DiagnosticHandler].
+    Diagnostic {
+        severity: Severity,
+        message: String,
+        line_number: usize,
+    },
+    Line {
+        number: usize,
+        file: Option<String>,
+        line: Range<usize>,
+        line_number: usize,
+    },
+}
+
+#[derive(Clone, Debug)]
+enum Condition {
+    Defined(String),
+    NotDefined(String),
+    Expression(String),
+}
+
+impl Condition {
+    fn holds(&self, macros: &MacroTable, builtins: Builtins) -> Result<bool, String> {
+        match self {
+            Condition::Defined(name) => Ok(macros.is_defined(name)),
+            Condition::NotDefined(name) => Ok(!macros.is_defined(name)),
+            Condition::Expression(expression) => evaluate_condition(expression, macros, builtins),
+        }
+    }
+}
+
+/// Tracks a conditional group that is still open while parsing.
+struct OpenConditional {
+    /// The index of the group's latest branch chunk.
+    last_branch: usize,
+    /// The indices of the group's `#elif` and `#else` chunks.
+    branches: Vec<usize>,
+    has_else: bool,
+    /// The opening directive of the group and its line number.
+    directive: String,
+    line_number: usize,
+}
+
+impl NodeChunkInternal {
+    fn view<'a>(&'a self, source: &'a str) -> NodeChunk<'a> {
+        match self {
+            NodeChunkInternal::Text { range, line_number } => NodeChunk::Text(TextChunk {
+                byte_range: range.clone(),
+                text: &source[range.clone()],
+                line_number: *line_number,
+            }),
+            NodeChunkInternal::Include {
+                path,
+                search_path_index,
+                include_path,
+                line_number,
+            } => NodeChunk::Include {
+                path: path.as_path(),
+                search_path_index: *search_path_index,
+                include_path,
+                line_number: *line_number,
+            },
+            NodeChunkInternal::Define {
+                name,
+                definition,
+                line,
+                line_number,
+            } => NodeChunk::Define(DefineChunk {
+                name,
+                definition,
+                line: TextChunk {
+                    byte_range: line.clone(),
+                    text: &source[line.clone()],
+                    line_number: *line_number,
+                },
+                line_number: *line_number,
+            }),
+            NodeChunkInternal::Undef { name, line_number } => NodeChunk::Undef {
+                name,
+                line_number: *line_number,
+            },
+            NodeChunkInternal::IncludeMacro { name, line_number } => NodeChunk::IncludeMacro {
+                name,
+                line_number: *line_number,
+            },
+            NodeChunkInternal::MissingInclude {
+                included_path,
+                optional,
+                line_number,
+                candidates,
+            } => NodeChunk::MissingInclude {
+                included_path,
+                optional: *optional,
+                line_number: *line_number,
+                candidates,
+            },
+            NodeChunkInternal::Conditional {
+                condition,
+                next,
+                line_number,
+            } => NodeChunk::Conditional {
+                condition,
+                next: *next,
+                line_number: *line_number,
+            },
+            NodeChunkInternal::Elif {
+                condition,
+                next,
+                end,
+                line_number,
+            } => NodeChunk::Elif {
+                condition,
+                next: *next,
+                end: *end,
+                line_number: *line_number,
+            },
+            NodeChunkInternal::Else { end, line_number } => NodeChunk::Else {
+                end: *end,
+                line_number: *line_number,
+            },
+            NodeChunkInternal::EndIf { line_number } => NodeChunk::EndIf {
+                line_number: *line_number,
+            },
+            NodeChunkInternal::PragmaOnce { line_number } => NodeChunk::PragmaOnce {
+                line_number: *line_number,
+            },
+            NodeChunkInternal::Error {
+                message,
+                line_number,
+            } => NodeChunk::Error {
+                message,
+                line_number: *line_number,
+            },
+            NodeChunkInternal::Warning {
+                message,
+                line_number,
+            } => NodeChunk::Warning {
+                message,
+                line_number: *line_number,
+            },
+            NodeChunkInternal::Message {
+                message,
+                line_number,
+            } => NodeChunk::Message {
+                message,
+                line_number: *line_number,
+            },
+            NodeChunkInternal::Custom {
+                name,
+                argument,
+                line_number,
+            } => NodeChunk::Custom {
+                name,
+                argument,
+                line_number: *line_number,
+            },
+            NodeChunkInternal::Diagnostic {
+                severity,
+                message,
+                line_number,
+            } => NodeChunk::Diagnostic {
+                severity: *severity,
+                message,
+                line_number: *line_number,
+            },
+            NodeChunkInternal::Line {
+                number,
+                file,
+                line,
+                line_number,
+            } => NodeChunk::Line(LineChunk {
+                number: *number,
+                file: file.as_deref(),
+                line: TextChunk {
+                    byte_range: line.clone(),
+                    text: &source[line.clone()],
+                    line_number: *line_number,
+                },
+                line_number: *line_number,
+            }),
+        }
+    }
+
+    fn set_next(&mut self, index: usize) {
+        if let NodeChunkInternal::Conditional { next, .. } | NodeChunkInternal::Elif { next, .. } =
+            self
+        {
+            *next = index;
+        }
+    }
+
+    fn set_end(&mut self, index: usize) {
+        if let NodeChunkInternal::Elif { end, .. } | NodeChunkInternal::Else { end, .. } = self {
+            *end = index;
+        }
+    }
+}
+
+struct TextChunk<'a> {
+    byte_range: Range<usize>,
+    text: &'a str,
+    line_number: usize,
+}
+
+impl<'a> TextChunk<'a> {
+    fn text(&self) -> &'a str {
+        self.text
+    }
+
+    fn byte_range(&self) -> Range<usize> {
+        self.byte_range.clone()
+    }
+
+    /// The (zero-based) number of the line on which the chunk starts.
+    fn line_number(&self) -> usize {
+        self.line_number
+    }
+}
+
+struct LineChunk<'a> {
+    number: usize,
+    file: Option<&'a str>,
+    line: TextChunk<'a>,
+    line_number: usize,
+}
+
+struct DefineChunk<'a> {
+    name: &'a str,
+    definition: &'a Macro,
+    line: TextChunk<'a>,
+    line_number: usize,
+}
+
+enum NodeChunk<'a> {
+    Text(TextChunk<'a>),
+    Include {
+        path: &'a Path,
+        search_path_index: Option<usize>,
+        include_path: &'a IncludePathBuf,
+        line_number: usize,
+    },
+    Define(DefineChunk<'a>),
+    Undef {
+        name: &'a str,
+        line_number: usize,
+    },
+    IncludeMacro {
+        name: &'a str,
+        line_number: usize,
+    },
+    MissingInclude {
+        included_path: &'a Path,
+        optional: bool,
+        line_number: usize,
+        candidates: &'a [PathBuf],
+    },
+    Conditional {
+        condition: &'a Condition,
+        next: usize,
+        line_number: usize,
+    },
+    Elif {
+        condition: &'a Condition,
+        next: usize,
+        end: usize,
+        line_number: usize,
+    },
+    Else {
+        end: usize,
+        line_number: usize,
+    },
+    EndIf {
+        line_number: usize,
+    },
+    PragmaOnce {
+        line_number: usize,
+    },
+    Error {
+        message: &'a str,
+        line_number: usize,
+    },
+    Warning {
+        message: &'a str,
+        line_number: usize,
+    },
+    Message {
+        message: &'a str,
+        line_number: usize,
+    },
+    Custom {
+        name: &'a str,
+        argument: &'a str,
+        line_number: usize,
+    },
+    Diagnostic {
+        severity: Severity,
+        message: &'a str,
+        line_number: usize,
+    },
+    Line(LineChunk<'a>),
+}
+
+struct ParsedNode {
+    path: PathBuf,
+    /// A hash of the file's content, excluding its `#pragma once` lines.
+    content_key: u64,
+    once: bool,
+    // The index of the `#define` chunk of the file's include guard, if it has one.
+    include_guard: Option<usize>,
+    source: String,
+    chunk_buffer: Vec<NodeChunkInternal>,
+    /// The recoverable errors that were collected while parsing.
+    errors: Vec<Error>,
+}
+
+impl ParsedNode {
+    /// Returns a copy of the node, without the errors that were collected while parsing it, as
+    /// for a node in a [PreprocessCache] (which has none).
+    fn clone_without_errors(&self) -> ParsedNode {
+        ParsedNode {
+            path: self.path.clone(),
+            content_key: self.content_key,
+            once: self.once,
+            include_guard: self.include_guard,
+            source: self.source.clone(),
+            chunk_buffer: self.chunk_buffer.clone(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Parses the file at `path`, which was found under the search path at `search_path_index`
+    /// (if any); `#include_next` directives continue the search after that search path.
+    ///
+    /// The file is read through `file_provider`, within the size limits of the `settings`; see
+    /// [ParseSettings] for how the file is parsed.
+    fn try_parse(
+        path: PathBuf,
+        search_path_index: Option<usize>,
+        included_from: Option<(PathBuf, usize)>,
+        search_paths: &SearchPaths,
+        file_provider: &dyn FileProvider,
+        settings: &ParseSettings,
+    ) -> Result<Self, Error> {
+        let collect_errors = settings.collect_errors;
+        let indented_includes = settings.indented_includes;
+        let source = settings.read(&path, included_from, file_provider)?;
+        let source_len = source.len();
+
+        let mut remainder = source.as_str();
+        let mut line_number = 0;
+        let mut chunk_buffer = Vec::new();
+        let mut once = false;
+        let mut once_lines = Vec::new();
+        let mut current_text_range = 0..0;
+        let mut current_text_line = 0;
+        let mut open_conditionals = Vec::new();
+        let mut errors = Vec::new();
+
+        let parse_error = |message: String, line_number: usize| {
+            ParseError::new(message, &path, &source, line_number)
+        };
+
+        while !remainder.is_empty() {
+            let line_start = source_len - remainder.len();
+            let unindented = remainder.trim_start_matches([' ', '\t']);
+            let line_input = if indented_includes && unindented.starts_with("#include") {
+                unindented
+            } else {
+                remainder
+            };
+            let parsed = match &settings.directive_prefix {
+                Some(prefix) => match parse_custom_line(remainder, prefix) {
+                    Err(nom::Err::Error(_)) => parse_line(line_input),
+                    parsed => parsed,
+                },
+                None => parse_line(line_input),
+            };
+            let (new_remainder, line) = match parsed {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    let (message, offset) = match err {
+                        nom::Err::Error(err) | nom::Err::Failure(err) => {
+                            (err.to_string(), source_len - err.remaining())
+                        }
+                        nom::Err::Incomplete(_) => {
+                            ("unexpected end of input".to_string(), line_start)
+                        }
+                    };
+
+                    let err = ParseError::at_offset(message, &path, &source, offset);
+
+                    if !collect_errors {
+                        return Err(err.into());
+                    }
+
+                    errors.push(err.into());
+
+                    (skip_line(remainder), Line::Text)
+                }
+            };
+
+            let pos = source_len - new_remainder.len();
+
+            if line == Line::Text {
+                current_text_range.end = pos;
+            } else {
+                let range = mem::replace(&mut current_text_range, pos..pos);
+                let text_line = mem::replace(&mut current_text_line, line_number + 1);
+
+                if !range.is_empty() {
+                    chunk_buffer.push(NodeChunkInternal::Text {
+                        range,
+                        line_number: text_line,
+                    })
+                }
+            }
+
+            let optional = matches!(line, Line::IncludeOptional(_));
+
+            match line {
+                Line::Include(target) | Line::IncludeOptional(target) if target.is_glob() => {
+                    let resolved =
+                        try_resolve_glob_include_path(&target, &path, search_paths, file_provider);
+                    let resolved = match resolved {
+                        Ok(resolved) => resolved,
+                        Err(err) if collect_errors => {
+                            errors.push(err.into_error(&path, &source, line_number));
+
+                            Vec::new()
+                        }
+                        Err(err) => return Err(err.into_error(&path, &source, line_number)),
+                    };
+
+                    if resolved.is_empty() {
+                        push_include(
+                            &mut chunk_buffer,
+                            &target,
+                            None,
+                            optional,
+                            line_number,
+                            &Vec::new,
+                        );
+                    }
+
+                    for resolved in resolved {
+                        push_include(
+                            &mut chunk_buffer,
+                            &target,
+                            Some(resolved),
+                            false,
+                            line_number,
+                            &Vec::new,
+                        );
+                    }
+                }
+                Line::Include(target) | Line::IncludeOptional(target) => {
+                    let resolved = try_resolve_include_path(
+                        &target,
+                        &path,
+                        line_number,
+                        search_paths,
+                        file_provider,
+                        None,
+                    );
+
+                    match resolved {
+                        Ok(resolved) => push_include(
+                            &mut chunk_buffer,
+                            &target,
+                            resolved,
+                            optional,
+                            line_number,
+                            &|| {
+                                missing_candidates(
+                                    &target,
+                                    &path,
+                                    search_paths,
+                                    file_provider,
+                                    None,
+                                )
+                            },
+                        ),
+                        Err(err) if collect_errors => {
+                            errors.push(err.into_error(&path, &source, line_number))
+                        }
+                        Err(err) => return Err(err.into_error(&path, &source, line_number)),
+                    }
+                }
+                Line::IncludeMacro(name) => {
+                    chunk_buffer.push(NodeChunkInternal::IncludeMacro {
+                        name: name.to_string(),
+                        line_number,
+                    });
+                }
+                Line::IncludeNext(target) => {
+                    // A file that was not found through the search paths has nothing to continue
+                    // from, in which case `#include_next` behaves like a regular `#include`.
+                    let resolved = try_resolve_include_path(
+                        &target,
+                        &path,
+                        line_number,
+                        search_paths,
+                        file_provider,
+                        search_path_index,
+                    );
+
+                    match resolved {
+                        Ok(resolved) => push_include(
+                            &mut chunk_buffer,
+                            &target,
+                            resolved,
+                            false,
+                            line_number,
+                            &|| {
+                                missing_candidates(
+                                    &target,
+                                    &path,
+                                    search_paths,
+                                    file_provider,
+                                    search_path_index,
+                                )
+                            },
+                        ),
+                        Err(err) if collect_errors => {
+                            errors.push(err.into_error(&path, &source, line_number))
+                        }
+                        Err(err) => return Err(err.into_error(&path, &source, line_number)),
+                    }
+                }
+                Line::PragmaOnce => {
+                    once = true;
+                    once_lines.push(line_start..pos);
+                    chunk_buffer.push(NodeChunkInternal::PragmaOnce { line_number });
+                }
+                Line::Define(define) => {
+                    let definition = if let Some(parameters) = define.parameters {
+                        Macro::function_like(parameters, &define.body)
+                    } else {
+                        Macro::object_like(&define.body)
+                    };
+
+                    chunk_buffer.push(NodeChunkInternal::Define {
+                        name: define.name.to_string(),
+                        definition,
+                        line: line_start..pos,
+                        line_number,
+                    });
+                }
+                Line::Undef(name) => {
+                    chunk_buffer.push(NodeChunkInternal::Undef {
+                        name: name.to_string(),
+                        line_number,
+                    });
+                }
+                Line::If(expression) => {
+                    open_conditionals.push(OpenConditional {
+                        last_branch: chunk_buffer.len(),
+                        branches: Vec::new(),
+                        has_else: false,
+                        directive: format!("#if {}", expression),
+                        line_number,
+                    });
+
+                    chunk_buffer.push(NodeChunkInternal::Conditional {
+                        condition: Condition::Expression(expression.to_string()),
+                        next: 0,
+                        line_number,
+                    });
+                }
+                Line::IfDef(name) => {
+                    open_conditionals.push(OpenConditional {
+                        last_branch: chunk_buffer.len(),
+                        branches: Vec::new(),
+                        has_else: false,
+                        directive: format!("#ifdef {}", name),
+                        line_number,
+                    });
+
+                    chunk_buffer.push(NodeChunkInternal::Conditional {
+                        condition: Condition::Defined(name.to_string()),
+                        next: 0,
+                        line_number,
+                    });
+                }
+                Line::IfNDef(name) => {
+                    open_conditionals.push(OpenConditional {
+                        last_branch: chunk_buffer.len(),
+                        branches: Vec::new(),
+                        has_else: false,
+                        directive: format!("#ifndef {}", name),
+                        line_number,
+                    });
+
+                    chunk_buffer.push(NodeChunkInternal::Conditional {
+                        condition: Condition::NotDefined(name.to_string()),
+                        next: 0,
+                        line_number,
+                    });
+                }
+                Line::Elif(expression) => {
+                    let index = chunk_buffer.len();
+                    let open = open_conditionals.last_mut().ok_or_else(|| {
+                        parse_error("`#elif` without a matching `#if`".to_string(), line_number)
+                    })?;
+
+                    if open.has_else {
+                        return Err(
+                            parse_error("`#elif` after `#else`".to_string(), line_number).into(),
+                        );
+                    }
+
+                    chunk_buffer[open.last_branch].set_next(index);
+                    open.last_branch = index;
+                    open.branches.push(index);
+
+                    chunk_buffer.push(NodeChunkInternal::Elif {
+                        condition: Condition::Expression(expression.to_string()),
+                        next: 0,
+                        end: 0,
+                        line_number,
+                    });
+                }
+                Line::Else => {
+                    let index = chunk_buffer.len();
+                    let open = open_conditionals.last_mut().ok_or_else(|| {
+                        parse_error("`#else` without a matching `#if`".to_string(), line_number)
+                    })?;
+
+                    if open.has_else {
+                        return Err(
+                            parse_error("`#else` after `#else`".to_string(), line_number).into(),
+                        );
+                    }
+
+                    chunk_buffer[open.last_branch].set_next(index);
+                    open.last_branch = index;
+                    open.branches.push(index);
+                    open.has_else = true;
+
+                    chunk_buffer.push(NodeChunkInternal::Else {
+                        end: 0,
+                        line_number,
+                    });
+                }
+                Line::EndIf => {
+                    let open = open_conditionals.pop().ok_or_else(|| {
+                        parse_error("`#endif` without a matching `#if`".to_string(), line_number)
+                    })?;
+
+                    let end = chunk_buffer.len();
+
+                    chunk_buffer[open.last_branch].set_next(end);
+
+                    for branch in open.branches {
+                        chunk_buffer[branch].set_end(end);
+                    }
+
+                    chunk_buffer.push(NodeChunkInternal::EndIf { line_number });
+                }
+                Line::Error(message) => {
+                    chunk_buffer.push(NodeChunkInternal::Error {
+                        message: message.to_string(),
+                        line_number,
+                    });
+                }
+                Line::Warning(message) => {
+                    chunk_buffer.push(NodeChunkInternal::Warning {
+                        message: message.to_string(),
+                        line_number,
+                    });
+                }
+                Line::Message(message) => {
+                    chunk_buffer.push(NodeChunkInternal::Message {
+                        message: message.to_string(),
+                        line_number,
+                    });
+                }
+                Line::Custom { name, argument } => {
+                    chunk_buffer.push(NodeChunkInternal::Custom {
+                        name: name.to_string(),
+                        argument: argument.to_string(),
+                        line_number,
+                    });
+                }
+                Line::Renumber { number, file } => {
+                    chunk_buffer.push(NodeChunkInternal::Line {
+                        number,
+                        file: file.map(|file| file.to_string()),
+                        line: line_start..pos,
+                        line_number,
+                    });
+                }
+                Line::Text => (),
+            }
+
+            remainder = new_remainder;
+            line_number += 1;
+        }
+
+        if let Some(open) = open_conditionals.pop() {
+            return Err(UnterminatedConditionalError {
+                directive: open.directive,
+                source_file: path.to_path_buf(),
+                source: source.clone(),
+                line_number: open.line_number,
+                display_root: None,
+            }
+            .into());
+        }
+
+        if !current_text_range.is_empty() {
+            chunk_buffer.push(NodeChunkInternal::Text {
+                range: current_text_range,
+                line_number: current_text_line,
+            })
+        }
+
+        let include_guard = detect_include_guard(&source, &chunk_buffer);
+
+        if include_guard.is_some() {
+            once = true;
+        }
+
+        // Hash the content without its `#pragma once` lines, see `Options::once_by_content`.
+        let mut hasher = DefaultHasher::new();
+        let mut hashed = 0;
+
+        for line in once_lines {
+            hasher.write(&source.as_bytes()[hashed..line.start]);
+            hashed = line.end;
+        }
+
+        hasher.write(&source.as_bytes()[hashed..]);
+
+        let content_key = hasher.finish();
+
+        Ok(ParsedNode {
+            path,
+            content_key,
+            once,
+            include_guard,
+            source,
+            chunk_buffer,
+            errors,
+        })
+    }
+
+    fn path(&self) -> &Path {
+        self.path.as_ref()
+    }
+
+    fn parse_error(&self, message: String, line_number: usize) -> ParseError {
+        ParseError::new(message, &self.path, &self.source, line_number)
+    }
+
+    /// The key that identifies the file (with the `id`) for `#pragma once`, see
+    /// [Options::once_by_content].
+    fn once_key(&self, id: FileId, options: &Options) -> OnceKey {
+        if options.once_by_content {
+            OnceKey::Content(self.content_key)
+        } else {
+            OnceKey::File(id)
+        }
+    }
+
+    fn source(&self) -> &str {
+        &self.source
+    }
+
+    fn once(&self) -> bool {
+        self.once
+    }
+
+    fn include_guard(&self) -> Option<usize> {
+        self.include_guard
+    }
+
+    fn get_chunk(&self, index: usize) -> Option<NodeChunk<'_>> {
+        self.chunk_buffer
+            .get(index)
+            .map(|chunk| chunk.view(&self.source))
+    }
+
+    fn chunks(&self) -> NodeChunks<'_> {
+        let ParsedNode {
+            source,
+            chunk_buffer,
+            ..
+        } = self;
+
+        NodeChunks {
+            source,
+            chunks: chunk_buffer.iter(),
+        }
+    }
+}
+
+struct NodeChunks<'a> {
+    source: &'a String,
+    chunks: slice::Iter<'a, NodeChunkInternal>,
+}
+
+impl<'a> Iterator for NodeChunks<'a> {
+    type Item = NodeChunk<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let NodeChunks { source, chunks } = self;
+
+        chunks.next().map(|chunk| chunk.view(source))
+    }
+}
+
+#[derive(Clone)]
+pub struct SourceMappedChunk<'a> {
+    text: &'a str,
+    source_path: &'a Path,
+    source: &'a str,
+    source_range: Range<usize>,
+    line_range: Range<usize>,
+}
+
+impl<'a> SourceMappedChunk<'a> {
+    pub fn text(&self) -> &str {
+        self.text
+    }
+
+    pub fn source_path(&self) -> &Path {
+        self.source_path
+    }
+
+    /// The full source text of the file at the [source_path](SourceMappedChunk::source_path).
+    pub fn source(&self) -> &str {
+        self.source
+    }
+
+    pub fn source_range(&self) -> Range<usize> {
+        self.source_range.clone()
+    }
+
+    /// The (zero-based) numbers of the lines of the source file that the
+    /// [source_range](SourceMappedChunk::source_range) spans.
+    pub fn line_range(&self) -> Range<usize> {
+        self.line_range.clone()
+    }
+
+    /// The (one-based) number of the line of the source file that the
+    /// [source_range](SourceMappedChunk::source_range) starts on.
+    pub fn start_line(&self) -> usize {
+        self.line_range.start + 1
+    }
+
+    /// The (one-based) column, in characters, at which the
+    /// [source_range](SourceMappedChunk::source_range) starts.
+    ///
+    /// For the expansion of a macro, this is the start of the macro invocation.
+    pub fn start_column(&self) -> usize {
+        column_at(self.source, self.source_range.start) + 1
+    }
+
+    /// Returns the chunk for the byte `range` of the text.
+    ///
+    /// Only if the text is the source text can the range be mapped precisely, rather than to the
+    /// macro invocation that the text is expanded from.
+    fn slice(&self, range: Range<usize>) -> SourceMappedChunk<'a> {
+        let text = self.text;
+        let verbatim = self.source.get(self.source_range.clone()) == Some(text);
+        let (source_range, line_range) = if verbatim {
+            let first_line = self.line_range.start + text[..range.start].matches('\n').count();
+
+            (
+                self.source_range.start + range.start..self.source_range.start + range.end,
+                line_span(&text[range.clone()], first_line),
+            )
+        } else {
+            (self.source_range.clone(), self.line_range.clone())
+        };
+
+        SourceMappedChunk {
+            text: &text[range],
+            source_path: self.source_path,
+            source: self.source,
+            source_range,
+            line_range,
+        }
+    }
+}
+
+/// Receives the output of preprocessing.
+///
+/// If sinking a chunk fails, preprocessing stops and fails with [Error::Sink].
+pub trait OutputSink {
+    type Error: StdError + Send + Sync + 'static;
+
+    fn sink(&mut self, chunk: &str) -> Result<(), Self::Error>;
+
+    fn sink_source_mapped(
+        &mut self,
+        source_mapped_chunk: SourceMappedChunk,
+    ) -> Result<(), Self::Error>;
+
+    /// Called when emission descends into the included file at `path`, for the include directive
+    /// at the (one-based) `line_number` of the file at `from`.
+    ///
+    /// Does nothing by default.
+    fn enter_include(
+        &mut self,
+        _path: &Path,
+        _from: &Path,
+        _line_number: usize,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called when emission returns from the included file at `path`, after all of its output
+    /// (including the newline that may follow it, see [Options::include_newline]).
+    ///
+    /// Does nothing by default.
+    fn exit_include(&mut self, _path: &Path) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called instead of [enter_include](OutputSink::enter_include) for an include that is
+    /// skipped, as the file was already included and contains `#pragma once` (or an include
+    /// guard), with the same (one-based) `line_number`.
+    ///
+    /// Does nothing by default.
+    fn skip_include(
+        &mut self,
+        _path: &Path,
+        _from: &Path,
+        _line_number: usize,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called once all output has been sunk.
+    ///
+    /// Does nothing by default.
+    fn finish(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// An [OutputSink] that writes the output to a [Write] implementation, see also
+/// [preprocess_to_writer].
+///
+/// Preprocessing fails with [Error::Sink] at the first IO error.
+#[derive(Debug)]
+pub struct IoSink<W> {
+    writer: W,
+}
+
+impl<W> IoSink<W>
+where
+    W: Write,
+{
+    pub fn new(writer: W) -> Self {
+        IoSink { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W> OutputSink for IoSink<W>
+where
+    W: Write,
+{
+    type Error = IOError;
+
+    fn sink(&mut self, chunk: &str) -> Result<(), IOError> {
+        self.writer.write_all(chunk.as_bytes())
+    }
+
+    fn sink_source_mapped(
+        &mut self,
+        source_mapped_chunk: SourceMappedChunk,
+    ) -> Result<(), IOError> {
+        self.writer.write_all(source_mapped_chunk.text.as_bytes())
+    }
+
+    /// Flushes the writer.
+    fn finish(&mut self) -> Result<(), IOError> {
+        self.writer.flush()
+    }
+}
+
+/// An [OutputSink] that writes the output to a [fmt::Write] implementation, e.g. a fixed-capacity
+/// string buffer.
+///
+/// Preprocessing fails with [Error::Sink] (with a [fmt::Error]) at the first chunk that fails to
+/// be written, such as a chunk that does not fit in a fixed-capacity buffer.
+#[derive(Debug)]
+pub struct FmtSink<W> {
+    writer: W,
+}
+
+impl<W> FmtSink<W>
+where
+    W: fmt::Write,
+{
+    pub fn new(writer: W) -> Self {
+        FmtSink { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W> OutputSink for FmtSink<W>
+where
+    W: fmt::Write,
+{
+    type Error = fmt::Error;
+
+    fn sink(&mut self, chunk: &str) -> Result<(), fmt::Error> {
+        self.writer.write_str(chunk)
+    }
+
+    fn sink_source_mapped(
+        &mut self,
+        source_mapped_chunk: SourceMappedChunk,
+    ) -> Result<(), fmt::Error> {
+        self.writer.write_str(source_mapped_chunk.text)
+    }
+}
+
+impl OutputSink for String {
+    type Error = Infallible;
+
+    fn sink(&mut self, chunk: &str) -> Result<(), Infallible> {
+        self.push_str(chunk);
+
+        Ok(())
+    }
+
+    fn sink_source_mapped(
+        &mut self,
+        source_mapped_chunk: SourceMappedChunk,
+    ) -> Result<(), Infallible> {
+        self.push_str(source_mapped_chunk.text);
+
+        Ok(())
+    }
+}
+
+/// An [OutputSink] that minifies the whitespace of the output before forwarding it to another
+/// sink, e.g. to shrink shaders that are embedded in a binary.
+///
+/// Trailing whitespace and indentation are removed, blank lines are dropped, and other runs of
+/// spaces and tabs are removed or collapsed into a single space, such that the output still
+/// consists of the same tokens. String literals, preprocessor directives and the lines that
+/// continue them are forwarded unchanged.
+#[derive(Debug)]
+pub struct MinifySink<S> {
+    output_sink: S,
+    minifier: Minifier,
+}
+
+impl<S> MinifySink<S>
+where
+    S: OutputSink,
+{
+    pub fn new(output_sink: S) -> Self {
+        MinifySink {
+            output_sink,
+            minifier: Minifier::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.output_sink
+    }
+}
+
+impl<S> OutputSink for MinifySink<S>
+where
+    S: OutputSink,
+{
+    type Error = S::Error;
+
+    fn sink(&mut self, chunk: &str) -> Result<(), S::Error> {
+        for piece in self.minifier.minify(chunk) {
+            match piece {
+                minify::Piece::Text(range) => self.output_sink.sink(&chunk[range])?,
+                minify::Piece::Whitespace(whitespace) => self.output_sink.sink(&whitespace)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sink_source_mapped(
+        &mut self,
+        source_mapped_chunk: SourceMappedChunk,
+    ) -> Result<(), S::Error> {
+        for piece in self.minifier.minify(source_mapped_chunk.text) {
+            match piece {
+                minify::Piece::Text(range) => self
+                    .output_sink
+                    .sink_source_mapped(source_mapped_chunk.slice(range))?,
+                minify::Piece::Whitespace(whitespace) => self.output_sink.sink(&whitespace)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn enter_include(
+        &mut self,
+        path: &Path,
+        from: &Path,
+        line_number: usize,
+    ) -> Result<(), S::Error> {
+        self.output_sink.enter_include(path, from, line_number)
+    }
+
+    fn exit_include(&mut self, path: &Path) -> Result<(), S::Error> {
+        self.output_sink.exit_include(path)
+    }
+
+    fn skip_include(
+        &mut self,
+        path: &Path,
+        from: &Path,
+        line_number: usize,
+    ) -> Result<(), S::Error> {
+        self.output_sink.skip_include(path, from, line_number)
+    }
+
+    fn finish(&mut self) -> Result<(), S::Error> {
+        self.output_sink.finish()
+    }
+}
+
+/// An [OutputSink] that forwards all output to two sinks, e.g. to write the output to a file
+/// while building a [SourceMap](crate::SourceMap) with a
+/// [SourceMapBuilder](crate::SourceMapBuilder).
+///
+/// Both sinks receive every chunk, even if the first fails on it. If both fail, the error of the
+/// first sink is reported.
+#[derive(Debug)]
+pub struct TeeSink<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> TeeSink<A, B>
+where
+    A: OutputSink,
+    B: OutputSink,
+{
+    pub fn new(first: A, second: B) -> Self {
+        TeeSink { first, second }
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+
+    fn combine(
+        first: Result<(), A::Error>,
+        second: Result<(), B::Error>,
+    ) -> Result<(), TeeError<A::Error, B::Error>> {
+        match (first, second) {
+            (Err(err), _) => Err(TeeError::First(err)),
+            (Ok(()), Err(err)) => Err(TeeError::Second(err)),
+            (Ok(()), Ok(())) => Ok(()),
+        }
+    }
+}
+
+impl<A, B> OutputSink for TeeSink<A, B>
+where
+    A: OutputSink,
+    B: OutputSink,
+{
+    type Error = TeeError<A::Error, B::Error>;
+
+    fn sink(&mut self, chunk: &str) -> Result<(), Self::Error> {
+        Self::combine(self.first.sink(chunk), self.second.sink(chunk))
+    }
+
+    fn sink_source_mapped(
+        &mut self,
+        source_mapped_chunk: SourceMappedChunk,
+    ) -> Result<(), Self::Error> {
+        Self::combine(
+            self.first.sink_source_mapped(source_mapped_chunk.clone()),
+            self.second.sink_source_mapped(source_mapped_chunk),
+        )
+    }
+
+    fn enter_include(
+        &mut self,
+        path: &Path,
+        from: &Path,
+        line_number: usize,
+    ) -> Result<(), Self::Error> {
+        Self::combine(
+            self.first.enter_include(path, from, line_number),
+            self.second.enter_include(path, from, line_number),
+        )
+    }
+
+    fn exit_include(&mut self, path: &Path) -> Result<(), Self::Error> {
+        Self::combine(
+            self.first.exit_include(path),
+            self.second.exit_include(path),
+        )
+    }
+
+    fn skip_include(
+        &mut self,
+        path: &Path,
+        from: &Path,
+        line_number: usize,
+    ) -> Result<(), Self::Error> {
+        Self::combine(
+            self.first.skip_include(path, from, line_number),
+            self.second.skip_include(path, from, line_number),
+        )
+    }
+
+    fn finish(&mut self) -> Result<(), Self::Error> {
+        Self::combine(self.first.finish(), self.second.finish())
+    }
+}
+
+/// An [OutputSink] that tallies the bytes and lines of the output per source file, and forwards
+/// it to another sink (or discards it, see [CountingSink::default]).
+///
+/// Lines are counted by their line endings. Text that the preprocessor inserts, such as the
+/// newline after an included file, is tallied separately as [synthetic](CountingSink::synthetic).
+/// A file that is included multiple times is tallied every time it is emitted.
+#[derive(Debug)]
+pub struct CountingSink<S = ()> {
+    output_sink: S,
+    per_file: BTreeMap<PathBuf, Counts>,
+    synthetic: Counts,
+}
+
+impl<S> CountingSink<S>
+where
+    S: OutputSink,
+{
+    pub fn new(output_sink: S) -> Self {
+        CountingSink {
+            output_sink,
+            per_file: BTreeMap::new(),
+            synthetic: Counts::default(),
+        }
+    }
+
+    /// The tallies of the output of each source file, by path.
+    pub fn per_file(&self) -> &BTreeMap<PathBuf, Counts> {
+        &self.per_file
+    }
+
+    /// The tally of the output that does not originate from a source file.
+    pub fn synthetic(&self) -> Counts {
+        self.synthetic
+    }
+
+    /// The tally of all output.
+    pub fn total(&self) -> Counts {
+        self.per_file
+            .values()
+            .fold(self.synthetic, |total, counts| Counts {
+                bytes: total.bytes + counts.bytes,
+                lines: total.lines + counts.lines,
+            })
+    }
+
+    pub fn into_inner(self) -> S {
+        self.output_sink
+    }
+}
+
+impl Default for CountingSink<()> {
+    fn default() -> Self {
+        CountingSink::new(())
+    }
+}
+
+impl<S> OutputSink for CountingSink<S>
+where
+    S: OutputSink,
+{
+    type Error = S::Error;
+
+    fn sink(&mut self, chunk: &str) -> Result<(), S::Error> {
+        self.synthetic.add(chunk);
+        self.output_sink.sink(chunk)
+    }
+
+    fn sink_source_mapped(
+        &mut self,
+        source_mapped_chunk: SourceMappedChunk,
+    ) -> Result<(), S::Error> {
+        match self.per_file.get_mut(source_mapped_chunk.source_path) {
+            Some(counts) => counts.add(source_mapped_chunk.text),
+            None => {
+                let mut counts = Counts::default();
+
+                counts.add(source_mapped_chunk.text);
+                self.per_file
+                    .insert(source_mapped_chunk.source_path.to_path_buf(), counts);
+            }
+        }
+
+        self.output_sink.sink_source_mapped(source_mapped_chunk)
+    }
+
+    fn enter_include(
+        &mut self,
+        path: &Path,
+        from: &Path,
+        line_number: usize,
+    ) -> Result<(), S::Error> {
+        self.output_sink.enter_include(path, from, line_number)
+    }
+
+    fn exit_include(&mut self, path: &Path) -> Result<(), S::Error> {
+        self.output_sink.exit_include(path)
+    }
+
+    fn skip_include(
+        &mut self,
+        path: &Path,
+        from: &Path,
+        line_number: usize,
+    ) -> Result<(), S::Error> {
+        self.output_sink.skip_include(path, from, line_number)
+    }
+
+    fn finish(&mut self) -> Result<(), S::Error> {
+        self.output_sink.finish()
+    }
+}
+
+/// The number of bytes and lines of output, see [CountingSink].
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Counts {
+    bytes: usize,
+    lines: usize,
+}
+
+impl Counts {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// The number of line endings.
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    fn add(&mut self, text: &str) {
+        self.bytes += text.len();
+        self.lines += text.matches('\n').count();
+    }
+}
+
+/// Discards all output, e.g. for a [CountingSink] that is not wrapping another sink.
+impl OutputSink for () {
+    type Error = Infallible;
+
+    fn sink(&mut self, _chunk: &str) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn sink_source_mapped(
+        &mut self,
+        _source_mapped_chunk: SourceMappedChunk,
+    ) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/// The error of a [TeeSink], from either its first or its second sink.
+#[derive(Debug)]
+pub enum TeeError<A, B> {
+    First(A),
+    Second(B),
+}
+
+impl<A, B> fmt::Display for TeeError<A, B>
+where
+    A: fmt::Display,
+    B: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TeeError::First(err) => err.fmt(f),
+            TeeError::Second(err) => err.fmt(f),
+        }
+    }
+}
+
+impl<A, B> StdError for TeeError<A, B>
+where
+    A: StdError + 'static,
+    B: StdError + 'static,
+{
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            TeeError::First(err) => Some(err),
+            TeeError::Second(err) => Some(err),
+        }
+    }
+}
+
+/// Receives the files that are used while preprocessing, along with the diagnostics that are
+/// reported for them.
+///
+/// Besides `()` and [NoopTracker], which track nothing, any closure that takes the path and the
+/// source of a file implements this trait, e.g. to collect the paths of the files that are used:
+///
+/// ```
+/// use std::path::{Path, PathBuf};
+///
+/// use include_preprocessor::{preprocess_str, SearchPaths};
+///
+/// let mut paths: Vec<PathBuf> = Vec::new();
+/// let mut tracker = |path: &Path, _source: &str| paths.push(path.to_path_buf());
+///
+/// let output = preprocess_str(
+///     "void main() {}\n",
+///     "/shaders/main.glsl",
+///     SearchPaths::new(),
+///     String::new(),
+///     &mut tracker,
+/// )
+/// .unwrap();
+///
+/// assert_eq!(output, "void main() {}\n");
+/// assert_eq!(paths, [PathBuf::from("/shaders/main.glsl")]);
+/// ```
+pub trait SourceTracker {
+    fn track(&mut self, path: &Path, source: &str);
+
+    /// Called when a `#warning` directive is reached, with the directive's message and the
+    /// (one-based) line number of the directive in `source_file`. Also called for an include
+    /// that only matched a file case-insensitively (see [SearchPaths::set_case_insensitive]).
+    ///
+    /// Warnings are ignored by default.
+    fn warning(&mut self, _message: &str, _source_file: &Path, _line_number: usize) {}
+
+    /// Called when a `#pragma message` directive is reached, with the directive's message and
+    /// the (one-based) line number of the directive in `source_file`. Also called for an
+    /// `#include_optional` directive for which no file was found.
+    ///
+    /// Notes are ignored by default.
+    fn note(&mut self, _message: &str, _source_file: &Path, _line_number: usize) {}
+
+    /// Called after the file at `parent` is tracked, for each of its include directives (as in
+    /// its [IncludeGraph]), with the (one-based) line number of the directive and the file at
+    /// `child` that it includes.
+    ///
+    /// Include directives are ignored by default.
+    fn track_include(
+        &mut self,
+        _parent: &Path,
+        _child: &Path,
+        _line_number: usize,
+        _kind: IncludeKind,
+    ) {
+    }
+
+    /// Called for a path at which an included file was looked for but not found, or for the entry
+    /// point if it does not exist, so that the output can be updated once the path is created.
+    ///
+    /// This is also called when preprocessing fails, as are [track](SourceTracker::track) for
+    /// the files that were loaded before the failure. Missing paths are ignored by default.
+    fn track_missing(&mut self, _path: &Path) {}
+}
+
+/// Tracks nothing, e.g. for a [DepfileTracker](crate::DepfileTracker) that is not wrapping another
+/// tracker.
+impl SourceTracker for () {
+    fn track(&mut self, _path: &Path, _source: &str) {}
+}
+
+/// A [SourceTracker] that tracks nothing and ignores all diagnostics, see also
+/// [preprocess_untracked].
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct NoopTracker;
+
+impl SourceTracker for NoopTracker {
+    fn track(&mut self, _path: &Path, _source: &str) {}
+}
+
+/// Calls the closure with the path and the source of each file that is tracked; diagnostics are
+/// ignored.
+impl<F> SourceTracker for F
+where
+    F: FnMut(&Path, &str),
+{
+    fn track(&mut self, path: &Path, source: &str) {
+        self(path, source)
+    }
+}
+
+/// Selects the branch of a conditional group to emit, starting from the (`#elif`, `#else` or
+/// `#endif`) chunk at `index` that follows a branch whose condition did not hold.
+///
+/// Returns the index of the first chunk of the selected branch; if no branch is selected, this
+/// is the index of the chunk that follows the group's `#endif`.
+fn select_branch(
+    node: &ParsedNode,
+    mut index: usize,
+    macros: &MacroTable,
+    builtins: Builtins,
+) -> Result<usize, Error> {
+    while let Some(NodeChunk::Elif {
+        condition,
+        next,
+        line_number,
+        ..
+    }) = node.get_chunk(index)
+    {
+        let builtins = Builtins {
+            line_number,
+            ..builtins
+        };
+
+        let holds = condition
+            .holds(macros, builtins)
+            .map_err(|message| node.parse_error(message, line_number))?;
+
+        if holds {
+            break;
+        }
+
+        index = next;
+    }
+
+    Ok(index + 1)
+}
+
+/// Finds the first line in `text` that starts with `#` and does not name one of the `allowed`
+/// directives, returning its index and (trimmed) text.
+///
+/// Any known directive is parsed into its own chunk, so every such line in a text chunk is an
+/// unknown (or malformed) directive.
+fn find_unknown_directive<'a>(
+    text: &'a str,
+    allowed: &HashSet<String>,
+) -> Option<(usize, &'a str)> {
+    text.lines().map(str::trim).enumerate().find(|(_, line)| {
+        if let Some(directive) = line.strip_prefix('#') {
+            let name = directive
+                .trim_start()
+                .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .next()
+                .unwrap_or("");
+
+            !allowed.contains(name)
+        } else {
+            false
+        }
+    })
+}
+
+/// Expands the macro named by an `#include MACRO` directive.
+fn expand_include_macro(
+    name: &str,
+    macros: &MacroTable,
+    builtins: Builtins,
+) -> Result<String, String> {
+    if !macros.is_defined(name) {
+        return Err(format!("`#include` macro `{}` is not defined", name));
+    }
+
+    let mut expanded = String::new();
+
+    macros
+        .expand(name, builtins, |text, _| expanded.push_str(text))
+        .map_err(|err| err.to_string())?;
+
+    Ok(expanded.trim().to_string())
+}
+
+/// Macro expands and evaluates an `#if` expression, returning whether the condition holds.
+fn evaluate_condition(
+    expression: &str,
+    macros: &MacroTable,
+    builtins: Builtins,
+) -> Result<bool, String> {
+    let mut expanded = String::new();
+
+    // `defined` operators must be resolved before macro expansion, as their operands would
+    // otherwise be expanded.
+    let resolved = expression::resolve_defined(expression, |name| macros.is_defined(name))
+        .map_err(|err| format!("invalid `#if` expression `{}`: {}", expression, err))?;
+
+    macros
+        .expand(&resolved, builtins, |text, _| expanded.push_str(text))
+        .map_err(|err| format!("invalid `#if` expression `{}`: {}", expression, err))?;
+
+    let value = expression::evaluate(&expanded)
+        .map_err(|err| format!("invalid `#if` expression `{}`: {}", expression, err))?;
+
+    Ok(value != 0)
+}
+
+/// The mapping established by a `#line` directive on line `line_number`, which sets the number of
+/// the line that follows it to `number` (and the file name to `file`, if specified).
+#[derive(Clone, Copy)]
+struct LineMapping<'a> {
+    line_number: usize,
+    number: usize,
+    file: Option<&'a str>,
+}
+
+impl LineMapping<'_> {
+    /// Maps a (zero-based) line number that follows the directive's line to the line number it
+    /// is attributed to.
+    fn map(&self, line_number: usize) -> usize {
+        self.number + (line_number - self.line_number - 1)
+    }
+}
+
+/// Tracks the file and line that the output continues from, to emit a `#line` directive when it
+/// does not continue from there, see [Options::line_markers].
+struct LineMarkers<'a> {
+    file_ids: &'a mut Vec<PathBuf>,
+    /// The file id and (zero-based) line number of the next line of output, if known.
+    position: Option<(usize, usize)>,
+}
+
+impl<'a> LineMarkers<'a> {
+    fn new(file_ids: &'a mut Vec<PathBuf>, root: &Path) -> Self {
+        file_ids.push(root.to_path_buf());
+
+        LineMarkers {
+            file_ids,
+            position: Some((0, 0)),
+        }
+    }
+
+    /// Emits a `#line` directive, unless the output already continues at the (zero-based)
+    /// `line_number` of `path`.
+    fn mark<S>(&mut self, output_sink: &mut S, path: &Path, line_number: usize) -> Result<(), Error>
+    where
+        S: OutputSink,
+    {
+        let file_id = match self.file_ids.iter().position(|file| file == path) {
+            Some(file_id) => file_id,
+            None => {
+                self.file_ids.push(path.to_path_buf());
+
+                self.file_ids.len() - 1
+            }
+        };
+
+        if self.position != Some((file_id, line_number)) {
+            output_sink
+                .sink(&format!("#line {} {}\n", line_number + 1, file_id))
+                .map_err(Error::sink)?;
+
+            self.position = Some((file_id, line_number));
+        }
+
+        Ok(())
+    }
+
+    /// Advances the position past output that spans `source_lines` lines of the current file, as
+    /// `output_lines` lines (which differ if e.g. a macro invocation spans several lines).
+    fn advance(&mut self, source_lines: usize, output_lines: usize) {
+        match &mut self.position {
+            Some((_, line_number)) if source_lines == output_lines => *line_number += source_lines,
+            _ => self.position = None,
+        }
+    }
+
+    /// Forgets the position after output that does not originate from a file.
+    fn reset(&mut self) {
+        self.position = None;
+    }
+}
+
+/// Sinks a blank line in place of the directive at the (zero-based) `line_number` of `path`.
+fn sink_blank_line<S>(
+    output_sink: &mut S,
+    line_markers: &mut Option<LineMarkers>,
+    path: &Path,
+    line_number: usize,
+) -> Result<(), Error>
+where
+    S: OutputSink,
+{
+    if let Some(line_markers) = line_markers {
+        line_markers.mark(output_sink, path, line_number)?;
+        line_markers.advance(1, 1);
+    }
+
+    output_sink.sink("\n").map_err(Error::sink)
+}
+
+/// Sinks a blank line in place of a directive of the `kind` at the (zero-based) `line_number` of
+/// `path`, if the `kind` is one of the [Options::blank_directives].
+fn sink_blank_directive<S>(
+    output_sink: &mut S,
+    line_markers: &mut Option<LineMarkers>,
+    kind: DirectiveKind,
+    path: &Path,
+    line_number: usize,
+    options: &Options,
+) -> Result<(), Error>
+where
+    S: OutputSink,
+{
+    if options.blank_directives.contains(&kind) {
+        sink_blank_line(output_sink, line_markers, path, line_number)
+    } else {
+        Ok(())
+    }
+}
+
+/// Sinks an include marker line from the `template`, for the file at `path` at nesting `depth`,
+/// see [Options::include_markers].
+fn sink_include_marker<S>(
+    output_sink: &mut EmitSink<S>,
+    template: &str,
+    path: &Path,
+    depth: usize,
+    options: &Options,
+) -> Result<(), Error>
+where
+    S: OutputSink,
+{
+    let path = diagnostic::display_path(path, options.display_root.as_deref());
+    let mut marker = template
+        .replace("{path}", &path)
+        .replace("{depth}", &depth.to_string());
+
+    if !output_sink.at_line_start() {
+        marker.insert(0, '\n');
+    }
+
+    if !marker.ends_with('\n') {
+        marker.push('\n');
+    }
+
+    output_sink.sink(&marker).map_err(Error::sink)
+}
+
+fn sink_line_directive<S>(
+    output_sink: &mut S,
+    number: usize,
+    file: Option<&str>,
+) -> Result<(), Error>
+where
+    S: OutputSink,
+{
+    let directive = if let Some(file) = file {
+        format!("#line {} \"{}\"\n", number, file)
+    } else {
+        format!("#line {}\n", number)
+    };
+
+    output_sink.sink(&directive).map_err(Error::sink)
+}
+
+/// Detects whether the entire file is wrapped in a classic `#ifndef NAME` / `#define NAME` /
+/// `#endif` include guard, and if so, returns the index of the guard's `#define` chunk.
+///
+/// Detection is conservative: only blank lines and `//` comments may appear outside of the guard,
+/// the guard may not have `#elif`/`#else` branches, and the guard macro may not be referenced
+/// anywhere else in the file.
+fn detect_include_guard(source: &str, chunks: &[NodeChunkInternal]) -> Option<usize> {
+    let is_blank = |chunk: &NodeChunkInternal| match chunk {
+        NodeChunkInternal::Text { range, .. } => source[range.clone()].lines().all(|line| {
+            let line = line.trim();
+
+            line.is_empty() || line.starts_with("//")
+        }),
+        NodeChunkInternal::PragmaOnce { .. } => true,
+        _ => false,
+    };
+
+    let mut significant = (0..chunks.len()).filter(|index| !is_blank(&chunks[*index]));
+
+    let first = significant.next()?;
+    let define = significant.next()?;
+    let last = significant.next_back()?;
+
+    match (&chunks[first], &chunks[define], &chunks[last]) {
+        (
+            NodeChunkInternal::Conditional {
+                condition: Condition::NotDefined(guard),
+                next,
+                ..
+            },
+            NodeChunkInternal::Define { name, .. },
+            NodeChunkInternal::EndIf { .. },
+        ) if guard == name && *next == last => {
+            let references = source
+                .match_indices(guard.as_str())
+                .filter(|(start, _)| {
+                    let is_identifier = |c: char| c.is_ascii_alphanumeric() || c == '_';
+                    let before = source[..*start].chars().next_back();
+                    let after = source[start + guard.len()..].chars().next();
+
+                    !before.map(is_identifier).unwrap_or(false)
+                        && !after.map(is_identifier).unwrap_or(false)
+                })
+                .count();
+
+            // One reference in the `#ifndef` line and one in the `#define` line.
+            if references == 2 {
+                Some(define)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Returns the (zero-based) number of the line in `source` that contains the byte at `offset`.
+fn line_number_at(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count()
+}
+
+/// Returns the (zero-based) numbers of the lines spanned by `text`, which starts on the
+/// (zero-based) line `first_line`; a trailing newline belongs to the last line.
+fn line_span(text: &str, first_line: usize) -> Range<usize> {
+    let body = text.strip_suffix('\n').unwrap_or(text);
+
+    first_line..first_line + body.matches('\n').count() + 1
+}
+
+/// Returns the byte offset of the start of the (zero-based) line `line_number` in `source`.
+fn line_offset(source: &str, line_number: usize) -> usize {
+    match line_number.checked_sub(1) {
+        Some(n) => source
+            .match_indices('\n')
+            .nth(n)
+            .map(|(offset, _)| offset + 1)
+            .unwrap_or(source.len()),
+        None => 0,
+    }
+}
+
+/// Returns the (zero-based) column, in characters, of the byte at `offset` in `source`.
+fn column_at(source: &str, offset: usize) -> usize {
+    let line_start = source[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+
+    source[line_start..offset].chars().count()
+}
+
+/// Pushes the chunk for an include directive for `target` that resolved to `resolved`, preceded by
+/// a warning if the file only matched case-insensitively; if no file was found, the chunk records
+/// the `candidates`.
+fn push_include(
+    chunk_buffer: &mut Vec<NodeChunkInternal>,
+    target: &IncludePath,
+    resolved: Option<ResolvedInclude>,
+    optional: bool,
+    line_number: usize,
+    candidates: &dyn Fn() -> Vec<PathBuf>,
+) {
+    let chunk = match resolved {
+        Some(resolved) => {
+            let warnings = resolved.warnings(target);
 
-                buf.push(&path);
+            if let Some(message) = warnings.case_mismatch {
+                chunk_buffer.push(NodeChunkInternal::Warning {
+                    message,
+                    line_number,
+                });
+            }
 
-                ParseError {
-                    source_file: buf,
+            if let Some(message) = warnings.shadowing {
+                chunk_buffer.push(NodeChunkInternal::Diagnostic {
+                    severity: Severity::Warning,
+                    message,
                     line_number,
-                    source: source.clone(),
-                    message: err.to_string(),
-                }
-            })?;
+                });
+            }
 
-            let pos = source_len - new_remainder.len();
+            NodeChunkInternal::Include {
+                path: resolved.path,
+                search_path_index: resolved.search_path_index,
+                include_path: IncludePathBuf::new(target),
+                line_number,
+            }
+        }
+        None => NodeChunkInternal::MissingInclude {
+            included_path: target.path().to_path_buf(),
+            optional,
+            line_number,
+            candidates: candidates(),
+        },
+    };
 
-            if line == Line::Text {
-                current_text_range.end = pos;
-            } else {
-                let range = mem::replace(&mut current_text_range, pos..pos);
+    chunk_buffer.push(chunk);
+}
 
-                if range.len() > 0 {
-                    chunk_buffer.push(NodeChunkInternal::Text(range))
-                }
-            }
+/// The paths at which `include_path` is looked for without being found, see
+/// [SourceTracker::track_missing].
+///
+/// The include path is resolved again to collect them, as this is only needed for an include
+/// path that is not found.
+fn missing_candidates(
+    include_path: &IncludePath,
+    including_file: &Path,
+    search_paths: &SearchPaths,
+    file_provider: &dyn FileProvider,
+    after: Option<usize>,
+) -> Vec<PathBuf> {
+    let probing = ProbingProvider {
+        inner: file_provider,
+        probes: Mutex::new(Vec::new()),
+    };
 
-            match line {
-                Line::Include(target) => {
-                    let resolved = try_resolve_include_path(
-                        target,
-                        (path.as_ref(), &source, line_number),
-                        search_paths,
-                    )?;
+    let _ = resolve_include_path(
+        include_path,
+        including_file,
+        search_paths,
+        &probing,
+        file_provider,
+        after,
+    );
 
-                    chunk_buffer.push(NodeChunkInternal::Include(resolved));
-                }
-                Line::PragmaOnce => {
-                    once = true;
-                }
-                Line::Text => (),
-            }
+    probing
+        .probes
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .filter(|candidate| !candidate.exists)
+        .map(|candidate| candidate.path)
+        .collect()
+}
 
-            remainder = new_remainder;
-            line_number += 1;
-        }
+/// Resolves an include path, returning the resolved file together with the index (into the
+/// quoted search order, i.e. the quoted paths followed by the base paths) of the search path it
+/// was found under, if any.
+///
+/// If `after` is given, only the search paths after that index are considered (`#include_next`).
+/// The search paths are those of the [SearchPathGroup] that matches the include path, or the
+/// default base and quoted paths if no group matches.
+///
+/// The [SearchPaths] aliases are applied to the include path first. An include path with a
+/// leading `/` is resolved against the [SearchPaths::root_path]; for
+/// `#include_next` the base paths after `after` are searched instead.
+///
+/// If a [ResolutionTracer] is set, it is passed the files that were probed for the directive on
+/// line `line_number` of `including_file`.
+fn try_resolve_include_path(
+    include_path: &IncludePath,
+    including_file: &Path,
+    line_number: usize,
+    search_paths: &SearchPaths,
+    file_provider: &dyn FileProvider,
+    after: Option<usize>,
+) -> Result<Option<ResolvedInclude>, ResolveError> {
+    let tracer = if let Some(tracer) = search_paths.tracer() {
+        tracer
+    } else {
+        return resolve_include_path(
+            include_path,
+            including_file,
+            search_paths,
+            file_provider,
+            file_provider,
+            after,
+        );
+    };
 
-        if current_text_range.len() != 0 {
-            chunk_buffer.push(NodeChunkInternal::Text(current_text_range))
-        }
+    let probing = ProbingProvider {
+        inner: file_provider,
+        probes: Mutex::new(Vec::new()),
+    };
 
-        let mut hasher = DefaultHasher::new();
+    let resolved = resolve_include_path(
+        include_path,
+        including_file,
+        search_paths,
+        &probing,
+        file_provider,
+        after,
+    );
 
-        path.hash(&mut hasher);
+    let include_path_string = match include_path {
+        IncludePath::Angle(path) => format!("<{}>", path.display()),
+        IncludePath::Quote(path) => format!("\"{}\"", path.display()),
+    };
 
-        let key = hasher.finish();
+    tracer.trace(ResolutionTrace {
+        source_file: including_file.to_path_buf(),
+        line_number,
+        include_path: include_path_string,
+        group: search_paths
+            .group_for(&search_paths.remap(include_path.path()))
+            .map(|group| group.pattern.clone()),
+        candidates: probing.probes.into_inner().unwrap(),
+        resolved_path: resolved
+            .as_ref()
+            .ok()
+            .and_then(|resolved| resolved.as_ref())
+            .map(|resolved| resolved.path.clone()),
+    });
 
-        Ok(ParsedNode {
-            path,
-            key,
-            once,
-            source,
-            chunk_buffer,
-        })
+    resolved
+}
+
+/// A [FileProvider] that records the paths that are checked for existence.
+#[derive(Debug)]
+struct ProbingProvider<'a> {
+    inner: &'a dyn FileProvider,
+    probes: Mutex<Vec<ResolutionCandidate>>,
+}
+
+impl FileProvider for ProbingProvider<'_> {
+    fn read(&self, path: &Path) -> Result<String, IOError> {
+        self.inner.read(path)
     }
 
-    fn path(&self) -> &Path {
-        self.path.as_ref()
+    fn exists(&self, path: &Path) -> bool {
+        let exists = self.inner.exists(path);
+
+        self.probes.lock().unwrap().push(ResolutionCandidate {
+            path: path.to_path_buf(),
+            exists,
+        });
+
+        exists
     }
 
-    fn key(&self) -> u64 {
-        self.key
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, IOError> {
+        self.inner.canonicalize(path)
     }
 
-    fn source(&self) -> &str {
-        &self.source
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, IOError> {
+        self.inner.read_dir(path)
     }
 
-    fn once(&self) -> bool {
-        self.once
+    fn modified(&self, path: &Path) -> Option<(SystemTime, u64)> {
+        self.inner.modified(path)
     }
+}
 
-    fn get_chunk(&self, index: usize) -> Option<NodeChunk> {
-        self.chunk_buffer.get(index).map(|chunk| match chunk {
-            NodeChunkInternal::Text(range) => NodeChunk::Text(TextChunk {
-                byte_range: range.clone(),
-                text: &self.source[range.clone()],
-            }),
-            NodeChunkInternal::Include(path) => NodeChunk::Include(path.as_path()),
-        })
+/// A [FileProvider] that records the queries that are made to it (other than reads), see
+/// [CacheEntry].
+#[derive(Debug)]
+struct RecordingProvider<'a> {
+    inner: &'a dyn FileProvider,
+    queries: Mutex<Vec<FileQuery>>,
+}
+
+impl RecordingProvider<'_> {
+    fn record(&self, query: FileQuery) {
+        self.queries.lock().unwrap().push(query);
     }
+}
 
-    fn chunks<'a>(&'a self) -> NodeChunks {
-        let ParsedNode {
-            source,
-            chunk_buffer,
-            ..
-        } = self;
+impl FileProvider for RecordingProvider<'_> {
+    fn read(&self, path: &Path) -> Result<String, IOError> {
+        self.inner.read(path)
+    }
 
-        NodeChunks {
-            source,
-            chunks: chunk_buffer.iter(),
-        }
+    fn exists(&self, path: &Path) -> bool {
+        let exists = self.inner.exists(path);
+
+        self.record(FileQuery::Exists(path.to_path_buf(), exists));
+
+        exists
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, IOError> {
+        let canonical = self.inner.canonicalize(path);
+
+        self.record(FileQuery::Canonicalize(
+            path.to_path_buf(),
+            canonical.as_ref().ok().cloned(),
+        ));
+
+        canonical
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, IOError> {
+        let entries = self.inner.read_dir(path);
+
+        self.record(FileQuery::ReadDir(
+            path.to_path_buf(),
+            entries.as_ref().ok().cloned(),
+        ));
+
+        entries
+    }
+
+    fn modified(&self, path: &Path) -> Option<(SystemTime, u64)> {
+        self.inner.modified(path)
     }
 }
 
-struct NodeChunks<'a> {
-    source: &'a String,
-    chunks: slice::Iter<'a, NodeChunkInternal>,
+/// Resolves an include path, see [try_resolve_include_path].
+///
+/// The files that the include path also matches under later search paths are looked up through
+/// `shadow_provider`, so that they are not traced as candidates.
+fn resolve_include_path(
+    include_path: &IncludePath,
+    including_file: &Path,
+    search_paths: &SearchPaths,
+    file_provider: &dyn FileProvider,
+    shadow_provider: &dyn FileProvider,
+    after: Option<usize>,
+) -> Result<Option<ResolvedInclude>, ResolveError> {
+    let search = |candidates: &mut dyn Iterator<Item = (usize, &PathBuf)>,
+                  path: &Path|
+     -> Result<Option<(FoundFile, Option<usize>)>, ResolveError> {
+        let mut candidates =
+            candidates.filter(|(index, _)| after.map(|after| *index > after).unwrap_or(true));
+
+        while let Some((index, search_path)) = candidates.next() {
+            if let Some(mut file) = find_file(search_path, path, search_paths, file_provider)? {
+                // With `#include_next`, files in later search paths are expected to be included
+                // in turn.
+                if after.is_none() {
+                    let canonical = shadow_provider.canonicalize(&file.path).ok();
+
+                    file.shadowed = candidates
+                        .filter_map(|(_, search_path)| {
+                            find_file(search_path, path, search_paths, shadow_provider)
+                                .ok()
+                                .flatten()
+                        })
+                        .map(|shadowed| shadowed.path)
+                        .filter(|shadowed| shadow_provider.canonicalize(shadowed).ok() != canonical)
+                        .collect();
+                }
+
+                return Ok(Some((file, Some(index))));
+            }
+        }
+
+        Ok(None)
+    };
+
+    let literal_path = include_path.path();
+
+    let custom = match search_paths.resolver() {
+        Some(resolver) if after.is_none() => {
+            resolver.resolve(include_path, including_file, search_paths)
+        }
+        _ => Resolution::Unhandled,
+    };
+
+    let custom = match custom {
+        Resolution::Resolved(path) if file_provider.exists(&path) => {
+            let file = FoundFile {
+                path,
+                case_insensitive_match: None,
+                shadowed: Vec::new(),
+            };
+
+            Some((file, None))
+        }
+        Resolution::Resolved(path) => {
+            return Err(ResolveError::Failed(format!(
+                "include resolves to `{}`, which does not exist",
+                path.display()
+            )))
+        }
+        Resolution::Unhandled => None,
+        Resolution::Failed(message) => return Err(ResolveError::Failed(message)),
+    };
+
+    let remapped = search_paths.remap(literal_path);
+    let include_path = match quoted_semantics(include_path, search_paths) {
+        IncludePath::Angle(_) => IncludePath::Angle(&remapped),
+        IncludePath::Quote(_) => IncludePath::Quote(&remapped),
+    };
+
+    let (base_paths, quoted_paths) = search_paths.paths_for(include_path.path());
+
+    // Base paths are at the end of the quoted search order, so that angle and quoted includes
+    // share the same indices.
+    let quoted_len = quoted_paths.len();
+    let quoted_search_order = || quoted_paths.iter().chain(base_paths.iter()).enumerate();
+
+    let rooted = include_path.path().strip_prefix("/").ok();
+
+    let resolved = match (&include_path, rooted) {
+        _ if custom.is_some() => Ok(custom),
+        (_, Some(path)) if after.is_none() => {
+            if let Some(root_path) = search_paths.root_path() {
+                let index = base_paths
+                    .iter()
+                    .position(|base_path| base_path == root_path)
+                    .map(|index| quoted_len + index);
+
+                Ok(find_file(root_path, path, search_paths, file_provider)?
+                    .map(|file| (file, index)))
+            } else {
+                Ok(None)
+            }
+        }
+        (&IncludePath::Angle(path), None) if search_paths.angle_quoted_search() => {
+            search(&mut quoted_search_order(), path)
+        }
+        (_, Some(path)) | (&IncludePath::Angle(path), None) => {
+            let mut candidates = base_paths
+                .iter()
+                .enumerate()
+                .map(|(index, search_path)| (quoted_len + index, search_path));
+
+            search(&mut candidates, path)
+        }
+        (&IncludePath::Quote(path), None) => {
+            let relative = if after.is_none() {
+                find_file(
+                    including_directory(including_file)?,
+                    path,
+                    search_paths,
+                    file_provider,
+                )?
+            } else {
+                None
+            };
+
+            if let Some(relative) = relative {
+                Ok(Some((relative, None)))
+            } else if search_paths.quoted_fallback() {
+                search(&mut quoted_search_order(), path)
+            } else {
+                Ok(None)
+            }
+        }
+    }?;
+
+    resolved
+        .map(|(file, search_path_index)| {
+            check_resolved(
+                file,
+                search_path_index,
+                literal_path,
+                search_paths,
+                file_provider,
+            )
+        })
+        .transpose()
 }
 
-impl<'a> Iterator for NodeChunks<'a> {
-    type Item = NodeChunk<'a>;
+/// Normalizes the path of a `file` that was found for the include path `literal_path`, and checks
+/// it against the [SearchPaths::set_sandbox_root] and the [SearchPaths::push_deny_pattern]
+/// patterns.
+fn check_resolved(
+    file: FoundFile,
+    search_path_index: Option<usize>,
+    literal_path: &Path,
+    search_paths: &SearchPaths,
+    file_provider: &dyn FileProvider,
+) -> Result<ResolvedInclude, ResolveError> {
+    let path = search_paths.normalize(&file.path, file_provider)?;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let NodeChunks { source, chunks } = self;
+    if let Some(sandbox_root) = search_paths.sandbox_root() {
+        let sandbox_root = search_paths
+            .normalize(sandbox_root, file_provider)
+            .unwrap_or_else(|_| sandbox_root.to_path_buf());
+
+        if !path.starts_with(sandbox_root) {
+            return Err(ResolveError::Escaped {
+                included_path: literal_path.to_path_buf(),
+                resolved_path: path,
+            });
+        }
+    }
+
+    let denied = search_paths.deny_patterns().find(|pattern| {
+        glob::matches_path(pattern, literal_path) || glob::matches_path(pattern, &path)
+    });
+
+    if let Some(pattern) = denied {
+        return Err(ResolveError::Denied {
+            pattern: pattern.to_string(),
+            resolved_path: path,
+        });
+    }
 
-        if let Some(chunk) = chunks.next() {
-            let chunk = match chunk {
-                NodeChunkInternal::Text(range) => NodeChunk::Text(TextChunk {
-                    byte_range: range.clone(),
-                    text: &source[range.clone()],
-                }),
-                NodeChunkInternal::Include(path) => NodeChunk::Include(path),
+    Ok(ResolvedInclude {
+        path,
+        search_path_index,
+        case_insensitive_match: file.case_insensitive_match,
+        shadowed: file.shadowed,
+    })
+}
+
+/// Resolves an include path that contains glob metacharacters (see [IncludePath::is_glob]) to
+/// every file that it matches, searching the same directories as [try_resolve_include_path].
+///
+/// The files are returned in search path order, sorted by path within each search path. A file
+/// that is matched under more than one search path is only returned once.
+fn try_resolve_glob_include_path(
+    include_path: &IncludePath,
+    including_file: &Path,
+    search_paths: &SearchPaths,
+    file_provider: &dyn FileProvider,
+) -> Result<Vec<ResolvedInclude>, ResolveError> {
+    let literal_path = include_path.path();
+    let remapped = search_paths.remap(literal_path);
+    let (base_paths, quoted_paths) = search_paths.paths_for(&remapped);
+    let quoted_len = quoted_paths.len();
+    let quoted_search_order = quoted_paths.iter().chain(base_paths.iter()).enumerate();
+
+    let (pattern, directories): (&Path, Vec<(Option<usize>, &Path)>) = match (
+        quoted_semantics(include_path, search_paths),
+        remapped.strip_prefix("/"),
+    ) {
+        (_, Ok(pattern)) => {
+            let directories = search_paths
+                .root_path()
+                .map(|root_path| {
+                    let index = base_paths
+                        .iter()
+                        .position(|base_path| base_path == root_path)
+                        .map(|index| quoted_len + index);
+
+                    (index, root_path)
+                })
+                .into_iter()
+                .collect();
+
+            (pattern, directories)
+        }
+        (IncludePath::Angle(_), _) if search_paths.angle_quoted_search() => (
+            &remapped,
+            quoted_search_order
+                .map(|(index, path)| (Some(index), path.as_path()))
+                .collect(),
+        ),
+        (IncludePath::Angle(_), _) => (
+            &remapped,
+            base_paths
+                .iter()
+                .enumerate()
+                .map(|(index, path)| (Some(quoted_len + index), path.as_path()))
+                .collect(),
+        ),
+        (IncludePath::Quote(_), _) => {
+            let mut directories = vec![(None, including_directory(including_file)?)];
+
+            if search_paths.quoted_fallback() {
+                directories
+                    .extend(quoted_search_order.map(|(index, path)| (Some(index), path.as_path())));
+            }
+
+            (&remapped, directories)
+        }
+    };
+
+    let mut seen = HashSet::new();
+    let mut resolved = Vec::new();
+
+    for (search_path_index, directory) in directories {
+        check_within_root(directory, pattern)?;
+
+        for path in find_glob_matches(directory, pattern, file_provider) {
+            let file = FoundFile {
+                path,
+                case_insensitive_match: None,
+                shadowed: Vec::new(),
             };
+            let include = check_resolved(
+                file,
+                search_path_index,
+                literal_path,
+                search_paths,
+                file_provider,
+            )?;
 
-            Some(chunk)
-        } else {
-            None
+            if seen.insert(include.path.clone()) {
+                resolved.push(include);
+            }
         }
     }
+
+    Ok(resolved)
 }
 
-pub struct SourceMappedChunk<'a> {
-    text: &'a str,
-    source_path: &'a Path,
-    source_range: Range<usize>,
+/// Converts an angle include path into a quoted include path if angle includes are resolved like
+/// quoted includes, see [SearchPaths::set_angle_as_quoted].
+fn quoted_semantics<'a>(
+    include_path: &IncludePath<'a>,
+    search_paths: &SearchPaths,
+) -> IncludePath<'a> {
+    match *include_path {
+        IncludePath::Angle(path) if search_paths.angle_as_quoted() => IncludePath::Quote(path),
+        IncludePath::Angle(path) => IncludePath::Angle(path),
+        IncludePath::Quote(path) => IncludePath::Quote(path),
+    }
 }
 
-impl<'a> SourceMappedChunk<'a> {
-    pub fn text(&self) -> &str {
-        &self.text
+/// The directory that a quoted include path is resolved against first.
+fn including_directory(including_file: &Path) -> Result<&Path, ResolveError> {
+    including_file.parent().ok_or_else(|| {
+        ResolveError::Failed(format!(
+            "`{}` has no parent directory",
+            including_file.display()
+        ))
+    })
+}
+
+/// Fails if joining `path` to `directory` leads above the root, e.g. `../a.glsl` in `/`.
+fn check_within_root(directory: &Path, path: &Path) -> Result<(), ResolveError> {
+    if file_provider::try_normalize(&directory.join(path)).is_some() {
+        Ok(())
+    } else {
+        Err(ResolveError::Failed(format!(
+            "include path `{}` leads above the root of `{}`",
+            path.display(),
+            directory.display()
+        )))
     }
+}
 
-    pub fn source_path(&self) -> &Path {
-        &self.source_path
+/// An owned [IncludePath].
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum IncludePathBuf {
+    Angle(PathBuf),
+    Quote(PathBuf),
+}
+
+impl IncludePathBuf {
+    fn new(include_path: &IncludePath) -> Self {
+        match include_path {
+            IncludePath::Angle(path) => IncludePathBuf::Angle(path.to_path_buf()),
+            IncludePath::Quote(path) => IncludePathBuf::Quote(path.to_path_buf()),
+        }
     }
 
-    pub fn source_range(&self) -> Range<usize> {
-        self.source_range.clone()
+    fn as_include_path(&self) -> IncludePath<'_> {
+        match self {
+            IncludePathBuf::Angle(path) => IncludePath::Angle(path),
+            IncludePathBuf::Quote(path) => IncludePath::Quote(path),
+        }
     }
 }
 
-pub trait OutputSink {
-    fn sink(&mut self, chunk: &str);
-
-    fn sink_source_mapped(&mut self, source_mapped_chunk: SourceMappedChunk);
+struct ResolvedInclude {
+    path: PathBuf,
+    search_path_index: Option<usize>,
+    /// If the file only matched case-insensitively, its path relative to the search directory.
+    case_insensitive_match: Option<PathBuf>,
+    /// The files that the include also matches under later search paths.
+    shadowed: Vec<PathBuf>,
 }
 
-impl OutputSink for String {
-    fn sink(&mut self, chunk: &str) {
-        self.push_str(chunk);
-    }
+impl ResolvedInclude {
+    /// The messages to warn with for the `include_path` this file was resolved from.
+    fn warnings(&self, include_path: &IncludePath) -> IncludeWarnings {
+        let case_mismatch = self.case_insensitive_match.as_ref().map(|matched| {
+            format!(
+                "include `{}` only matches `{}` case-insensitively",
+                include_path.path().display(),
+                matched.display()
+            )
+        });
+
+        let shadowing = if self.shadowed.is_empty() {
+            None
+        } else {
+            let shadowed: Vec<String> = self
+                .shadowed
+                .iter()
+                .map(|path| format!("`{}`", path.display()))
+                .collect();
+
+            Some(format!(
+                "include `{}` resolves to `{}`, which shadows {}",
+                include_path.path().display(),
+                self.path.display(),
+                shadowed.join(", ")
+            ))
+        };
 
-    fn sink_source_mapped(&mut self, source_mapped_chunk: SourceMappedChunk) {
-        self.push_str(source_mapped_chunk.text)
+        IncludeWarnings {
+            case_mismatch,
+            shadowing,
+        }
     }
 }
 
-pub trait SourceTracker {
-    fn track(&mut self, path: &Path, source: &str);
+struct IncludeWarnings {
+    /// The file only matched case-insensitively.
+    case_mismatch: Option<String>,
+    /// The include also matches files under later search paths.
+    shadowing: Option<String>,
 }
 
-fn try_resolve_include_path(
-    include_path: IncludePath,
-    included_from: (&Path, &str, usize),
+struct FoundFile {
+    path: PathBuf,
+    case_insensitive_match: Option<PathBuf>,
+    shadowed: Vec<PathBuf>,
+}
+
+/// Finds the file for `path` in `directory`, trying the [SearchPaths::extensions] if `path` does
+/// not exist as written, and then a case-insensitive match if enabled.
+fn find_file(
+    directory: &Path,
+    path: &Path,
     search_paths: &SearchPaths,
-) -> Result<PathBuf, Error> {
-    let mut resolved = None;
+    file_provider: &dyn FileProvider,
+) -> Result<Option<FoundFile>, ResolveError> {
+    check_within_root(directory, path)?;
+
+    let exact = directory.join(path);
 
-    let path = match include_path {
-        IncludePath::Angle(path) => {
-            for search_path in search_paths.base_paths() {
-                let join = search_path.join(path);
+    if file_provider.exists(&exact) {
+        return Ok(Some(FoundFile {
+            path: exact,
+            case_insensitive_match: None,
+            shadowed: Vec::new(),
+        }));
+    }
 
-                if join.is_file() {
-                    resolved = Some(join);
+    let with_extensions: Vec<PathBuf> = search_paths
+        .extensions()
+        .map(|extension| {
+            let mut candidate = path.as_os_str().to_os_string();
 
-                    break;
+            candidate.push(extension);
+
+            PathBuf::from(candidate)
+        })
+        .collect();
+
+    let mut candidates: Vec<PathBuf> = with_extensions
+        .iter()
+        .map(|candidate| directory.join(candidate))
+        .filter(|candidate| file_provider.exists(candidate))
+        .collect();
+    let mut case_insensitive = false;
+
+    if candidates.is_empty() && search_paths.case_insensitive() {
+        for candidate in iter::once(path).chain(with_extensions.iter().map(PathBuf::as_path)) {
+            candidates.extend(find_case_insensitive(directory, candidate, file_provider));
+        }
+
+        candidates.sort();
+        candidates.dedup();
+
+        case_insensitive = true;
+    }
+
+    if candidates.len() > 1 {
+        return Err(ResolveError::Ambiguous { candidates });
+    }
+
+    Ok(candidates.pop().map(|path| {
+        let case_insensitive_match = if case_insensitive {
+            Some(path.strip_prefix(directory).unwrap_or(&path).to_path_buf())
+        } else {
+            None
+        };
+
+        FoundFile {
+            path,
+            case_insensitive_match,
+            shadowed: Vec::new(),
+        }
+    }))
+}
+
+/// Finds all files in `directory` that match the glob `pattern`, sorted by path.
+///
+/// A `**` component matches any number of directories.
+fn find_glob_matches(
+    directory: &Path,
+    pattern: &Path,
+    file_provider: &dyn FileProvider,
+) -> Vec<PathBuf> {
+    let mut matches = vec![directory.to_path_buf()];
+
+    for component in pattern.components() {
+        let name = component.as_os_str().to_string_lossy();
+
+        if name == "**" {
+            let mut pending = mem::take(&mut matches);
+
+            // Every entry that can be read as a directory is a directory.
+            while let Some(parent) = pending.pop() {
+                if let Ok(entries) = file_provider.read_dir(&parent) {
+                    pending.extend(entries);
+                    matches.push(parent);
                 }
             }
-
-            path
+        } else if name.contains(['*', '?']) {
+            matches = matches
+                .iter()
+                .flat_map(|parent| file_provider.read_dir(parent).unwrap_or_default())
+                .filter(|entry| {
+                    entry
+                        .file_name()
+                        .map(|file_name| glob::matches_path(&name, Path::new(file_name)))
+                        .unwrap_or(false)
+                })
+                .collect();
+        } else {
+            matches = matches.iter().map(|m| m.join(component)).collect();
         }
-        IncludePath::Quote(path) => {
-            let join = included_from.0.parent().unwrap().join(path);
+    }
+
+    matches.retain(|m| file_provider.exists(m));
+    matches.sort();
+    matches.dedup();
+
+    matches
+}
+
+/// Finds all files in `directory` that match `path` when compared case-insensitively, component
+/// by component.
+fn find_case_insensitive(
+    directory: &Path,
+    path: &Path,
+    file_provider: &dyn FileProvider,
+) -> Vec<PathBuf> {
+    let mut matches = vec![directory.to_path_buf()];
+
+    for component in path.components() {
+        let name = if let Component::Normal(name) = component {
+            name.to_string_lossy().to_lowercase()
+        } else {
+            matches = matches.iter().map(|m| m.join(component)).collect();
 
-            if join.is_file() {
-                resolved = Some(join);
+            continue;
+        };
+
+        let mut next = Vec::new();
+
+        for parent in matches {
+            // Not a directory (or does not exist)
+            let entries = if let Ok(entries) = file_provider.read_dir(&parent) {
+                entries
             } else {
-                for search_path in search_paths.quoted_paths() {
-                    let join = search_path.join(path);
+                continue;
+            };
 
-                    if join.is_file() {
-                        resolved = Some(join);
+            for entry in entries {
+                let matches = entry
+                    .file_name()
+                    .map(|file_name| file_name.to_string_lossy().to_lowercase() == name)
+                    .unwrap_or(false);
 
-                        break;
-                    }
+                if matches {
+                    next.push(entry);
                 }
             }
-
-            path
         }
-    };
 
-    if let Some(resolved) = resolved {
-        Ok(resolved.canonicalize()?)
-    } else {
-        Err(FileNotFoundError {
-            included_path: path.to_path_buf(),
-            source_file: included_from.0.to_path_buf(),
-            source: included_from.1.to_string(),
-            line_number: included_from.2,
+        matches = next;
+    }
+
+    matches.retain(|m| file_provider.exists(m));
+
+    matches
+}
+
+/// An error that occurred while resolving an include path.
+enum ResolveError {
+    IO(FileAccessError),
+    /// More than one file matches the include path.
+    Ambiguous {
+        candidates: Vec<PathBuf>,
+    },
+    /// A custom [Resolver] failed with a message.
+    Failed(String),
+    /// The include matches a deny pattern.
+    Denied {
+        pattern: String,
+        resolved_path: PathBuf,
+    },
+    /// The include resolves to a file outside of the sandbox root.
+    Escaped {
+        included_path: PathBuf,
+        resolved_path: PathBuf,
+    },
+}
+
+impl ResolveError {
+    /// Converts into an [Error] for the include directive on line `line_number` of
+    /// `source_file`.
+    fn into_error(self, source_file: &Path, source: &str, line_number: usize) -> Error {
+        match self {
+            ResolveError::IO(err) => FileAccessError {
+                included_from: Some((source_file.to_path_buf(), line_number)),
+                ..err
+            }
+            .into(),
+            ResolveError::Ambiguous { candidates } => {
+                let candidates: Vec<String> = candidates
+                    .iter()
+                    .map(|candidate| format!("`{}`", candidate.display()))
+                    .collect();
+
+                let message = format!("ambiguous include, it matches {}", candidates.join(" and "));
+
+                ParseError::new(message, source_file, source, line_number).into()
+            }
+            ResolveError::Failed(message) => {
+                ParseError::new(message, source_file, source, line_number).into()
+            }
+            ResolveError::Denied {
+                pattern,
+                resolved_path,
+            } => DeniedIncludeError {
+                pattern,
+                resolved_path,
+                source_file: source_file.to_path_buf(),
+                source: source.to_string(),
+                line_number,
+                display_root: None,
+            }
+            .into(),
+            ResolveError::Escaped {
+                included_path,
+                resolved_path,
+            } => SandboxEscapeError {
+                included_path,
+                resolved_path,
+                source_file: source_file.to_path_buf(),
+                source: source.to_string(),
+                line_number,
+                display_root: None,
+            }
+            .into(),
         }
-        .into())
+    }
+}
+
+impl From<FileAccessError> for ResolveError {
+    fn from(err: FileAccessError) -> Self {
+        ResolveError::IO(err)
     }
 }
@@ -1,7 +1,40 @@
+mod blank_lines;
+mod comments;
+mod depfile;
+mod diagnostic;
+mod expression;
+mod file_provider;
+mod glob;
 mod include_preprocessor;
 mod line_parser;
+mod macros;
+mod minify;
+#[cfg(feature = "serde")]
+mod serialize;
+mod source_map;
 
+pub use self::depfile::{write_depfile, DepfileTracker};
+pub use self::file_provider::{FileProvider, MemoryFs, OverlayProvider, StdFs};
+#[cfg(feature = "tokio")]
+pub use self::include_preprocessor::preprocess_async;
 pub use self::include_preprocessor::{
-    preprocess, Error, FileNotFoundError, OutputSink, ParseError, SearchPaths, SourceMappedChunk,
-    SourceTracker,
+    expand_home, parse_tree, parse_tree_with_options, preprocess, preprocess_batch,
+    preprocess_dependencies, preprocess_dependencies_with_options, preprocess_include_graph,
+    preprocess_include_graph_with_options, preprocess_reader, preprocess_str,
+    preprocess_str_with_options, preprocess_to_writer, preprocess_untracked,
+    preprocess_with_diagnostics, preprocess_with_options, preprocess_with_report,
+    CancellationToken, Chunk, CommentReplacement, CommentStyle, Concurrency, ContentFilter,
+    CountingSink, Counts, CustomDirective, DefinedMacro, DeniedIncludeError, Diagnostic,
+    DiagnosticHandler, DirectiveAction, DirectiveError, DirectiveHandler, DirectiveKind, Error,
+    ErrorKind, FileAccessError, FileNotFoundError, FileOperation, FmtSink, HomeDirError,
+    IncludeCycleError, IncludeEdge, IncludeGraph, IncludeMarkers, IncludeNewline,
+    InvalidSearchPath, InvalidSearchPathReason, IoSink, LineDirectiveMode, LineEndings, LoadEvent,
+    LoadProgress, MinifySink, NoopTracker, Options, OutputSink, ParseError, ParsedFile, ParsedTree,
+    PathNormalization, PreprocessCache, PreprocessReport, PreprocessStats, Preprocessor,
+    ProgressObserver, Resolution, ResolutionCandidate, ResolutionCollector, ResolutionTrace,
+    ResolutionTracer, Resolver, SandboxEscapeError, SearchPathGroup, SearchPathValidation,
+    SearchPaths, Session, Severity, SizeLimit, SizeLimitError, SourceMappedChunk, SourceTracker,
+    TeeError, TeeSink, UnterminatedConditionalError,
 };
+pub use self::line_parser::{IncludeKind, IncludePath};
+pub use self::source_map::{OutputPosition, SourceMap, SourceMapBuilder, SourcePosition};
@@ -1,22 +1,76 @@
+use std::borrow::Cow;
 use std::fmt;
 use std::path::Path;
 
 use nom::branch::alt;
 use nom::bytes::complete::{is_not, tag};
-use nom::character::complete::{char, line_ending, not_line_ending, space0, space1};
-use nom::combinator::{not, opt, peek};
-use nom::error::{ErrorKind, ParseError};
-use nom::sequence::{delimited, tuple};
+use nom::character::complete::{
+    alpha1, alphanumeric1, char, digit1, line_ending, not_line_ending, space0, space1,
+};
+use nom::combinator::{all_consuming, cut, eof, map, map_res, opt, peek, recognize, verify};
+use nom::error::{ErrorKind, FromExternalError, ParseError};
+use nom::multi::{many0_count, separated_list0};
+use nom::sequence::{delimited, pair, preceded, tuple};
 use nom::IResult;
 
 #[derive(PartialEq, Debug)]
 pub enum Line<'a> {
     Text,
     Include(IncludePath<'a>),
+    IncludeMacro(&'a str),
+    IncludeNext(IncludePath<'a>),
+    IncludeOptional(IncludePath<'a>),
     PragmaOnce,
+    Define(Define<'a>),
+    Undef(&'a str),
+    If(&'a str),
+    IfDef(&'a str),
+    IfNDef(&'a str),
+    Elif(&'a str),
+    Else,
+    EndIf,
+    Error(&'a str),
+    Warning(&'a str),
+    Message(&'a str),
+    /// A custom directive, see [parse_custom_line].
+    Custom {
+        name: &'a str,
+        argument: &'a str,
+    },
+    Renumber {
+        number: usize,
+        file: Option<&'a str>,
+    },
 }
 
-pub struct Error;
+#[derive(PartialEq, Debug)]
+pub struct Define<'a> {
+    pub name: &'a str,
+    pub parameters: Option<Vec<&'a str>>,
+    /// The body without its comments, see [strip_body_comments].
+    pub body: Cow<'a, str>,
+}
+
+pub struct Error {
+    message: &'static str,
+    /// The length of the input that remained where the error occurred.
+    remaining: usize,
+}
+
+impl Error {
+    fn malformed(message: &'static str) -> impl Fn(Error) -> Error {
+        move |err| Error {
+            message,
+            remaining: err.remaining,
+        }
+    }
+
+    /// The length of the input that remained where the error occurred, from which the position
+    /// of the error can be derived.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+}
 
 impl From<Error> for nom::Err<Error> {
     fn from(err: Error) -> Self {
@@ -25,31 +79,118 @@ impl From<Error> for nom::Err<Error> {
 }
 
 impl ParseError<&'_ str> for Error {
-    fn from_error_kind(_input: &str, _kind: ErrorKind) -> Self {
-        Error
+    fn from_error_kind(input: &str, _kind: ErrorKind) -> Self {
+        Error {
+            message: "malformed directive",
+            remaining: input.len(),
+        }
     }
 
-    fn append(_input: &str, _kind: ErrorKind, _other: Self) -> Self {
-        Error
+    fn append(_input: &str, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+impl<E> FromExternalError<&'_ str, E> for Error {
+    fn from_external_error(input: &str, kind: ErrorKind, _err: E) -> Self {
+        Error::from_error_kind(input, kind)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message)
     }
 }
 
 impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "malformed `#include ...` directive")
+        f.write_str(self.message)
     }
 }
 
+/// The path of an include directive, e.g. `<lighting.glsl>` or `"lighting.glsl"`.
 #[derive(PartialEq, Debug)]
 pub enum IncludePath<'a> {
     Angle(&'a Path),
     Quote(&'a Path),
 }
 
-pub fn parse_line(input: &str) -> IResult<&str, Line, Error> {
-    alt((line_pragma_once, line_text, line_include))(input)
+/// Whether an include directive uses an [IncludePath::Angle] or an [IncludePath::Quote] path.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum IncludeKind {
+    Angle,
+    Quote,
+}
+
+impl<'a> IncludePath<'a> {
+    pub fn path(&self) -> &'a Path {
+        match self {
+            IncludePath::Angle(path) | IncludePath::Quote(path) => path,
+        }
+    }
+
+    pub fn kind(&self) -> IncludeKind {
+        match self {
+            IncludePath::Angle(_) => IncludeKind::Angle,
+            IncludePath::Quote(_) => IncludeKind::Quote,
+        }
+    }
+
+    /// Whether the path contains glob metacharacters (`*` or `?`), in which case the directive
+    /// includes every file that matches it.
+    pub fn is_glob(&self) -> bool {
+        self.path().to_string_lossy().contains(['*', '?'])
+    }
+}
+
+pub fn parse_line(input: &str) -> IResult<&str, Line<'_>, Error> {
+    alt((
+        line_pragma_once,
+        line_pragma_message,
+        line_include,
+        line_include_next,
+        line_include_optional,
+        line_define,
+        line_undef,
+        line_if,
+        line_ifdef,
+        line_ifndef,
+        line_elif,
+        line_else,
+        line_endif,
+        line_error,
+        line_warning,
+        line_line,
+        line_text,
+    ))(input)
 }
 
+/// Parses a line that starts with `prefix` as a custom directive, e.g. `#pragma ipp bind_group 2`
+/// for the prefix `#pragma ipp`; fails with a (recoverable) [nom::Err::Error] if the line does not
+/// start with the prefix, followed by whitespace or the end of the line.
+pub fn parse_custom_line<'a>(input: &'a str, prefix: &str) -> IResult<&'a str, Line<'a>, Error> {
+    let (rem, (_, name, argument, _)) = preceded(
+        pair(tag(prefix), peek(alt((space1, line_end)))),
+        cut(tuple((
+            space1,
+            is_not(" \t\r\n"),
+            opt(preceded(space1, not_line_ending)),
+            line_end,
+        ))),
+    )(input)
+    .map_err(|err| err.map(Error::malformed("malformed custom directive")))?;
+
+    Ok((
+        rem,
+        Line::Custom {
+            name,
+            argument: argument.unwrap_or("").trim_end(),
+        },
+    ))
+}
+
+/// Returns the remainder of `input` after its first line.
 pub fn skip_line(input: &str) -> &str {
     let res: IResult<&str, (&str, &str), (&str, ErrorKind)> =
         tuple((not_line_ending, line_ending))(input);
@@ -57,42 +198,306 @@ pub fn skip_line(input: &str) -> &str {
     res.unwrap_or(("", ("", ""))).0
 }
 
-fn line_text(input: &str) -> IResult<&str, Line, Error> {
-    let result: IResult<_, _, nom::error::Error<&str>> = tuple((
-        not(peek(tuple((tag("#include"), space1)))),
-        not_line_ending,
-        opt(line_ending),
-    ))(input);
-
-    let (rem, _) = result.map_err(|_| Error)?;
+fn line_text(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, _) = tuple((not_line_ending, opt(line_ending)))(input)?;
 
     Ok((rem, Line::Text))
 }
 
-fn line_pragma_once(input: &str) -> IResult<&str, Line, Error> {
-    let (rem, _) = tuple((tag("#pragma"), space1, tag("once"), space0, line_ending))(input)?;
+fn line_end(input: &str) -> IResult<&str, &str, Error> {
+    alt((line_ending, eof))(input)
+}
+
+fn line_pragma_once(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, _) = tuple((tag("#pragma"), space1, tag("once"), space0, line_end))(input)?;
 
     Ok((rem, Line::PragmaOnce))
 }
 
-fn line_include(input: &str) -> IResult<&str, Line, Error> {
-    let (rem, (_, _, path, _, _)) =
-        tuple((tag("#include"), space1, include_path, space0, line_ending))(input)?;
+fn line_pragma_message(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, (_, message, _)) = tuple((
+        pair(tag("#pragma"), pair(space1, tag("message"))),
+        opt(preceded(space1, not_line_ending)),
+        line_end,
+    ))(input)?;
+
+    Ok((rem, Line::Message(message_text(message.unwrap_or("")))))
+}
+
+fn line_include(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, (line, _, _)) = preceded(
+        pair(tag("#include"), space1),
+        cut(tuple((
+            alt((
+                map(include_path, Line::Include),
+                map(identifier, Line::IncludeMacro),
+            )),
+            space0,
+            line_end,
+        ))),
+    )(input)
+    .map_err(|err| err.map(Error::malformed("malformed `#include ...` directive")))?;
+
+    Ok((rem, line))
+}
+
+fn line_include_next(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, (path, _, _)) = preceded(
+        pair(tag("#include_next"), space1),
+        cut(tuple((include_path, space0, line_end))),
+    )(input)
+    .map_err(|err| err.map(Error::malformed("malformed `#include_next ...` directive")))?;
+
+    Ok((rem, Line::IncludeNext(path)))
+}
+
+fn line_include_optional(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, (path, _, _)) = preceded(
+        pair(tag("#include_optional"), space1),
+        cut(tuple((include_path, space0, line_end))),
+    )(input)
+    .map_err(|err| {
+        err.map(Error::malformed(
+            "malformed `#include_optional ...` directive",
+        ))
+    })?;
+
+    Ok((rem, Line::IncludeOptional(path)))
+}
+
+fn line_define(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, (name, parameters, body, _)) = preceded(
+        pair(tag("#define"), space1),
+        cut(tuple((
+            identifier,
+            opt(define_parameters),
+            define_value,
+            line_end,
+        ))),
+    )(input)
+    .map_err(|err| err.map(Error::malformed("malformed `#define ...` directive")))?;
+
+    Ok((
+        rem,
+        Line::Define(Define {
+            name,
+            parameters,
+            body,
+        }),
+    ))
+}
+
+fn line_undef(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, (name, _)) = preceded(
+        pair(tag("#undef"), space1),
+        cut(tuple((identifier, directive_end))),
+    )(input)
+    .map_err(|err| err.map(Error::malformed("malformed `#undef ...` directive")))?;
+
+    Ok((rem, Line::Undef(name)))
+}
+
+fn line_if(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, (expression, _)) = preceded(
+        pair(tag("#if"), space1),
+        cut(tuple((
+            verify(not_line_ending, |s: &str| !s.trim().is_empty()),
+            line_end,
+        ))),
+    )(input)
+    .map_err(|err| err.map(Error::malformed("malformed `#if ...` directive")))?;
+
+    Ok((rem, Line::If(expression.trim())))
+}
+
+fn line_ifdef(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, (name, _)) = preceded(
+        pair(tag("#ifdef"), space1),
+        cut(tuple((identifier, directive_end))),
+    )(input)
+    .map_err(|err| err.map(Error::malformed("malformed `#ifdef ...` directive")))?;
+
+    Ok((rem, Line::IfDef(name)))
+}
+
+fn line_ifndef(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, (name, _)) = preceded(
+        pair(tag("#ifndef"), space1),
+        cut(tuple((identifier, directive_end))),
+    )(input)
+    .map_err(|err| err.map(Error::malformed("malformed `#ifndef ...` directive")))?;
+
+    Ok((rem, Line::IfNDef(name)))
+}
+
+fn line_elif(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, (expression, _)) = preceded(
+        pair(tag("#elif"), space1),
+        cut(tuple((
+            verify(not_line_ending, |s: &str| !s.trim().is_empty()),
+            line_end,
+        ))),
+    )(input)
+    .map_err(|err| err.map(Error::malformed("malformed `#elif ...` directive")))?;
+
+    Ok((rem, Line::Elif(expression.trim())))
+}
 
-    Ok((rem, Line::Include(path)))
+fn line_else(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, _) = tuple((tag("#else"), opt(pair(space1, not_line_ending)), line_end))(input)?;
+
+    Ok((rem, Line::Else))
 }
 
-fn include_path(input: &str) -> IResult<&str, IncludePath, Error> {
+fn line_endif(input: &str) -> IResult<&str, Line<'_>, Error> {
+    // Trailing text after `#endif` (or `#else`) is commonly used to label the group that is
+    // being closed (e.g. `#endif // FOO_H`) and is ignored.
+    let (rem, _) = tuple((tag("#endif"), opt(pair(space1, not_line_ending)), line_end))(input)?;
+
+    Ok((rem, Line::EndIf))
+}
+
+fn line_error(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, (_, message, _)) = tuple((
+        tag("#error"),
+        opt(preceded(space1, not_line_ending)),
+        line_end,
+    ))(input)?;
+
+    Ok((rem, Line::Error(message_text(message.unwrap_or("")))))
+}
+
+fn line_warning(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, (_, message, _)) = tuple((
+        tag("#warning"),
+        opt(preceded(space1, not_line_ending)),
+        line_end,
+    ))(input)?;
+
+    Ok((rem, Line::Warning(message_text(message.unwrap_or("")))))
+}
+
+fn line_line(input: &str) -> IResult<&str, Line<'_>, Error> {
+    let (rem, (number, file, _)) = preceded(
+        pair(tag("#line"), space1),
+        cut(tuple((
+            map_res(digit1, |digits: &str| digits.parse()),
+            opt(preceded(
+                space1,
+                delimited(char('"'), is_not("\"\r\n"), char('"')),
+            )),
+            directive_end,
+        ))),
+    )(input)
+    .map_err(|err| err.map(Error::malformed("malformed `#line ...` directive")))?;
+
+    Ok((rem, Line::Renumber { number, file }))
+}
+
+/// Trims a directive's message text and removes the quotes if it was written as a string
+/// literal.
+fn message_text(message: &str) -> &str {
+    let message = message.trim();
+
+    message
+        .strip_prefix('"')
+        .and_then(|m| m.strip_suffix('"'))
+        .unwrap_or(message)
+}
+
+/// Matches the end of a directive line, which may be followed by a `//` comment.
+fn directive_end(input: &str) -> IResult<&str, &str, Error> {
+    recognize(tuple((
+        space0,
+        opt(pair(tag("//"), not_line_ending)),
+        line_end,
+    )))(input)
+}
+
+fn define_parameters(input: &str) -> IResult<&str, Vec<&str>, Error> {
+    delimited(
+        char('('),
+        separated_list0(char(','), delimited(space0, identifier, space0)),
+        preceded(space0, char(')')),
+    )(input)
+}
+
+fn define_value(input: &str) -> IResult<&str, Cow<'_, str>, Error> {
+    let (rem, value) = opt(preceded(space1, not_line_ending))(input)?;
+
+    Ok((rem, strip_body_comments(value.unwrap_or(""))))
+}
+
+/// Removes the comments from the body of a `#define` directive, so that they do not end up
+/// wherever the macro is expanded.
+///
+/// As in C, a `//` comment ends the body and a `/* ... */` comment is replaced by a space; a `/*`
+/// comment that is not terminated on the same line ends the body as well. Comment markers inside
+/// of string literals are left alone.
+fn strip_body_comments(body: &str) -> Cow<'_, str> {
+    let mut string = false;
+    let mut escaped = false;
+
+    for (index, c) in body.char_indices() {
+        if string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => string = false,
+                _ => (),
+            }
+
+            continue;
+        }
+
+        let after = &body[index + c.len_utf8()..];
+
+        match c {
+            '"' => string = true,
+            '/' if after.starts_with('/') => return Cow::Borrowed(body[..index].trim_end()),
+            '/' if after.starts_with('*') => {
+                return match after[1..].find("*/") {
+                    Some(length) => {
+                        let rest = strip_body_comments(&after[1 + length + 2..]);
+                        let joined = format!("{} {}", body[..index].trim_end(), rest);
+
+                        Cow::Owned(joined.trim().to_string())
+                    }
+                    None => Cow::Borrowed(body[..index].trim_end()),
+                };
+            }
+            _ => (),
+        }
+    }
+
+    Cow::Borrowed(body.trim_end())
+}
+
+fn identifier(input: &str) -> IResult<&str, &str, Error> {
+    recognize(pair(
+        alt((alpha1, tag("_"))),
+        many0_count(alt((alphanumeric1, tag("_")))),
+    ))(input)
+}
+
+/// Parses `input` as a complete `<...>` or `"..."` include path.
+pub fn parse_include_path(input: &str) -> Option<IncludePath<'_>> {
+    all_consuming(include_path)(input)
+        .ok()
+        .map(|(_, path)| path)
+}
+
+fn include_path(input: &str) -> IResult<&str, IncludePath<'_>, Error> {
     alt((angle_path, quote_path))(input)
 }
 
-fn angle_path(input: &str) -> IResult<&str, IncludePath, Error> {
+fn angle_path(input: &str) -> IResult<&str, IncludePath<'_>, Error> {
     let (rem, target) = delimited(char('<'), is_not(">\r\n"), char('>'))(input)?;
 
     Ok((rem, IncludePath::Angle(target.as_ref())))
 }
 
-fn quote_path(input: &str) -> IResult<&str, IncludePath, Error> {
+fn quote_path(input: &str) -> IResult<&str, IncludePath<'_>, Error> {
     let (rem, target) = delimited(char('"'), is_not("\"\r\n"), char('"'))(input)?;
 
     Ok((rem, IncludePath::Quote(target.as_ref())))
@@ -115,7 +520,7 @@ mod tests {
         #pragma unknown\n\
         #include <angle_path_unclosed\n\
         #include \"quote_path_unclosed\n\
-        #include undelimited\n\
+        #include undelimited.glsl\n\
         ";
 
         let res = parse_line(rem);
@@ -204,6 +609,404 @@ mod tests {
 
         assert!(res.is_err());
 
+        skip_line(rem);
+    }
+
+    #[test]
+    fn test_parse_define() {
+        let rem = "\
+        #define MAX_LIGHTS 8\n\
+        #define   SPACED   some value  \n\
+        #define EMPTY\n\
+        #defined NOT_A_DIRECTIVE\n\
+        #define 1INVALID\n\
+        #define SATURATE(x) clamp((x), 0.0, 1.0)\n\
+        #define MIX3( a,b , t ) mix(a, b, t)\n\
+        #define NO_PARAMS() 1\n\
+        #define NOT_FUNCTION_LIKE (x) x\n\
+        #define UNCLOSED(x x\n\
+        #undef MAX_LIGHTS\n\
+        #undef MAX_LIGHTS 8\n\
+        #define LAST_LINE 1";
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, object_like("MAX_LIGHTS", "8"));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, object_like("SPACED", "some value"));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, object_like("EMPTY", ""));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Text);
+
+        assert!(parse_line(rem).is_err());
+
         let rem = skip_line(rem);
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(
+            line,
+            function_like("SATURATE", &["x"], "clamp((x), 0.0, 1.0)")
+        );
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(
+            line,
+            function_like("MIX3", &["a", "b", "t"], "mix(a, b, t)")
+        );
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, function_like("NO_PARAMS", &[], "1"));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, object_like("NOT_FUNCTION_LIKE", "(x) x"));
+
+        assert!(parse_line(rem).is_err());
+
+        let rem = skip_line(rem);
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Undef("MAX_LIGHTS"));
+
+        assert!(parse_line(rem).is_err());
+
+        let rem = skip_line(rem);
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, object_like("LAST_LINE", "1"));
+        assert!(rem.is_empty());
+    }
+
+    #[test]
+    fn test_parse_define_comments() {
+        let rem = "\
+        #define A 8 // max\n\
+        #define B /* x */ 1 /* y */+/**/2\n\
+        #define C(x) (x) /* unterminated\n\
+        #define D \"//\" \"/*\" // not in strings\n\
+        #define E // only a comment\n";
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, object_like("A", "8"));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, object_like("B", "1 + 2"));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, function_like("C", &["x"], "(x)"));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, object_like("D", "\"//\" \"/*\""));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, object_like("E", ""));
+        assert!(rem.is_empty());
+    }
+
+    #[test]
+    fn test_parse_conditionals() {
+        let rem = "\
+        #if VERSION >= 300 && !LEGACY  \n\
+        #if   \n\
+        #ifdef SHADOWS\n\
+        #ifndef   SHADOWS  // comment\n\
+        #endif\n\
+        #endif // SHADOWS\n\
+        #endifx\n\
+        #ifdef\n\
+        #ifdef 1SHADOWS\n\
+        #ifndef SHADOWS trailing\n\
+        #elif QUALITY == 1\n\
+        #elif  \n\
+        #else\n\
+        #else // comment\n\
+        #elsewhere\n\
+        #endif";
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::If("VERSION >= 300 && !LEGACY"));
+
+        assert!(parse_line(rem).is_err());
+
+        let rem = skip_line(rem);
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::IfDef("SHADOWS"));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::IfNDef("SHADOWS"));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::EndIf);
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::EndIf);
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Text);
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Text);
+
+        assert!(parse_line(rem).is_err());
+
+        let rem = skip_line(rem);
+
+        assert!(parse_line(rem).is_err());
+
+        let rem = skip_line(rem);
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Elif("QUALITY == 1"));
+
+        assert!(parse_line(rem).is_err());
+
+        let rem = skip_line(rem);
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Else);
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Else);
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Text);
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::EndIf);
+        assert!(rem.is_empty());
+    }
+
+    #[test]
+    fn test_parse_error_and_warning() {
+        let rem = "\
+        #error \"include core.glsl first\"\n\
+        #error   unquoted message  \n\
+        #error\n\
+        #errors\n\
+        #warning \"deprecated\"\n\
+        #pragma   message \"deprecated, use pbr_common.glsl\"\n\
+        #pragma messages\n";
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Error("include core.glsl first"));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Error("unquoted message"));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Error(""));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Text);
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Warning("deprecated"));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Message("deprecated, use pbr_common.glsl"));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Text);
+        assert!(rem.is_empty());
+    }
+
+    #[test]
+    fn test_parse_custom_line() {
+        let prefix = "#pragma ipp";
+
+        assert_eq!(
+            parse_custom_line("#pragma ipp bind_group  2 \nfloat a;\n", prefix).unwrap(),
+            (
+                "float a;\n",
+                Line::Custom {
+                    name: "bind_group",
+                    argument: "2"
+                }
+            )
+        );
+        assert_eq!(
+            parse_custom_line("#pragma ipp\tstage\r\n", prefix).unwrap(),
+            (
+                "",
+                Line::Custom {
+                    name: "stage",
+                    argument: ""
+                }
+            )
+        );
+        assert!(matches!(
+            parse_custom_line("#pragma ippx a\n", prefix),
+            Err(nom::Err::Error(_))
+        ));
+        assert!(matches!(
+            parse_custom_line("#pragma once\n", prefix),
+            Err(nom::Err::Error(_))
+        ));
+        assert!(matches!(
+            parse_custom_line("#pragma ipp  \n", prefix),
+            Err(nom::Err::Failure(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_line_directive() {
+        let rem = "\
+        #line 42\n\
+        #line 7 \"template.glsl\" // generated\n\
+        #lines\n\
+        #line forty-two\n";
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(
+            line,
+            Line::Renumber {
+                number: 42,
+                file: None
+            }
+        );
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(
+            line,
+            Line::Renumber {
+                number: 7,
+                file: Some("template.glsl")
+            }
+        );
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::Text);
+        assert!(parse_line(rem).is_err());
+    }
+
+    #[test]
+    fn test_parse_include_macro() {
+        let rem = "\
+        #include PLATFORM_HEADER\n\
+        #include   PLATFORM_HEADER  \n";
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::IncludeMacro("PLATFORM_HEADER"));
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(line, Line::IncludeMacro("PLATFORM_HEADER"));
+        assert!(rem.is_empty());
+
+        assert_eq!(
+            parse_include_path("\"gl/platform.glsl\""),
+            Some(IncludePath::Quote("gl/platform.glsl".as_ref()))
+        );
+        assert_eq!(
+            parse_include_path("<gl/platform.glsl>"),
+            Some(IncludePath::Angle("gl/platform.glsl".as_ref()))
+        );
+        assert_eq!(parse_include_path("gl/platform.glsl"), None);
+        assert_eq!(parse_include_path("\"a.glsl\" trailing"), None);
+    }
+
+    #[test]
+    fn test_parse_include_next() {
+        let rem = "\
+        #include_next <lighting.glsl>\n\
+        #include_next \"lighting.glsl\"\n\
+        #include_next lighting.glsl\n";
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(
+            line,
+            Line::IncludeNext(IncludePath::Angle("lighting.glsl".as_ref()))
+        );
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(
+            line,
+            Line::IncludeNext(IncludePath::Quote("lighting.glsl".as_ref()))
+        );
+
+        assert!(parse_line(rem).is_err());
+    }
+
+    #[test]
+    fn test_parse_include_optional() {
+        let rem = "\
+        #include_optional <overrides/post_fx.glsl>\n\
+        #include_optional \"overrides/post_fx.glsl\"\n\
+        #include_optional overrides/post_fx.glsl\n";
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(
+            line,
+            Line::IncludeOptional(IncludePath::Angle("overrides/post_fx.glsl".as_ref()))
+        );
+
+        let (rem, line) = parse_line(rem).unwrap();
+
+        assert_eq!(
+            line,
+            Line::IncludeOptional(IncludePath::Quote("overrides/post_fx.glsl".as_ref()))
+        );
+
+        assert!(parse_line(rem).is_err());
+    }
+
+    fn object_like<'a>(name: &'a str, body: &'a str) -> Line<'a> {
+        Line::Define(Define {
+            name,
+            parameters: None,
+            body: body.into(),
+        })
+    }
+
+    fn function_like<'a>(name: &'a str, parameters: &[&'a str], body: &'a str) -> Line<'a> {
+        Line::Define(Define {
+            name,
+            parameters: Some(parameters.to_vec()),
+            body: body.into(),
+        })
     }
 }
@@ -0,0 +1,469 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Macro {
+    parameters: Option<Vec<String>>,
+    body: String,
+}
+
+impl Macro {
+    pub fn parameters(&self) -> Option<&[String]> {
+        self.parameters.as_deref()
+    }
+
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    pub fn object_like(body: &str) -> Self {
+        Macro {
+            parameters: None,
+            body: body.to_string(),
+        }
+    }
+
+    pub fn function_like<'a, I>(parameters: I, body: &str) -> Self
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        Macro {
+            parameters: Some(parameters.into_iter().map(|p| p.to_string()).collect()),
+            body: body.to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExpansionErrorKind {
+    DepthExceeded {
+        name: String,
+        max_depth: usize,
+    },
+    ArgumentCount {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    UnterminatedArguments {
+        name: String,
+    },
+}
+
+/// An error that occurred while expanding a macro invocation.
+///
+/// The `offset` is the byte offset (relative to the expanded text) of the top-level macro
+/// invocation that failed to expand.
+#[derive(Debug)]
+pub struct ExpansionError {
+    pub kind: ExpansionErrorKind,
+    pub offset: usize,
+}
+
+impl fmt::Display for ExpansionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExpansionErrorKind::DepthExceeded { name, max_depth } => write!(
+                f,
+                "expansion of macro `{}` exceeded the maximum depth of {} (recursive macro?)",
+                name, max_depth
+            ),
+            ExpansionErrorKind::ArgumentCount {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "macro `{}` expects {} argument(s), but {} were given",
+                name, expected, found
+            ),
+            ExpansionErrorKind::UnterminatedArguments { name } => {
+                write!(f, "unterminated argument list for macro `{}`", name)
+            }
+        }
+    }
+}
+
+/// The context in which the builtin `__FILE__`, `__LINE__`, `__BASE_FILE__` and
+/// `__INCLUDE_DEPTH__` macros are expanded.
+#[derive(Clone, Copy)]
+pub struct Builtins<'a> {
+    pub file: &'a Path,
+    /// The entry point of the preprocessing run.
+    pub base_file: &'a Path,
+    /// Whether `__FILE__` and `__BASE_FILE__` expand to just the file name, rather than the full
+    /// path.
+    pub file_name_only: bool,
+    /// The number of includes between the entry point and `file`.
+    pub include_depth: usize,
+    /// The (zero-based) number of the line on which the expanded text starts.
+    pub line_number: usize,
+}
+
+impl Builtins<'_> {
+    fn expand(&self, name: &str) -> Option<String> {
+        match name {
+            "__FILE__" => Some(self.file_string(self.file)),
+            "__LINE__" => Some((self.line_number + 1).to_string()),
+            "__BASE_FILE__" => Some(self.file_string(self.base_file)),
+            "__INCLUDE_DEPTH__" => Some(self.include_depth.to_string()),
+            _ => None,
+        }
+    }
+
+    fn file_string(&self, file: &Path) -> String {
+        let file = if self.file_name_only {
+            file.file_name().map(Path::new).unwrap_or(file)
+        } else {
+            file
+        };
+
+        format!("{:?}", file.to_string_lossy())
+    }
+}
+
+pub struct MacroTable {
+    macros: HashMap<String, Macro>,
+    /// The file and (zero-based) line number of the latest `#define` of a macro; macros that were
+    /// not defined by a directive (e.g. [Options::defines](crate::Options::defines)) have none.
+    origins: HashMap<String, (PathBuf, usize)>,
+    max_depth: usize,
+}
+
+impl MacroTable {
+    pub fn new(max_depth: usize) -> Self {
+        MacroTable {
+            macros: HashMap::new(),
+            origins: HashMap::new(),
+            max_depth,
+        }
+    }
+
+    /// Defines `name` as `definition`.
+    ///
+    /// Returns `false` if `name` was already defined with a different definition, in which case
+    /// the existing definition is left untouched.
+    pub fn define(&mut self, name: &str, definition: Macro) -> bool {
+        if let Some(existing) = self.macros.get(name) {
+            existing == &definition
+        } else {
+            self.macros.insert(name.to_string(), definition);
+
+            true
+        }
+    }
+
+    /// Like [define](MacroTable::define), but also records the location of the `#define`
+    /// directive.
+    pub fn define_at(
+        &mut self,
+        name: &str,
+        definition: Macro,
+        source_file: &Path,
+        line_number: usize,
+    ) -> bool {
+        let defined = self.define(name, definition);
+
+        if defined {
+            self.origins
+                .insert(name.to_string(), (source_file.to_path_buf(), line_number));
+        }
+
+        defined
+    }
+
+    pub fn undefine(&mut self, name: &str) {
+        self.macros.remove(name);
+        self.origins.remove(name);
+    }
+
+    /// Iterates over all defined macros, together with the location of their latest `#define`
+    /// directive (if any).
+    pub fn definitions(&self) -> impl Iterator<Item = (&str, &Macro, Option<(&Path, usize)>)> {
+        self.macros.iter().map(move |(name, definition)| {
+            let origin = self
+                .origins
+                .get(name)
+                .map(|(path, line_number)| (path.as_path(), *line_number));
+
+            (name.as_str(), definition, origin)
+        })
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.macros.contains_key(name)
+    }
+
+    /// Expands every macro invocation in `text`, passing the result to `emit` as a sequence of
+    /// pieces together with the byte range in `text` each piece originates from.
+    pub fn expand<F>(
+        &self,
+        text: &str,
+        mut builtins: Builtins,
+        mut emit: F,
+    ) -> Result<(), ExpansionError>
+    where
+        F: FnMut(&str, Range<usize>),
+    {
+        let mut flushed = 0;
+        let mut pos = 0;
+        let mut counted = 0;
+
+        while let Some(identifier) = next_identifier(text, &mut pos) {
+            let start = identifier.start;
+
+            // Keep track of the line of the current (top-level) invocation for `__LINE__`
+            builtins.line_number += text[counted..start].matches('\n').count();
+            counted = start;
+
+            let invocation = self
+                .try_invoke(text, identifier, 0, &builtins)
+                .map_err(|kind| ExpansionError {
+                    kind,
+                    offset: start,
+                })?;
+
+            if let Some((end, expansion)) = invocation {
+                if flushed < start {
+                    emit(&text[flushed..start], flushed..start);
+                }
+
+                emit(&expansion, start..end);
+
+                flushed = end;
+                pos = end;
+            }
+        }
+
+        if flushed < text.len() {
+            emit(&text[flushed..], flushed..text.len());
+        }
+
+        Ok(())
+    }
+
+    fn expand_into(
+        &self,
+        text: &str,
+        depth: usize,
+        builtins: &Builtins,
+        output: &mut String,
+    ) -> Result<(), ExpansionErrorKind> {
+        let mut flushed = 0;
+        let mut pos = 0;
+
+        while let Some(identifier) = next_identifier(text, &mut pos) {
+            let start = identifier.start;
+
+            if let Some((end, expansion)) = self.try_invoke(text, identifier, depth, builtins)? {
+                output.push_str(&text[flushed..start]);
+                output.push_str(&expansion);
+
+                flushed = end;
+                pos = end;
+            }
+        }
+
+        output.push_str(&text[flushed..]);
+
+        Ok(())
+    }
+
+    /// If the `identifier` in `text` names a macro, returns the end offset of the invocation
+    /// and its fully expanded replacement text.
+    fn try_invoke(
+        &self,
+        text: &str,
+        identifier: Range<usize>,
+        depth: usize,
+        builtins: &Builtins,
+    ) -> Result<Option<(usize, String)>, ExpansionErrorKind> {
+        let name = &text[identifier.clone()];
+
+        let definition = if let Some(definition) = self.macros.get(name) {
+            definition
+        } else {
+            return Ok(builtins
+                .expand(name)
+                .map(|expansion| (identifier.end, expansion)));
+        };
+
+        if depth >= self.max_depth {
+            return Err(ExpansionErrorKind::DepthExceeded {
+                name: name.to_string(),
+                max_depth: self.max_depth,
+            });
+        }
+
+        let mut expansion = String::new();
+
+        if let Some(parameters) = &definition.parameters {
+            // A function-like macro name that is not followed by an argument list is not an
+            // invocation.
+            let (arguments, end) = match parse_arguments(text, identifier.end) {
+                Arguments::Parsed { arguments, end } => (arguments, end),
+                Arguments::Unterminated => {
+                    return Err(ExpansionErrorKind::UnterminatedArguments {
+                        name: name.to_string(),
+                    })
+                }
+                Arguments::Absent => return Ok(None),
+            };
+
+            let arguments: Vec<&str> = arguments
+                .into_iter()
+                .map(|range| text[range].trim())
+                .collect();
+
+            // `F()` passes a single empty argument, which is also how a call to a macro without
+            // parameters is written.
+            let found = if parameters.is_empty() && arguments == [""] {
+                0
+            } else {
+                arguments.len()
+            };
+
+            if found != parameters.len() {
+                return Err(ExpansionErrorKind::ArgumentCount {
+                    name: name.to_string(),
+                    expected: parameters.len(),
+                    found,
+                });
+            }
+
+            let substituted = substitute(&definition.body, parameters, &arguments);
+
+            self.expand_into(&substituted, depth + 1, builtins, &mut expansion)?;
+
+            Ok(Some((end, expansion)))
+        } else {
+            self.expand_into(&definition.body, depth + 1, builtins, &mut expansion)?;
+
+            Ok(Some((identifier.end, expansion)))
+        }
+    }
+}
+
+/// Replaces every occurrence of a parameter identifier in `body` with the corresponding
+/// argument.
+fn substitute(body: &str, parameters: &[String], arguments: &[&str]) -> String {
+    let mut output = String::new();
+    let mut flushed = 0;
+    let mut pos = 0;
+
+    while let Some(identifier) = next_identifier(body, &mut pos) {
+        let name = &body[identifier.clone()];
+
+        if let Some(index) = parameters.iter().position(|p| p == name) {
+            output.push_str(&body[flushed..identifier.start]);
+            output.push_str(arguments[index]);
+
+            flushed = identifier.end;
+        }
+    }
+
+    output.push_str(&body[flushed..]);
+
+    output
+}
+
+enum Arguments {
+    Absent,
+    Unterminated,
+    Parsed {
+        arguments: Vec<Range<usize>>,
+        end: usize,
+    },
+}
+
+/// Parses a parenthesized argument list starting at `start` (after optional whitespace).
+///
+/// On success, returns the byte ranges of the arguments and the offset just past the closing
+/// parenthesis.
+fn parse_arguments(text: &str, start: usize) -> Arguments {
+    let bytes = text.as_bytes();
+    let mut pos = start;
+
+    while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+        pos += 1;
+    }
+
+    if bytes.get(pos) != Some(&b'(') {
+        return Arguments::Absent;
+    }
+
+    pos += 1;
+
+    let mut arguments = Vec::new();
+    let mut argument_start = pos;
+    let mut nesting = 0;
+
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b'(' => nesting += 1,
+            b')' if nesting > 0 => nesting -= 1,
+            b')' => {
+                arguments.push(argument_start..pos);
+
+                return Arguments::Parsed {
+                    arguments,
+                    end: pos + 1,
+                };
+            }
+            b',' if nesting == 0 => {
+                arguments.push(argument_start..pos);
+
+                argument_start = pos + 1;
+            }
+            _ => (),
+        }
+
+        pos += 1;
+    }
+
+    Arguments::Unterminated
+}
+
+/// Finds the next identifier in `text` at or after `pos`, and advances `pos` past it.
+fn next_identifier(text: &str, pos: &mut usize) -> Option<Range<usize>> {
+    let bytes = text.as_bytes();
+
+    while *pos < bytes.len() {
+        let byte = bytes[*pos];
+
+        if is_identifier_start(byte) {
+            let start = *pos;
+
+            *pos += 1;
+
+            while *pos < bytes.len() && is_identifier_continue(bytes[*pos]) {
+                *pos += 1;
+            }
+
+            return Some(start..*pos);
+        } else if byte.is_ascii_digit() {
+            // Skip over numeric literals as a whole, so that suffixes (e.g. the `u` in `1u`)
+            // are never mistaken for identifiers.
+            *pos += 1;
+
+            while *pos < bytes.len() && (is_identifier_continue(bytes[*pos]) || bytes[*pos] == b'.')
+            {
+                *pos += 1;
+            }
+        } else {
+            *pos += 1;
+        }
+    }
+
+    None
+}
+
+fn is_identifier_start(byte: u8) -> bool {
+    byte.is_ascii_alphabetic() || byte == b'_'
+}
+
+fn is_identifier_continue(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
@@ -0,0 +1,234 @@
+use std::mem;
+use std::ops::Range;
+
+/// A piece of a chunk of text after its whitespace is minified, see [Minifier::minify].
+#[derive(PartialEq, Eq, Debug)]
+pub(crate) enum Piece {
+    /// A byte range of the chunk that is retained.
+    Text(Range<usize>),
+    /// Whitespace that is not (or not entirely) part of the chunk: a single space that separates
+    /// two tokens, or whitespace that was held back at the end of a previous chunk.
+    Whitespace(String),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    /// Only whitespace has been seen on the current line.
+    LineStart,
+    Code {
+        /// The last character that was retained on the current line.
+        last: char,
+    },
+    String {
+        escaped: bool,
+    },
+    /// A preprocessor directive, or a line that continues one, which is retained as is.
+    Directive {
+        /// The last character of the line, other than a carriage return.
+        last: char,
+    },
+}
+
+/// Minifies the whitespace of the chunks of text that are passed to it, see
+/// [MinifySink](crate::MinifySink).
+///
+/// Trailing whitespace and indentation are removed, lines that are blank are dropped, and any
+/// other run of spaces and tabs is removed, or collapsed into a single space where the characters
+/// on either side could otherwise join into a different token. String literals, preprocessor
+/// directives and the lines that continue them are not changed.
+#[derive(Debug)]
+pub(crate) struct Minifier {
+    state: State,
+    // The whitespace since the last character that was retained.
+    whitespace: String,
+}
+
+impl Minifier {
+    pub(crate) fn new() -> Self {
+        Minifier {
+            state: State::LineStart,
+            whitespace: String::new(),
+        }
+    }
+
+    /// Splits `text` into the pieces that remain after minifying its whitespace.
+    ///
+    /// Whitespace at the end of `text` is held back until the next chunk shows whether it is
+    /// needed.
+    pub(crate) fn minify(&mut self, text: &str) -> Vec<Piece> {
+        let mut pieces = Vec::new();
+        // The start of the range that is currently being retained, if any.
+        let mut start = match self.state {
+            State::String { .. } | State::Directive { .. } => Some(0),
+            _ => None,
+        };
+
+        for (index, c) in text.char_indices() {
+            match self.state {
+                State::Directive { last } => {
+                    self.state = match c {
+                        // A line that ends with a backslash continues the directive.
+                        '\n' if last == '\\' => State::Directive { last: '\n' },
+                        '\n' => {
+                            push_text(&mut pieces, start.take(), index + 1);
+
+                            State::LineStart
+                        }
+                        '\r' => State::Directive { last },
+                        _ => State::Directive { last: c },
+                    };
+                }
+                State::String { escaped } => {
+                    self.state = match c {
+                        _ if escaped => State::String { escaped: false },
+                        '\\' => State::String { escaped: true },
+                        '"' => State::Code { last: c },
+                        // An unterminated string ends at the end of its line.
+                        '\n' => {
+                            push_text(&mut pieces, start.take(), index + 1);
+
+                            State::LineStart
+                        }
+                        _ => State::String { escaped: false },
+                    };
+                }
+                _ if is_whitespace(c) => {
+                    push_text(&mut pieces, start.take(), index);
+                    self.whitespace.push(c);
+                }
+                State::LineStart if c == '\n' => {
+                    // A blank line.
+                    self.whitespace.clear();
+                }
+                State::LineStart if c == '#' => {
+                    pieces.extend(self.take_whitespace().map(Piece::Whitespace));
+                    start = Some(index);
+                    self.state = State::Directive { last: c };
+                }
+                State::LineStart => {
+                    self.whitespace.clear();
+                    start = Some(index);
+                    self.state = code_state(c);
+                }
+                State::Code { .. } if c == '\n' => {
+                    // Drop the trailing whitespace, but retain a carriage return that ends the
+                    // line.
+                    if self.whitespace.ends_with('\r') {
+                        pieces.push(Piece::Whitespace("\r".to_string()));
+                    }
+
+                    self.whitespace.clear();
+                    start.get_or_insert(index);
+                    push_text(&mut pieces, start.take(), index + 1);
+                    self.state = State::LineStart;
+                }
+                State::Code { last } => {
+                    if !self.whitespace.is_empty() {
+                        self.whitespace.clear();
+
+                        if separates(last, c) {
+                            pieces.push(Piece::Whitespace(" ".to_string()));
+                        }
+                    }
+
+                    start.get_or_insert(index);
+                    self.state = code_state(c);
+                }
+            }
+        }
+
+        push_text(&mut pieces, start, text.len());
+
+        pieces
+    }
+
+    fn take_whitespace(&mut self) -> Option<String> {
+        if self.whitespace.is_empty() {
+            None
+        } else {
+            Some(mem::take(&mut self.whitespace))
+        }
+    }
+}
+
+fn is_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\r')
+}
+
+fn code_state(c: char) -> State {
+    if c == '"' {
+        State::String { escaped: false }
+    } else {
+        State::Code { last: c }
+    }
+}
+
+/// Whether whitespace between `a` and `b` has to be retained to keep them in separate tokens.
+///
+/// This errs on the side of retaining whitespace: between two characters that may be part of a
+/// word, a number or a string, and between two characters that may be part of an operator.
+fn separates(a: char, b: char) -> bool {
+    let is_word = |c: char| c.is_alphanumeric() || matches!(c, '_' | '.' | '"');
+    let is_operator = |c: char| "+-*/%<>=!&|^#.:".contains(c);
+
+    (is_word(a) && is_word(b)) || (is_operator(a) && is_operator(b))
+}
+
+fn push_text(pieces: &mut Vec<Piece>, start: Option<usize>, end: usize) {
+    match start {
+        Some(start) if start < end => pieces.push(Piece::Text(start..end)),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(minifier: &mut Minifier, chunks: &[&str]) -> String {
+        let mut output = String::new();
+
+        for chunk in chunks {
+            for piece in minifier.minify(chunk) {
+                match piece {
+                    Piece::Text(range) => output.push_str(&chunk[range]),
+                    Piece::Whitespace(whitespace) => output.push_str(&whitespace),
+                }
+            }
+        }
+
+        output
+    }
+
+    #[test]
+    fn test_minify() {
+        assert_eq!(
+            apply(
+                &mut Minifier::new(),
+                &["  float a = b  +  +c;  \r\n\n \t\nvec2 v = vec2( 1.0 , .5 ) ;\n"]
+            ),
+            "float a=b+ +c;\r\nvec2 v=vec2(1.0,.5);\n"
+        );
+        assert_eq!(
+            apply(
+                &mut Minifier::new(),
+                &["  #define  A   \\\n   1 \n  x  =  \"a   b\" ;\n"]
+            ),
+            "  #define  A   \\\n   1 \nx=\"a   b\";\n"
+        );
+    }
+
+    #[test]
+    fn test_minify_across_chunks() {
+        assert_eq!(
+            apply(
+                &mut Minifier::new(),
+                &[
+                    "int", "  ", " a =", " 1", ";  ", "\n", "  ", "\n", "  #", "x  \n", "a \"",
+                    " \""
+                ]
+            ),
+            "int a=1;\n  #x  \na \" \""
+        );
+    }
+}
@@ -0,0 +1,309 @@
+//! [Serialize] implementations for the error types, enabled by the `serde` feature.
+//!
+//! Paths are serialized as (lossily converted) strings, line numbers are one-based, and instead
+//! of the full source text of a file only the line the error refers to is included.
+
+use std::path::{Path, PathBuf};
+
+use serde::ser::{SerializeSeq, SerializeStruct};
+use serde::{Serialize, Serializer};
+
+use crate::{
+    DeniedIncludeError, DirectiveError, Error, ErrorKind, FileAccessError, FileNotFoundError,
+    FileOperation, IncludeCycleError, InvalidSearchPath, InvalidSearchPathReason, ParseError,
+    SandboxEscapeError, SizeLimit, SizeLimitError, UnterminatedConditionalError,
+};
+
+/// Returns the (one-based) line `line_number` of `source`, without its line ending.
+fn source_line(source: &str, line_number: usize) -> &str {
+    source.lines().nth(line_number - 1).unwrap_or("")
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+/// Serializes a list of include directives as a sequence of `{ path, line_number }` structs.
+struct Includes<'a>(&'a [(PathBuf, usize)]);
+
+impl Serialize for Includes<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+
+        for (path, line_number) in self.0 {
+            seq.serialize_element(&Include(path, *line_number))?;
+        }
+
+        seq.end()
+    }
+}
+
+struct Include<'a>(&'a Path, usize);
+
+impl Serialize for Include<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Include", 2)?;
+
+        state.serialize_field("path", &path_string(self.0))?;
+        state.serialize_field("line_number", &self.1)?;
+
+        state.end()
+    }
+}
+
+impl Serialize for ErrorKind {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&format!("{:?}", self))
+    }
+}
+
+/// Serialized as a struct with the error's `kind`, `path` and `line` (see [Error::kind],
+/// [Error::path] and [Error::line]), and the `details` of the specific error; the details of
+/// [ErrorKind::Sink] are the sink's error message, those of [ErrorKind::Multiple] are the list of
+/// errors, and [ErrorKind::Cancelled] errors have no details.
+impl Serialize for Error {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Error", 4)?;
+
+        state.serialize_field("kind", &self.kind())?;
+        state.serialize_field("path", &self.path().map(path_string))?;
+        state.serialize_field("line", &self.line())?;
+
+        match self {
+            Error::FileNotFound(err) => state.serialize_field("details", err)?,
+            Error::IO(err) => state.serialize_field("details", err)?,
+            Error::Parse(err) => state.serialize_field("details", err)?,
+            Error::Directive(err) => state.serialize_field("details", err)?,
+            Error::UnterminatedConditional(err) => state.serialize_field("details", err)?,
+            Error::IncludeCycle(err) => state.serialize_field("details", err)?,
+            Error::DeniedInclude(err) => state.serialize_field("details", err)?,
+            Error::SandboxEscape(err) => state.serialize_field("details", err)?,
+            Error::InvalidSearchPath(err) => state.serialize_field("details", err)?,
+            Error::SizeLimit(err) => state.serialize_field("details", err)?,
+            Error::Sink(err) => state.serialize_field("details", &err.to_string())?,
+            Error::Multiple(errors) => state.serialize_field("details", errors)?,
+            Error::Cancelled => state.serialize_field("details", &())?,
+        }
+
+        state.end()
+    }
+}
+
+impl Serialize for FileNotFoundError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("FileNotFoundError", 6)?;
+
+        state.serialize_field("included_path", &path_string(self.included_path()))?;
+        state.serialize_field("source_file", &path_string(self.source_file()))?;
+        state.serialize_field("line_number", &self.line_number())?;
+        state.serialize_field("column", &self.column())?;
+        state.serialize_field(
+            "source_line",
+            source_line(self.source(), self.line_number()),
+        )?;
+        state.serialize_field("include_chain", &Includes(self.include_chain()))?;
+
+        state.end()
+    }
+}
+
+impl Serialize for FileAccessError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let operation = match self.operation() {
+            FileOperation::Read => "Read",
+            FileOperation::Canonicalize => "Canonicalize",
+            FileOperation::Filter => "Filter",
+        };
+
+        let mut state = serializer.serialize_struct("FileAccessError", 4)?;
+
+        state.serialize_field("path", &path_string(self.path()))?;
+        state.serialize_field("operation", operation)?;
+        state.serialize_field(
+            "included_from",
+            &self
+                .included_from()
+                .map(|(path, line_number)| Include(path, line_number)),
+        )?;
+        state.serialize_field("message", &self.io_error().to_string())?;
+
+        state.end()
+    }
+}
+
+impl Serialize for SizeLimitError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let limit = match self.limit() {
+            SizeLimit::File => "File",
+            SizeLimit::Total => "Total",
+        };
+
+        let mut state = serializer.serialize_struct("SizeLimitError", 5)?;
+
+        state.serialize_field("path", &path_string(self.path()))?;
+        state.serialize_field("limit", limit)?;
+        state.serialize_field("size", &self.size())?;
+        state.serialize_field("max", &self.max())?;
+        state.serialize_field(
+            "included_from",
+            &self
+                .included_from()
+                .map(|(path, line_number)| Include(path, line_number)),
+        )?;
+
+        state.end()
+    }
+}
+
+impl Serialize for ParseError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("ParseError", 5)?;
+
+        state.serialize_field("message", self.message())?;
+        state.serialize_field("source_file", &path_string(self.source_file()))?;
+        state.serialize_field("line_number", &self.line_number())?;
+        state.serialize_field("column", &self.column())?;
+        state.serialize_field(
+            "source_line",
+            source_line(self.source(), self.line_number()),
+        )?;
+
+        state.end()
+    }
+}
+
+impl Serialize for DirectiveError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("DirectiveError", 4)?;
+
+        state.serialize_field("message", self.message())?;
+        state.serialize_field("source_file", &path_string(self.source_file()))?;
+        state.serialize_field("line_number", &self.line_number())?;
+        state.serialize_field(
+            "source_line",
+            source_line(self.source(), self.line_number()),
+        )?;
+
+        state.end()
+    }
+}
+
+impl Serialize for UnterminatedConditionalError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("UnterminatedConditionalError", 4)?;
+
+        state.serialize_field("directive", self.directive())?;
+        state.serialize_field("source_file", &path_string(self.source_file()))?;
+        state.serialize_field("line_number", &self.line_number())?;
+        state.serialize_field(
+            "source_line",
+            source_line(self.source(), self.line_number()),
+        )?;
+
+        state.end()
+    }
+}
+
+impl Serialize for IncludeCycleError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("IncludeCycleError", 1)?;
+
+        state.serialize_field("includes", &Includes(self.includes()))?;
+
+        state.end()
+    }
+}
+
+impl Serialize for DeniedIncludeError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("DeniedIncludeError", 5)?;
+
+        state.serialize_field("pattern", self.pattern())?;
+        state.serialize_field("resolved_path", &path_string(self.resolved_path()))?;
+        state.serialize_field("source_file", &path_string(self.source_file()))?;
+        state.serialize_field("line_number", &self.line_number())?;
+        state.serialize_field(
+            "source_line",
+            source_line(self.source(), self.line_number()),
+        )?;
+
+        state.end()
+    }
+}
+
+impl Serialize for SandboxEscapeError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("SandboxEscapeError", 5)?;
+
+        state.serialize_field("included_path", &path_string(self.included_path()))?;
+        state.serialize_field("resolved_path", &path_string(self.resolved_path()))?;
+        state.serialize_field("source_file", &path_string(self.source_file()))?;
+        state.serialize_field("line_number", &self.line_number())?;
+        state.serialize_field(
+            "source_line",
+            source_line(self.source(), self.line_number()),
+        )?;
+
+        state.end()
+    }
+}
+
+impl Serialize for InvalidSearchPath {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let (reason, message) = match self.reason() {
+            InvalidSearchPathReason::Missing => ("Missing", None),
+            InvalidSearchPathReason::NotADirectory => ("NotADirectory", None),
+            InvalidSearchPathReason::Unreadable(message) => ("Unreadable", Some(message)),
+        };
+
+        let mut state = serializer.serialize_struct("InvalidSearchPath", 3)?;
+
+        state.serialize_field("path", &path_string(self.path()))?;
+        state.serialize_field("reason", reason)?;
+        state.serialize_field("message", &message)?;
+
+        state.end()
+    }
+}
@@ -0,0 +1,494 @@
+use std::path::{Path, PathBuf};
+
+use crate::{OutputSink, SourceMappedChunk};
+
+/// Maps the lines of the output of preprocessing back to the lines of the files they originate
+/// from, see [SourceMapBuilder].
+#[derive(Clone, Default, Debug)]
+pub struct SourceMap {
+    files: Vec<PathBuf>,
+    /// The index into `files` and the (zero-based) line number of every output line, or `None`
+    /// for a synthetic line.
+    lines: Vec<Option<(usize, usize)>>,
+    /// The source text of every file in `files`.
+    #[cfg(feature = "source-map")]
+    sources_content: Vec<String>,
+    /// The segments of every output line.
+    segments: Vec<Vec<Segment>>,
+}
+
+/// A segment of an output line, that starts at `column`. Synthetic output has no origin.
+#[derive(Clone, Copy, Debug)]
+struct Segment {
+    column: Column,
+    origin: Option<Origin>,
+}
+
+/// The position in a source file that a [Segment] originates from.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Origin {
+    /// The index into the files.
+    file: usize,
+    /// The (zero-based) line number.
+    line_number: usize,
+    column: Column,
+    /// Whether the segment is a copy of the source text, so that every column within the segment
+    /// maps to the corresponding column of the source.
+    verbatim: bool,
+}
+
+/// A column, both as a byte offset and in UTF-16 code units (as used by Source Map V3).
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+struct Column {
+    bytes: usize,
+    utf16: usize,
+}
+
+impl Column {
+    /// The column at the end of `text`, if it starts at this column.
+    fn advance(self, text: &str) -> Column {
+        Column {
+            bytes: self.bytes + text.len(),
+            utf16: self.utf16 + text.encode_utf16().count(),
+        }
+    }
+}
+
+/// A position in a source file, see [SourceMap::resolve].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SourcePosition<'a> {
+    path: &'a Path,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> SourcePosition<'a> {
+    pub fn path(&self) -> &'a Path {
+        self.path
+    }
+
+    /// The (one-based) line number.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The (zero-based) column, as a byte offset into the line.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+}
+
+/// A position in the output, see [SourceMap::find_in_output].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct OutputPosition {
+    line: usize,
+    column: usize,
+}
+
+impl OutputPosition {
+    /// The (one-based) line number.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The (zero-based) column, as a byte offset into the line.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+}
+
+impl SourceMap {
+    /// Returns the file and the (one-based) line number that the (one-based) `output_line`
+    /// originates from.
+    ///
+    /// Returns `None` if the output does not have that line, or if the line is synthetic, i.e.
+    /// it starts with output that the preprocessor inserted, such as the newline that follows an
+    /// included file.
+    pub fn lookup(&self, output_line: usize) -> Option<(&Path, usize)> {
+        let (file, line_number) = (*self.lines.get(output_line.checked_sub(1)?)?)?;
+
+        Some((&self.files[file], line_number + 1))
+    }
+
+    /// The number of lines of the output.
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Returns the position in a source file that the position in the output at the (one-based)
+    /// `output_line` and the (zero-based) byte offset `output_column` into that line originates
+    /// from.
+    ///
+    /// Returns `None` if the output does not have that position, or if it is synthetic. A position
+    /// inside of the expansion of a macro resolves to the start of the macro invocation.
+    pub fn resolve(&self, output_line: usize, output_column: usize) -> Option<SourcePosition<'_>> {
+        let segments = self.segments.get(output_line.checked_sub(1)?)?;
+        let segment = segments
+            .iter()
+            .take_while(|segment| segment.column.bytes <= output_column)
+            .last()?;
+        let origin = segment.origin?;
+
+        let offset = if origin.verbatim {
+            output_column - segment.column.bytes
+        } else {
+            0
+        };
+
+        Some(SourcePosition {
+            path: &self.files[origin.file],
+            line: origin.line_number + 1,
+            column: origin.column.bytes + offset,
+        })
+    }
+
+    /// Returns the positions in the output at which the (one-based) `source_line` of the file at
+    /// `path` was emitted, in order.
+    ///
+    /// There is a position for every output line that contains output of the source line, which
+    /// is the start of the first segment of that output. A file that is included several times
+    /// (e.g. without `#pragma once`) has several positions, and a line that was not emitted (e.g.
+    /// a directive, or a line in a disabled conditional group) has none.
+    pub fn find_in_output<P>(&self, path: P, source_line: usize) -> Vec<OutputPosition>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let file = match self.files.iter().position(|file| file == path) {
+            Some(file) => file,
+            None => return Vec::new(),
+        };
+
+        self.segments
+            .iter()
+            .enumerate()
+            .filter_map(|(index, segments)| {
+                let segment = segments.iter().find(|segment| {
+                    segment
+                        .origin
+                        .map(|origin| (origin.file, origin.line_number + 1))
+                        == Some((file, source_line))
+                })?;
+
+                Some(OutputPosition {
+                    line: index + 1,
+                    column: segment.column.bytes,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the source map as a [Source Map V3](https://sourcemaps.info/spec.html) JSON
+    /// document, with the source text of every file in `sourcesContent`.
+    ///
+    /// Every chunk of output starts a segment that maps to the position of the chunk's source
+    /// range, and synthetic output starts a segment without a mapping. Columns are counted in
+    /// UTF-16 code units, as by browsers.
+    #[cfg(feature = "source-map")]
+    pub fn to_json(&self) -> String {
+        let sources: Vec<String> = self
+            .files
+            .iter()
+            .map(|file| json_string(&file.to_string_lossy()))
+            .collect();
+        let sources_content: Vec<String> = self
+            .sources_content
+            .iter()
+            .map(|source| json_string(source))
+            .collect();
+
+        format!(
+            "{{\"version\":3,\"sources\":[{}],\"sourcesContent\":[{}],\"names\":[],\"mappings\":{}}}",
+            sources.join(","),
+            sources_content.join(","),
+            json_string(&self.mappings())
+        )
+    }
+
+    /// Encodes the segments as the `mappings` of a Source Map V3 document.
+    #[cfg(feature = "source-map")]
+    fn mappings(&self) -> String {
+        let mut mappings = String::new();
+
+        // All fields but the output column are relative to the previous mapped segment.
+        let mut previous = (0, 0, 0);
+
+        for (index, line) in self.segments.iter().enumerate() {
+            if index > 0 {
+                mappings.push(';');
+            }
+
+            let mut previous_column = 0;
+
+            for (index, segment) in line.iter().enumerate() {
+                if index > 0 {
+                    mappings.push(',');
+                }
+
+                encode_vlq(&mut mappings, segment.column.utf16, previous_column);
+                previous_column = segment.column.utf16;
+
+                if let Some(origin) = segment.origin {
+                    let origin = (origin.file, origin.line_number, origin.column.utf16);
+
+                    encode_vlq(&mut mappings, origin.0, previous.0);
+                    encode_vlq(&mut mappings, origin.1, previous.1);
+                    encode_vlq(&mut mappings, origin.2, previous.2);
+                    previous = origin;
+                }
+            }
+        }
+
+        mappings
+    }
+}
+
+/// Appends the base64 VLQ encoding of `value - previous` to `output`.
+#[cfg(feature = "source-map")]
+fn encode_vlq(output: &mut String, value: usize, previous: usize) {
+    const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    // The sign is stored in the least significant bit.
+    let mut vlq = if value >= previous {
+        ((value - previous) as u64) << 1
+    } else {
+        (((previous - value) as u64) << 1) | 1
+    };
+
+    loop {
+        let mut digit = vlq & 0b11111;
+
+        vlq >>= 5;
+
+        if vlq > 0 {
+            digit |= 0b100000;
+        }
+
+        output.push(BASE64[digit as usize] as char);
+
+        if vlq == 0 {
+            break;
+        }
+    }
+}
+
+#[cfg(feature = "source-map")]
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+
+    escaped.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped.push('"');
+
+    escaped
+}
+
+/// An [OutputSink] that forwards the output to another sink, while building a [SourceMap] of it.
+///
+/// Every output line is attributed to the chunk that contributes its first character. The lines
+/// of a chunk map to the corresponding lines of its source range; if a chunk has more lines than
+/// its source range (e.g. because a macro expands to several lines), the excess lines map to the
+/// last line of the range.
+#[derive(Debug)]
+pub struct SourceMapBuilder<S> {
+    output_sink: S,
+    source_map: SourceMap,
+    at_line_start: bool,
+    column: Column,
+}
+
+impl<S> SourceMapBuilder<S>
+where
+    S: OutputSink,
+{
+    pub fn new(output_sink: S) -> Self {
+        SourceMapBuilder {
+            output_sink,
+            source_map: SourceMap {
+                segments: vec![Vec::new()],
+                ..SourceMap::default()
+            },
+            at_line_start: true,
+            column: Column::default(),
+        }
+    }
+
+    /// Returns the wrapped sink and the source map of the output that was sunk into it.
+    pub fn finish(self) -> (S, SourceMap) {
+        (self.output_sink, self.source_map)
+    }
+
+    /// Records the lines that start in `text`, where `origin` returns the origin of the line with
+    /// the given (zero-based) index in `text`.
+    fn record<F>(&mut self, text: &str, mut origin: F)
+    where
+        F: FnMut(usize) -> Option<Origin>,
+    {
+        for (index, line) in text.split_inclusive('\n').enumerate() {
+            let origin = origin(index);
+
+            if self.at_line_start {
+                self.source_map
+                    .lines
+                    .push(origin.map(|origin| (origin.file, origin.line_number)));
+            }
+
+            let segments = self.source_map.segments.last_mut().unwrap();
+            let content = line.strip_suffix('\n').unwrap_or(line);
+
+            // Synthetic output only needs a segment to end a preceding mapped segment.
+            let ends_mapping = segments
+                .last()
+                .map(|segment| segment.origin.is_some())
+                .unwrap_or(false);
+
+            if !content.is_empty() && (origin.is_some() || ends_mapping) {
+                segments.push(Segment {
+                    column: self.column,
+                    origin,
+                });
+            }
+
+            if line.ends_with('\n') {
+                self.source_map.segments.push(Vec::new());
+                self.column = Column::default();
+            } else {
+                self.column = self.column.advance(content);
+            }
+
+            self.at_line_start = line.ends_with('\n');
+        }
+    }
+
+    fn file_index(&mut self, path: &Path, _source: &str) -> usize {
+        let files = &mut self.source_map.files;
+
+        match files.iter().position(|file| file == path) {
+            Some(index) => index,
+            None => {
+                files.push(path.to_path_buf());
+
+                #[cfg(feature = "source-map")]
+                self.source_map.sources_content.push(_source.to_string());
+
+                files.len() - 1
+            }
+        }
+    }
+}
+
+impl<S> OutputSink for SourceMapBuilder<S>
+where
+    S: OutputSink,
+{
+    type Error = S::Error;
+
+    fn sink(&mut self, chunk: &str) -> Result<(), S::Error> {
+        self.record(chunk, |_| None);
+        self.output_sink.sink(chunk)
+    }
+
+    fn sink_source_mapped(
+        &mut self,
+        source_mapped_chunk: SourceMappedChunk,
+    ) -> Result<(), S::Error> {
+        let source = source_mapped_chunk.source();
+        let file = self.file_index(source_mapped_chunk.source_path(), source);
+        let line_range = source_mapped_chunk.line_range();
+        let last_line = line_range.end.saturating_sub(1).max(line_range.start);
+
+        let source_range = source_mapped_chunk.source_range();
+        let line_start = source[..source_range.start]
+            .rfind('\n')
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let column = Column::default().advance(&source[line_start..source_range.start]);
+        // Line endings may have been converted, see `Options::line_endings`.
+        let verbatim = source_mapped_chunk
+            .text()
+            .chars()
+            .filter(|c| *c != '\r')
+            .eq(source[source_range].chars().filter(|c| *c != '\r'));
+
+        self.record(source_mapped_chunk.text(), |index| {
+            // Subsequent lines of the chunk start at the start of their source line.
+            Some(Origin {
+                file,
+                line_number: (line_range.start + index).min(last_line),
+                column: if index == 0 {
+                    column
+                } else {
+                    Column::default()
+                },
+                verbatim,
+            })
+        });
+        self.output_sink.sink_source_mapped(source_mapped_chunk)
+    }
+
+    fn enter_include(
+        &mut self,
+        path: &Path,
+        from: &Path,
+        line_number: usize,
+    ) -> Result<(), S::Error> {
+        self.output_sink.enter_include(path, from, line_number)
+    }
+
+    fn exit_include(&mut self, path: &Path) -> Result<(), S::Error> {
+        self.output_sink.exit_include(path)
+    }
+
+    fn skip_include(
+        &mut self,
+        path: &Path,
+        from: &Path,
+        line_number: usize,
+    ) -> Result<(), S::Error> {
+        self.output_sink.skip_include(path, from, line_number)
+    }
+
+    fn finish(&mut self) -> Result<(), S::Error> {
+        self.output_sink.finish()
+    }
+}
+
+#[cfg(all(test, feature = "source-map"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_vlq() {
+        let encode = |value, previous| {
+            let mut output = String::new();
+
+            encode_vlq(&mut output, value, previous);
+
+            output
+        };
+
+        assert_eq!(encode(0, 0), "A");
+        assert_eq!(encode(1, 0), "C");
+        assert_eq!(encode(0, 1), "D");
+        assert_eq!(encode(15, 0), "e");
+        assert_eq!(encode(16, 0), "gB");
+        assert_eq!(encode(0, 16), "hB");
+        assert_eq!(encode(1000, 0), "w+B");
+    }
+}
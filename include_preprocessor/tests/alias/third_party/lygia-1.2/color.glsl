@@ -0,0 +1 @@
+float color = 1.0;
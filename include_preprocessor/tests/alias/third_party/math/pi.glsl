@@ -0,0 +1 @@
+float pi = 3.14;
@@ -0,0 +1 @@
+float common = 1.0;
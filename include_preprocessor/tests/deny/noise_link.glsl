@@ -0,0 +1 @@
+third_party/gpl/noise.glsl
\ No newline at end of file
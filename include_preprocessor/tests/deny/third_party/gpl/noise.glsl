@@ -0,0 +1 @@
+float noise = 1.0;
@@ -0,0 +1,3 @@
+﻿#version 450
+#include "utf8_bom_lib.glsl"
+void main() {}
@@ -0,0 +1 @@
+float exact = 2.0;
@@ -0,0 +1 @@
+float exact = 3.0;
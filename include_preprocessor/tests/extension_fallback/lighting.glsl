@@ -0,0 +1 @@
+float lighting = 1.0;
@@ -0,0 +1 @@
+float shared = 1.0;
@@ -0,0 +1 @@
+float shared = 2.0;
@@ -0,0 +1 @@
+float bloom = 1.0;
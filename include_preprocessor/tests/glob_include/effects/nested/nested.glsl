@@ -0,0 +1 @@
+float nested = 1.0;
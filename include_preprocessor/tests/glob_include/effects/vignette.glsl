@@ -0,0 +1 @@
+float vignette = 1.0;
@@ -0,0 +1,5 @@
+#extension GL_GOOGLE_include_directive :   enable
+
+  vec4 shade(vec2 uv)   {
+      return vec4( uv , uv.x + +uv.y , 1.0 );
+  }
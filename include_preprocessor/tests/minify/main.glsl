@@ -0,0 +1,14 @@
+#version 450 core
+
+#include "lib.glsl"
+
+   // The entry point.
+void main()  {
+    vec4 color = shade( vec2 ( 0.5 , .25 ) ) ;   
+	color.rgb  *=  - - 1.0;
+
+
+    if ( color.a >= 0.5 && color.r != 0.0 ) {
+        gl_FragColor = color ;
+    }
+}
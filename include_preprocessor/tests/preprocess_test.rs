@@ -1,17 +1,50 @@
 use std::env;
-use std::path::Path;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
-use include_preprocessor::{preprocess, SearchPaths, SourceTracker};
-use std::collections::HashSet;
+use include_preprocessor::{
+    expand_home, parse_tree, preprocess, preprocess_batch, preprocess_dependencies,
+    preprocess_dependencies_with_options, preprocess_include_graph, preprocess_reader,
+    preprocess_str, preprocess_str_with_options, preprocess_to_writer, preprocess_untracked,
+    preprocess_with_diagnostics, preprocess_with_options, preprocess_with_report,
+    CancellationToken, Chunk, CommentReplacement, CommentStyle, Concurrency, ContentFilter,
+    CountingSink, CustomDirective, DepfileTracker, DirectiveAction, DirectiveHandler,
+    DirectiveKind, Error, ErrorKind, FileOperation, FileProvider, FmtSink, IncludeKind,
+    IncludeMarkers, IncludeNewline, IncludePath, InvalidSearchPathReason, IoSink,
+    LineDirectiveMode, LineEndings, LoadEvent, LoadProgress, MemoryFs, MinifySink, NoopTracker,
+    Options, OutputSink, OverlayProvider, PathNormalization, PreprocessCache, Preprocessor,
+    ProgressObserver, Resolution, ResolutionCollector, ResolutionTrace, Resolver, SearchPathGroup,
+    SearchPathValidation, SearchPaths, Session, Severity, SizeLimit, SourceMapBuilder,
+    SourceMappedChunk, SourceTracker, TeeError, TeeSink,
+};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::ops::Range;
+#[cfg(feature = "threadpool")]
+use threadpool::ThreadPool;
 
 struct TestPathTracker {
     paths: HashSet<String>,
+    warnings: Vec<(String, PathBuf, usize)>,
+    notes: Vec<(String, PathBuf, usize)>,
+    includes: Vec<(PathBuf, PathBuf, usize, IncludeKind)>,
+    missing: Vec<PathBuf>,
 }
 
 impl TestPathTracker {
     fn new() -> Self {
         TestPathTracker {
             paths: HashSet::new(),
+            warnings: Vec::new(),
+            notes: Vec::new(),
+            includes: Vec::new(),
+            missing: Vec::new(),
         }
     }
 }
@@ -20,6 +53,31 @@ impl SourceTracker for TestPathTracker {
     fn track(&mut self, path: &Path, _source: &str) {
         self.paths.insert(path.to_str().unwrap().to_string());
     }
+
+    fn warning(&mut self, message: &str, source_file: &Path, line_number: usize) {
+        self.warnings
+            .push((message.to_string(), source_file.to_path_buf(), line_number));
+    }
+
+    fn note(&mut self, message: &str, source_file: &Path, line_number: usize) {
+        self.notes
+            .push((message.to_string(), source_file.to_path_buf(), line_number));
+    }
+
+    fn track_include(
+        &mut self,
+        parent: &Path,
+        child: &Path,
+        line_number: usize,
+        kind: IncludeKind,
+    ) {
+        self.includes
+            .push((parent.to_path_buf(), child.to_path_buf(), line_number, kind));
+    }
+
+    fn track_missing(&mut self, path: &Path) {
+        self.missing.push(path.to_path_buf());
+    }
 }
 
 #[test]
@@ -83,3 +141,6610 @@ fn test_preprocess_valid_2() {
         .paths
         .contains(base_path.join("tests/valid_2/c.txt").to_str().unwrap()));
 }
+
+#[test]
+fn test_preprocess_define() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/define/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+
+    let actual = res.unwrap();
+    let expected = include_str!("expected_define.txt");
+
+    assert_eq!(&actual, expected);
+}
+
+#[test]
+fn test_preprocess_define_comments() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/define_comments/a.txt");
+    let mut path_tracker = TestPathTracker::new();
+    let actual = preprocess(
+        entry_point,
+        SearchPaths::new(),
+        String::new(),
+        &mut path_tracker,
+    )
+    .unwrap();
+
+    assert_eq!(actual, include_str!("expected_define_comments.txt"));
+}
+
+#[test]
+fn test_preprocess_define_keep_directives() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/define/common.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        keep_define_directives: true,
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        entry_point,
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    assert!(res.is_ok());
+
+    let actual = res.unwrap();
+
+    assert_eq!(
+        &actual,
+        "\n#define MAX_LIGHTS 8\n#define LIGHT_RANGE 10.0\n"
+    );
+}
+
+#[test]
+fn test_preprocess_define_redefinition() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/redefine/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::Parse(err)) = res {
+        assert_eq!(err.source_file(), base_path.join("tests/redefine/b.txt"));
+        assert_eq!(err.line_number(), 2);
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_preprocess_function_like_macros() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/function_macros/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+
+    let actual = res.unwrap();
+    let expected = include_str!("expected_function_macros.txt");
+
+    assert_eq!(&actual, expected);
+}
+
+#[test]
+fn test_preprocess_recursive_macro() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/recursive_macro/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::Parse(err)) = res {
+        assert!(err.message().contains("`RECURSE`"));
+        assert_eq!(err.line_number(), 4);
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_preprocess_conditional() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/conditional/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+
+    let actual = res.unwrap();
+    let expected = include_str!("expected_conditional.txt");
+
+    assert_eq!(&actual, expected);
+}
+
+#[test]
+fn test_preprocess_unbalanced_endif() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/unbalanced_endif/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::Parse(err)) = res {
+        assert_eq!(
+            err.source_file(),
+            base_path.join("tests/unbalanced_endif/a.txt")
+        );
+        assert_eq!(err.line_number(), 2);
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_preprocess_if_expression() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/if_expression/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+
+    let actual = res.unwrap();
+    let expected = include_str!("expected_if_expression.txt");
+
+    assert_eq!(&actual, expected);
+}
+
+#[test]
+fn test_preprocess_malformed_if_expression() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/malformed_if/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::Parse(err)) = res {
+        assert!(err.message().contains("`VERSION >= 300`"));
+        assert_eq!(
+            err.source_file(),
+            base_path.join("tests/malformed_if/a.txt")
+        );
+        assert_eq!(err.line_number(), 3);
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_preprocess_elif_else() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/elif/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+
+    let actual = res.unwrap();
+    let expected = include_str!("expected_elif.txt");
+
+    assert_eq!(&actual, expected);
+}
+
+#[test]
+fn test_preprocess_duplicate_else() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/duplicate_else/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::Parse(err)) = res {
+        assert_eq!(err.line_number(), 3);
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_preprocess_elif_after_else() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/elif_after_else/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::Parse(err)) = res {
+        assert_eq!(err.line_number(), 3);
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_preprocess_undef() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/undef/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+
+    let actual = res.unwrap();
+    let expected = include_str!("expected_undef.txt");
+
+    assert_eq!(&actual, expected);
+}
+
+#[test]
+fn test_preprocess_error_directive() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/error_directive/invalid.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::Directive(err)) = res {
+        assert_eq!(err.message(), "include core.txt first");
+        assert_eq!(
+            err.source_file(),
+            base_path.join("tests/error_directive/lighting.txt")
+        );
+        assert_eq!(err.line_number(), 2);
+    } else {
+        panic!("expected a directive error");
+    }
+}
+
+#[test]
+fn test_preprocess_error_directive_disabled() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/error_directive/valid.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+    assert_eq!(&res.unwrap(), "float lighting = 1.0;\n");
+}
+
+#[test]
+fn test_preprocess_warning_directive() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/warning_directive/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+    assert_eq!(&res.unwrap(), "float a = 1.0;\n");
+    assert_eq!(
+        path_tracker.warnings,
+        vec![(
+            "legacy.txt is deprecated".to_string(),
+            base_path.join("tests/warning_directive/legacy.txt"),
+            2
+        )]
+    );
+}
+
+#[test]
+fn test_preprocess_external_defines() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/external_defines/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let mut options = Options::default();
+
+    options
+        .define("USE_NORMAL_MAPPING", "1")
+        .define("VERSION", "300");
+
+    let res = preprocess_with_options(
+        entry_point,
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "uniform sampler2D normal_map;\nout vec4 color;\nint version = 300;\n"
+    );
+}
+
+#[test]
+fn test_preprocess_builtin_macros() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/builtin_macros/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point.clone(), search_paths, buffer, &mut path_tracker);
+
+    let a = entry_point.canonicalize().unwrap();
+    let b = base_path
+        .join("tests/builtin_macros/b.txt")
+        .canonicalize()
+        .unwrap();
+    let expected = format!(
+        "const int a = 2;\nconst char* b = {:?};\n\nconst char* location = {:?} : 5;\nconst bool line_six = true;\n",
+        b.to_string_lossy(),
+        a.to_string_lossy()
+    );
+
+    assert!(res.is_ok());
+    assert_eq!(res.unwrap(), expected);
+}
+
+#[test]
+fn test_preprocess_builtin_macros_file_name_only() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/builtin_macros/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        file_macro_name_only: true,
+        ..Options::default()
+    };
+
+    let res = preprocess_with_options(
+        entry_point,
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "const int a = 2;\nconst char* b = \"b.txt\";\n\nconst char* location = \"a.txt\" : 5;\nconst bool line_six = true;\n"
+    );
+}
+
+#[test]
+fn test_preprocess_include_next() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(base_path.join("tests/include_next/override"));
+    search_paths.push_base_path(base_path.join("tests/include_next/default"));
+
+    let entry_point = base_path.join("tests/include_next/main.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "float default_lighting = 1.0;\nfloat override_lighting = 2.0;\nvoid main() {}\n"
+    );
+}
+
+#[test]
+fn test_preprocess_include_next_not_found() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(base_path.join("tests/include_next/override"));
+    search_paths.push_base_path(base_path.join("tests/include_next/default"));
+
+    let entry_point = base_path.join("tests/include_next/orphan_main.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::FileNotFound(err)) = res {
+        assert_eq!(err.included_path(), Path::new("orphan.txt"));
+        assert_eq!(
+            err.source_file(),
+            base_path.join("tests/include_next/override/orphan.txt")
+        );
+        assert_eq!(err.line_number(), 2);
+    } else {
+        panic!("expected a file not found error");
+    }
+}
+
+#[test]
+fn test_preprocess_include_guard() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/include_guard/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "// A classic include guard\nfloat guarded = 1.0;\n\nint referenced =  1;\nint referenced =  1;\n"
+    );
+}
+
+#[test]
+fn test_preprocess_strip_include_guards() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/include_guard/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        keep_define_directives: true,
+        strip_include_guards: true,
+        ..Options::default()
+    };
+
+    let res = preprocess_with_options(
+        entry_point,
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "// A classic include guard\nfloat guarded = 1.0;\n\n#define REFERENCED_H\nint referenced =  1;\n#define REFERENCED_H\nint referenced =  1;\n"
+    );
+}
+
+#[test]
+fn test_preprocess_line_directives_strip() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/line_directive/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        line_directives: LineDirectiveMode::Strip,
+        ..Options::default()
+    };
+
+    let res = preprocess_with_options(
+        entry_point,
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "float a = 1.0;\nfloat generated = 3.0;\nfloat b = 2.0;\n"
+    );
+}
+
+#[test]
+fn test_preprocess_line_directives_pass_through() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/line_directive/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        line_directives: LineDirectiveMode::PassThrough,
+        ..Options::default()
+    };
+
+    let res = preprocess_with_options(
+        entry_point,
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "float a = 1.0;\n#line 10 \"template.glsl\"\nfloat generated = 3.0;\nfloat b = 2.0;\n"
+    );
+}
+
+#[test]
+fn test_preprocess_line_directives_rewrite() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/line_directive/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        line_directives: LineDirectiveMode::Rewrite,
+        ..Options::default()
+    };
+
+    let res = preprocess_with_options(
+        entry_point.clone(),
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    let expected = format!(
+        "float a = 1.0;\n#line 10 \"template.glsl\"\nfloat generated = 3.0;\n#line 3 \"{}\"\nfloat b = 2.0;\n",
+        entry_point.canonicalize().unwrap().display()
+    );
+
+    assert!(res.is_ok());
+    assert_eq!(res.unwrap(), expected);
+}
+
+#[test]
+fn test_preprocess_line_markers() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/a.glsl",
+            "#version 450\n#define X 1\n#include \"b.glsl\"\nfloat c;\n#line 100\n#include \"b.glsl\"\nfloat d;\n",
+        )
+        .insert("/shaders/b.glsl", "float b;\n#if X\nfloat x;\n#endif\nfloat y;\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        line_directives: LineDirectiveMode::Rewrite,
+        line_markers: true,
+        ..Options::default()
+    };
+    let (output, report) = preprocess_with_report(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        output,
+        "#version 450\n\
+         #line 1 1\n\
+         float b;\n\
+         #line 3 1\n\
+         float x;\n\
+         #line 5 1\n\
+         float y;\n\
+         #line 4 0\n\
+         float c;\n\
+         #line 1 1\n\
+         float b;\n\
+         #line 3 1\n\
+         float x;\n\
+         #line 5 1\n\
+         float y;\n\
+         #line 7 0\n\
+         float d;\n"
+    );
+    assert_eq!(
+        report.file_ids(),
+        &[
+            PathBuf::from("/shaders/a.glsl"),
+            PathBuf::from("/shaders/b.glsl")
+        ]
+    );
+}
+
+#[test]
+fn test_preprocess_include_newline() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/a.glsl",
+            "#include \"b.glsl\"\n#include \"c.glsl\"\nfloat a;\n",
+        )
+        .insert("/shaders/b.glsl", "float b;")
+        .insert("/shaders/c.glsl", "float c;\n");
+
+    let preprocess_with = |include_newline| {
+        let options = Options {
+            file_provider: Arc::new(files.clone()),
+            include_newline,
+            ..Options::default()
+        };
+
+        preprocess_with_options(
+            "/shaders/a.glsl",
+            SearchPaths::new(),
+            options,
+            String::new(),
+            &mut TestPathTracker::new(),
+        )
+        .unwrap()
+    };
+
+    assert_eq!(
+        preprocess_with(IncludeNewline::IfMissing),
+        "float b;\nfloat c;\nfloat a;\n"
+    );
+    assert_eq!(
+        preprocess_with(IncludeNewline::Always),
+        "float b;\nfloat c;\n\nfloat a;\n"
+    );
+    assert_eq!(
+        preprocess_with(IncludeNewline::Never),
+        "float b;float c;\nfloat a;\n"
+    );
+}
+
+/// Records the source-mapped chunks of the output.
+struct ChunkRecorder {
+    output: String,
+    chunks: Vec<(String, PathBuf, Range<usize>)>,
+}
+
+impl OutputSink for ChunkRecorder {
+    type Error = Infallible;
+
+    fn sink(&mut self, chunk: &str) -> Result<(), Infallible> {
+        self.output.push_str(chunk);
+
+        Ok(())
+    }
+
+    fn sink_source_mapped(&mut self, chunk: SourceMappedChunk) -> Result<(), Infallible> {
+        self.output.push_str(chunk.text());
+        self.chunks.push((
+            chunk.text().to_string(),
+            chunk.source_path().to_path_buf(),
+            chunk.source_range(),
+        ));
+
+        Ok(())
+    }
+}
+
+/// Records the start position of each source mapped chunk.
+#[derive(Default)]
+struct PositionRecorder {
+    positions: Vec<(String, usize, usize)>,
+}
+
+impl OutputSink for PositionRecorder {
+    type Error = Infallible;
+
+    fn sink(&mut self, _chunk: &str) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn sink_source_mapped(&mut self, chunk: SourceMappedChunk) -> Result<(), Infallible> {
+        self.positions.push((
+            chunk.text().to_string(),
+            chunk.start_line(),
+            chunk.start_column(),
+        ));
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_source_mapped_chunk_start_position() {
+    let mut files = MemoryFs::new();
+
+    files.insert("/shaders/a.glsl", "#define B b\nfloat a;\n\nfloat ä = B;\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let recorder = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        PositionRecorder::default(),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        recorder.positions,
+        vec![
+            ("float a;\n\nfloat ä = ".to_string(), 2, 1),
+            ("b".to_string(), 4, 11),
+            (";\n".to_string(), 4, 12),
+        ]
+    );
+}
+
+#[test]
+fn test_preprocess_line_endings() {
+    let a = "float a;\r\n#include \"b.glsl\"\r\n#include \"c.glsl\"\r\nfloat d;\r\n";
+    let b = "float b;\nfloat b2;\n";
+    // Without a trailing newline, so that a newline is inserted after it.
+    let c = "float c;";
+
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/a.glsl", a)
+        .insert("/shaders/b.glsl", b)
+        .insert("/shaders/c.glsl", c);
+
+    let preprocess_with = |line_endings| {
+        let options = Options {
+            file_provider: Arc::new(files.clone()),
+            line_endings,
+            ..Options::default()
+        };
+
+        preprocess_with_options(
+            "/shaders/a.glsl",
+            SearchPaths::new(),
+            options,
+            ChunkRecorder {
+                output: String::new(),
+                chunks: Vec::new(),
+            },
+            &mut TestPathTracker::new(),
+        )
+        .unwrap()
+    };
+
+    let preserved = preprocess_with(LineEndings::Preserve);
+    let lf = preprocess_with(LineEndings::Lf);
+    let crlf = preprocess_with(LineEndings::CrLf);
+
+    assert_eq!(
+        preserved.output,
+        "float a;\r\nfloat b;\nfloat b2;\nfloat c;\nfloat d;\r\n"
+    );
+    assert_eq!(
+        lf.output,
+        "float a;\nfloat b;\nfloat b2;\nfloat c;\nfloat d;\n"
+    );
+    assert_eq!(
+        crlf.output,
+        "float a;\r\nfloat b;\r\nfloat b2;\r\nfloat c;\r\nfloat d;\r\n"
+    );
+
+    // The source ranges refer to the original text, whatever the line endings of the output.
+    for recorder in [preserved, lf, crlf] {
+        for (text, path, range) in recorder.chunks {
+            let source = match path.to_str().unwrap() {
+                "/shaders/a.glsl" => a,
+                "/shaders/b.glsl" => b,
+                _ => c,
+            };
+
+            assert_eq!(text.replace('\r', ""), source[range].replace('\r', ""));
+        }
+    }
+}
+
+#[test]
+fn test_preprocess_ensure_trailing_newline() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/no_newline.glsl", "float a;")
+        .insert(
+            "/shaders/include.glsl",
+            "float a;\n#include \"b.glsl\"\n\n\n",
+        )
+        .insert("/shaders/b.glsl", "float b;\n\n")
+        .insert("/shaders/crlf.glsl", "float a;\r\n\r\n")
+        .insert("/shaders/blank.glsl", "\n\n");
+
+    let preprocess_with = |entry_point, line_endings| {
+        let options = Options {
+            file_provider: Arc::new(files.clone()),
+            line_endings,
+            ensure_trailing_newline: true,
+            ..Options::default()
+        };
+
+        preprocess_with_options(
+            entry_point,
+            SearchPaths::new(),
+            options,
+            ChunkRecorder {
+                output: String::new(),
+                chunks: Vec::new(),
+            },
+            &mut TestPathTracker::new(),
+        )
+        .unwrap()
+    };
+
+    let recorders = [
+        (
+            preprocess_with("/shaders/no_newline.glsl", LineEndings::Preserve),
+            "float a;\n",
+        ),
+        (
+            preprocess_with("/shaders/include.glsl", LineEndings::Preserve),
+            "float a;\nfloat b;\n",
+        ),
+        (
+            preprocess_with("/shaders/include.glsl", LineEndings::CrLf),
+            "float a;\r\nfloat b;\r\n",
+        ),
+        (
+            preprocess_with("/shaders/crlf.glsl", LineEndings::Preserve),
+            "float a;\r\n",
+        ),
+        (
+            preprocess_with("/shaders/crlf.glsl", LineEndings::Lf),
+            "float a;\n",
+        ),
+        (
+            preprocess_with("/shaders/blank.glsl", LineEndings::Preserve),
+            "",
+        ),
+    ];
+
+    for (recorder, expected) in recorders {
+        assert_eq!(recorder.output, expected);
+
+        // The source ranges of the chunks exclude the trimmed line endings.
+        for (text, path, range) in recorder.chunks {
+            let source = files.read(&path).unwrap();
+
+            assert_eq!(text.replace('\r', ""), source[range].replace('\r', ""));
+        }
+    }
+}
+
+#[test]
+fn test_preprocess_strip_comments() {
+    let a = "float a; // a\n#include \"b.glsl\"\n/* multi\n line */ float c = 1.0/2.0;\n";
+    // The block comment is not terminated, but does not continue into `a.glsl`.
+    let b = "#define URL \"http://b\"\nURL; // b\n/* b";
+
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/a.glsl", a)
+        .insert("/shaders/b.glsl", b);
+
+    let mut options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+
+    options
+        .strip_comment(CommentStyle::Line)
+        .strip_comment(CommentStyle::Block);
+
+    let recorder = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        ChunkRecorder {
+            output: String::new(),
+            chunks: Vec::new(),
+        },
+        &mut TestPathTracker::new(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        recorder.output,
+        "float a;  \n\"http://b\";  \n \n \n float c = 1.0/2.0;\n"
+    );
+
+    // The retained text of the source files is mapped to its own source range.
+    for (text, path, range) in recorder.chunks {
+        let source = if path == Path::new("/shaders/a.glsl") {
+            a
+        } else {
+            b
+        };
+
+        if text != "\"http://b\"" {
+            assert_eq!(text, &source[range]);
+        }
+    }
+}
+
+#[test]
+fn test_preprocess_collapse_blank_lines() {
+    let a = "float a;\n\n  \n#include \"b.glsl\"\n\n\nfloat c;\n";
+    let b = "#pragma once\n\nfloat b;\n\n";
+
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/a.glsl", a)
+        .insert("/shaders/b.glsl", b);
+
+    let preprocess_with = |max| {
+        let mut options = Options {
+            file_provider: Arc::new(files.clone()),
+            include_newline: IncludeNewline::Always,
+            ..Options::default()
+        };
+
+        options
+            .blank_directive(DirectiveKind::Pragma)
+            .collapse_blank_lines(max);
+
+        preprocess_with_options(
+            "/shaders/a.glsl",
+            SearchPaths::new(),
+            options,
+            ChunkRecorder {
+                output: String::new(),
+                chunks: Vec::new(),
+            },
+            &mut TestPathTracker::new(),
+        )
+        .unwrap()
+    };
+
+    let one = preprocess_with(1);
+    let none = preprocess_with(0);
+
+    assert_eq!(one.output, "float a;\n\nfloat b;\n\nfloat c;\n");
+    assert_eq!(none.output, "float a;\nfloat b;\nfloat c;\n");
+
+    for recorder in [one, none] {
+        for (text, path, range) in recorder.chunks {
+            let source = if path == Path::new("/shaders/a.glsl") {
+                a
+            } else {
+                b
+            };
+
+            assert_eq!(text, &source[range]);
+        }
+    }
+}
+
+#[test]
+fn test_preprocess_indent_includes() {
+    let a = "root:\n  #include \"block.yaml\"\nend: 1\n";
+    let block = "key: 1\n\nnested:\n\t#include \"inner.yaml\"\n";
+    let inner = "x: 2\r\ny: 3";
+
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/config/a.yaml", a)
+        .insert("/config/block.yaml", block)
+        .insert("/config/inner.yaml", inner);
+
+    let options = Options {
+        file_provider: Arc::new(files.clone()),
+        indent_includes: true,
+        ..Options::default()
+    };
+    let recorder = preprocess_with_options(
+        "/config/a.yaml",
+        SearchPaths::new(),
+        options,
+        ChunkRecorder {
+            output: String::new(),
+            chunks: Vec::new(),
+        },
+        &mut TestPathTracker::new(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        recorder.output,
+        "root:\n  key: 1\n\n  nested:\n  \tx: 2\r\n  \ty: 3\nend: 1\n"
+    );
+
+    // The indentation is not part of the source mapped chunks.
+    for (text, path, range) in recorder.chunks {
+        assert_eq!(text, &files.read(&path).unwrap()[range]);
+    }
+}
+
+#[test]
+fn test_preprocess_source_map() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/a.glsl",
+            "#define PAIR(a, b) a; b;\nfloat a;\n#include \"b.glsl\"\nPAIR(float x,\n float y)\nfloat c;\n",
+        )
+        .insert("/shaders/b.glsl", "float b;\nfloat d;");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let (output, source_map) = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        SourceMapBuilder::new(String::new()),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap()
+    .finish();
+
+    assert_eq!(
+        output,
+        "float a;\nfloat b;\nfloat d;\nfloat x; float y;\nfloat c;\n"
+    );
+
+    let a = Path::new("/shaders/a.glsl");
+    let b = Path::new("/shaders/b.glsl");
+
+    assert_eq!(source_map.len(), 5);
+    assert_eq!(source_map.lookup(0), None);
+    assert_eq!(source_map.lookup(1), Some((a, 2)));
+    assert_eq!(source_map.lookup(2), Some((b, 1)));
+    // The last line of `b.glsl` is completed by the newline that follows the include.
+    assert_eq!(source_map.lookup(3), Some((b, 2)));
+    // The invocation spans two lines of the source, but only one of the output.
+    assert_eq!(source_map.lookup(4), Some((a, 4)));
+    assert_eq!(source_map.lookup(5), Some((a, 6)));
+    assert_eq!(source_map.lookup(6), None);
+}
+
+#[test]
+fn test_preprocess_include_markers() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/a.glsl",
+            "float a;\n#include \"lib/b.glsl\"\n#include \"lib/b.glsl\"\n",
+        )
+        .insert("/shaders/lib/b.glsl", "#pragma once\n#include \"c.glsl\"\n")
+        .insert("/shaders/lib/c.glsl", "float c;");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        include_markers: Some(IncludeMarkers {
+            end: "// end {path} ({depth})".to_string(),
+            ..IncludeMarkers::default()
+        }),
+        display_root: Some(PathBuf::from("/shaders")),
+        include_newline: IncludeNewline::Never,
+        ..Options::default()
+    };
+    let (output, source_map) = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        SourceMapBuilder::new(String::new()),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap()
+    .finish();
+
+    assert_eq!(
+        output,
+        "float a;\n\
+        // >>> begin \"lib/b.glsl\"\n\
+        // >>> begin \"lib/c.glsl\"\n\
+        float c;\n\
+        // end lib/c.glsl (2)\n\
+        // end lib/b.glsl (1)\n\
+        // skipped (already included)\n"
+    );
+
+    // Only the lines of the source files are attributed to them.
+    let mapped: Vec<_> = (1..=7).map(|line| source_map.lookup(line)).collect();
+
+    assert_eq!(
+        mapped,
+        vec![
+            Some((Path::new("/shaders/a.glsl"), 1)),
+            None,
+            None,
+            Some((Path::new("/shaders/lib/c.glsl"), 1)),
+            None,
+            None,
+            None
+        ]
+    );
+}
+
+#[test]
+fn test_preprocess_prologue_epilogue() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/a.glsl", "float a;\n#include \"b.glsl\"\n")
+        .insert("/shaders/b.glsl", "float b;");
+
+    let mut options = Options {
+        file_provider: Arc::new(files),
+        line_markers: true,
+        ..Options::default()
+    };
+
+    options.prologue("#version 450").epilogue("// end\n");
+
+    let (output, source_map) = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        SourceMapBuilder::new(String::new()),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap()
+    .finish();
+
+    assert_eq!(
+        output,
+        "#version 450\n#line 1 0\nfloat a;\n#line 1 1\nfloat b;\n// end\n"
+    );
+
+    let a = Path::new("/shaders/a.glsl");
+    let b = Path::new("/shaders/b.glsl");
+    let mapped: Vec<_> = (1..=6).map(|line| source_map.lookup(line)).collect();
+
+    assert_eq!(
+        mapped,
+        vec![None, None, Some((a, 1)), None, Some((b, 1)), None]
+    );
+}
+
+/// Records the include notifications, interleaved with the output.
+#[derive(Default)]
+struct IncludeRecorder {
+    events: Vec<String>,
+}
+
+impl OutputSink for IncludeRecorder {
+    type Error = Infallible;
+
+    fn sink(&mut self, chunk: &str) -> Result<(), Infallible> {
+        self.events.push(chunk.to_string());
+
+        Ok(())
+    }
+
+    fn sink_source_mapped(&mut self, chunk: SourceMappedChunk) -> Result<(), Infallible> {
+        self.events.push(chunk.text().to_string());
+
+        Ok(())
+    }
+
+    fn enter_include(
+        &mut self,
+        path: &Path,
+        from: &Path,
+        line_number: usize,
+    ) -> Result<(), Infallible> {
+        self.events.push(format!(
+            "enter {} from {}:{}",
+            path.display(),
+            from.display(),
+            line_number
+        ));
+
+        Ok(())
+    }
+
+    fn exit_include(&mut self, path: &Path) -> Result<(), Infallible> {
+        self.events.push(format!("exit {}", path.display()));
+
+        Ok(())
+    }
+
+    fn skip_include(
+        &mut self,
+        path: &Path,
+        from: &Path,
+        line_number: usize,
+    ) -> Result<(), Infallible> {
+        self.events.push(format!(
+            "skip {} from {}:{}",
+            path.display(),
+            from.display(),
+            line_number
+        ));
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_preprocess_include_notifications() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/a.glsl",
+            "float a;\n#include \"b.glsl\"\n#include \"b.glsl\"\nfloat d;\n",
+        )
+        .insert("/b.glsl", "#pragma once\n#include \"c.glsl\"\n")
+        .insert("/c.glsl", "float c;\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let recorder = preprocess_with_options(
+        "/a.glsl",
+        SearchPaths::new(),
+        options,
+        IncludeRecorder::default(),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        recorder.events,
+        vec![
+            "float a;\n",
+            "enter /b.glsl from /a.glsl:2",
+            "enter /c.glsl from /b.glsl:2",
+            "float c;\n",
+            "exit /c.glsl",
+            "exit /b.glsl",
+            "skip /b.glsl from /a.glsl:3",
+            "float d;\n",
+        ]
+    );
+}
+
+#[test]
+fn test_preprocess_source_map_synthetic_lines() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/a.glsl",
+            "float a;\n#include \"b.glsl\"\nfloat c;\n",
+        )
+        .insert("/shaders/b.glsl", "float b;\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        include_newline: IncludeNewline::Always,
+        line_markers: true,
+        ..Options::default()
+    };
+    let (output, source_map) = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        SourceMapBuilder::new(String::new()),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap()
+    .finish();
+
+    assert_eq!(
+        output,
+        "float a;\n#line 1 1\nfloat b;\n\n#line 3 0\nfloat c;\n"
+    );
+
+    let a = Path::new("/shaders/a.glsl");
+    let b = Path::new("/shaders/b.glsl");
+
+    assert_eq!(source_map.lookup(1), Some((a, 1)));
+    assert_eq!(source_map.lookup(2), None);
+    assert_eq!(source_map.lookup(3), Some((b, 1)));
+    assert_eq!(source_map.lookup(4), None);
+    assert_eq!(source_map.lookup(5), None);
+    assert_eq!(source_map.lookup(6), Some((a, 3)));
+}
+
+#[test]
+fn test_source_map_positions() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/a.glsl",
+            "#define SCALE 2.0\nfloat a;\n#include \"common.glsl\"\nfloat b = SCALE * 3.0;\n#include \"common.glsl\"\n",
+        )
+        .insert("/shaders/common.glsl", "float c;\nfloat d;\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let (output, source_map) = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        SourceMapBuilder::new(String::new()),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap()
+    .finish();
+
+    assert_eq!(
+        output,
+        "float a;\nfloat c;\nfloat d;\nfloat b = 2.0 * 3.0;\nfloat c;\nfloat d;\n"
+    );
+
+    let a = Path::new("/shaders/a.glsl");
+    let common = Path::new("/shaders/common.glsl");
+    let resolve = |line, column| {
+        source_map
+            .resolve(line, column)
+            .map(|position| (position.path(), position.line(), position.column()))
+    };
+
+    assert_eq!(resolve(1, 0), Some((a, 2, 0)));
+    assert_eq!(resolve(6, 3), Some((common, 2, 3)));
+    // A position in the expansion of `SCALE` resolves to the invocation.
+    assert_eq!(resolve(4, 11), Some((a, 4, 10)));
+    assert_eq!(resolve(4, 14), Some((a, 4, 16)));
+    assert_eq!(resolve(7, 0), None);
+    assert_eq!(resolve(100, 0), None);
+
+    let find = |path, line| {
+        source_map
+            .find_in_output(path, line)
+            .iter()
+            .map(|position| (position.line(), position.column()))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(find(common, 2), vec![(3, 0), (6, 0)]);
+    assert_eq!(find(a, 4), vec![(4, 0)]);
+    assert_eq!(find(a, 1), vec![]);
+    assert_eq!(find(Path::new("/shaders/other.glsl"), 1), vec![]);
+}
+
+fn blank_directives_options() -> Options {
+    let mut options = Options {
+        line_directives: LineDirectiveMode::Strip,
+        ..Options::default()
+    };
+
+    options
+        .blank_directive(DirectiveKind::Include)
+        .blank_directive(DirectiveKind::Pragma)
+        .blank_directive(DirectiveKind::Define)
+        .blank_directive(DirectiveKind::Conditional)
+        .blank_directive(DirectiveKind::Line)
+        .blank_directive(DirectiveKind::Warning);
+
+    options
+}
+
+#[test]
+fn test_preprocess_blank_directives() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/blank_directives/a.txt");
+    let mut path_tracker = TestPathTracker::new();
+    let output = preprocess_with_options(
+        &entry_point,
+        search_paths,
+        blank_directives_options(),
+        String::new(),
+        &mut path_tracker,
+    )
+    .unwrap();
+
+    let entry_lines = std::fs::read_to_string(&entry_point)
+        .unwrap()
+        .lines()
+        .count();
+    let common_lines = std::fs::read_to_string(base_path.join("tests/blank_directives/common.txt"))
+        .unwrap()
+        .lines()
+        .count();
+
+    // Only the expanded include adds lines.
+    assert_eq!(output.lines().count(), entry_lines + common_lines);
+    assert_eq!(
+        output,
+        "\n\nfloat a = 1.0;\n\nfloat b = 2.0;\n\n\n\n\n\nfloat common = 0.0;\n\n\n\n\nfloat c = 3.0;\n"
+    );
+}
+
+#[test]
+fn test_preprocess_blank_directives_by_kind() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/blank_directives/a.txt");
+    let mut path_tracker = TestPathTracker::new();
+    let mut options = Options {
+        line_directives: LineDirectiveMode::Strip,
+        ..Options::default()
+    };
+
+    options.blank_directive(DirectiveKind::Conditional);
+
+    let output = preprocess_with_options(
+        &entry_point,
+        search_paths,
+        options,
+        String::new(),
+        &mut path_tracker,
+    )
+    .unwrap();
+
+    assert_eq!(output.lines().count(), 6);
+    assert_eq!(
+        output,
+        "float a = 1.0;\n\nfloat b = 2.0;\n\nfloat common = 0.0;\nfloat c = 3.0;\n"
+    );
+}
+
+#[test]
+fn test_preprocess_blank_directives_line_markers() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/blank_directives/a.txt");
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        line_markers: true,
+        ..blank_directives_options()
+    };
+    let output = preprocess_with_options(
+        &entry_point,
+        search_paths,
+        options,
+        String::new(),
+        &mut path_tracker,
+    )
+    .unwrap();
+
+    // The blank lines keep the output in step with the source, so that markers are only needed
+    // around the include.
+    assert_eq!(
+        output,
+        "\n\nfloat a = 1.0;\n\nfloat b = 2.0;\n\n\n\n\n\
+         #line 1 1\n\nfloat common = 0.0;\n\n\
+         #line 11 0\n\n\n\nfloat c = 3.0;\n"
+    );
+}
+
+#[test]
+fn test_preprocess_defined_operator() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/defined_operator/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+    assert_eq!(&res.unwrap(), "float shadows = 1.0;\n");
+}
+
+#[test]
+fn test_preprocess_include_macro() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/include_macro/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "float common = 1.0;\nfloat platform = 1.0;\nvoid main() {}\n"
+    );
+    assert!(path_tracker.paths.contains(&format!(
+        "{}/tests/include_macro/gl/common.txt",
+        cargo_manifest_dir
+    )));
+}
+
+#[test]
+fn test_preprocess_include_macro_undefined() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/include_macro/undefined.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::Parse(err)) = res {
+        assert!(err.message().contains("PLATFORM_HEADER"));
+        assert_eq!(err.line_number(), 1);
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_preprocess_include_macro_invalid() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/include_macro/invalid.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::Parse(err)) = res {
+        assert!(err.message().contains("PLATFORM_HEADER"));
+        assert_eq!(err.line_number(), 2);
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_preprocess_pragma_message() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/pragma_message/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+    assert_eq!(&res.unwrap(), "float a = 1.0;\n");
+    assert_eq!(
+        path_tracker.notes,
+        vec![(
+            "deprecated, use pbr_common.glsl".to_string(),
+            base_path.join("tests/pragma_message/a.txt"),
+            1
+        )]
+    );
+    assert!(path_tracker.warnings.is_empty());
+}
+
+#[test]
+fn test_preprocess_unterminated_conditional() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/unterminated_conditional/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::UnterminatedConditional(err)) = res {
+        assert_eq!(err.directive(), "#ifndef INNER");
+        assert_eq!(
+            err.source_file(),
+            base_path.join("tests/unterminated_conditional/a.txt")
+        );
+        assert_eq!(err.line_number(), 6);
+    } else {
+        panic!("expected an unterminated conditional error");
+    }
+}
+
+#[test]
+fn test_preprocess_include_depth() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/include_depth/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        file_macro_name_only: true,
+        ..Options::default()
+    };
+
+    let res = preprocess_with_options(
+        entry_point,
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "int depth = 1; // \"a.txt\"\nint depth = 2; // \"a.txt\"\n"
+    );
+}
+
+#[test]
+fn test_preprocess_with_report() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/report/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let mut options = Options::default();
+
+    options.define("VERSION", "300");
+
+    let res = preprocess_with_report(
+        entry_point,
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    assert!(res.is_ok());
+
+    let (_, report) = res.unwrap();
+    let defined_macros = report.defined_macros();
+
+    assert_eq!(
+        defined_macros.keys().collect::<Vec<_>>(),
+        vec!["MAX_LIGHTS", "SATURATE", "VERSION"]
+    );
+
+    let max_lights = &defined_macros["MAX_LIGHTS"];
+
+    assert_eq!(max_lights.parameters(), None);
+    assert_eq!(max_lights.body(), "8");
+    assert_eq!(
+        max_lights.source_file(),
+        Some(base_path.join("tests/report/b.txt").as_path())
+    );
+    assert_eq!(max_lights.line_number(), Some(1));
+
+    let saturate = &defined_macros["SATURATE"];
+
+    assert_eq!(saturate.parameters(), Some(&["x".to_string()][..]));
+    assert_eq!(saturate.body(), "clamp((x), 0.0, 1.0)");
+    assert_eq!(saturate.line_number(), Some(2));
+
+    let version = &defined_macros["VERSION"];
+
+    assert_eq!(version.body(), "300");
+    assert_eq!(version.source_file(), None);
+    assert_eq!(version.line_number(), None);
+}
+
+#[test]
+fn test_preprocess_stats() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/valid/a.txt");
+    let start = SystemTime::now();
+    let (output, report) = preprocess_with_report(
+        entry_point,
+        search_paths,
+        Options::default(),
+        String::new(),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap();
+    let elapsed = start.elapsed().unwrap();
+    let stats = report.stats();
+
+    assert_eq!(output, include_str!("expected.txt"));
+    assert_eq!(stats.files_read(), 3);
+    assert_eq!(
+        stats.bytes_read(),
+        include_str!("valid/a.txt").len()
+            + include_str!("valid/b.txt").len()
+            + include_str!("valid/c.txt").len()
+    );
+    assert_eq!(stats.bytes_emitted(), output.len());
+    // The second include of `c.txt`, which contains `#pragma once`.
+    assert_eq!(stats.skipped_includes(), 1);
+    assert!(stats.load_time() > Duration::ZERO);
+    assert!(stats.load_time() + stats.write_time() <= elapsed);
+}
+
+#[test]
+fn test_preprocess_strict_directives() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/strict_directives/valid.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let mut options = Options {
+        strict_directives: true,
+        ..Options::default()
+    };
+
+    options
+        .allow_directive("version")
+        .allow_directive("extension");
+
+    let res = preprocess_with_options(
+        entry_point,
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "#version 300 es\n#extension GL_EXT_shadow_samplers : enable\nfloat a = 1.0;\n"
+    );
+}
+
+#[test]
+fn test_preprocess_strict_directives_unknown() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/strict_directives/invalid.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let mut options = Options {
+        strict_directives: true,
+        ..Options::default()
+    };
+
+    options.allow_directive("version");
+
+    let res = preprocess_with_options(
+        entry_point,
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    if let Err(Error::Parse(err)) = res {
+        assert_eq!(err.message(), "unknown directive `#pragma onse`");
+        assert_eq!(
+            err.source_file(),
+            base_path.join("tests/strict_directives/invalid.txt")
+        );
+        assert_eq!(err.line_number(), 2);
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_preprocess_extension_fallback() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+    search_paths.push_extension(".glsl");
+    search_paths.push_extension(".wgsl");
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/extension_fallback/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "float lighting = 1.0;\nfloat exact = 1.0;\nvoid main() {}\n"
+    );
+    assert!(path_tracker.paths.contains(&format!(
+        "{}/tests/extension_fallback/lighting.glsl",
+        cargo_manifest_dir
+    )));
+}
+
+#[test]
+fn test_preprocess_extension_fallback_ambiguous() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+    search_paths.push_extension(".glsl");
+    search_paths.push_extension(".wgsl");
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/extension_fallback/ambiguous.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::Parse(err)) = res {
+        assert!(err.message().contains("shared.glsl"));
+        assert!(err.message().contains("shared.wgsl"));
+        assert_eq!(err.line_number(), 2);
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_preprocess_case_insensitive() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths
+        .push_base_path(&cargo_manifest_dir)
+        .set_case_insensitive(true);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/case_insensitive/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+    assert_eq!(&res.unwrap(), "float common = 1.0;\nvoid main() {}\n");
+    assert_eq!(
+        path_tracker.warnings,
+        vec![(
+            "include `lib/Common.GLSL` only matches `Lib/common.glsl` case-insensitively"
+                .to_string(),
+            base_path.join("tests/case_insensitive/a.txt"),
+            1
+        )]
+    );
+}
+
+#[test]
+fn test_preprocess_case_insensitive_disabled() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/case_insensitive/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(matches!(res, Err(Error::FileNotFound(_))));
+}
+
+#[test]
+fn test_preprocess_case_insensitive_ambiguous() {
+    // Files that only differ in case cannot be checked out on every file system, so serve them
+    // from memory.
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/a.txt", "#include \"Shared.glsl\"\n")
+        .insert("/shaders/shared.glsl", "float shared = 1.0;\n")
+        .insert("/shaders/SHARED.glsl", "float shared = 2.0;\n");
+
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.set_case_insensitive(true);
+
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/a.txt",
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    if let Err(Error::Parse(err)) = res {
+        assert!(err.message().contains("/shaders/shared.glsl"));
+        assert!(err.message().contains("/shaders/SHARED.glsl"));
+        assert_eq!(err.line_number(), 1);
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_preprocess_root_relative() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/root_relative/nested/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "float colors = 1.0;\nfloat colors = 1.0;\nvoid main() {}\n"
+    );
+}
+
+#[test]
+fn test_preprocess_root_relative_root_path() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+    search_paths.set_root_path(base_path.join("tests/root_relative"));
+
+    let entry_point = base_path.join("tests/root_relative/nested/b.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "float colors = 1.0;\nfloat colors = 1.0;\nvoid main() {}\n"
+    );
+}
+
+#[test]
+fn test_preprocess_memory_fs() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/main.glsl",
+            "#include \"lib/math.glsl\"\n#include <common.glsl>\nvoid main() {}\n",
+        )
+        .insert(
+            "/shaders/lib/math.glsl",
+            "#pragma once\n#include \"../../include/common.glsl\"\nfloat math = 1.0;\n",
+        )
+        .insert(
+            "/include/common.glsl",
+            "#pragma once\nfloat common = 1.0;\n",
+        );
+
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path("/include");
+
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/main.glsl",
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "float common = 1.0;\nfloat math = 1.0;\nvoid main() {}\n"
+    );
+    assert!(path_tracker.paths.contains("/shaders/lib/math.glsl"));
+    assert!(path_tracker.paths.contains("/include/common.glsl"));
+}
+
+#[test]
+fn test_preprocess_memory_fs_missing_include() {
+    let mut files = MemoryFs::new();
+
+    files.insert("/shaders/main.glsl", "#include \"missing.glsl\"\n");
+
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/main.glsl",
+        SearchPaths::new(),
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    if let Err(Error::FileNotFound(err)) = res {
+        assert_eq!(err.included_path(), Path::new("missing.glsl"));
+        assert_eq!(err.source_file(), Path::new("/shaders/main.glsl"));
+    } else {
+        panic!("expected a file not found error");
+    }
+}
+
+#[test]
+fn test_preprocess_overlay_provider() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let mut files = OverlayProvider::new();
+
+    files
+        .insert(
+            base_path.join("tests/overlay/b.txt"),
+            "#pragma once\nfloat b = 2.0;\n",
+        )
+        .insert(base_path.join("tests/overlay/c.txt"), "float c = 1.0;\n");
+
+    let entry_point = base_path.join("tests/overlay/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        entry_point,
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "float b = 2.0;\nfloat c = 1.0;\nvoid main() {}\n"
+    );
+}
+
+#[test]
+fn test_preprocess_include_cycle() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/include_cycle/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+    let a = base_path.join("tests/include_cycle/a.txt");
+    let b = base_path.join("tests/include_cycle/b.txt");
+
+    if let Err(Error::IncludeCycle(cycle)) = &res {
+        assert_eq!(cycle.includes(), &[(a.clone(), 2), (b.clone(), 1)]);
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            format!(
+                "include cycle: {0}:2 → {1}:1 → {0}",
+                a.display(),
+                b.display()
+            )
+        );
+    } else {
+        panic!("expected an include cycle error");
+    }
+}
+
+#[test]
+fn test_preprocess_include_cycle_once() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/include_cycle/once_a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+    assert_eq!(&res.unwrap(), "float b = 1.0;\nfloat a = 1.0;\n");
+}
+
+#[test]
+fn test_preprocess_alias() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+    search_paths.push_alias("lygia/", "tests/alias/third_party/lygia-1.2/");
+    search_paths.push_alias("lygia/math/", "tests/alias/third_party/math/");
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/alias/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "float color = 1.0;\nfloat pi = 3.14;\nvoid main() {}\n"
+    );
+}
+
+#[test]
+fn test_preprocess_alias_not_found() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+    search_paths.push_alias("lygia/", "tests/alias/third_party/lygia-1.2/");
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/alias/missing.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::FileNotFound(err)) = res {
+        assert_eq!(err.included_path(), Path::new("lygia/missing.glsl"));
+    } else {
+        panic!("expected a file not found error");
+    }
+}
+
+#[test]
+fn test_preprocess_include_optional() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/include_optional/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+    assert_eq!(&res.unwrap(), "float present = 1.0;\nvoid main() {}\n");
+    assert_eq!(
+        path_tracker.notes,
+        vec![(
+            "optional include `overrides/post_fx.glsl` was not found".to_string(),
+            base_path.join("tests/include_optional/a.txt"),
+            1
+        )]
+    );
+    assert!(path_tracker.paths.contains(&format!(
+        "{}/tests/include_optional/present.txt",
+        cargo_manifest_dir
+    )));
+}
+
+#[test]
+fn test_preprocess_deny_pattern() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+    search_paths.push_deny_pattern("third_party/gpl/**");
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/deny/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::DeniedInclude(err)) = res {
+        assert_eq!(err.pattern(), "third_party/gpl/**");
+        assert_eq!(
+            err.resolved_path(),
+            base_path.join("tests/deny/third_party/gpl/noise.glsl")
+        );
+        assert_eq!(err.source_file(), base_path.join("tests/deny/a.txt"));
+        assert_eq!(err.line_number(), 2);
+    } else {
+        panic!("expected a denied include error");
+    }
+}
+
+#[test]
+fn test_preprocess_deny_pattern_symlink() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+    search_paths.push_deny_pattern("third_party/gpl/**");
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/deny/symlink.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::DeniedInclude(err)) = res {
+        assert_eq!(
+            err.resolved_path(),
+            base_path.join("tests/deny/third_party/gpl/noise.glsl")
+        );
+        assert_eq!(err.line_number(), 1);
+    } else {
+        panic!("expected a denied include error");
+    }
+}
+
+#[test]
+fn test_preprocess_sandbox_root() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.set_sandbox_root(base_path.join("tests/sandbox/root"));
+
+    let entry_point = base_path.join("tests/sandbox/root/c.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert!(res.is_ok());
+    assert_eq!(&res.unwrap(), "float inner = 1.0;\nvoid main() {}\n");
+}
+
+#[test]
+fn test_preprocess_sandbox_root_escape() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.set_sandbox_root(base_path.join("tests/sandbox/root"));
+
+    let entry_point = base_path.join("tests/sandbox/root/a.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::SandboxEscape(err)) = res {
+        assert_eq!(err.included_path(), Path::new("../outside.txt"));
+        assert_eq!(
+            err.resolved_path(),
+            base_path.join("tests/sandbox/outside.txt")
+        );
+        assert_eq!(
+            err.source_file(),
+            base_path.join("tests/sandbox/root/a.txt")
+        );
+        assert_eq!(err.line_number(), 2);
+    } else {
+        panic!("expected a sandbox escape error");
+    }
+}
+
+#[test]
+fn test_preprocess_sandbox_root_symlink() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.set_sandbox_root(base_path.join("tests/sandbox/root"));
+
+    let entry_point = base_path.join("tests/sandbox/root/b.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    if let Err(Error::SandboxEscape(err)) = res {
+        assert_eq!(err.included_path(), Path::new("link.txt"));
+        assert_eq!(
+            err.resolved_path(),
+            base_path.join("tests/sandbox/outside.txt")
+        );
+    } else {
+        panic!("expected a sandbox escape error");
+    }
+}
+
+#[derive(Debug)]
+struct RegistryResolver;
+
+impl Resolver for RegistryResolver {
+    fn resolve(
+        &self,
+        include_path: &IncludePath,
+        _including_file: &Path,
+        _search_paths: &SearchPaths,
+    ) -> Resolution {
+        let path = include_path.path().to_str().unwrap();
+
+        match path.strip_prefix("registry:") {
+            Some("missing") => Resolution::Failed("`missing` is not registered".to_string()),
+            Some(name) => Resolution::Resolved(format!("/registry/{}.glsl", name).into()),
+            None => Resolution::Unhandled,
+        }
+    }
+}
+
+#[test]
+fn test_preprocess_resolver() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/main.glsl",
+            "#include <registry:blur>\n#include \"common.glsl\"\nvoid main() {}\n",
+        )
+        .insert("/shaders/common.glsl", "float common = 1.0;\n")
+        .insert("/registry/blur.glsl", "float blur = 1.0;\n");
+
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.set_resolver(RegistryResolver);
+
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/main.glsl",
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    assert!(res.is_ok());
+    assert_eq!(
+        &res.unwrap(),
+        "float blur = 1.0;\nfloat common = 1.0;\nvoid main() {}\n"
+    );
+    assert!(path_tracker.paths.contains("/registry/blur.glsl"));
+}
+
+#[test]
+fn test_preprocess_resolver_failed() {
+    let mut files = MemoryFs::new();
+
+    files.insert("/shaders/main.glsl", "#include <registry:missing>\n");
+
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.set_resolver(RegistryResolver);
+
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/main.glsl",
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    if let Err(Error::Parse(err)) = res {
+        assert_eq!(err.message(), "`missing` is not registered");
+        assert_eq!(err.line_number(), 1);
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_search_paths_from_env() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let paths = env::join_paths([
+        base_path.join("tests/include_next/override"),
+        PathBuf::new(),
+        base_path.join("tests/include_next/default"),
+    ])
+    .unwrap();
+
+    env::set_var("INCLUDE_PREPROCESSOR_TEST_PATHS", &paths);
+
+    assert_eq!(
+        SearchPaths::from_env_str(&paths).base_paths(),
+        &[
+            base_path.join("tests/include_next/override"),
+            base_path.join("tests/include_next/default")
+        ]
+    );
+    assert_eq!(
+        SearchPaths::from_env_var("INCLUDE_PREPROCESSOR_TEST_PATHS").base_paths(),
+        SearchPaths::from_env_str(&paths).base_paths()
+    );
+    assert!(
+        SearchPaths::from_env_var("INCLUDE_PREPROCESSOR_TEST_PATHS_UNSET")
+            .base_paths()
+            .is_empty()
+    );
+
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    assert_eq!(
+        search_paths.extend_from_env_var("INCLUDE_PREPROCESSOR_TEST_PATHS"),
+        2
+    );
+    assert_eq!(
+        search_paths.extend_from_env_var("INCLUDE_PREPROCESSOR_TEST_PATHS_UNSET"),
+        0
+    );
+    assert_eq!(search_paths.extend_from_env_str(""), 0);
+    assert_eq!(
+        search_paths.base_paths(),
+        &[
+            base_path.to_path_buf(),
+            base_path.join("tests/include_next/override"),
+            base_path.join("tests/include_next/default")
+        ]
+    );
+}
+
+#[test]
+fn test_search_paths_builder() {
+    let search_paths = SearchPaths::new()
+        .with_base_path("a")
+        .with_base_path("b")
+        .with_quoted_path("c");
+
+    assert_eq!(
+        search_paths.base_paths(),
+        &[PathBuf::from("a"), PathBuf::from("b")]
+    );
+    assert_eq!(search_paths.quoted_paths(), &[PathBuf::from("c")]);
+
+    let mut cloned = search_paths.clone();
+
+    cloned.push_base_path("d").push_quoted_path("e");
+
+    assert_eq!(search_paths.base_paths().len(), 2);
+    assert_eq!(cloned.base_paths().len(), 3);
+    assert_eq!(cloned.quoted_paths().len(), 2);
+    assert!(format!("{:?}", cloned).contains("\"e\""));
+}
+
+#[test]
+fn test_search_paths_validate() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths
+        .push_base_path(base_path.join("tests/valid"))
+        .push_base_path(base_path.join("tests/missing"))
+        .push_quoted_path(base_path.join("tests/valid/a.txt"));
+
+    let invalid = search_paths.validate();
+
+    assert_eq!(invalid.len(), 2);
+    assert_eq!(invalid[0].path(), base_path.join("tests/missing"));
+    assert_eq!(invalid[0].reason(), &InvalidSearchPathReason::Missing);
+    assert_eq!(invalid[1].path(), base_path.join("tests/valid/a.txt"));
+    assert_eq!(invalid[1].reason(), &InvalidSearchPathReason::NotADirectory);
+}
+
+#[test]
+fn test_preprocess_search_path_validation() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let search_paths = SearchPaths::new().with_base_path(base_path.join("tests/missing"));
+    let entry_point = base_path.join("tests/valid/c.txt");
+
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        search_path_validation: SearchPathValidation::Warn,
+        ..Options::default()
+    };
+    let (_, report) = preprocess_with_report(
+        &entry_point,
+        search_paths.clone(),
+        options,
+        String::new(),
+        &mut path_tracker,
+    )
+    .unwrap();
+    let diagnostics = report.diagnostics();
+
+    assert!(path_tracker.warnings.is_empty());
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].severity(), Severity::Warning);
+    assert_eq!(diagnostics[0].path(), base_path.join("tests/missing"));
+    assert_eq!(diagnostics[0].line(), None);
+    assert_eq!(
+        diagnostics[0].to_string(),
+        format!(
+            "{0}: warning: search path `{0}` does not exist",
+            base_path.join("tests/missing").display()
+        )
+    );
+
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        search_path_validation: SearchPathValidation::Strict,
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        &entry_point,
+        search_paths,
+        options,
+        String::new(),
+        &mut path_tracker,
+    );
+
+    if let Err(Error::InvalidSearchPath(err)) = res {
+        assert_eq!(err.path(), base_path.join("tests/missing"));
+        assert_eq!(err.reason(), &InvalidSearchPathReason::Missing);
+    } else {
+        panic!("expected an invalid search path error");
+    }
+}
+
+/// Preprocesses an entry point that consists of the single `include` line, with the given angle
+/// include and quoted include policy, and returns whether the include was resolved.
+fn resolves_with_policy(include: &str, angle_quoted_search: bool, quoted_fallback: bool) -> bool {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/src/main.txt", format!("{}\n", include))
+        .insert("/src/local.txt", "local\n")
+        .insert("/quoted/q.txt", "quoted\n")
+        .insert("/base/b.txt", "base\n");
+
+    let mut search_paths = SearchPaths::new()
+        .with_base_path("/base")
+        .with_quoted_path("/quoted");
+
+    search_paths.set_angle_quoted_search(angle_quoted_search);
+    search_paths.set_quoted_fallback(quoted_fallback);
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/src/main.txt",
+        search_paths,
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    match res {
+        Ok(_) => true,
+        Err(Error::FileNotFound(_)) => false,
+        Err(err) => panic!("unexpected error: {:?}", err),
+    }
+}
+
+#[test]
+fn test_preprocess_search_policy_default() {
+    assert!(resolves_with_policy("#include <b.txt>", false, true));
+    assert!(!resolves_with_policy("#include <q.txt>", false, true));
+    assert!(!resolves_with_policy("#include <local.txt>", false, true));
+    assert!(resolves_with_policy("#include \"local.txt\"", false, true));
+    assert!(resolves_with_policy("#include \"q.txt\"", false, true));
+    assert!(resolves_with_policy("#include \"b.txt\"", false, true));
+}
+
+#[test]
+fn test_preprocess_search_policy_angle_quoted_search() {
+    assert!(resolves_with_policy("#include <b.txt>", true, true));
+    assert!(resolves_with_policy("#include <q.txt>", true, true));
+    assert!(!resolves_with_policy("#include <local.txt>", true, true));
+    assert!(resolves_with_policy("#include \"q.txt\"", true, true));
+    assert!(resolves_with_policy("#include \"b.txt\"", true, true));
+}
+
+#[test]
+fn test_preprocess_search_policy_no_quoted_fallback() {
+    assert!(resolves_with_policy("#include <b.txt>", false, false));
+    assert!(!resolves_with_policy("#include <q.txt>", false, false));
+    assert!(resolves_with_policy("#include \"local.txt\"", false, false));
+    assert!(!resolves_with_policy("#include \"q.txt\"", false, false));
+    assert!(!resolves_with_policy("#include \"b.txt\"", false, false));
+}
+
+#[test]
+fn test_preprocess_search_policy_angle_quoted_search_no_quoted_fallback() {
+    assert!(resolves_with_policy("#include <b.txt>", true, false));
+    assert!(resolves_with_policy("#include <q.txt>", true, false));
+    assert!(!resolves_with_policy("#include <local.txt>", true, false));
+    assert!(resolves_with_policy("#include \"local.txt\"", true, false));
+    assert!(!resolves_with_policy("#include \"q.txt\"", true, false));
+    assert!(!resolves_with_policy("#include \"b.txt\"", true, false));
+}
+
+#[test]
+fn test_preprocess_path_normalization_canonical() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/path_normalization/main.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, SearchPaths::new(), buffer, &mut path_tracker);
+
+    // The symbolic link resolves to the file that was already included.
+    assert_eq!(&res.unwrap(), "float lib = 1.0;\nvoid main() {}\n");
+    assert!(!path_tracker.paths.contains(&format!(
+        "{}/tests/path_normalization/link.txt",
+        cargo_manifest_dir
+    )));
+}
+
+#[test]
+fn test_preprocess_path_normalization_lexical() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.set_path_normalization(PathNormalization::Lexical);
+
+    let entry_point = base_path.join("tests/path_normalization/main.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    // The symbolic link is a different file than its target.
+    assert_eq!(
+        &res.unwrap(),
+        "float lib = 1.0;\nfloat lib = 1.0;\nvoid main() {}\n"
+    );
+    assert!(path_tracker.paths.contains(&format!(
+        "{}/tests/path_normalization/link.txt",
+        cargo_manifest_dir
+    )));
+}
+
+#[test]
+fn test_preprocess_path_normalization_lexical_memory_fs() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/main.glsl", "#include \"lib/../common.glsl\"\n")
+        .insert("/shaders/common.glsl", "float common = 1.0;\n");
+
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.set_path_normalization(PathNormalization::Lexical);
+
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/./main.glsl",
+        search_paths,
+        options,
+        buffer,
+        &mut path_tracker,
+    );
+
+    assert_eq!(&res.unwrap(), "float common = 1.0;\n");
+    assert!(path_tracker.paths.contains("/shaders/main.glsl"));
+    assert!(path_tracker.paths.contains("/shaders/common.glsl"));
+}
+
+#[test]
+fn test_search_paths_expand_home() {
+    let home = env::home_dir().unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths
+        .push_base_path_expand_home("~/shaders/common")
+        .unwrap()
+        .push_base_path_expand_home("~")
+        .unwrap()
+        .push_quoted_path_expand_home("~artist/shaders")
+        .unwrap()
+        .push_quoted_path_expand_home("/shaders/~")
+        .unwrap();
+
+    assert_eq!(
+        search_paths.base_paths(),
+        &[home.join("shaders/common"), home.clone()]
+    );
+    assert_eq!(
+        search_paths.quoted_paths(),
+        &[
+            PathBuf::from("~artist/shaders"),
+            PathBuf::from("/shaders/~")
+        ]
+    );
+    assert_eq!(expand_home("shaders").unwrap(), Path::new("shaders"));
+}
+
+#[test]
+fn test_preprocess_search_path_groups() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/src/main.txt",
+            "#include <common.glsl>\n#include <common.wgsl>\n",
+        )
+        .insert("/src/other.txt", "#include <other.wgsl>\n")
+        .insert("/glsl/common.glsl", "glsl\n")
+        .insert("/glsl/other.wgsl", "other\n")
+        .insert("/wgsl/common.wgsl", "wgsl\n");
+
+    let mut wgsl = SearchPathGroup::new("*.wgsl");
+
+    wgsl.push_base_path("/wgsl");
+
+    let mut search_paths = SearchPaths::new().with_base_path("/glsl");
+
+    search_paths.push_group(wgsl);
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/src/main.txt",
+        search_paths.clone(),
+        options.clone(),
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    assert_eq!(&res.unwrap(), "glsl\nwgsl\n");
+
+    // The group is used instead of the default search paths.
+    let res = preprocess_with_options(
+        "/src/other.txt",
+        search_paths,
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::FileNotFound(err)) = res {
+        assert_eq!(err.included_path(), Path::new("other.wgsl"));
+    } else {
+        panic!("expected a file not found error");
+    }
+}
+
+#[test]
+fn test_preprocess_glob_include() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/glob_include/main.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, SearchPaths::new(), buffer, &mut path_tracker);
+
+    assert_eq!(
+        &res.unwrap(),
+        "float bloom = 1.0;\nfloat blur = 1.0;\nfloat vignette = 1.0;\nvoid main() {}\n"
+    );
+    assert_eq!(path_tracker.notes.len(), 1);
+    assert_eq!(
+        path_tracker.notes[0].0,
+        "optional include `missing/*.glsl` was not found"
+    );
+}
+
+#[test]
+fn test_preprocess_glob_include_recursive() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/glob_include/recursive.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, SearchPaths::new(), buffer, &mut path_tracker);
+
+    assert_eq!(
+        &res.unwrap(),
+        "float bloom = 1.0;\nfloat blur = 1.0;\nfloat nested = 1.0;\nfloat vignette = 1.0;\n"
+    );
+}
+
+#[test]
+fn test_preprocess_glob_include_no_matches() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/glob_include/missing.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, SearchPaths::new(), buffer, &mut path_tracker);
+
+    if let Err(Error::FileNotFound(err)) = res {
+        assert_eq!(err.included_path(), Path::new("effects/*.wgsl"));
+        assert_eq!(err.line_number(), 1);
+    } else {
+        panic!("expected a file not found error");
+    }
+}
+
+#[test]
+fn test_preprocess_glob_include_search_paths() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/src/main.txt", "#include <effects/*.glsl>\n")
+        .insert("/a/effects/bloom.glsl", "a bloom\n")
+        .insert("/b/effects/blur.glsl", "b blur\n")
+        .insert("/b/effects/bloom.glsl", "b bloom\n");
+
+    let search_paths = SearchPaths::new()
+        .with_base_path("/a")
+        .with_base_path("/b")
+        .with_base_path("/a/../a");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/src/main.txt",
+        search_paths,
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    // Matches are ordered by search path and de-duplicated by canonical path.
+    assert_eq!(&res.unwrap(), "a bloom\nb bloom\nb blur\n");
+}
+
+#[test]
+fn test_preprocess_memory_fs_above_root() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/main.glsl", "#include \"../common/a.glsl\"\n")
+        .insert("/common/a.glsl", "#include \"../../b.glsl\"\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/main.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::Parse(err)) = res {
+        assert_eq!(
+            err.message(),
+            "include path `../../b.glsl` leads above the root of `/common`"
+        );
+        assert_eq!(err.source_file(), Path::new("/common/a.glsl"));
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_preprocess_angle_as_quoted() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/src/main.txt",
+            "#include <local.txt>\n#include <q.txt>\n#include <b.txt>\n",
+        )
+        .insert("/src/local.txt", "local\n")
+        .insert("/quoted/q.txt", "quoted\n")
+        .insert("/base/b.txt", "base\n")
+        .insert("/src/missing.txt", "#include <other.txt>\n");
+
+    let mut search_paths = SearchPaths::new()
+        .with_base_path("/base")
+        .with_quoted_path("/quoted");
+
+    search_paths.set_angle_as_quoted(true);
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/src/main.txt",
+        search_paths.clone(),
+        options.clone(),
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    assert_eq!(&res.unwrap(), "local\nquoted\nbase\n");
+
+    let res = preprocess_with_options(
+        "/src/missing.txt",
+        search_paths,
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::FileNotFound(err)) = res {
+        assert_eq!(err.included_path(), Path::new("other.txt"));
+    } else {
+        panic!("expected a file not found error");
+    }
+}
+
+#[test]
+fn test_preprocess_once_by_content() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/once_by_content/main.txt");
+
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(
+        &entry_point,
+        SearchPaths::new(),
+        String::new(),
+        &mut path_tracker,
+    );
+
+    assert_eq!(
+        &res.unwrap(),
+        "float util = 1.0;\nfloat util = 1.0;\nvoid main() {}\n"
+    );
+
+    let mut path_tracker = TestPathTracker::new();
+    let options = Options {
+        once_by_content: true,
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        &entry_point,
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut path_tracker,
+    );
+
+    assert_eq!(&res.unwrap(), "float util = 1.0;\nvoid main() {}\n");
+    assert!(path_tracker.paths.contains(&format!(
+        "{}/tests/once_by_content/vendor_a/util.txt",
+        cargo_manifest_dir
+    )));
+    assert!(path_tracker.paths.contains(&format!(
+        "{}/tests/once_by_content/vendor_b/util.txt",
+        cargo_manifest_dir
+    )));
+}
+
+#[test]
+fn test_preprocess_resolution_trace() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let dir = base_path.join("tests/resolution_trace");
+    let collector = Arc::new(ResolutionCollector::new());
+    let mut search_paths = SearchPaths::new()
+        .with_base_path(dir.join("a"))
+        .with_base_path(dir.join("b"));
+
+    search_paths.set_tracer(collector.clone());
+
+    let entry_point = dir.join("main.txt");
+    let buffer = String::new();
+    let mut path_tracker = TestPathTracker::new();
+    let res = preprocess(entry_point, search_paths, buffer, &mut path_tracker);
+
+    assert_eq!(&res.unwrap(), "a common\nonly b\n");
+
+    let traces = collector.traces();
+    let candidates = |trace: &ResolutionTrace| -> Vec<(PathBuf, bool)> {
+        trace
+            .candidates()
+            .iter()
+            .map(|candidate| (candidate.path().to_path_buf(), candidate.exists()))
+            .collect()
+    };
+
+    assert_eq!(traces.len(), 2);
+
+    assert_eq!(traces[0].source_file(), dir.join("main.txt"));
+    assert_eq!(traces[0].line_number(), 1);
+    assert_eq!(traces[0].include_path(), "<common.txt>");
+    assert_eq!(traces[0].group(), None);
+    assert_eq!(
+        candidates(&traces[0]),
+        vec![(dir.join("a/common.txt"), true)]
+    );
+    assert_eq!(
+        traces[0].resolved_path(),
+        Some(dir.join("a/common.txt").as_path())
+    );
+
+    assert_eq!(traces[1].line_number(), 2);
+    assert_eq!(traces[1].include_path(), "<only_b.txt>");
+    assert_eq!(
+        candidates(&traces[1]),
+        vec![
+            (dir.join("a/only_b.txt"), false),
+            (dir.join("b/only_b.txt"), true)
+        ]
+    );
+    assert_eq!(
+        traces[1].resolved_path(),
+        Some(dir.join("b/only_b.txt").as_path())
+    );
+}
+
+#[test]
+fn test_preprocess_parse_error_position() {
+    let mut files = MemoryFs::new();
+
+    // `ü` and `ö` are two bytes each, a tab is a single character.
+    files.insert("/shaders/a.glsl", "// ü\n#include\t<ö/a.glsl> x\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::Parse(err)) = res {
+        assert_eq!(err.message(), "malformed `#include ...` directive");
+        assert_eq!(err.line_number(), 2);
+        assert_eq!(err.column(), 20);
+        assert_eq!(err.byte_offset(), 27);
+        assert_eq!(&err.source()[err.byte_offset()..], "x\n");
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_preprocess_file_not_found_position() {
+    let mut files = MemoryFs::new();
+
+    files.insert("/shaders/a.glsl", "// ü\n#include\t\"ü/missing.glsl\"\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::FileNotFound(err)) = res {
+        assert_eq!(err.line_number(), 2);
+        assert_eq!(err.column(), 9);
+        assert_eq!(err.byte_offset(), 15);
+    } else {
+        panic!("expected a file not found error");
+    }
+}
+
+#[test]
+fn test_preprocess_collect_errors() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/a.glsl",
+            "#include \"missing_a.glsl\"\n#include <b.glsl\n#include \"b.glsl\"\n",
+        )
+        .insert("/shaders/b.glsl", "#include \"missing_b.glsl\"\nb\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        collect_errors: true,
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::Multiple(errors)) = res {
+        assert_eq!(errors.len(), 3);
+
+        let mut missing: Vec<_> = errors
+            .iter()
+            .filter_map(|err| match err {
+                Error::FileNotFound(err) => Some(err.included_path().to_path_buf()),
+                _ => None,
+            })
+            .collect();
+
+        missing.sort();
+
+        assert_eq!(
+            missing,
+            vec![
+                PathBuf::from("missing_a.glsl"),
+                PathBuf::from("missing_b.glsl")
+            ]
+        );
+        assert!(errors
+            .iter()
+            .any(|err| matches!(err, Error::Parse(err) if err.line_number() == 2)));
+    } else {
+        panic!("expected multiple errors");
+    }
+}
+
+#[test]
+fn test_preprocess_collect_errors_fatal() {
+    let mut files = MemoryFs::new();
+
+    files.insert(
+        "/shaders/a.glsl",
+        "#include \"missing.glsl\"\n#error stop\n#include \"missing.glsl\"\n",
+    );
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        collect_errors: true,
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::Multiple(errors)) = res {
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(errors[0], Error::FileNotFound(_)));
+        assert!(matches!(errors[1], Error::Directive(_)));
+    } else {
+        panic!("expected multiple errors");
+    }
+}
+
+#[test]
+fn test_preprocess_recover_includes() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/a.glsl",
+            "#include \"once.glsl\"\n#include \"missing_a.glsl\"\n#include \"b.glsl\"\n#include \"once.glsl\"\na\n",
+        )
+        .insert("/shaders/b.glsl", "#define B \"missing_b.glsl\"\n#include B\nb\n")
+        .insert("/shaders/once.glsl", "#pragma once\nonce\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        recover_includes: true,
+        ..Options::default()
+    };
+    let mut path_tracker = TestPathTracker::new();
+    let (output, report) = preprocess_with_report(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut path_tracker,
+    )
+    .unwrap();
+
+    assert_eq!(
+        output,
+        "once\n// [ipp] missing include: \"missing_a.glsl\"\n// [ipp] missing include: \"missing_b.glsl\"\nb\na\n"
+    );
+
+    let diagnostics: Vec<_> = report
+        .diagnostics()
+        .iter()
+        .map(|diagnostic| {
+            (
+                diagnostic.message(),
+                diagnostic.path().to_path_buf(),
+                diagnostic.line(),
+            )
+        })
+        .collect();
+
+    assert_eq!(
+        diagnostics,
+        vec![
+            (
+                "missing include: \"missing_a.glsl\"",
+                PathBuf::from("/shaders/a.glsl"),
+                Some(2)
+            ),
+            (
+                "missing include: \"missing_b.glsl\"",
+                PathBuf::from("/shaders/b.glsl"),
+                Some(2)
+            ),
+        ]
+    );
+
+    let mut paths: Vec<_> = path_tracker.paths.into_iter().collect();
+
+    paths.sort();
+
+    assert_eq!(
+        paths,
+        vec!["/shaders/a.glsl", "/shaders/b.glsl", "/shaders/once.glsl"]
+    );
+}
+
+#[test]
+fn test_preprocess_with_diagnostics() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/a.glsl",
+            "#include <common.glsl>\n#include <next.glsl>\n#warning careful\n",
+        )
+        .insert("/first/common.glsl", "first\n")
+        .insert("/second/common.glsl", "second\n")
+        .insert("/first/next.glsl", "#include_next <next.glsl>\n")
+        .insert("/second/next.glsl", "next\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let mut diagnostics = Vec::new();
+    let (output, report) = preprocess_with_diagnostics(
+        "/shaders/a.glsl",
+        SearchPaths::new()
+            .with_base_path("/first")
+            .with_base_path("/second"),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+        &mut diagnostics,
+    )
+    .unwrap();
+
+    assert_eq!(output, "first\nnext\n");
+    assert_eq!(report.diagnostics(), diagnostics.as_slice());
+
+    let diagnostics: Vec<_> = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            (
+                diagnostic.severity(),
+                diagnostic.message(),
+                diagnostic.path().to_path_buf(),
+                diagnostic.line(),
+            )
+        })
+        .collect();
+
+    assert_eq!(
+        diagnostics,
+        vec![
+            (
+                Severity::Warning,
+                "include `common.glsl` resolves to `/first/common.glsl`, which shadows \
+                 `/second/common.glsl`",
+                PathBuf::from("/shaders/a.glsl"),
+                Some(1)
+            ),
+            (
+                Severity::Warning,
+                "include `next.glsl` resolves to `/first/next.glsl`, which shadows \
+                 `/second/next.glsl`",
+                PathBuf::from("/shaders/a.glsl"),
+                Some(2)
+            ),
+            (
+                Severity::Warning,
+                "careful",
+                PathBuf::from("/shaders/a.glsl"),
+                Some(3)
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_preprocess_display_root() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/project/shaders/a.glsl",
+            "#include <common.glsl>\n#include \"b.glsl\"\n",
+        )
+        .insert("/project/shaders/b.glsl", "#include \"missing.glsl\"\n")
+        .insert("/project/include/common.glsl", "common\n")
+        .insert("/other/common.glsl", "other\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        display_root: Some(PathBuf::from("/project/")),
+        ..Options::default()
+    };
+    let mut diagnostics = Vec::new();
+    let res = preprocess_with_diagnostics(
+        "/project/shaders/a.glsl",
+        SearchPaths::new()
+            .with_base_path("/project/include")
+            .with_base_path("/other"),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+        &mut diagnostics,
+    );
+
+    assert_eq!(
+        diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect::<Vec<_>>(),
+        vec![
+            "shaders/a.glsl:1:1: warning: include `common.glsl` resolves to \
+             `include/common.glsl`, which shadows `/other/common.glsl`"
+        ]
+    );
+    assert_eq!(diagnostics[0].path(), Path::new("/project/shaders/a.glsl"));
+
+    if let Err(Error::FileNotFound(err)) = res {
+        assert_eq!(err.source_file(), Path::new("/project/shaders/b.glsl"));
+        assert_eq!(
+            err.render(false),
+            "error: could not find `missing.glsl`\n \
+             --> shaders/b.glsl:1:10\n  \
+             |\n\
+             1 | #include \"missing.glsl\"\n  \
+             |          ^^^^^^^^^^^^^^\n  \
+             = note: included from shaders/a.glsl:2"
+        );
+    } else {
+        panic!("expected file not found error");
+    }
+}
+
+#[test]
+fn test_preprocess_display_root_errors() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/project/shaders/error.glsl",
+            "a
+#error stop
+",
+        )
+        .insert("/project/shaders/a.glsl", "#include \"b.glsl\"\n")
+        .insert("/project/shaders/b.glsl", "b\n#include \"a.glsl\"\n");
+
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor.file_provider(files).display_root("/project/");
+
+    let err = run(&preprocessor, "/project/shaders/error.glsl").unwrap_err();
+
+    assert_eq!(err.to_string(), "shaders/error.glsl:2: #error stop");
+
+    let err = run(&preprocessor, "/project/shaders/a.glsl").unwrap_err();
+
+    assert_eq!(
+        err.to_string(),
+        "include cycle: shaders/a.glsl:1 → shaders/b.glsl:2 → shaders/a.glsl"
+    );
+    assert_eq!(err.path(), Some(Path::new("/project/shaders/a.glsl")));
+
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path("/project/missing");
+    preprocessor
+        .search_paths(search_paths)
+        .search_path_validation(SearchPathValidation::Strict);
+
+    let err = run(&preprocessor, "/project/shaders/error.glsl").unwrap_err();
+
+    assert_eq!(err.to_string(), "search path `missing` does not exist");
+}
+
+#[test]
+fn test_preprocess_to_writer() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/valid/a.txt");
+    let expected = preprocess(
+        &entry_point,
+        search_paths.clone(),
+        String::new(),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap();
+
+    let output = preprocess_to_writer(
+        &entry_point,
+        search_paths,
+        Vec::new(),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap();
+
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+}
+
+/// A writer that fails once more than `capacity` bytes are written.
+struct BoundedWriter {
+    written: usize,
+    capacity: usize,
+}
+
+impl io::Write for BoundedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written + buf.len() > self.capacity {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "writer is full"));
+        }
+
+        self.written += buf.len();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_preprocess_to_writer_error() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/a.glsl", "a\n#include \"b.glsl\"\na\n")
+        .insert("/shaders/b.glsl", "bbbbbbbb\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let writer = BoundedWriter {
+        written: 0,
+        capacity: 4,
+    };
+    let res = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        IoSink::new(writer),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::Sink(err)) = res {
+        let err = err.downcast_ref::<io::Error>().unwrap();
+
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    } else {
+        panic!("expected sink error");
+    }
+
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let res = preprocess_to_writer(
+        format!("{}/tests/valid/a.txt", cargo_manifest_dir),
+        SearchPaths::new().with_base_path(&cargo_manifest_dir),
+        BoundedWriter {
+            written: 0,
+            capacity: 0,
+        },
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(err @ Error::Sink(_)) = res {
+        assert!(err.to_string().starts_with("failed to write the output: "));
+        assert!(err.source().is_some());
+    } else {
+        panic!("expected sink error");
+    }
+}
+
+/// A string buffer with a fixed capacity, that fails to write a string that does not fit.
+struct FixedBuffer {
+    buffer: String,
+    capacity: usize,
+}
+
+impl fmt::Write for FixedBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.buffer.len() + s.len() > self.capacity {
+            return Err(fmt::Error);
+        }
+
+        self.buffer.push_str(s);
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_preprocess_fmt_sink() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/a.glsl", "a\n#include \"b.glsl\"\na\n")
+        .insert("/shaders/b.glsl", "bbbbbbbb\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let buffer = FixedBuffer {
+        buffer: String::new(),
+        capacity: 64,
+    };
+    let buffer = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options.clone(),
+        FmtSink::new(buffer),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap()
+    .into_inner();
+
+    assert_eq!(buffer.buffer, "a\nbbbbbbbb\na\n");
+
+    let mut buffer = FixedBuffer {
+        buffer: String::new(),
+        capacity: 8,
+    };
+    let res = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        FmtSink::new(&mut buffer),
+        &mut TestPathTracker::new(),
+    );
+
+    // The overflow is reported, and nothing is written past it.
+    if let Err(Error::Sink(err)) = res {
+        assert!(err.downcast_ref::<fmt::Error>().is_some());
+    } else {
+        panic!("expected sink error");
+    }
+
+    assert_eq!(buffer.buffer, "a\n");
+}
+
+#[test]
+fn test_preprocess_counting_sink() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/a.glsl",
+            "a\n#include \"b.glsl\"\n#include \"b.glsl\"\n#include \"c.glsl\"\n#include \"c.glsl\"\n",
+        )
+        .insert("/shaders/b.glsl", "#pragma once\nbb\n")
+        .insert("/shaders/c.glsl", "ccc");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let counts = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        CountingSink::new(String::new()),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap();
+
+    let per_file: Vec<_> = counts
+        .per_file()
+        .iter()
+        .map(|(path, counts)| (path.to_str().unwrap(), counts.bytes(), counts.lines()))
+        .collect();
+
+    // `b.glsl` is only emitted once, `c.glsl` twice; the newlines that follow `c.glsl` are
+    // synthetic.
+    assert_eq!(
+        per_file,
+        vec![
+            ("/shaders/a.glsl", 2, 1),
+            ("/shaders/b.glsl", 3, 1),
+            ("/shaders/c.glsl", 6, 0),
+        ]
+    );
+    assert_eq!(
+        (counts.synthetic().bytes(), counts.synthetic().lines()),
+        (2, 2)
+    );
+    assert_eq!((counts.total().bytes(), counts.total().lines()), (13, 4));
+    assert_eq!(counts.into_inner(), "a\nbb\nccc\nccc\n");
+}
+
+/// Splits `text` into tokens, to compare the output of a [MinifySink] to the original: runs of
+/// characters that may form a word or number, string literals, operators (the longest that
+/// matches) and any other single character.
+fn tokens(text: &str) -> Vec<&str> {
+    const OPERATORS: [&str; 22] = [
+        "<<=", ">>=", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "^^", "+=", "-=",
+        "*=", "/=", "%=", "&=", "|=", "^=", "//",
+    ];
+
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '.';
+    let mut tokens = Vec::new();
+    let mut remainder = text.trim_start();
+
+    while let Some(c) = remainder.chars().next() {
+        let len = if is_word(c) {
+            remainder.find(|c| !is_word(c)).unwrap_or(remainder.len())
+        } else if c == '"' {
+            remainder[1..]
+                .find('"')
+                .map_or(remainder.len(), |end| end + 2)
+        } else if let Some(operator) = OPERATORS.iter().find(|op| remainder.starts_with(*op)) {
+            operator.len()
+        } else {
+            c.len_utf8()
+        };
+
+        tokens.push(&remainder[..len]);
+        remainder = remainder[len..].trim_start();
+    }
+
+    tokens
+}
+
+#[test]
+fn test_preprocess_minify_sink() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/minify/main.glsl");
+
+    let original = preprocess(
+        &entry_point,
+        SearchPaths::new(),
+        String::new(),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap();
+    let minified = preprocess(
+        &entry_point,
+        SearchPaths::new(),
+        MinifySink::new(String::new()),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap()
+    .into_inner();
+
+    assert_eq!(minified, include_str!("expected_minify.txt"));
+    assert_eq!(tokens(&minified), tokens(&original));
+
+    // Directives are not changed.
+    for line in original.lines().filter(|line| line.starts_with('#')) {
+        assert!(minified.lines().any(|minified_line| minified_line == line));
+    }
+}
+
+/// Runs `preprocessor` on `entry_point`, collecting the output in a string.
+fn run(preprocessor: &Preprocessor, entry_point: &str) -> Result<String, Error> {
+    preprocessor.run(entry_point, String::new(), &mut TestPathTracker::new())
+}
+
+#[test]
+fn test_preprocess_str() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/lib.glsl", "lib\n#include \"main.glsl\"\n")
+        .insert("/shaders/main.glsl", "on disk\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let mut path_tracker = TestPathTracker::new();
+    let output = preprocess_str_with_options(
+        "#pragma once\nmain\n#include \"lib.glsl\"\n",
+        "/shaders/./main.glsl",
+        SearchPaths::new(),
+        options.clone(),
+        String::new(),
+        &mut path_tracker,
+    )
+    .unwrap();
+
+    // The virtual entry point takes the place of the file at its path, and is only included once.
+    assert_eq!(output, "main\nlib\n");
+    assert!(path_tracker.paths.contains("/shaders/main.glsl"));
+
+    let res = preprocess_str_with_options(
+        "main\n#include \"missing.glsl\"\n",
+        "/shaders/generated.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::FileNotFound(err)) = res {
+        assert_eq!(err.source_file(), Path::new("/shaders/generated.glsl"));
+        assert_eq!(err.line_number(), 2);
+    } else {
+        panic!("expected a file not found error");
+    }
+
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let output = preprocess_str(
+        "#include \"b.txt\"\n",
+        base_path.join("tests/valid/virtual.txt"),
+        SearchPaths::new(),
+        String::new(),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap();
+
+    assert_eq!(output, "File B Line 1\n");
+
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor.define("X", "1");
+
+    let output = preprocessor
+        .run_str(
+            "X\n",
+            "virtual.glsl",
+            String::new(),
+            &mut TestPathTracker::new(),
+        )
+        .unwrap();
+
+    assert_eq!(output, "1\n");
+}
+
+#[test]
+fn test_preprocess_reader() {
+    let mut files = MemoryFs::new();
+
+    files.insert("/shaders/lib.glsl", "lib\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let mut path_tracker = TestPathTracker::new();
+    let output = preprocess_reader(
+        "main\n#include \"lib.glsl\"\n".as_bytes(),
+        "/shaders/stdin.glsl",
+        SearchPaths::new(),
+        options.clone(),
+        String::new(),
+        &mut path_tracker,
+    )
+    .unwrap();
+
+    assert_eq!(output, "main\nlib\n");
+    assert!(path_tracker.paths.contains("/shaders/stdin.glsl"));
+    assert!(path_tracker.paths.contains("/shaders/lib.glsl"));
+
+    let res = preprocess_reader(
+        &b"main\n\xffmain\n"[..],
+        "/shaders/stdin.glsl",
+        SearchPaths::new(),
+        options.clone(),
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::IO(err)) = res {
+        assert_eq!(err.path(), Path::new("/shaders/stdin.glsl"));
+        assert_eq!(err.operation(), FileOperation::Read);
+        assert_eq!(err.io_error().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.io_error().to_string(),
+            "invalid UTF-8 sequence at byte offset 5"
+        );
+    } else {
+        panic!("expected a file access error");
+    }
+
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor
+        .file_provider(MemoryFs::new())
+        .max_reader_size(5);
+
+    let output = preprocessor
+        .run_reader(
+            "main\n".as_bytes(),
+            "/stdin.glsl",
+            String::new(),
+            &mut TestPathTracker::new(),
+        )
+        .unwrap();
+
+    assert_eq!(output, "main\n");
+
+    let res = preprocessor.run_reader(
+        "main\n\n".as_bytes(),
+        "/stdin.glsl",
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::IO(err)) = res {
+        assert_eq!(
+            err.io_error().to_string(),
+            "source exceeds the size limit of 5 bytes"
+        );
+    } else {
+        panic!("expected a file access error");
+    }
+}
+
+#[test]
+fn test_preprocess_dependencies() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    for (entry, expected) in [
+        (
+            "valid/a.txt",
+            vec!["valid/a.txt", "valid/b.txt", "valid/c.txt"],
+        ),
+        (
+            "include_macro/a.txt",
+            vec![
+                "include_macro/a.txt",
+                "include_macro/gl/common.txt",
+                "include_macro/gl/platform.txt",
+            ],
+        ),
+    ] {
+        let entry_point = base_path.join("tests").join(entry);
+        let dependencies = preprocess_dependencies(&entry_point, search_paths.clone()).unwrap();
+        let expected: Vec<PathBuf> = expected
+            .iter()
+            .map(|path| base_path.join("tests").join(path))
+            .collect();
+
+        assert_eq!(dependencies, expected);
+
+        let mut path_tracker = TestPathTracker::new();
+
+        preprocess(
+            &entry_point,
+            search_paths.clone(),
+            String::new(),
+            &mut path_tracker,
+        )
+        .unwrap();
+
+        let tracked: HashSet<String> = dependencies
+            .iter()
+            .map(|path| path.to_str().unwrap().to_string())
+            .collect();
+
+        assert_eq!(tracked, path_tracker.paths);
+    }
+
+    let res = Preprocessor::with_options(search_paths, Options::default())
+        .dependencies(base_path.join("tests/include_macro/undefined.txt"));
+
+    if let Err(Error::Parse(err)) = res {
+        assert!(err.message().contains("PLATFORM_HEADER"));
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_preprocess_include_graph() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let a = base_path.join("tests/valid/a.txt");
+    let b = base_path.join("tests/valid/b.txt");
+    let c = base_path.join("tests/valid/c.txt");
+    let graph = preprocess_include_graph(&a, search_paths.clone()).unwrap();
+
+    assert_eq!(graph.entry_point(), a);
+    assert_eq!(
+        graph.nodes().collect::<Vec<_>>(),
+        [a.as_path(), b.as_path(), c.as_path()]
+    );
+
+    let edges: Vec<_> = graph
+        .includes_of(&a)
+        .iter()
+        .map(|edge| {
+            (
+                edge.line_number(),
+                edge.include_path(),
+                edge.included_file(),
+            )
+        })
+        .collect();
+
+    assert_eq!(
+        edges,
+        [
+            (3, IncludePath::Quote("b.txt".as_ref()), b.as_path()),
+            (
+                7,
+                IncludePath::Angle("tests/valid/b.txt".as_ref()),
+                b.as_path()
+            ),
+            (11, IncludePath::Quote("c.txt".as_ref()), c.as_path()),
+            (
+                15,
+                IncludePath::Angle("tests/valid/c.txt".as_ref()),
+                c.as_path()
+            ),
+        ]
+    );
+    assert!(graph.includes_of(&b).is_empty());
+    assert!(graph
+        .includes_of(&base_path.join("tests/missing.txt"))
+        .is_empty());
+    assert_eq!(
+        graph
+            .included_by(&b)
+            .map(|(path, edge)| (path, edge.line_number()))
+            .collect::<Vec<_>>(),
+        [(a.as_path(), 3), (a.as_path(), 7), (c.as_path(), 5)]
+    );
+
+    let entry_point = base_path.join("tests/include_macro/a.txt");
+    let platform = base_path.join("tests/include_macro/gl/platform.txt");
+    let (_, report) = preprocess_with_report(
+        &entry_point,
+        search_paths.clone(),
+        Options::default(),
+        String::new(),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap();
+    let graph = report.include_graph();
+    let edge = &graph.includes_of(&entry_point)[0];
+
+    assert_eq!(graph.includes_of(&entry_point).len(), 1);
+    assert_eq!(edge.line_number(), 2);
+    assert_eq!(
+        edge.include_path(),
+        IncludePath::Quote("gl/platform.txt".as_ref())
+    );
+    assert_eq!(edge.included_file(), platform);
+    assert_eq!(
+        graph.nodes().collect::<Vec<_>>(),
+        Preprocessor::with_options(search_paths, Options::default())
+            .include_graph(&entry_point)
+            .unwrap()
+            .nodes()
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_preprocess_track_include() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let a = base_path.join("tests/valid/a.txt");
+    let b = base_path.join("tests/valid/b.txt");
+    let c = base_path.join("tests/valid/c.txt");
+    let mut path_tracker = TestPathTracker::new();
+
+    preprocess(&a, search_paths.clone(), String::new(), &mut path_tracker).unwrap();
+    path_tracker
+        .includes
+        .sort_by(|x, y| (&x.0, x.2).cmp(&(&y.0, y.2)));
+
+    let expected = [
+        (a.clone(), b.clone(), 3, IncludeKind::Quote),
+        (a.clone(), b.clone(), 7, IncludeKind::Angle),
+        (a.clone(), c.clone(), 11, IncludeKind::Quote),
+        (a.clone(), c.clone(), 15, IncludeKind::Angle),
+        (c.clone(), b.clone(), 5, IncludeKind::Quote),
+    ];
+
+    assert_eq!(path_tracker.includes, expected);
+
+    // A file that is shared between the entry points of a batch only has its includes tracked
+    // once.
+    let mut depfile_tracker = DepfileTracker::new(TestPathTracker::new());
+
+    for result in preprocess_batch(
+        &[&a, &c],
+        search_paths,
+        Options::default(),
+        &mut depfile_tracker,
+    ) {
+        result.unwrap();
+    }
+
+    let mut path_tracker = depfile_tracker.into_inner();
+
+    path_tracker
+        .includes
+        .sort_by(|x, y| (&x.0, x.2).cmp(&(&y.0, y.2)));
+
+    assert_eq!(path_tracker.includes, expected);
+}
+
+#[test]
+fn test_preprocess_track_on_failure() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/main.glsl", "#include \"a.glsl\"\nmain\n")
+        .insert("/shaders/a.glsl", "#include <lib/b.glsl>\na\n")
+        .insert("/include/lib/b.glsl", "#include \"missing.glsl\"\nb\n");
+
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path("/include");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let mut path_tracker = TestPathTracker::new();
+    let err = preprocess_with_options(
+        "/shaders/main.glsl",
+        search_paths.clone(),
+        options.clone(),
+        String::new(),
+        &mut path_tracker,
+    )
+    .unwrap_err();
+
+    let mut paths: Vec<_> = path_tracker.paths.iter().map(String::as_str).collect();
+
+    paths.sort();
+
+    // The files that were loaded before the failure are still tracked, as are the paths at which
+    // the missing file was looked for.
+    assert_eq!(
+        paths,
+        [
+            "/include/lib/b.glsl",
+            "/shaders/a.glsl",
+            "/shaders/main.glsl"
+        ]
+    );
+    assert_eq!(path_tracker.includes.len(), 2);
+    assert_eq!(
+        path_tracker.missing,
+        [
+            PathBuf::from("/include/lib/missing.glsl"),
+            PathBuf::from("/include/missing.glsl")
+        ]
+    );
+
+    assert_eq!(err.kind(), ErrorKind::FileNotFound);
+
+    let mut path_tracker = TestPathTracker::new();
+
+    preprocess_with_options(
+        "/shaders/missing_main.glsl",
+        search_paths,
+        options,
+        String::new(),
+        &mut path_tracker,
+    )
+    .unwrap_err();
+
+    assert!(path_tracker.paths.is_empty());
+    assert_eq!(
+        path_tracker.missing,
+        [PathBuf::from("/shaders/missing_main.glsl")]
+    );
+}
+
+#[test]
+fn test_parse_tree() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let a = base_path.join("tests/valid/a.txt");
+    let b = base_path.join("tests/valid/b.txt");
+    let c = base_path.join("tests/valid/c.txt");
+    let tree = parse_tree(&a, search_paths).unwrap();
+
+    assert_eq!(tree.entry_point(), a);
+    assert_eq!(tree.root().unwrap().path(), a);
+    assert_eq!(
+        tree.files().map(|file| file.path()).collect::<Vec<_>>(),
+        [a.as_path(), b.as_path(), c.as_path()]
+    );
+    assert!(tree.file(&base_path.join("tests/missing.txt")).is_none());
+
+    let includes: Vec<_> = tree
+        .root()
+        .unwrap()
+        .chunks()
+        .filter_map(|chunk| match chunk {
+            Chunk::Include {
+                resolved,
+                directive_line,
+            } => Some((*directive_line, resolved.as_path())),
+            _ => None,
+        })
+        .collect();
+
+    assert_eq!(
+        includes,
+        [
+            (3, b.as_path()),
+            (7, b.as_path()),
+            (11, c.as_path()),
+            (15, c.as_path())
+        ]
+    );
+
+    let c_file = tree.file(&c).unwrap();
+
+    assert!(c_file.is_once());
+    assert!(!tree.file(&b).unwrap().is_once());
+    assert_eq!(c_file.source(), include_str!("valid/c.txt"));
+
+    let chunks: Vec<_> = c_file
+        .chunks()
+        .map(|chunk| match chunk {
+            Chunk::Text { range } => format!("text {:?}", &c_file.source()[range.clone()]),
+            Chunk::Include {
+                resolved,
+                directive_line,
+            } => format!(
+                "include {} at {}",
+                resolved.strip_prefix(base_path).unwrap().display(),
+                directive_line
+            ),
+            _ => unreachable!(),
+        })
+        .collect();
+
+    assert_eq!(
+        chunks,
+        [
+            "text \"\\nFile C Line 3\\n\\n\"",
+            "include tests/valid/b.txt at 5",
+            "text \"\\nFile C Line 7\\n\"",
+        ]
+    );
+}
+
+#[test]
+fn test_preprocess_depfile() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/main shader.glsl",
+            "#include \"lib/$common.glsl\"\n#include \"lib/#util.glsl\"\nvoid main() {}\n",
+        )
+        .insert(
+            "/shaders/lib/$common.glsl",
+            "#pragma once\n#include \"#util.glsl\"\nfloat common;\n",
+        )
+        .insert("/shaders/lib/#util.glsl", "float util;\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let mut tracker = DepfileTracker::new(TestPathTracker::new());
+
+    preprocess_with_options(
+        "/shaders/main shader.glsl",
+        SearchPaths::new(),
+        options.clone(),
+        String::new(),
+        &mut tracker,
+    )
+    .unwrap();
+
+    let mut depfile = Vec::new();
+
+    tracker
+        .write_depfile("out/main shader.spv", &mut depfile)
+        .unwrap();
+
+    assert_eq!(
+        String::from_utf8(depfile).unwrap(),
+        "out/main\\ shader.spv: /shaders/lib/\\#util.glsl /shaders/lib/$$common.glsl \
+         /shaders/main\\ shader.glsl\n"
+    );
+
+    assert_eq!(
+        tracker.dependencies(),
+        preprocess_dependencies_with_options(
+            "/shaders/main shader.glsl",
+            SearchPaths::new(),
+            options
+        )
+        .unwrap()
+    );
+    assert_eq!(tracker.into_inner().paths.len(), 3);
+}
+
+/// A [FileProvider] with files that can be edited, which records the files it reads, and reports
+/// the number of edits to a file as its modification time.
+#[derive(Debug, Default)]
+struct EditableFs {
+    files: Mutex<(MemoryFs, HashMap<PathBuf, u64>)>,
+    reads: Mutex<Vec<PathBuf>>,
+}
+
+impl EditableFs {
+    fn edit(&self, path: &str, source: &str) {
+        let mut files = self.files.lock().unwrap();
+
+        files.0.insert(path, source);
+        *files.1.entry(PathBuf::from(path)).or_default() += 1;
+    }
+
+    fn take_reads(&self) -> Vec<PathBuf> {
+        mem::take(&mut *self.reads.lock().unwrap())
+    }
+}
+
+impl FileProvider for EditableFs {
+    fn read(&self, path: &Path) -> Result<String, io::Error> {
+        self.reads.lock().unwrap().push(path.to_path_buf());
+        self.files.lock().unwrap().0.read(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().0.exists(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, io::Error> {
+        self.files.lock().unwrap().0.canonicalize(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, io::Error> {
+        self.files.lock().unwrap().0.read_dir(path)
+    }
+
+    fn modified(&self, path: &Path) -> Option<(SystemTime, u64)> {
+        let files = self.files.lock().unwrap();
+        let edits = files.1.get(path)?;
+        let len = files.0.read(path).ok()?.len();
+
+        Some((
+            SystemTime::UNIX_EPOCH + Duration::from_secs(*edits),
+            len as u64,
+        ))
+    }
+}
+
+#[test]
+fn test_preprocess_cache() {
+    let files = Arc::new(EditableFs::default());
+
+    files.edit(
+        "/shaders/main.glsl",
+        "#include \"a.glsl\"\n#include_optional \"b.glsl\"\nmain\n",
+    );
+    files.edit("/shaders/a.glsl", "a\n");
+
+    let cache = PreprocessCache::new();
+    let options = Options {
+        file_provider: files.clone(),
+        cache: Some(cache.clone()),
+        ..Options::default()
+    };
+    let mut preprocessor = Preprocessor::with_options(SearchPaths::new(), options);
+    let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+    assert_eq!(
+        run(&preprocessor, "/shaders/main.glsl").unwrap(),
+        "a\nmain\n"
+    );
+    assert_eq!(
+        files.take_reads(),
+        paths(&["/shaders/main.glsl", "/shaders/a.glsl"])
+    );
+    assert_eq!(cache.len(), 2);
+
+    // Nothing changed.
+    assert_eq!(
+        run(&preprocessor, "/shaders/main.glsl").unwrap(),
+        "a\nmain\n"
+    );
+    assert_eq!(files.take_reads(), paths(&[]));
+
+    // Only the file that changed is read again.
+    files.edit("/shaders/a.glsl", "a2\n");
+
+    assert_eq!(
+        run(&preprocessor, "/shaders/main.glsl").unwrap(),
+        "a2\nmain\n"
+    );
+    assert_eq!(files.take_reads(), paths(&["/shaders/a.glsl"]));
+
+    // The file that includes a missing file is parsed again when it appears.
+    files.edit("/shaders/b.glsl", "b\n");
+
+    assert_eq!(
+        run(&preprocessor, "/shaders/main.glsl").unwrap(),
+        "a2\nb\nmain\n"
+    );
+    assert_eq!(
+        files.take_reads(),
+        paths(&["/shaders/main.glsl", "/shaders/b.glsl"])
+    );
+
+    // The cache is shared between entry points.
+    assert_eq!(run(&preprocessor, "/shaders/a.glsl").unwrap(), "a2\n");
+    assert_eq!(files.take_reads(), paths(&[]));
+
+    // A file provider without modification times is compared by content.
+    let mut memory_fs = MemoryFs::new();
+
+    memory_fs.insert("/shaders/a.glsl", "a2\n");
+    preprocessor.file_provider(memory_fs);
+
+    assert_eq!(run(&preprocessor, "/shaders/a.glsl").unwrap(), "a2\n");
+
+    cache.clear();
+
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_preprocess_session() {
+    let files = Arc::new(EditableFs::default());
+
+    files.edit("/shaders/a.glsl", "#include \"common.glsl\"\na\n");
+    files.edit("/shaders/b.glsl", "#include \"common.glsl\"\nb\n");
+    files.edit("/shaders/common.glsl", "#include \"lib.glsl\"\ncommon\n");
+    files.edit("/shaders/lib.glsl", "lib\n");
+
+    let options = Options {
+        file_provider: files.clone(),
+        ..Options::default()
+    };
+    let session = Preprocessor::with_options(SearchPaths::new(), options).session();
+    let preprocess = |entry_point: &str| {
+        session
+            .preprocess(entry_point, String::new(), &mut TestPathTracker::new())
+            .unwrap()
+    };
+    let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+    assert_eq!(preprocess("/shaders/a.glsl"), "lib\ncommon\na\n");
+    assert_eq!(
+        files.take_reads(),
+        paths(&[
+            "/shaders/a.glsl",
+            "/shaders/common.glsl",
+            "/shaders/lib.glsl"
+        ])
+    );
+
+    // The shared files are not read again.
+    assert_eq!(preprocess("/shaders/b.glsl"), "lib\ncommon\nb\n");
+    assert_eq!(files.take_reads(), paths(&["/shaders/b.glsl"]));
+
+    // Files are not checked for changes during the session.
+    files.edit("/shaders/lib.glsl", "lib2\n");
+
+    assert_eq!(preprocess("/shaders/a.glsl"), "lib\ncommon\na\n");
+    assert_eq!(files.take_reads(), paths(&[]));
+}
+
+/// A [SourceTracker] that records every file it is passed, and the files of the warnings.
+#[derive(Default)]
+struct TrackedLog {
+    tracked: Vec<PathBuf>,
+    warnings: Vec<PathBuf>,
+}
+
+impl SourceTracker for TrackedLog {
+    fn track(&mut self, path: &Path, _source: &str) {
+        self.tracked.push(path.to_path_buf());
+    }
+
+    fn warning(&mut self, _message: &str, source_file: &Path, _line_number: usize) {
+        self.warnings.push(source_file.to_path_buf());
+    }
+}
+
+#[test]
+fn test_preprocess_batch() {
+    let files = Arc::new(EditableFs::default());
+
+    files.edit("/shaders/a.glsl", "#include \"common.glsl\"\na\n");
+    files.edit("/shaders/b.glsl", "#include \"common.glsl\"\nb\n");
+    files.edit("/shaders/broken.glsl", "#include \"missing.glsl\"\n");
+    files.edit("/shaders/common.glsl", "#warning shared\ncommon\n");
+
+    let options = Options {
+        file_provider: files.clone(),
+        ..Options::default()
+    };
+    let mut log = TrackedLog::default();
+    let results = preprocess_batch(
+        &[
+            "/shaders/a.glsl",
+            "/shaders/broken.glsl",
+            "/shaders/missing.glsl",
+            "/shaders/b.glsl",
+        ],
+        SearchPaths::new(),
+        options,
+        &mut log,
+    );
+    let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+    assert_eq!(results.len(), 4);
+    assert_eq!(results[0].as_ref().unwrap(), "common\na\n");
+    assert_eq!(
+        results[1].as_ref().unwrap_err().kind(),
+        ErrorKind::FileNotFound
+    );
+    assert!(results[2].is_err());
+    assert_eq!(results[3].as_ref().unwrap(), "common\nb\n");
+
+    // Every file is tracked once, in the order of the entry points; as with `preprocess`, the
+    // files that an entry point that fails loaded are tracked as well.
+    assert_eq!(
+        log.tracked,
+        paths(&[
+            "/shaders/a.glsl",
+            "/shaders/common.glsl",
+            "/shaders/broken.glsl",
+            "/shaders/b.glsl"
+        ])
+    );
+    assert_eq!(
+        log.warnings,
+        paths(&["/shaders/common.glsl", "/shaders/common.glsl"])
+    );
+
+    let session = Session::new(SearchPaths::new(), Options::default());
+
+    assert!(session
+        .preprocess_batch::<&str, _>(&[], &mut TrackedLog::default())
+        .is_empty());
+}
+
+#[test]
+fn test_preprocessor_builder() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/a.glsl", "#include <common.glsl>\nA = X;\n")
+        .insert("/shaders/b.glsl", "#include <common.glsl>\nB = X;\n")
+        .insert("/lib/common.glsl", "// common\n");
+
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor
+        .search_paths(SearchPaths::new().with_base_path("/lib"))
+        .file_provider(files)
+        .define("X", "1");
+
+    // One configuration drives multiple entry points, and can be cloned to vary it.
+    let mut other = preprocessor.clone();
+
+    other.define("X", "2");
+
+    assert_eq!(
+        run(&preprocessor, "/shaders/a.glsl").unwrap(),
+        "// common\nA = 1;\n"
+    );
+    assert_eq!(
+        run(&preprocessor, "/shaders/b.glsl").unwrap(),
+        "// common\nB = 1;\n"
+    );
+    assert_eq!(
+        run(&other, "/shaders/a.glsl").unwrap(),
+        "// common\nA = 2;\n"
+    );
+
+    let (_, report) = preprocessor
+        .line_markers(true)
+        .run_with_report(
+            "/shaders/a.glsl",
+            String::new(),
+            &mut TestPathTracker::new(),
+        )
+        .unwrap();
+
+    assert_eq!(
+        report.file_ids(),
+        [
+            PathBuf::from("/shaders/a.glsl"),
+            PathBuf::from("/lib/common.glsl")
+        ]
+    );
+}
+
+#[test]
+fn test_preprocessor_directive_options() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/a.glsl",
+            "a\n#include \"guard.glsl\"\n#define Y 2\nY\n#line 10\na\n",
+        )
+        .insert("/shaders/guard.glsl", "#ifndef G\n#define G\ng\n#endif\n")
+        .insert(
+            "/shaders/strict.glsl",
+            "#version 450\n#inlcude \"a.glsl\"\n",
+        );
+
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor.file_provider(files);
+
+    assert_eq!(
+        run(
+            preprocessor
+                .clone()
+                .keep_define_directives(true)
+                .strip_include_guards(true),
+            "/shaders/a.glsl"
+        )
+        .unwrap(),
+        "a\ng\n#define Y 2\n2\n#line 10\na\n"
+    );
+    assert_eq!(
+        run(
+            preprocessor
+                .clone()
+                .blank_directive(DirectiveKind::Define)
+                .line_directives(LineDirectiveMode::Strip),
+            "/shaders/a.glsl"
+        )
+        .unwrap(),
+        "a\n\ng\n\n2\na\n"
+    );
+    assert_eq!(
+        run(preprocessor.clone().line_markers(true), "/shaders/a.glsl").unwrap(),
+        "a\n#line 3 1\ng\n#line 4 0\n2\n#line 6 0\na\n"
+    );
+
+    let mut strict = preprocessor.clone();
+
+    strict.strict_directives(true).allow_directive("version");
+
+    assert!(matches!(
+        run(&strict, "/shaders/strict.glsl"),
+        Err(Error::Parse(_))
+    ));
+    assert_eq!(
+        run(&preprocessor, "/shaders/strict.glsl").unwrap(),
+        "#version 450\n#inlcude \"a.glsl\"\n"
+    );
+}
+
+#[test]
+fn test_preprocessor_output_options() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/a.glsl", "a\r\n\r\n\r\n  #include \"b.glsl\"\r\na")
+        .insert("/shaders/b.glsl", "b");
+
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor.file_provider(files);
+
+    assert_eq!(
+        run(&preprocessor, "/shaders/a.glsl").unwrap(),
+        "a\r\n\r\n\r\n  #include \"b.glsl\"\r\na"
+    );
+    assert_eq!(
+        run(
+            preprocessor
+                .clone()
+                .indent_includes(true)
+                .include_newline(IncludeNewline::Never),
+            "/shaders/a.glsl"
+        )
+        .unwrap(),
+        "a\r\n\r\n\r\n  ba"
+    );
+    assert_eq!(
+        run(
+            preprocessor
+                .clone()
+                .indent_includes(true)
+                .line_endings(LineEndings::Lf)
+                .collapse_blank_lines(1)
+                .ensure_trailing_newline(true),
+            "/shaders/a.glsl"
+        )
+        .unwrap(),
+        "a\n\n  b\na\n"
+    );
+    assert_eq!(
+        run(
+            preprocessor
+                .clone()
+                .indent_includes(true)
+                .include_markers(IncludeMarkers::default())
+                .prologue("#version 450")
+                .epilogue("// end"),
+            "/shaders/a.glsl"
+        )
+        .unwrap(),
+        "#version 450\na\r\n\r\n\r\n// >>> begin \"/shaders/b.glsl\"\n  b\n// <<< end \"/shaders/b.glsl\"\na\n// end"
+    );
+}
+
+#[test]
+fn test_preprocessor_comment_options() {
+    let mut files = MemoryFs::new();
+
+    files.insert("/shaders/a.glsl", "a; // a\nb; /* b */ \"//\"\n");
+
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor
+        .file_provider(files)
+        .strip_comment(CommentStyle::Line);
+
+    assert_eq!(
+        run(&preprocessor, "/shaders/a.glsl").unwrap(),
+        "a;  \nb; /* b */ \"//\"\n"
+    );
+    assert_eq!(
+        run(
+            preprocessor
+                .clone()
+                .strip_comment(CommentStyle::Block)
+                .comment_replacement(CommentReplacement::Remove)
+                .preserve_strings(false),
+            "/shaders/a.glsl"
+        )
+        .unwrap(),
+        "a; \nb;  \"\n"
+    );
+}
+
+#[test]
+fn test_preprocessor_macro_options() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/a.glsl",
+            "#include \"x/once.glsl\"\n#include \"y/once.glsl\"\n__FILE__\n",
+        )
+        .insert("/shaders/x/once.glsl", "#pragma once\nonce\n")
+        .insert("/shaders/y/once.glsl", "#pragma once\nonce\n")
+        .insert(
+            "/shaders/nested.glsl",
+            "#define A B\n#define B C\n#define C 1\nA\n",
+        );
+
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor.file_provider(files);
+
+    assert_eq!(
+        run(&preprocessor, "/shaders/a.glsl").unwrap(),
+        "once\nonce\n\"/shaders/a.glsl\"\n"
+    );
+    assert_eq!(
+        run(
+            preprocessor
+                .clone()
+                .once_by_content(true)
+                .file_macro_name_only(true),
+            "/shaders/a.glsl"
+        )
+        .unwrap(),
+        "once\n\"a.glsl\"\n"
+    );
+    assert_eq!(run(&preprocessor, "/shaders/nested.glsl").unwrap(), "1\n");
+    assert!(matches!(
+        run(
+            preprocessor.clone().max_macro_expansion_depth(1),
+            "/shaders/nested.glsl"
+        ),
+        Err(Error::Parse(_))
+    ));
+}
+
+#[test]
+fn test_preprocessor_error_options() {
+    let mut files = MemoryFs::new();
+
+    files.insert(
+        "/shaders/a.glsl",
+        "#include \"missing.glsl\"\n#include \"other.glsl\"\na\n",
+    );
+
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor.file_provider(files).display_root("/shaders");
+
+    if let Err(Error::FileNotFound(err)) = run(&preprocessor, "/shaders/a.glsl") {
+        assert!(err.render(false).contains("a.glsl:1"));
+        assert!(!err.render(false).contains("/shaders/a.glsl"));
+    } else {
+        panic!("expected a file not found error");
+    }
+
+    if let Err(Error::Multiple(errors)) =
+        run(preprocessor.clone().collect_errors(true), "/shaders/a.glsl")
+    {
+        assert_eq!(errors.len(), 2);
+    } else {
+        panic!("expected multiple errors");
+    }
+
+    assert_eq!(
+        run(
+            preprocessor.clone().recover_includes(true),
+            "/shaders/a.glsl"
+        )
+        .unwrap(),
+        "// [ipp] missing include: \"missing.glsl\"\n// [ipp] missing include: \"other.glsl\"\na\n"
+    );
+
+    let mut strict = preprocessor.clone();
+
+    strict
+        .search_paths(SearchPaths::new().with_base_path("/missing"))
+        .search_path_validation(SearchPathValidation::Strict);
+
+    assert!(matches!(
+        run(&strict, "/shaders/a.glsl"),
+        Err(Error::InvalidSearchPath(_))
+    ));
+}
+
+#[test]
+fn test_preprocessor_size_options() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/main.glsl", "#include \"a.glsl\"\nmain\n")
+        .insert("/shaders/a.glsl", "float a;\n");
+
+    let mut preprocessor = Preprocessor::new();
+
+    // The main file is 23 bytes, the included file 9 bytes.
+    preprocessor
+        .file_provider(files)
+        .max_file_size(23)
+        .max_total_size(32);
+
+    assert_eq!(
+        run(&preprocessor, "/shaders/main.glsl").unwrap(),
+        "float a;\nmain\n"
+    );
+
+    if let Err(Error::SizeLimit(err)) =
+        run(preprocessor.clone().max_file_size(22), "/shaders/main.glsl")
+    {
+        assert_eq!(err.path(), Path::new("/shaders/main.glsl"));
+        assert_eq!(err.limit(), SizeLimit::File);
+        assert_eq!(err.max(), 22);
+    } else {
+        panic!("expected a size limit error");
+    }
+
+    if let Err(Error::SizeLimit(err)) = run(
+        preprocessor.clone().max_total_size(31),
+        "/shaders/main.glsl",
+    ) {
+        assert_eq!(err.path(), Path::new("/shaders/a.glsl"));
+        assert_eq!(err.limit(), SizeLimit::Total);
+        assert_eq!(err.max(), 31);
+    } else {
+        panic!("expected a size limit error");
+    }
+}
+
+#[test]
+fn test_preprocessor_cancellation() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/main.glsl", "#include \"a.glsl\"\nmain\n")
+        .insert("/shaders/a.glsl", "a\n");
+
+    let token = CancellationToken::new();
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor
+        .file_provider(files)
+        .cancellation(token.clone());
+
+    assert_eq!(
+        run(&preprocessor, "/shaders/main.glsl").unwrap(),
+        "a\nmain\n"
+    );
+
+    // The token is shared with the configuration, so cancelling it stops later runs.
+    token.cancel();
+
+    assert!(matches!(
+        run(&preprocessor, "/shaders/main.glsl"),
+        Err(Error::Cancelled)
+    ));
+}
+
+#[test]
+fn test_preprocessor_concurrency() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/main.glsl",
+            "#include \"a.glsl\"\n#include \"b.glsl\"\nmain\n",
+        )
+        .insert("/shaders/a.glsl", "#include \"b.glsl\"\na\n")
+        .insert("/shaders/b.glsl", "#pragma once\nb\n");
+
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor
+        .file_provider(files)
+        .concurrency(Concurrency::SingleThreaded);
+
+    assert_eq!(
+        run(&preprocessor, "/shaders/main.glsl").unwrap(),
+        "b\na\nmain\n"
+    );
+
+    let concurrencies: &[Concurrency] = &[
+        #[cfg(feature = "threadpool")]
+        Concurrency::Pool(ThreadPool::new(2)),
+        #[cfg(feature = "rayon")]
+        Concurrency::Rayon,
+    ];
+
+    for concurrency in concurrencies {
+        assert_eq!(
+            run(
+                preprocessor.clone().concurrency(concurrency.clone()),
+                "/shaders/main.glsl"
+            )
+            .unwrap(),
+            "b\na\nmain\n",
+            "{:?}",
+            concurrency
+        );
+    }
+}
+
+#[test]
+fn test_preprocessor_cache() {
+    let files = Arc::new(EditableFs::default());
+
+    files.edit("/shaders/main.glsl", "#include \"a.glsl\"\nmain\n");
+    files.edit("/shaders/a.glsl", "a\n");
+
+    let cache = PreprocessCache::new();
+    let options = Options {
+        file_provider: files.clone(),
+        ..Options::default()
+    };
+    let mut preprocessor = Preprocessor::with_options(SearchPaths::new(), options);
+
+    preprocessor.cache(cache.clone());
+
+    assert_eq!(
+        run(&preprocessor, "/shaders/main.glsl").unwrap(),
+        "a\nmain\n"
+    );
+    assert_eq!(files.take_reads().len(), 2);
+    assert_eq!(cache.len(), 2);
+
+    // The second run reuses the cached files.
+    assert_eq!(
+        run(&preprocessor, "/shaders/main.glsl").unwrap(),
+        "a\nmain\n"
+    );
+    assert!(files.take_reads().is_empty());
+}
+
+#[test]
+fn test_preprocess_tee_sink() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/a.glsl", "a\n#include \"b.glsl\"\na\n")
+        .insert("/shaders/b.glsl", "bbbbbbbb");
+
+    let options = Options {
+        file_provider: Arc::new(files.clone()),
+        ..Options::default()
+    };
+    let (output, counts) = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        TeeSink::new(String::new(), CountingSink::default()),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap()
+    .into_inner();
+
+    assert_eq!(output, "a\nbbbbbbbb\na\n");
+    assert_eq!(counts.total().bytes(), output.len());
+    assert_eq!(counts.total().lines(), 3);
+
+    // The second sink still receives the chunk that the first fails on.
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let writer = BoundedWriter {
+        written: 0,
+        capacity: 4,
+    };
+    let res = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        TeeSink::new(IoSink::new(writer), CountingSink::default()),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::Sink(err)) = res {
+        match err
+            .downcast_ref::<TeeError<io::Error, Infallible>>()
+            .unwrap()
+        {
+            TeeError::First(err) => assert_eq!(err.kind(), io::ErrorKind::WriteZero),
+            TeeError::Second(_) => panic!("expected an error of the first sink"),
+        }
+    } else {
+        panic!("expected sink error");
+    }
+}
+
+#[test]
+fn test_preprocess_file_not_found_include_chain() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/a.glsl", "a\n#include \"b.glsl\"\n")
+        .insert("/shaders/b.glsl", "#include \"c.glsl\"\n")
+        .insert("/shaders/c.glsl", "c\nc\n#include \"missing.glsl\"\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::FileNotFound(err)) = res {
+        assert_eq!(err.source_file(), Path::new("/shaders/c.glsl"));
+        assert_eq!(err.line_number(), 3);
+        assert_eq!(
+            err.include_chain(),
+            &[
+                (PathBuf::from("/shaders/a.glsl"), 2),
+                (PathBuf::from("/shaders/b.glsl"), 1)
+            ]
+        );
+    } else {
+        panic!("expected a file not found error");
+    }
+}
+
+#[test]
+fn test_parse_error_render() {
+    let mut files = MemoryFs::new();
+
+    files.insert("/shaders/a.glsl", "// ü\n#include\t<ö/a.glsl> x\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::Parse(err)) = res {
+        assert_eq!(
+            format!("{}\n", err.render(false)),
+            include_str!("expected_render_parse_error.txt")
+        );
+        assert!(err.render(true).contains("\x1b[1;31m^\x1b[0m"));
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_file_not_found_error_render() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/a.glsl", "a\n#include \"b.glsl\"\n")
+        .insert("/shaders/b.glsl", "b\n#include  \"ü/missing.glsl\"  \n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::FileNotFound(err)) = res {
+        assert_eq!(
+            format!("{}\n", err.render(false)),
+            include_str!("expected_render_file_not_found.txt")
+        );
+    } else {
+        panic!("expected a file not found error");
+    }
+}
+
+/// A [FileProvider] that takes a while to read any file named `slow.glsl`, and records in `events`
+/// when it has finished reading.
+#[derive(Debug)]
+struct SlowFs {
+    files: MemoryFs,
+    events: Arc<Mutex<Vec<String>>>,
+}
+
+impl FileProvider for SlowFs {
+    fn read(&self, path: &Path) -> Result<String, io::Error> {
+        if path.ends_with("slow.glsl") {
+            thread::sleep(Duration::from_millis(200));
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("read {}", path.display()));
+        }
+
+        self.files.read(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.exists(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, io::Error> {
+        self.files.canonicalize(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, io::Error> {
+        self.files.read_dir(path)
+    }
+}
+
+/// Collects the output, and records in `events` when a chunk is sunk.
+struct EventSink {
+    output: String,
+    events: Arc<Mutex<Vec<String>>>,
+}
+
+impl OutputSink for EventSink {
+    type Error = Infallible;
+
+    fn sink(&mut self, chunk: &str) -> Result<(), Infallible> {
+        self.output.push_str(chunk);
+
+        Ok(())
+    }
+
+    fn sink_source_mapped(
+        &mut self,
+        source_mapped_chunk: SourceMappedChunk,
+    ) -> Result<(), Infallible> {
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("sink {:?}", source_mapped_chunk.text()));
+        self.output.push_str(source_mapped_chunk.text());
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_preprocess_streams_while_loading() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/main.glsl",
+            "main\n#include \"slow.glsl\"\n#include \"a.glsl\"\n",
+        )
+        .insert("/shaders/slow.glsl", "slow\n")
+        .insert("/shaders/a.glsl", "a\n");
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let options = Options {
+        file_provider: Arc::new(SlowFs {
+            files,
+            events: events.clone(),
+        }),
+        ..Options::default()
+    };
+    let sink = EventSink {
+        output: String::new(),
+        events: events.clone(),
+    };
+
+    let sink = preprocess_with_options(
+        "/shaders/main.glsl",
+        SearchPaths::new(),
+        options,
+        sink,
+        &mut TestPathTracker::new(),
+    )
+    .unwrap();
+
+    assert_eq!(sink.output, "main\nslow\na\n");
+
+    // The output that precedes `slow.glsl` is emitted while it is still being read.
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            "sink \"main\\n\"",
+            "read /shaders/slow.glsl",
+            "sink \"slow\\n\"",
+            "sink \"a\\n\"",
+        ]
+    );
+}
+
+/// A [ProgressObserver] that records every [LoadProgress], and panics after the second one if
+/// `panic` is set.
+#[derive(Default, Debug)]
+struct ProgressLog {
+    events: Mutex<Vec<(LoadEvent, PathBuf, usize, usize)>>,
+    panic: bool,
+}
+
+impl ProgressObserver for ProgressLog {
+    fn progress(&self, progress: LoadProgress) {
+        let mut events = self.events.lock().unwrap();
+
+        events.push((
+            progress.event(),
+            progress.path().to_path_buf(),
+            progress.discovered(),
+            progress.loaded(),
+        ));
+
+        if self.panic && events.len() == 2 {
+            drop(events);
+
+            panic!("observer panicked");
+        }
+    }
+}
+
+#[test]
+fn test_preprocess_progress() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/main.glsl",
+            "#include \"a.glsl\"\n#include \"b.glsl\"\n",
+        )
+        .insert("/shaders/a.glsl", "#include \"b.glsl\"\na\n")
+        .insert("/shaders/b.glsl", "#pragma once\nb\n");
+
+    let log = Arc::new(ProgressLog::default());
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor.file_provider(files).progress(log.clone());
+
+    let output = run(&preprocessor, "/shaders/main.glsl").unwrap();
+
+    let events = log.events.lock().unwrap();
+    let path = |path: &str| PathBuf::from(path);
+
+    // The files are loaded concurrently, so only the events of the entry point come in a fixed
+    // order.
+    assert_eq!(events.len(), 6);
+    assert_eq!(
+        events[..2],
+        [
+            (LoadEvent::Discovered, path("/shaders/main.glsl"), 1, 0),
+            (LoadEvent::Loaded, path("/shaders/main.glsl"), 1, 1),
+        ]
+    );
+
+    for file in ["/shaders/a.glsl", "/shaders/b.glsl"] {
+        let position = |event: LoadEvent| {
+            events
+                .iter()
+                .position(|(e, p, _, _)| *e == event && *p == path(file))
+                .unwrap()
+        };
+
+        assert!(position(LoadEvent::Discovered) < position(LoadEvent::Loaded));
+    }
+
+    assert_eq!(events.last().map(|event| (event.2, event.3)), Some((3, 3)));
+
+    // A panicking observer does not break preprocessing.
+    preprocessor.progress(ProgressLog {
+        panic: true,
+        ..ProgressLog::default()
+    });
+
+    assert_eq!(run(&preprocessor, "/shaders/main.glsl").unwrap(), output);
+}
+
+#[test]
+fn test_preprocess_cancellation() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/main.glsl",
+            "main\n#include \"a/slow.glsl\"\n#include \"b/slow.glsl\"\n#include \"c/slow.glsl\"\n\
+             #include \"d/slow.glsl\"\n",
+        )
+        .insert("/shaders/a/slow.glsl", "a\n")
+        .insert("/shaders/b/slow.glsl", "b\n")
+        .insert("/shaders/c/slow.glsl", "c\n")
+        .insert("/shaders/d/slow.glsl", "d\n");
+
+    let token = CancellationToken::new();
+    let options = Options {
+        file_provider: Arc::new(SlowFs {
+            files,
+            events: Arc::new(Mutex::new(Vec::new())),
+        }),
+        cancellation: Some(token.clone()),
+        collect_errors: true,
+        ..Options::default()
+    };
+
+    let canceller = {
+        let token = token.clone();
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            token.cancel();
+        })
+    };
+
+    let start = SystemTime::now();
+    let err = preprocess_with_options(
+        "/shaders/main.glsl",
+        SearchPaths::new(),
+        options.clone(),
+        String::new(),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap_err();
+
+    canceller.join().unwrap();
+
+    // The run does not wait for the files that are still being read, other than a file it reads
+    // itself if it is single-threaded.
+    assert!(start.elapsed().unwrap() < Duration::from_millis(400));
+    assert!(matches!(err, Error::Cancelled));
+    assert_eq!(err.kind(), ErrorKind::Cancelled);
+    assert_eq!(err.path(), None);
+
+    // A run with a token that is already cancelled does not start.
+    let err = preprocess_with_options(
+        "/shaders/main.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap_err();
+
+    assert!(matches!(err, Error::Cancelled));
+    assert_eq!(err.to_string(), "preprocessing was cancelled");
+}
+
+#[test]
+fn test_preprocess_concurrency() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let mut fixtures: Vec<PathBuf> = std::fs::read_dir(base_path.join("tests"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+
+    fixtures.sort();
+
+    let concurrencies: &[Concurrency] = &[
+        #[cfg(feature = "threadpool")]
+        Concurrency::NewPool,
+        #[cfg(feature = "threadpool")]
+        Concurrency::Pool(ThreadPool::new(2)),
+        #[cfg(feature = "rayon")]
+        Concurrency::Rayon,
+    ];
+    let run = |entry_point: &Path, concurrency: Concurrency| {
+        let mut search_paths = SearchPaths::new();
+
+        search_paths.push_base_path(&cargo_manifest_dir);
+
+        let options = Options {
+            concurrency,
+            ..Options::default()
+        };
+        let mut path_tracker = TestPathTracker::new();
+        let result = preprocess_with_options(
+            entry_point,
+            search_paths,
+            options,
+            String::new(),
+            &mut path_tracker,
+        );
+        let mut paths: Vec<String> = path_tracker.paths.into_iter().collect();
+
+        paths.sort();
+
+        // The errors are compared through their `Debug` representation.
+        (format!("{:?}", result), paths, path_tracker.warnings)
+    };
+    let mut compared = 0;
+
+    for fixture in fixtures {
+        for entry_point in ["a.txt", "main.txt", "main.glsl"] {
+            let entry_point = fixture.join(entry_point);
+
+            if !entry_point.exists() {
+                continue;
+            }
+
+            let expected = run(&entry_point, Concurrency::SingleThreaded);
+
+            for concurrency in concurrencies {
+                assert_eq!(
+                    run(&entry_point, concurrency.clone()),
+                    expected,
+                    "{} ({:?})",
+                    entry_point.display(),
+                    concurrency
+                );
+            }
+
+            compared += 1;
+        }
+    }
+
+    assert!(compared > 30);
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_preprocess_rayon_single_thread_pool() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/main.glsl", "#include \"a.glsl\"\nmain\n")
+        .insert("/shaders/a.glsl", "#include \"b.glsl\"\na\n")
+        .insert("/shaders/b.glsl", "b\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        concurrency: Concurrency::Rayon,
+        ..Options::default()
+    };
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap();
+
+    // The only thread of the pool runs the jobs that load the files while it waits for them.
+    let output = pool
+        .install(|| {
+            preprocess_with_options(
+                "/shaders/main.glsl",
+                SearchPaths::new(),
+                options,
+                String::new(),
+                &mut TestPathTracker::new(),
+            )
+        })
+        .unwrap();
+
+    assert_eq!(output, "b\na\nmain\n");
+}
+
+/// A [FileProvider] that reads from the file system, and records the files that are read.
+#[cfg(feature = "tokio")]
+#[derive(Default, Debug)]
+struct ReadLog {
+    reads: Mutex<Vec<PathBuf>>,
+}
+
+#[cfg(feature = "tokio")]
+impl FileProvider for ReadLog {
+    fn read(&self, path: &Path) -> Result<String, io::Error> {
+        self.reads.lock().unwrap().push(path.to_path_buf());
+
+        include_preprocessor::StdFs.read(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        include_preprocessor::StdFs.exists(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, io::Error> {
+        include_preprocessor::StdFs.canonicalize(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, io::Error> {
+        include_preprocessor::StdFs.read_dir(path)
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_preprocess_async() {
+    use include_preprocessor::preprocess_async;
+    use tokio::sync::Semaphore;
+
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let read_log = Arc::new(ReadLog::default());
+    let options = Options {
+        file_provider: read_log.clone(),
+        ..Options::default()
+    };
+    let semaphore = Arc::new(Semaphore::new(2));
+    let mut path_tracker = TestPathTracker::new();
+    let output = preprocess_async(
+        base_path.join("tests/valid/a.txt"),
+        search_paths.clone(),
+        options,
+        semaphore.clone(),
+        String::new(),
+        &mut path_tracker,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(output, include_str!("expected.txt"));
+
+    for file in ["a.txt", "b.txt", "c.txt"] {
+        let path = base_path.join("tests/valid").join(file);
+
+        assert!(path_tracker.paths.contains(path.to_str().unwrap()));
+    }
+
+    // The files were read with `tokio::fs`, rather than through the file provider.
+    assert!(read_log.reads.lock().unwrap().is_empty());
+
+    // Errors are the same as for the synchronous path.
+    let entry_point = base_path.join("tests/include_cycle/a.txt");
+    let err = preprocess_async(
+        &entry_point,
+        search_paths.clone(),
+        Options::default(),
+        semaphore,
+        String::new(),
+        &mut TestPathTracker::new(),
+    )
+    .await
+    .unwrap_err();
+    let expected = preprocess(
+        &entry_point,
+        search_paths,
+        String::new(),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap_err();
+
+    assert_eq!(format!("{:?}", err), format!("{:?}", expected));
+}
+
+/// A [FileProvider] that fails to read any file named `unreadable.glsl`, and to canonicalize any
+/// file named `broken.glsl`.
+#[derive(Debug)]
+struct FaultyFs(MemoryFs);
+
+impl FileProvider for FaultyFs {
+    fn read(&self, path: &Path) -> Result<String, io::Error> {
+        if path.ends_with("unreadable.glsl") {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "permission denied",
+            ))
+        } else {
+            self.0.read(path)
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.0.exists(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, io::Error> {
+        if path.ends_with("broken.glsl") {
+            Err(io::Error::other("broken link"))
+        } else {
+            self.0.canonicalize(path)
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, io::Error> {
+        self.0.read_dir(path)
+    }
+}
+
+#[test]
+fn test_preprocess_file_access_error() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/main.glsl",
+            "main\n\n\n#include \"unreadable.glsl\"\n",
+        )
+        .insert("/shaders/unreadable.glsl", "unreadable\n");
+
+    let options = Options {
+        file_provider: Arc::new(FaultyFs(files)),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/main.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::IO(err)) = res {
+        assert_eq!(err.path(), Path::new("/shaders/unreadable.glsl"));
+        assert_eq!(err.operation(), FileOperation::Read);
+        assert_eq!(
+            err.included_from(),
+            Some((Path::new("/shaders/main.glsl"), 4))
+        );
+        assert_eq!(
+            err.to_string(),
+            "failed to read `/shaders/unreadable.glsl` (included from `/shaders/main.glsl:4`): \
+             permission denied"
+        );
+    } else {
+        panic!("expected an IO error");
+    }
+}
+
+#[test]
+fn test_preprocess_file_access_error_canonicalize() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/main.glsl", "#include \"broken.glsl\"\n")
+        .insert("/shaders/broken.glsl", "broken\n");
+
+    let options = Options {
+        file_provider: Arc::new(FaultyFs(files)),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/main.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::IO(err)) = res {
+        assert_eq!(err.path(), Path::new("/shaders/broken.glsl"));
+        assert_eq!(err.operation(), FileOperation::Canonicalize);
+        assert_eq!(
+            err.included_from(),
+            Some((Path::new("/shaders/main.glsl"), 1))
+        );
+    } else {
+        panic!("expected an IO error");
+    }
+}
+
+/// A [FileProvider] that reports a size of 1 MiB for any file named `huge.glsl`, whatever its
+/// actual size.
+#[derive(Debug)]
+struct SizedFs(MemoryFs);
+
+impl FileProvider for SizedFs {
+    fn read(&self, path: &Path) -> Result<String, io::Error> {
+        self.0.read(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.0.exists(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, io::Error> {
+        self.0.canonicalize(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, io::Error> {
+        self.0.read_dir(path)
+    }
+
+    fn modified(&self, path: &Path) -> Option<(SystemTime, u64)> {
+        let size = if path.ends_with("huge.glsl") {
+            1 << 20
+        } else {
+            self.0.read(path).ok()?.len() as u64
+        };
+
+        Some((SystemTime::UNIX_EPOCH, size))
+    }
+}
+
+#[test]
+fn test_preprocess_size_limit() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/main.glsl",
+            "main
+#include \"a.glsl\"
+#include \"b.glsl\"
+#include \"a.glsl\"
+",
+        )
+        .insert("/shaders/a.glsl", "float a;\n")
+        .insert("/shaders/b.glsl", "float b;\nfloat bb;\n")
+        .insert("/shaders/uses_b.glsl", "#include \"b.glsl\"\n")
+        .insert("/shaders/huge.glsl", "float huge;\n")
+        .insert("/shaders/uses_huge.glsl", "#include \"huge.glsl\"\n");
+
+    let preprocess_with = |entry: &str, max_file_size, max_total_size| {
+        let options = Options {
+            file_provider: Arc::new(SizedFs(files.clone())),
+            max_file_size,
+            max_total_size,
+            ..Options::default()
+        };
+
+        preprocess_with_options(
+            entry,
+            SearchPaths::new(),
+            options,
+            String::new(),
+            &mut TestPathTracker::new(),
+        )
+    };
+
+    // The main file is 59 bytes, and every file counts once.
+    assert_eq!(
+        preprocess_with("/shaders/main.glsl", Some(59), Some(87)).unwrap(),
+        "main\nfloat a;\nfloat b;\nfloat bb;\nfloat a;\n"
+    );
+
+    match preprocess_with("/shaders/uses_b.glsl", Some(18), None) {
+        Err(Error::SizeLimit(err)) => {
+            assert_eq!(err.path(), Path::new("/shaders/b.glsl"));
+            assert_eq!(err.limit(), SizeLimit::File);
+            assert_eq!(err.size(), 19);
+            assert_eq!(err.max(), 18);
+            assert_eq!(
+                err.included_from(),
+                Some((Path::new("/shaders/uses_b.glsl"), 1))
+            );
+        }
+        res => panic!("expected a size limit error, got {:?}", res),
+    }
+
+    match preprocess_with("/shaders/main.glsl", None, Some(80)) {
+        Err(Error::SizeLimit(err)) => {
+            assert_eq!(err.limit(), SizeLimit::Total);
+            assert!(err.size() > 80);
+            assert_eq!(err.max(), 80);
+        }
+        res => panic!("expected a size limit error, got {:?}", res),
+    }
+
+    // The size that the file provider reports is checked before the file is read.
+    let err = preprocess_with("/shaders/uses_huge.glsl", Some(1000), None).unwrap_err();
+
+    assert_eq!(err.kind(), ErrorKind::SizeLimit);
+    assert_eq!(err.path(), Some(Path::new("/shaders/huge.glsl")));
+    assert_eq!(err.line(), None);
+
+    if let Error::SizeLimit(err) = err {
+        assert_eq!(
+            err.to_string(),
+            "`/shaders/huge.glsl` (included from `/shaders/uses_huge.glsl:1`) is 1048576 bytes, \
+             which exceeds the maximum file size of 1000 bytes"
+        );
+    } else {
+        panic!("expected a size limit error");
+    }
+}
+
+#[test]
+fn test_preprocess_error_line_number_first_line() {
+    let mut files = MemoryFs::new();
+
+    files.insert("/shaders/a.glsl", "#error first\na\nb\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::Directive(err)) = res {
+        assert_eq!(err.line_number(), 1);
+        assert_eq!(err.line_number_zero_based(), 0);
+    } else {
+        panic!("expected a directive error");
+    }
+}
+
+#[test]
+fn test_preprocess_error_line_number_last_line() {
+    let mut files = MemoryFs::new();
+
+    // The last line is not terminated by a newline.
+    files.insert("/shaders/a.glsl", "a\nb\n#include \"missing.glsl\"");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        ..Options::default()
+    };
+    let res = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    );
+
+    if let Err(Error::FileNotFound(err)) = res {
+        assert_eq!(err.line_number(), 3);
+        assert_eq!(err.line_number_zero_based(), 2);
+    } else {
+        panic!("expected a file not found error");
+    }
+}
+
+#[test]
+fn test_error_kind() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/a.glsl", "a\n#include \"b.glsl\"\n")
+        .insert("/shaders/b.glsl", "#include \"missing.glsl\"\n")
+        .insert("/shaders/cycle.glsl", "\n#include \"cycle.glsl\"\n");
+
+    let preprocess_file = |path: &str| {
+        let options = Options {
+            file_provider: Arc::new(files.clone()),
+            ..Options::default()
+        };
+
+        preprocess_with_options(
+            path,
+            SearchPaths::new(),
+            options,
+            String::new(),
+            &mut TestPathTracker::new(),
+        )
+        .unwrap_err()
+    };
+
+    let err = preprocess_file("/shaders/a.glsl");
+
+    assert_eq!(err.kind(), ErrorKind::FileNotFound);
+    assert_eq!(err.path(), Some(Path::new("/shaders/b.glsl")));
+    assert_eq!(err.line(), Some(1));
+
+    let err = preprocess_file("/shaders/cycle.glsl");
+
+    assert_eq!(err.kind(), ErrorKind::Cycle);
+    assert_eq!(err.path(), Some(Path::new("/shaders/cycle.glsl")));
+    assert_eq!(err.line(), Some(2));
+
+    let err = preprocess_file("/shaders/missing.glsl");
+
+    assert_eq!(err.kind(), ErrorKind::Io);
+    assert_eq!(err.path(), Some(Path::new("/shaders/missing.glsl")));
+    assert_eq!(err.line(), None);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_error_serialize() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/a.glsl",
+            "a\n#include \"b.glsl\"\n#include <b.glsl\n",
+        )
+        .insert("/shaders/b.glsl", "b\n#include \"missing.glsl\"\n");
+
+    let options = Options {
+        file_provider: Arc::new(files),
+        collect_errors: true,
+        ..Options::default()
+    };
+    let err = preprocess_with_options(
+        "/shaders/a.glsl",
+        SearchPaths::new(),
+        options,
+        String::new(),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap_err();
+
+    assert_eq!(
+        format!("{}\n", serde_json::to_string_pretty(&err).unwrap()),
+        include_str!("expected_error_serialize.json")
+    );
+}
+
+/// A decoded Source Map V3 segment: the output column and the `(source, line, column)` origin.
+#[cfg(feature = "source-map")]
+type DecodedSegment = (i64, Option<(i64, i64, i64)>);
+
+/// Decodes the `mappings` of a Source Map V3 document into the segments of every line.
+#[cfg(feature = "source-map")]
+fn decode_mappings(mappings: &str) -> Vec<Vec<DecodedSegment>> {
+    const BASE64: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut origin = (0, 0, 0);
+
+    mappings
+        .split(';')
+        .map(|line| {
+            let mut column = 0;
+
+            line.split(',')
+                .filter(|segment| !segment.is_empty())
+                .map(|segment| {
+                    let mut fields = Vec::new();
+                    let mut value = 0;
+                    let mut shift = 0;
+
+                    for c in segment.chars() {
+                        let digit = BASE64.find(c).unwrap() as i64;
+
+                        value += (digit & 31) << shift;
+                        shift += 5;
+
+                        if digit & 32 == 0 {
+                            let magnitude = value >> 1;
+
+                            fields.push(if value & 1 == 1 {
+                                -magnitude
+                            } else {
+                                magnitude
+                            });
+                            value = 0;
+                            shift = 0;
+                        }
+                    }
+
+                    column += fields[0];
+
+                    if fields.len() == 4 {
+                        origin = (
+                            origin.0 + fields[1],
+                            origin.1 + fields[2],
+                            origin.2 + fields[3],
+                        );
+
+                        (column, Some(origin))
+                    } else {
+                        (column, None)
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(feature = "source-map")]
+#[test]
+fn test_source_map_to_json() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/source_map/a.txt");
+    let (output, source_map) = preprocess(
+        entry_point,
+        search_paths,
+        SourceMapBuilder::new(String::new()),
+        &mut TestPathTracker::new(),
+    )
+    .unwrap()
+    .finish();
+
+    assert_eq!(
+        output,
+        "float a = 2.0;\nfloat b;\nfloat c;\n  float b2;\nfloat d;\n"
+    );
+
+    let json: serde_json::Value = serde_json::from_str(&source_map.to_json()).unwrap();
+    let files = ["a.txt", "b.txt", "c.txt"];
+
+    assert_eq!(json["version"], 3);
+
+    for (index, file) in files.iter().enumerate() {
+        let path = base_path.join("tests/source_map").join(file);
+
+        assert_eq!(json["sources"][index], path.to_str().unwrap());
+        assert_eq!(
+            json["sourcesContent"][index],
+            std::fs::read_to_string(path).unwrap()
+        );
+    }
+
+    assert_eq!(json["sources"].as_array().unwrap().len(), 3);
+    assert_eq!(
+        decode_mappings(json["mappings"].as_str().unwrap()),
+        vec![
+            // The expansion of `SCALE` and the text that follows it map to their own positions.
+            vec![
+                (0, Some((0, 1, 0))),
+                (10, Some((0, 1, 10))),
+                (13, Some((0, 1, 15)))
+            ],
+            vec![(0, Some((1, 0, 0)))],
+            vec![(0, Some((2, 0, 0)))],
+            vec![(0, Some((1, 2, 0)))],
+            vec![(0, Some((0, 3, 0)))],
+            vec![],
+        ]
+    );
+}
+
+#[test]
+fn test_preprocess_closure_tracker() {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let mut search_paths = SearchPaths::new();
+
+    search_paths.push_base_path(&cargo_manifest_dir);
+
+    let entry_point = base_path.join("tests/valid/a.txt");
+    let mut tracked = Vec::new();
+    let mut tracker = |path: &Path, source: &str| tracked.push((path.to_path_buf(), source.len()));
+
+    let actual = preprocess(
+        &entry_point,
+        search_paths.clone(),
+        String::new(),
+        &mut tracker,
+    )
+    .unwrap();
+
+    assert_eq!(actual, include_str!("expected.txt"));
+
+    tracked.sort();
+
+    let expected: Vec<(PathBuf, usize)> = ["a.txt", "b.txt", "c.txt"]
+        .iter()
+        .map(|name| {
+            let path = base_path.join("tests/valid").join(name);
+            let len = std::fs::read_to_string(&path).unwrap().len();
+
+            (path, len)
+        })
+        .collect();
+
+    assert_eq!(tracked, expected);
+
+    // A closure can also be wrapped, e.g. by a DepfileTracker.
+    let mut paths = Vec::new();
+    let mut depfile_tracker =
+        DepfileTracker::new(|path: &Path, _: &str| paths.push(path.to_path_buf()));
+
+    preprocess(
+        &entry_point,
+        search_paths.clone(),
+        String::new(),
+        &mut depfile_tracker,
+    )
+    .unwrap();
+
+    let dependencies = depfile_tracker.dependencies().to_vec();
+
+    drop(depfile_tracker);
+    paths.sort();
+
+    assert_eq!(paths, dependencies);
+
+    assert_eq!(
+        preprocess_untracked(&entry_point, search_paths.clone(), String::new()).unwrap(),
+        actual
+    );
+    assert_eq!(
+        preprocess(&entry_point, search_paths, String::new(), &mut NoopTracker).unwrap(),
+        actual
+    );
+}
+
+#[derive(Default, Debug)]
+struct BindGroups {
+    directives: Mutex<Vec<(String, String, PathBuf, usize)>>,
+}
+
+impl DirectiveHandler for BindGroups {
+    fn handle(&self, directive: &CustomDirective) -> Result<DirectiveAction, String> {
+        self.directives.lock().unwrap().push((
+            directive.name().to_string(),
+            directive.argument().to_string(),
+            directive.source_file().to_path_buf(),
+            directive.line_number(),
+        ));
+
+        match directive.name() {
+            "bind_group" => Ok(DirectiveAction::Replace(format!(
+                "layout(set = {})",
+                directive.argument()
+            ))),
+            "drop" => Ok(DirectiveAction::Drop),
+            name => Err(format!("unknown custom directive `{}`", name)),
+        }
+    }
+}
+
+#[test]
+fn test_preprocess_directive_handler() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/main.glsl",
+            "#pragma ipp bind_group 2\n#include \"lib.glsl\"\n#pragma ipp drop\n#if 0\n#pragma ipp fail\n#endif\n#pragma ippx not custom\nfloat a;\n",
+        )
+        .insert("/shaders/lib.glsl", "#pragma ipp bind_group  3  \nfloat b;\n")
+        .insert("/shaders/fail.glsl", "float a;\n#pragma ipp fail\n")
+        .insert("/shaders/malformed.glsl", "#pragma ipp\n");
+
+    let handler = Arc::new(BindGroups::default());
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor
+        .file_provider(files.clone())
+        .directive_handler(handler.clone());
+
+    assert_eq!(
+        run(&preprocessor, "/shaders/main.glsl").unwrap(),
+        "layout(set = 2)\nlayout(set = 3)\nfloat b;\n#pragma ippx not custom\nfloat a;\n"
+    );
+    assert_eq!(
+        *handler.directives.lock().unwrap(),
+        [
+            (
+                "bind_group".to_string(),
+                "2".to_string(),
+                PathBuf::from("/shaders/main.glsl"),
+                1
+            ),
+            (
+                "bind_group".to_string(),
+                "3".to_string(),
+                PathBuf::from("/shaders/lib.glsl"),
+                1
+            ),
+            (
+                "drop".to_string(),
+                String::new(),
+                PathBuf::from("/shaders/main.glsl"),
+                3
+            ),
+        ]
+    );
+
+    match run(&preprocessor, "/shaders/fail.glsl") {
+        Err(Error::Parse(err)) => {
+            assert_eq!(err.message(), "unknown custom directive `fail`");
+            assert_eq!(err.line_number(), 2);
+        }
+        res => panic!("expected a parse error, got {:?}", res),
+    }
+
+    match run(&preprocessor, "/shaders/malformed.glsl") {
+        Err(Error::Parse(err)) => assert_eq!(err.message(), "malformed custom directive"),
+        res => panic!("expected a parse error, got {:?}", res),
+    }
+
+    // Dropped directives can be replaced by blank lines, and a different prefix can be used.
+    preprocessor
+        .blank_directive(DirectiveKind::Custom)
+        .directive_prefix("#pragma ippx");
+
+    assert_eq!(
+        run(&preprocessor, "/shaders/fail.glsl").unwrap(),
+        "float a;\n#pragma ipp fail\n"
+    );
+
+    preprocessor.directive_prefix("#pragma ipp");
+
+    assert_eq!(
+        run(&preprocessor, "/shaders/main.glsl").unwrap(),
+        "layout(set = 2)\nlayout(set = 3)\nfloat b;\n\n#pragma ippx not custom\nfloat a;\n"
+    );
+
+    // Without a handler, custom directives are treated as text.
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor.file_provider(files);
+
+    assert_eq!(
+        run(&preprocessor, "/shaders/fail.glsl").unwrap(),
+        "float a;\n#pragma ipp fail\n"
+    );
+}
+
+#[derive(Debug)]
+struct TemplateFilter;
+
+impl ContentFilter for TemplateFilter {
+    fn filter(&self, path: &Path, contents: String) -> io::Result<String> {
+        if !path.to_string_lossy().ends_with(".in") {
+            return Ok(contents);
+        }
+
+        if contents.contains("@MISSING@") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown placeholder `@MISSING@`",
+            ));
+        }
+
+        Ok(contents.replace("@TABLE@", "const float TABLE[2] = float[](0.5, 1.0);"))
+    }
+}
+
+#[test]
+fn test_preprocess_content_filter() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert(
+            "/shaders/main.glsl",
+            "#include \"table.glsl.in\"\nfloat a = TABLE[1];\n",
+        )
+        .insert("/shaders/table.glsl.in", "@TABLE@\n@X\n")
+        .insert(
+            "/shaders/broken.glsl",
+            "float b;\n#include \"broken.glsl.in\"\n",
+        )
+        .insert("/shaders/broken.glsl.in", "@MISSING@\n");
+
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor
+        .file_provider(files)
+        .content_filter(TemplateFilter);
+
+    let mut sources = Vec::new();
+    let output = preprocessor
+        .run(
+            "/shaders/main.glsl",
+            String::new(),
+            &mut |path: &Path, source: &str| sources.push((path.to_path_buf(), source.to_string())),
+        )
+        .unwrap();
+
+    assert_eq!(
+        output,
+        "const float TABLE[2] = float[](0.5, 1.0);\n@X\nfloat a = TABLE[1];\n"
+    );
+
+    sources.sort();
+
+    assert_eq!(
+        sources,
+        [
+            (
+                PathBuf::from("/shaders/main.glsl"),
+                "#include \"table.glsl.in\"\nfloat a = TABLE[1];\n".to_string()
+            ),
+            (
+                PathBuf::from("/shaders/table.glsl.in"),
+                "const float TABLE[2] = float[](0.5, 1.0);\n@X\n".to_string()
+            ),
+        ]
+    );
+
+    match run(&preprocessor, "/shaders/broken.glsl") {
+        Err(Error::IO(err)) => {
+            assert_eq!(err.path(), Path::new("/shaders/broken.glsl.in"));
+            assert_eq!(err.operation(), FileOperation::Filter);
+            assert_eq!(
+                err.included_from(),
+                Some((Path::new("/shaders/broken.glsl"), 2))
+            );
+            assert_eq!(
+                err.to_string(),
+                "failed to filter `/shaders/broken.glsl.in` (included from \
+                 `/shaders/broken.glsl:2`): unknown placeholder `@MISSING@`"
+            );
+        }
+        res => panic!("expected an IO error, got {:?}", res),
+    }
+}
+
+fn preprocess_encoding_test(name: &str) -> Result<String, Error> {
+    let cargo_manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let base_path: &Path = cargo_manifest_dir.as_ref();
+    let entry_point = base_path.join("tests/encoding").join(name);
+
+    preprocess(
+        entry_point,
+        SearchPaths::new(),
+        String::new(),
+        &mut TestPathTracker::new(),
+    )
+}
+
+fn encoding_error(name: &str) -> String {
+    match preprocess_encoding_test(name) {
+        Err(Error::IO(err)) => {
+            assert!(err.path().ends_with(name));
+            assert_eq!(err.operation(), FileOperation::Read);
+
+            err.io_error().to_string()
+        }
+        res => panic!("expected an IO error, got {:?}", res),
+    }
+}
+
+#[test]
+fn test_preprocess_utf8_bom() {
+    assert_eq!(
+        preprocess_encoding_test("utf8_bom.glsl").unwrap(),
+        "#version 450\nfloat a = 1.0; // ü\nvoid main() {}\n"
+    );
+    assert_eq!(
+        encoding_error("invalid_utf8_bom.glsl"),
+        "invalid UTF-8 sequence at byte offset 4"
+    );
+}
+
+#[cfg(feature = "encoding")]
+#[test]
+fn test_preprocess_utf16() {
+    assert_eq!(
+        preprocess_encoding_test("utf16le.glsl").unwrap(),
+        "#version 450\nfloat b = 1.0; // ü\nvoid main() {}\n"
+    );
+    assert_eq!(
+        encoding_error("invalid_utf16le.glsl"),
+        "invalid UTF-16LE sequence at byte offset 4"
+    );
+}
+
+#[cfg(not(feature = "encoding"))]
+#[test]
+fn test_preprocess_utf16_unsupported() {
+    assert_eq!(
+        encoding_error("utf16le.glsl"),
+        "UTF-16LE (detected from the byte order mark) is only supported with the `encoding` \
+         feature"
+    );
+}
+
+#[test]
+fn test_preprocess_many_files() {
+    const COUNT: usize = 2000;
+
+    let mut files = MemoryFs::new();
+    let mut main = String::new();
+    let mut expected = String::from("common\n");
+
+    files.insert("/shaders/common.glsl", "#pragma once\ncommon\n");
+
+    for index in 0..COUNT {
+        let name = format!("file_{}.glsl", index);
+
+        files.insert(
+            format!("/shaders/lib/{}", name),
+            format!(
+                "#pragma once\n#include \"../common.glsl\"\nfile {}\n",
+                index
+            ),
+        );
+        // Every file is included twice, and only emitted the first time.
+        main.push_str(&format!(
+            "#include \"lib/{0}\"\n#include \"lib/{0}\"\n",
+            name
+        ));
+        expected.push_str(&format!("file {}\n", index));
+    }
+
+    files.insert("/shaders/main.glsl", main);
+
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor.file_provider(files);
+
+    for concurrency in [Concurrency::default(), Concurrency::SingleThreaded] {
+        let mut path_tracker = TestPathTracker::new();
+
+        preprocessor.concurrency(concurrency);
+
+        let output = preprocessor
+            .run("/shaders/main.glsl", String::new(), &mut path_tracker)
+            .unwrap();
+
+        assert_eq!(output, expected);
+        assert_eq!(path_tracker.paths.len(), COUNT + 2);
+    }
+}
+
+#[test]
+fn test_preprocess_max_depth() {
+    let mut files = MemoryFs::new();
+
+    for depth in 0..5 {
+        files.insert(
+            format!("/shaders/depth_{}.glsl", depth),
+            format!("depth {}\n#include \"depth_{}.glsl\"\n", depth, depth + 1),
+        );
+    }
+
+    files.insert("/shaders/depth_5.glsl", "depth 5\n");
+
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor.file_provider(files).max_depth(5);
+
+    let output = run(&preprocessor, "/shaders/depth_0.glsl").unwrap();
+
+    assert_eq!(
+        output,
+        "depth 0\ndepth 1\ndepth 2\ndepth 3\ndepth 4\ndepth 5\n"
+    );
+
+    preprocessor.max_depth(4);
+
+    if let Err(Error::Parse(err)) = run(&preprocessor, "/shaders/depth_0.glsl") {
+        assert_eq!(err.source_file(), Path::new("/shaders/depth_4.glsl"));
+        assert_eq!(err.line_number(), 2);
+        assert!(err
+            .message()
+            .contains("maximum include depth of 4 exceeded"));
+    } else {
+        panic!("expected a parse error");
+    }
+}
+
+#[test]
+fn test_preprocess_cache_content_filter() {
+    let mut files = MemoryFs::new();
+
+    files
+        .insert("/shaders/main.glsl", "#include \"table.glsl.in\"\nmain\n")
+        .insert("/shaders/table.glsl.in", "@TABLE@\n");
+
+    let mut preprocessor = Preprocessor::new();
+
+    preprocessor
+        .file_provider(files)
+        .content_filter(TemplateFilter)
+        .cache(PreprocessCache::new());
+
+    for files_read in [2, 0] {
+        let (output, report) = preprocessor
+            .run_with_report("/shaders/main.glsl", String::new(), &mut NoopTracker)
+            .unwrap();
+
+        assert_eq!(output, "const float TABLE[2] = float[](0.5, 1.0);\nmain\n");
+        // The filtered source of a file without a modification time is still a cache hit.
+        assert_eq!(report.stats().files_read(), files_read);
+    }
+}
@@ -1,13 +1,19 @@
-#![feature(proc_macro_span, track_path)]
+#![feature(proc_macro_diagnostic, proc_macro_span, track_path)]
 
 use std::env;
 
-use include_preprocessor::{preprocess, SearchPaths, SourceTracker};
+use include_preprocessor::{preprocess_with_options, Options, SearchPaths, SourceTracker};
 use proc_macro::tracked_path;
-use proc_macro::{Literal, Span, TokenStream, TokenTree};
+use proc_macro::{Diagnostic, Level, Literal, Span, TokenStream, TokenTree};
 use std::path::Path;
 use syn::{parse_macro_input, LitStr};
 
+/// The maximum size of a single file that the macro includes.
+const MAX_FILE_SIZE: u64 = 16 << 20;
+
+/// The maximum combined size of the files that the macro includes.
+const MAX_TOTAL_SIZE: u64 = 64 << 20;
+
 #[proc_macro]
 pub fn include_str_ipp(input: TokenStream) -> TokenStream {
     let path = parse_macro_input!(input as LitStr);
@@ -25,8 +31,26 @@ pub fn include_str_ipp(input: TokenStream) -> TokenStream {
 
     let output = if source_join.is_file() {
         let buffer = String::new();
+        let options = Options {
+            max_file_size: Some(MAX_FILE_SIZE),
+            max_total_size: Some(MAX_TOTAL_SIZE),
+            ..Options::default()
+        };
 
-        preprocess(source_join, search_paths, buffer, &mut ProcMacroPathTracker).unwrap()
+        match preprocess_with_options(
+            source_join,
+            search_paths,
+            options,
+            buffer,
+            &mut ProcMacroPathTracker,
+        ) {
+            Ok(output) => output,
+            Err(err) => {
+                return syn::Error::new(path.span(), err.to_string())
+                    .to_compile_error()
+                    .into()
+            }
+        }
     } else {
         panic!("Entry (`{:?}`) point is not a file!", source_join);
     };
@@ -44,4 +68,29 @@ impl SourceTracker for ProcMacroPathTracker {
     fn track(&mut self, path: &Path, _source: &str) {
         tracked_path::path(path.to_str().expect("cannot track non-unicode path"));
     }
+
+    // A missing path is tracked as well, so that the macro is expanded again once it is created.
+    fn track_missing(&mut self, path: &Path) {
+        if let Some(path) = path.to_str() {
+            tracked_path::path(path);
+        }
+    }
+
+    fn warning(&mut self, message: &str, source_file: &Path, line_number: usize) {
+        Diagnostic::spanned(
+            Span::call_site(),
+            Level::Warning,
+            format!("{}:{}: {}", source_file.display(), line_number, message),
+        )
+        .emit();
+    }
+
+    fn note(&mut self, message: &str, source_file: &Path, line_number: usize) {
+        Diagnostic::spanned(
+            Span::call_site(),
+            Level::Note,
+            format!("{}:{}: {}", source_file.display(), line_number, message),
+        )
+        .emit();
+    }
 }